@@ -1,17 +1,15 @@
 use anyhow::Result;
 use criterion::{criterion_group, criterion_main, Criterion};
 use db_server::{
-    start_client_with_config, start_server_with_config, ClientConfig, CommandRequest, ServerConfig,
-    StorageConfig, YamuxCtrl,
+    start_client_with_config, start_server_with_config, ClientConfig, ClientMux, CommandRequest,
+    ServerConfig, StorageConfig,
 };
 use futures::StreamExt;
 
 use rand::prelude::SliceRandom;
 use std::time::Duration;
-use tokio::net::TcpStream;
 use tokio::runtime::Builder;
 use tokio::time;
-use tokio_rustls::client::TlsStream;
 use tracing::{info, span};
 
 use tracing_subscriber::{layer::SubscriberExt, prelude::*, EnvFilter};
@@ -29,12 +27,12 @@ async fn start_server() -> Result<()> {
     Ok(())
 }
 
-async fn connect() -> Result<YamuxCtrl<TlsStream<TcpStream>>> {
+async fn connect() -> Result<ClientMux> {
     let addr = "127.0.0.1:9999";
     let mut config: ClientConfig = toml::from_str(include_str!("../fixtures/client.conf"))?;
     config.general.addr = addr.into();
 
-    Ok(start_client_with_config(&config).await?)
+    start_client_with_config(&config).await
 }
 
 async fn start_subscribers(topic: &'static str) -> Result<()> {
@@ -4,8 +4,10 @@ fn main() {
     // 创建一个编译文件
     let mut config = prost_build::Config::new();
 
-    config.bytes(&["."]);
-    config.type_attribute(".", "#[derive(PartialOrd)]");
+    config.bytes(["."]);
+    // 只有需要排序的类型才 derive PartialOrd，prost 的 enum 已经自带了
+    config.type_attribute(".abi.Value", "#[derive(PartialOrd)]");
+    config.type_attribute(".abi.Kvpair", "#[derive(PartialOrd)]");
 
     config
         .out_dir("src/pb")
@@ -13,7 +15,7 @@ fn main() {
         .unwrap();
 
     Command::new("cargo")
-        .args(&["fmt", "--", "src/*.rs"])
+        .args(["fmt", "--", "src/*.rs"])
         .status()
         .expect("cargo fmt failed");
 
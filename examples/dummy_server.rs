@@ -27,9 +27,11 @@ async fn main() -> Result<()> {
             while let Some(Ok(msg)) = async_stream.next().await {
                 info!("Got a new command: {:?}", msg);
 
-                let mut response = CommandResponse::default();
-                response.status = 404;
-                response.message = "Not Found".to_string();
+                let response = CommandResponse {
+                    status: 404,
+                    message: "Not Found".to_string(),
+                    ..Default::default()
+                };
                 async_stream.send(response).await.unwrap();
             }
             info!("Client {:?} disconnected", addr)
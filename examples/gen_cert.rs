@@ -21,7 +21,7 @@ async fn main() -> Result<()> {
 
 fn create_ca() -> Result<CertPem> {
     let (cert, key) = generate_ca(
-        &["acme.inc"],
+        ["acme.inc"],
         "CN",
         "Acme Inc.",
         "Acme CA",
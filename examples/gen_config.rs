@@ -25,6 +25,19 @@ fn main() -> Result<()> {
             path: "/tmp/db-log".into(),
             rotation: RotationConfig::Daily,
         },
+        auth: None,
+        limits: Default::default(),
+        metrics: None,
+        tracing: Default::default(),
+        shutdown: Default::default(),
+        timeouts: Default::default(),
+        admission: Default::default(),
+        slowlog: Default::default(),
+        audit: None,
+        backup_dir: None,
+        restore: None,
+        listeners: vec![],
+        replication: Default::default(),
     };
 
     fs::write(
@@ -40,6 +53,11 @@ fn main() -> Result<()> {
             ca: Some(CA_CERT.into()),
             domain: "dbserver.acme.inc".into(),
         },
+        reconnect: Default::default(),
+        pool: Default::default(),
+        cache: None,
+        auth: None,
+        connector: Default::default(),
     };
 
     fs::write(
@@ -1,11 +1,10 @@
 use anyhow::Result;
-use db_server::{CommandRequest, MemTable, Service, ServiceInner, SledDb};
+use db_server::{CommandRequest, MemTable, Service, ServiceInner};
 use futures::prelude::*;
 use prost::Message;
 use tokio::net::TcpListener;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use tracing::info;
-use tracing_subscriber;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use async_prost::AsyncProstStream;
-use db_server::{CommandRequest, CommandResponse, MemTable, Service, ServiceInner, SledDb};
+use db_server::{CommandRequest, CommandResponse, Service, ServiceInner, SledDb};
 use futures::prelude::*;
 use tokio::net::TcpListener;
 use tracing::info;
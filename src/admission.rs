@@ -0,0 +1,148 @@
+//! 准入控制：连接数、每个连接的 stream 数和正在处理的请求数都有上限，超过时直接回复 503，不排队
+//!
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::metrics::metrics;
+use crate::{KvError, RuntimeSettings};
+
+/// 整个服务器共享的上限，每个连接的 stream 上限在连接建立时单独创建
+#[derive(Clone, Default)]
+pub struct Admission {
+    connections: Limit,
+    requests: Limit,
+    settings: Arc<RuntimeSettings>,
+}
+
+impl Admission {
+    /// 上限来自运行时配置，修改后立即生效
+    pub fn new(settings: Arc<RuntimeSettings>) -> Self {
+        Self {
+            connections: Limit::shared("connections", settings.max_connections.clone()),
+            requests: Limit::shared("requests", settings.max_in_flight.clone()),
+            settings,
+        }
+    }
+
+    /// 新连接占用一个名额，连接关闭时释放 Permit
+    pub fn connection(&self) -> Result<Permit, KvError> {
+        self.connections.try_acquire()
+    }
+
+    /// 一个新连接上的 stream 上限
+    pub fn streams(&self) -> Limit {
+        let max = self
+            .settings
+            .max_streams_per_connection
+            .load(Ordering::Relaxed);
+        Limit::new("streams", max)
+    }
+
+    /// 运行时配置
+    pub fn settings(&self) -> &RuntimeSettings {
+        &self.settings
+    }
+
+    /// 所有连接正在处理的请求的上限
+    pub fn requests(&self) -> Limit {
+        self.requests.clone()
+    }
+}
+
+/// 计数上限，clone 之后共享名额。默认不限制
+#[derive(Clone, Default)]
+pub struct Limit {
+    name: &'static str,
+    // 为 0 时不限制，可以随时修改
+    max: Arc<AtomicU64>,
+    used: Arc<AtomicU64>,
+}
+
+impl Limit {
+    /// name 用于错误信息和监控指标，max 为 0 表示不限制
+    pub fn new(name: &'static str, max: u64) -> Self {
+        Self::shared(name, Arc::new(AtomicU64::new(max)))
+    }
+
+    // 上限和运行时配置共享
+    pub(crate) fn shared(name: &'static str, max: Arc<AtomicU64>) -> Self {
+        Self {
+            name,
+            max,
+            used: Default::default(),
+        }
+    }
+
+    /// 占用一个名额，用完时返回 Unavailable 并记录到监控指标
+    pub fn try_acquire(&self) -> Result<Permit, KvError> {
+        let max = self.max.load(Ordering::Relaxed);
+        let used = self.used.fetch_add(1, Ordering::AcqRel) + 1;
+        let permit = Permit {
+            used: self.used.clone(),
+        };
+        if max > 0 && used > max {
+            drop(permit);
+            metrics().record_rejection(self.name);
+            return Err(KvError::Unavailable(format!(
+                "Too many concurrent {} (limit {})",
+                self.name, max
+            )));
+        }
+        Ok(permit)
+    }
+
+    /// 当前占用的名额
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Acquire)
+    }
+}
+
+/// 占用的名额，drop 时归还
+pub struct Permit {
+    used: Arc<AtomicU64>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.used.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limit_should_reject_when_exhausted() {
+        let limit = Limit::new("connections", 1);
+        let permit = limit.try_acquire().unwrap();
+        let err = limit.try_acquire().err().unwrap();
+        assert!(err.to_string().contains("Too many concurrent connections"));
+        assert!(err.is_retryable());
+
+        drop(permit);
+        assert_eq!(limit.used(), 0);
+        assert!(limit.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn zero_should_mean_unlimited() {
+        let limit = Limit::new("requests", 0);
+        let permits: Vec<_> = (0..100).map(|_| limit.try_acquire().unwrap()).collect();
+        assert_eq!(limit.used(), 100);
+        drop(permits);
+    }
+
+    #[test]
+    fn shared_limit_should_follow_settings() {
+        let settings = Arc::new(RuntimeSettings::default());
+        settings.max_connections.store(1, Ordering::Relaxed);
+        let admission = Admission::new(settings.clone());
+        let _permit = admission.connection().unwrap();
+        assert!(admission.connection().is_err());
+
+        settings.max_connections.store(2, Ordering::Relaxed);
+        assert!(admission.connection().is_ok());
+    }
+}
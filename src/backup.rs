@@ -0,0 +1,118 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand};
+use db_server::{
+    restore_snapshot, verify_snapshot, ClientConfig, KvClient, ServerConfig, SledDb,
+    SnapshotManifest, StorageConfig,
+};
+use tokio::fs::{self, File};
+use tokio::io::BufWriter;
+
+/// 备份和恢复 KV server 的数据
+///
+/// 备份文件和存储后端无关：SledDb 的备份可以恢复到 MemTable，反过来也一样。
+#[derive(Parser, Debug)]
+#[command(name = "kv-backup", version)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// 在线备份运行中的服务器，保存到本地文件后校验一遍
+    Backup {
+        /// 客户端配置文件
+        #[arg(short, long)]
+        config: Option<PathBuf>,
+
+        /// 服务器地址，覆盖配置文件中的设置
+        #[arg(long)]
+        addr: Option<String>,
+
+        /// 备份文件
+        file: PathBuf,
+    },
+    /// 校验备份文件，输出其中的 table 和 key 个数
+    Verify {
+        /// 备份文件
+        file: PathBuf,
+    },
+    /// 离线恢复到服务器配置的存储中，服务器需要先停止。
+    /// MemTable 没有持久化，在服务器配置中设置 restore 在启动时恢复
+    Restore {
+        /// 服务器配置文件
+        #[arg(short, long, conflicts_with = "sled")]
+        config: Option<PathBuf>,
+
+        /// 恢复到这个目录下的 sled 数据库，不使用配置文件
+        #[arg(long)]
+        sled: Option<PathBuf>,
+
+        /// 备份文件
+        file: PathBuf,
+    },
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    match Args::parse().command {
+        Command::Backup { config, addr, file } => backup(config, addr, &file).await,
+        Command::Verify { file } => {
+            print_manifest(&verify_snapshot(&file)?);
+            Ok(())
+        }
+        Command::Restore { config, sled, file } => {
+            let path = match (config, sled) {
+                (_, Some(path)) => path,
+                (Some(config), None) => {
+                    match ServerConfig::load(&config.to_string_lossy())?.storage {
+                        StorageConfig::SledDb(path) => PathBuf::from(path),
+                        StorageConfig::MemTable => bail!(
+                            "MemTable has no persistent storage, set restore in the server config instead"
+                        ),
+                    }
+                }
+                (None, None) => bail!("Either --config or --sled is required"),
+            };
+            let manifest = restore_snapshot(&file, &SledDb::new(&path))?;
+            print_manifest(&manifest);
+            println!("Restored into {}", path.display());
+            Ok(())
+        }
+    }
+}
+
+async fn backup(config: Option<PathBuf>, addr: Option<String>, file: &Path) -> Result<()> {
+    let mut config = match &config {
+        Some(path) => ClientConfig::load(&path.to_string_lossy())?,
+        None => ClientConfig::default(),
+    };
+    if let Some(addr) = addr {
+        config.general.addr = addr;
+    }
+
+    // 先写临时文件，校验通过后再改名
+    let tmp = file.with_extension("tmp");
+    let client = KvClient::connect(&config).await?;
+    let mut writer = BufWriter::new(File::create(&tmp).await?);
+    let size = client.backup(&mut writer).await?;
+    writer.into_inner().sync_all().await?;
+    let manifest = verify_snapshot(&tmp)?;
+    fs::rename(&tmp, file).await?;
+
+    print_manifest(&manifest);
+    println!("Saved {} bytes to {}", size, file.display());
+    Ok(())
+}
+
+fn print_manifest(manifest: &SnapshotManifest) {
+    println!(
+        "Snapshot from {} storage, created at {} ms",
+        manifest.storage, manifest.created_ms
+    );
+    for table in &manifest.tables {
+        println!("  {:<24} {} keys", table.name, table.keys);
+    }
+}
@@ -0,0 +1,705 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use bytes::Bytes;
+use clap::Parser;
+use db_server::{
+    start_client_with_config, BoxedStream, ClientConfig, ClientMux, CommandRequest,
+    ProstClientStream,
+};
+use futures::future;
+use hdrhistogram::Histogram;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use rand_distr::{Distribution, Zipf};
+use serde::Serialize;
+
+/// 对运行中的 KV server 施加压力，统计吞吐量和延迟分布
+///
+/// 每个 TLS 连接上打开若干个 yamux stream，每个 stream 由一个任务循环发送命令。
+#[derive(Parser, Debug)]
+#[command(name = "kv-benchmark", version)]
+struct Args {
+    /// 客户端配置文件
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// 服务器地址，覆盖配置文件中的设置
+    #[arg(long)]
+    addr: Option<String>,
+
+    /// TLS 连接个数
+    #[arg(short = 'n', long, default_value_t = 4)]
+    connections: usize,
+
+    /// 每个连接上并发的 stream 个数
+    #[arg(short, long, default_value_t = 8)]
+    streams: usize,
+
+    /// 总共发送的命令个数
+    #[arg(short, long, default_value_t = 100_000, conflicts_with = "duration")]
+    requests: u64,
+
+    /// 按时间运行（秒），设置后忽略 --requests
+    #[arg(short, long)]
+    duration: Option<u64>,
+
+    /// 命令比例，比如 get=80,set=20。支持 get、set、del、exists、mget、publish
+    #[arg(short, long, default_value = "get=80,set=20")]
+    mix: CommandMix,
+
+    /// key 的个数
+    #[arg(short, long, default_value_t = 10_000)]
+    keyspace: u64,
+
+    /// key 的分布：uniform 或 zipf
+    #[arg(long, default_value = "uniform")]
+    distribution: KeyDistribution,
+
+    /// zipf 分布的指数，越大热点越集中
+    #[arg(long, default_value_t = 0.99)]
+    zipf_exponent: f64,
+
+    /// 写入的 value 大小（字节），可以是固定值或范围，比如 100 或 16-1024
+    #[arg(short, long, default_value = "100")]
+    value_size: ValueSize,
+
+    /// 每个 stream 一次发送的命令个数，这一批命令的延迟都按整批计算
+    #[arg(short = 'P', long, default_value_t = 1)]
+    pipeline: usize,
+
+    /// 使用的 table
+    #[arg(long, default_value = "bench")]
+    table: String,
+
+    /// 开始前把所有 key 写入一遍，让 get 都能命中
+    #[arg(long)]
+    populate: bool,
+
+    /// 报告格式：text 或 json
+    #[arg(short, long, default_value = "text")]
+    format: ReportFormat,
+}
+
+// mget 每次读取的 key 个数
+const MGET_KEYS: usize = 10;
+// --populate 时每次 pipeline 写入的 key 个数
+const POPULATE_BATCH: usize = 100;
+// publish 使用的主题
+const PUBLISH_TOPIC: &str = "bench";
+// 延迟统计的上限是 60 秒，单位微秒
+const MAX_LATENCY_US: u64 = 60_000_000;
+
+type ClientStream = ProstClientStream<BoxedStream>;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.connections == 0 || args.streams == 0 || args.pipeline == 0 || args.keyspace == 0 {
+        bail!("connections, streams, pipeline and keyspace must be positive");
+    }
+
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(&path.to_string_lossy())?,
+        None => ClientConfig::default(),
+    };
+    if let Some(addr) = &args.addr {
+        config.general.addr = addr.clone();
+    }
+
+    let (_ctrls, mut streams) = open_streams(&config, args.connections, args.streams).await?;
+    let workload = Arc::new(Workload::new(&args)?);
+    if args.populate {
+        populate(&workload, &mut streams).await?;
+    }
+
+    let remaining = Arc::new(AtomicU64::new(match args.duration {
+        Some(_) => u64::MAX,
+        None => args.requests,
+    }));
+    let deadline = args
+        .duration
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+
+    let start = Instant::now();
+    let tasks = streams.into_iter().enumerate().map(|(i, stream)| {
+        let workload = workload.clone();
+        let remaining = remaining.clone();
+        tokio::spawn(run_worker(
+            stream,
+            workload,
+            remaining,
+            deadline,
+            args.pipeline,
+            i as u64,
+        ))
+    });
+
+    let mut stats = Stats::default();
+    for res in future::join_all(tasks).await {
+        stats.merge(res??);
+    }
+    let report = Report::new(&args, &stats, start.elapsed());
+
+    match args.format {
+        ReportFormat::Text => print!("{}", report),
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
+
+// 返回的 ClientMux 被释放时连接会关闭，需要一直持有到测试结束
+async fn open_streams(
+    config: &ClientConfig,
+    connections: usize,
+    streams: usize,
+) -> Result<(Vec<ClientMux>, Vec<ClientStream>)> {
+    let mut ctrls = Vec::with_capacity(connections);
+    let mut result = Vec::with_capacity(connections * streams);
+    for _ in 0..connections {
+        let mut ctrl = start_client_with_config(config).await?;
+        for _ in 0..streams {
+            result.push(ctrl.open_stream().await?);
+        }
+        ctrls.push(ctrl);
+    }
+    Ok((ctrls, result))
+}
+
+// 把 keyspace 平均分给所有 stream 写入
+async fn populate(workload: &Workload, streams: &mut [ClientStream]) -> Result<()> {
+    let n = streams.len() as u64;
+    let tasks = streams
+        .iter_mut()
+        .enumerate()
+        .map(|(i, stream)| async move {
+            let mut rng = StdRng::seed_from_u64(i as u64);
+            let keys: Vec<u64> = (i as u64..workload.keyspace).step_by(n as usize).collect();
+            for chunk in keys.chunks(POPULATE_BATCH) {
+                let cmds: Vec<_> = chunk
+                    .iter()
+                    .map(|k| {
+                        let value = workload.value(&mut rng).into();
+                        CommandRequest::new_hset(&workload.table, format!("key:{}", k), value)
+                    })
+                    .collect();
+                stream.execute_pipeline(&cmds).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        });
+    future::try_join_all(tasks).await?;
+    Ok(())
+}
+
+async fn run_worker(
+    mut stream: ClientStream,
+    workload: Arc<Workload>,
+    remaining: Arc<AtomicU64>,
+    deadline: Option<Instant>,
+    pipeline: usize,
+    seed: u64,
+) -> Result<Stats> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut stats = Stats::default();
+    let mut kinds = Vec::with_capacity(pipeline);
+    let mut cmds = Vec::with_capacity(pipeline);
+
+    loop {
+        if matches!(deadline, Some(d) if Instant::now() >= d) {
+            break;
+        }
+        let n = claim(&remaining, pipeline as u64);
+        if n == 0 {
+            break;
+        }
+
+        kinds.clear();
+        cmds.clear();
+        for _ in 0..n {
+            let (kind, cmd) = workload.next(&mut rng);
+            kinds.push(kind);
+            cmds.push(cmd);
+        }
+
+        let start = Instant::now();
+        let responses = stream.execute_pipeline(&cmds).await?;
+        let elapsed = start.elapsed();
+        for (kind, res) in kinds.iter().zip(responses) {
+            stats.record(*kind, elapsed, res.status);
+        }
+    }
+
+    Ok(stats)
+}
+
+// 从剩余的命令数中领取最多 n 个
+fn claim(remaining: &AtomicU64, n: u64) -> u64 {
+    let mut current = remaining.load(Ordering::Relaxed);
+    loop {
+        let take = current.min(n);
+        if take == 0 {
+            return 0;
+        }
+        match remaining.compare_exchange_weak(
+            current,
+            current - take,
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        ) {
+            Ok(_) => return take,
+            Err(v) => current = v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum CommandKind {
+    Get,
+    Set,
+    Del,
+    Exists,
+    Mget,
+    Publish,
+}
+
+impl FromStr for CommandKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "get" => Self::Get,
+            "set" => Self::Set,
+            "del" => Self::Del,
+            "exists" => Self::Exists,
+            "mget" => Self::Mget,
+            "publish" => Self::Publish,
+            _ => bail!("Unknown command in mix: {}", s),
+        })
+    }
+}
+
+impl fmt::Display for CommandKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Get => "get",
+            Self::Set => "set",
+            Self::Del => "del",
+            Self::Exists => "exists",
+            Self::Mget => "mget",
+            Self::Publish => "publish",
+        };
+        f.write_str(name)
+    }
+}
+
+/// 命令和它们的权重
+#[derive(Debug, Clone, PartialEq)]
+struct CommandMix(Vec<(CommandKind, u32)>);
+
+impl FromStr for CommandMix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mix = s
+            .split(',')
+            .map(|item| {
+                let (name, weight) = item
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("Expected <command>=<weight>, got {}", item))?;
+                Ok((name.trim().parse()?, weight.trim().parse()?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if mix.iter().map(|(_, w)| w).sum::<u32>() == 0 {
+            bail!("Command mix must have a positive weight");
+        }
+        Ok(Self(mix))
+    }
+}
+
+impl CommandMix {
+    fn pick(&self, rng: &mut impl Rng) -> CommandKind {
+        let total: u32 = self.0.iter().map(|(_, w)| w).sum();
+        let mut n = rng.gen_range(0..total);
+        for (kind, weight) in self.0.iter() {
+            if n < *weight {
+                return *kind;
+            }
+            n -= weight;
+        }
+        unreachable!("weights add up to total")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum KeyDistribution {
+    Uniform,
+    Zipf,
+}
+
+impl FromStr for KeyDistribution {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uniform" => Ok(Self::Uniform),
+            "zipf" | "zipfian" => Ok(Self::Zipf),
+            _ => bail!("Unknown distribution: {} (expected uniform or zipf)", s),
+        }
+    }
+}
+
+/// value 的大小范围，包含两端
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ValueSize {
+    min: usize,
+    max: usize,
+}
+
+impl FromStr for ValueSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (min, max) = match s.split_once('-') {
+            Some((min, max)) => (min.trim().parse()?, max.trim().parse()?),
+            None => {
+                let size = s.trim().parse()?;
+                (size, size)
+            }
+        };
+        if min > max {
+            bail!("Invalid value size range: {}", s);
+        }
+        Ok(Self { min, max })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReportFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => bail!("Unknown report format: {} (expected text or json)", s),
+        }
+    }
+}
+
+/// 生成要发送的命令
+struct Workload {
+    table: String,
+    mix: CommandMix,
+    keyspace: u64,
+    zipf: Option<Zipf<f64>>,
+    value_size: ValueSize,
+    // 所有 value 都是这块随机数据的切片，避免每次生成
+    payload: Bytes,
+}
+
+impl Workload {
+    fn new(args: &Args) -> Result<Self> {
+        let zipf = match args.distribution {
+            KeyDistribution::Uniform => None,
+            KeyDistribution::Zipf => Some(
+                Zipf::new(args.keyspace, args.zipf_exponent)
+                    .map_err(|e| anyhow!("Invalid zipf parameters: {:?}", e))?,
+            ),
+        };
+        let mut payload = vec![0u8; args.value_size.max];
+        StdRng::seed_from_u64(0).fill_bytes(&mut payload);
+
+        Ok(Self {
+            table: args.table.clone(),
+            mix: args.mix.clone(),
+            keyspace: args.keyspace,
+            zipf,
+            value_size: args.value_size,
+            payload: payload.into(),
+        })
+    }
+
+    fn next(&self, rng: &mut impl Rng) -> (CommandKind, CommandRequest) {
+        let kind = self.mix.pick(rng);
+        let cmd = match kind {
+            CommandKind::Get => CommandRequest::new_hget(&self.table, self.key(rng)),
+            CommandKind::Set => {
+                CommandRequest::new_hset(&self.table, self.key(rng), self.value(rng).into())
+            }
+            CommandKind::Del => CommandRequest::new_hdel(&self.table, self.key(rng)),
+            CommandKind::Exists => CommandRequest::new_hexist(&self.table, self.key(rng)),
+            CommandKind::Mget => {
+                let keys = (0..MGET_KEYS).map(|_| self.key(rng)).collect();
+                CommandRequest::new_hmget(&self.table, keys)
+            }
+            CommandKind::Publish => {
+                CommandRequest::new_publish(PUBLISH_TOPIC, vec![self.value(rng).into()])
+            }
+        };
+        (kind, cmd)
+    }
+
+    fn key(&self, rng: &mut impl Rng) -> String {
+        let n = match &self.zipf {
+            // zipf 的结果在 [1, keyspace] 之间，1 是最热的 key
+            Some(zipf) => zipf.sample(rng) as u64 - 1,
+            None => rng.gen_range(0..self.keyspace),
+        };
+        format!("key:{}", n)
+    }
+
+    fn value(&self, rng: &mut impl Rng) -> Bytes {
+        let size = rng.gen_range(self.value_size.min..=self.value_size.max);
+        self.payload.slice(..size)
+    }
+}
+
+/// 每种命令的延迟分布和错误数
+#[derive(Default)]
+struct Stats {
+    commands: BTreeMap<CommandKind, CommandStats>,
+}
+
+struct CommandStats {
+    latency: Histogram<u64>,
+    errors: u64,
+}
+
+impl Default for CommandStats {
+    fn default() -> Self {
+        Self {
+            latency: Histogram::new_with_bounds(1, MAX_LATENCY_US, 3).unwrap(),
+            errors: 0,
+        }
+    }
+}
+
+impl Stats {
+    fn record(&mut self, kind: CommandKind, latency: Duration, status: u32) {
+        let stats = self.commands.entry(kind).or_default();
+        stats.latency.saturating_record(latency.as_micros() as u64);
+        // key 不存在的 404 是正常结果
+        if !(200..300).contains(&status) && status != 404 {
+            stats.errors += 1;
+        }
+    }
+
+    fn merge(&mut self, other: Stats) {
+        for (kind, s) in other.commands {
+            let stats = self.commands.entry(kind).or_default();
+            stats.latency.add(&s.latency).unwrap();
+            stats.errors += s.errors;
+        }
+    }
+
+    fn total(&self) -> CommandStats {
+        let mut total = CommandStats::default();
+        for s in self.commands.values() {
+            total.latency.add(&s.latency).unwrap();
+            total.errors += s.errors;
+        }
+        total
+    }
+}
+
+/// 测试结果，延迟的单位是毫秒
+#[derive(Debug, Serialize)]
+struct Report {
+    connections: usize,
+    streams: usize,
+    pipeline: usize,
+    duration_secs: f64,
+    requests: u64,
+    errors: u64,
+    throughput: f64,
+    latency: LatencyReport,
+    commands: BTreeMap<String, CommandReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommandReport {
+    requests: u64,
+    errors: u64,
+    throughput: f64,
+    latency: LatencyReport,
+}
+
+#[derive(Debug, Serialize)]
+struct LatencyReport {
+    mean: f64,
+    p50: f64,
+    p99: f64,
+    p999: f64,
+    max: f64,
+}
+
+impl LatencyReport {
+    fn new(h: &Histogram<u64>) -> Self {
+        let ms = |us: u64| us as f64 / 1000.0;
+        Self {
+            mean: h.mean() / 1000.0,
+            p50: ms(h.value_at_quantile(0.5)),
+            p99: ms(h.value_at_quantile(0.99)),
+            p999: ms(h.value_at_quantile(0.999)),
+            max: ms(h.max()),
+        }
+    }
+}
+
+impl Report {
+    fn new(args: &Args, stats: &Stats, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64();
+        let total = stats.total();
+        let commands = stats
+            .commands
+            .iter()
+            .map(|(kind, s)| {
+                let report = CommandReport {
+                    requests: s.latency.len(),
+                    errors: s.errors,
+                    throughput: s.latency.len() as f64 / secs,
+                    latency: LatencyReport::new(&s.latency),
+                };
+                (kind.to_string(), report)
+            })
+            .collect();
+
+        Self {
+            connections: args.connections,
+            streams: args.streams,
+            pipeline: args.pipeline,
+            duration_secs: secs,
+            requests: total.latency.len(),
+            errors: total.errors,
+            throughput: total.latency.len() as f64 / secs,
+            latency: LatencyReport::new(&total.latency),
+            commands,
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.2}s, {} connections x {} streams, pipeline {}",
+            self.requests, self.duration_secs, self.connections, self.streams, self.pipeline
+        )?;
+        writeln!(
+            f,
+            "throughput: {:.0} req/s, errors: {}",
+            self.throughput, self.errors
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<10}{:>10}{:>8}{:>12}{:>10}{:>10}{:>10}{:>10}",
+            "command", "requests", "errors", "req/s", "p50 ms", "p99 ms", "p999 ms", "max ms"
+        )?;
+        let rows = self
+            .commands
+            .iter()
+            .map(|(name, c)| {
+                (
+                    name.as_str(),
+                    c.requests,
+                    c.errors,
+                    c.throughput,
+                    &c.latency,
+                )
+            })
+            .chain([(
+                "total",
+                self.requests,
+                self.errors,
+                self.throughput,
+                &self.latency,
+            )]);
+        for (name, requests, errors, throughput, l) in rows {
+            writeln!(
+                f,
+                "{:<10}{:>10}{:>8}{:>12.0}{:>10.3}{:>10.3}{:>10.3}{:>10.3}",
+                name, requests, errors, throughput, l.p50, l.p99, l.p999, l.max
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_mix_should_follow_weights() {
+        let mix: CommandMix = "get=3, set=1".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let gets = (0..10_000)
+            .filter(|_| mix.pick(&mut rng) == CommandKind::Get)
+            .count();
+        assert!((7_000..8_000).contains(&gets));
+
+        assert!("get=0".parse::<CommandMix>().is_err());
+        assert!("foo=1".parse::<CommandMix>().is_err());
+        assert!("get".parse::<CommandMix>().is_err());
+    }
+
+    #[test]
+    fn value_size_should_be_parsed() {
+        assert_eq!(
+            "100".parse::<ValueSize>().unwrap(),
+            ValueSize { min: 100, max: 100 }
+        );
+        assert_eq!(
+            "16-1024".parse::<ValueSize>().unwrap(),
+            ValueSize { min: 16, max: 1024 }
+        );
+        assert!("10-1".parse::<ValueSize>().is_err());
+    }
+
+    #[test]
+    fn zipf_keys_should_be_skewed() {
+        let args = Args::parse_from(["kv-benchmark", "--distribution", "zipf", "-k", "1000"]);
+        let workload = Workload::new(&args).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let hot = (0..10_000)
+            .filter(|_| workload.key(&mut rng) == "key:0")
+            .count();
+        // 均匀分布下大约是 10 次
+        assert!(hot > 500);
+    }
+
+    #[test]
+    fn claim_should_not_exceed_remaining() {
+        let remaining = AtomicU64::new(5);
+        assert_eq!(claim(&remaining, 3), 3);
+        assert_eq!(claim(&remaining, 3), 2);
+        assert_eq!(claim(&remaining, 3), 0);
+    }
+
+    #[test]
+    fn stats_should_ignore_not_found() {
+        let mut stats = Stats::default();
+        stats.record(CommandKind::Get, Duration::from_millis(1), 200);
+        stats.record(CommandKind::Get, Duration::from_millis(2), 404);
+        let mut other = Stats::default();
+        other.record(CommandKind::Set, Duration::from_millis(3), 500);
+        stats.merge(other);
+
+        let total = stats.total();
+        assert_eq!(total.latency.len(), 3);
+        assert_eq!(total.errors, 1);
+    }
+}
@@ -0,0 +1,1007 @@
+//! 在进程内创建服务器：可以使用任意的存储、中间件和监听地址，启动后返回 ServerHandle
+//!
+
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use futures::{future, SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{oneshot, watch};
+use tokio::time;
+use tokio_util::compat::FuturesAsyncReadCompatExt;
+use tracing::{info, span, warn, Instrument};
+
+use crate::metrics::{metrics, serve_metrics, GaugeGuard};
+use crate::network::idle_expired;
+use crate::shutdown::{Drain, ServerHandle, Shutdown};
+use crate::{
+    peer_identity, Acl, Admission, AdmissionConfig, AuditLog, BoxedStream, ClientConfig,
+    CommandRequest, CommandResponse, Identity, KvError, LimitsConfig, ListenerConfig, Middleware,
+    ProstServerStream, ProstStream, ReplicationConfig, RuntimeSettings, Service, ServiceInner,
+    Session, SlowlogConfig, Storage, TimeoutConfig, TlsServerAcceptor, YamuxCtrl,
+};
+
+// accept 出错后等待多久再继续
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// 拒绝连接时等待客户端读取错误的时间
+const REJECT_GRACE: Duration = Duration::from_secs(1);
+
+// 连接使用的传输层
+#[derive(Clone)]
+enum Transport {
+    Tls(TlsServerAcceptor),
+    // 不加密，只应该用在可信的网络里
+    Plaintext,
+}
+
+// 监听要求的传输层
+enum TransportKind {
+    // 使用 tls 或 plaintext 设置的传输层
+    Default,
+    Tls,
+    Plaintext,
+}
+
+// 监听的地址
+enum Bind {
+    Addr(String),
+    Tcp(TcpListener),
+    Unix { path: PathBuf, mode: Option<u32> },
+}
+
+struct ListenerSpec {
+    bind: Bind,
+    transport: TransportKind,
+    yamux: bool,
+}
+
+// 连接上的 stream 怎么划分
+#[derive(Clone)]
+enum Framing {
+    // 用 yamux 在一个连接上打开多个 stream
+    Yamux(Option<yamux::Config>),
+    // 整个连接就是一个 stream
+    Direct,
+}
+
+/// 服务器的构建器
+///
+/// ```no_run
+/// # async fn run() -> anyhow::Result<()> {
+/// use db_server::{MemTable, ServerBuilder};
+///
+/// let server = ServerBuilder::new(MemTable::new())
+///     .plaintext()
+///     .listen("127.0.0.1:0")
+///     .start()
+///     .await?;
+/// println!("listening on {}", server.local_addr());
+/// server.shutdown().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerBuilder<Store> {
+    service: ServiceInner<Store>,
+    transport: Option<Transport>,
+    yamux: Option<yamux::Config>,
+    listeners: Vec<ListenerSpec>,
+    metrics_addr: Option<String>,
+    drain_timeout: Duration,
+    timeouts: TimeoutConfig,
+    admission: AdmissionConfig,
+    slowlog: SlowlogConfig,
+    // 作为 follower 时连接 leader 的配置
+    leader: Option<ClientConfig>,
+}
+
+impl<Store: Storage> ServerBuilder<Store> {
+    pub fn new(store: Store) -> Self {
+        Self::with_service(ServiceInner::new(store))
+    }
+
+    /// 使用已经配置好的 ServiceInner，比如注册了函数指针形式的事件
+    pub fn with_service(service: ServiceInner<Store>) -> Self {
+        Self {
+            service,
+            transport: None,
+            yamux: None,
+            listeners: Vec::new(),
+            metrics_addr: None,
+            drain_timeout: Duration::from_secs(10),
+            timeouts: TimeoutConfig::default(),
+            admission: AdmissionConfig::default(),
+            slowlog: SlowlogConfig::default(),
+            leader: None,
+        }
+    }
+
+    /// 使用 TLS，listen 和 listener 添加的地址需要在 tls 和 plaintext 中选一个
+    pub fn tls(mut self, acceptor: TlsServerAcceptor) -> Self {
+        self.transport = Some(Transport::Tls(acceptor));
+        self
+    }
+
+    /// 不加密，连接都没有证书中的身份
+    pub fn plaintext(mut self) -> Self {
+        self.transport = Some(Transport::Plaintext);
+        self
+    }
+
+    /// 每个连接上 yamux 的配置，不设置时使用默认配置
+    pub fn yamux_config(mut self, config: yamux::Config) -> Self {
+        self.yamux = Some(config);
+        self
+    }
+
+    /// 添加一个监听地址，端口可以是 0，启动后从 ServerHandle 拿到实际的地址
+    pub fn listen(self, addr: impl Into<String>) -> Self {
+        self.push(Bind::Addr(addr.into()), TransportKind::Default, true)
+    }
+
+    /// 添加一个已经绑定的 listener
+    pub fn listener(self, listener: TcpListener) -> Self {
+        self.push(Bind::Tcp(listener), TransportKind::Default, true)
+    }
+
+    /// 监听 Unix domain socket，不加密，访问控制依靠文件权限
+    ///
+    /// 启动时会删除遗留的 socket 文件，关闭时删除自己创建的文件
+    pub fn listen_unix(self, path: impl Into<PathBuf>, mode: Option<u32>) -> Self {
+        let path = path.into();
+        self.push(Bind::Unix { path, mode }, TransportKind::Plaintext, true)
+    }
+
+    /// 按配置添加监听，配置中的 tcp 监听是否允许明文需要调用方检查
+    pub fn add_listener(self, config: &ListenerConfig) -> Self {
+        match config {
+            ListenerConfig::Tls { addr, yamux } => {
+                self.push(Bind::Addr(addr.clone()), TransportKind::Tls, *yamux)
+            }
+            ListenerConfig::Tcp { addr, yamux, .. } => {
+                self.push(Bind::Addr(addr.clone()), TransportKind::Plaintext, *yamux)
+            }
+            ListenerConfig::Unix { path, mode, yamux } => {
+                let bind = Bind::Unix {
+                    path: path.into(),
+                    mode: *mode,
+                };
+                self.push(bind, TransportKind::Plaintext, *yamux)
+            }
+        }
+    }
+
+    fn push(mut self, bind: Bind, transport: TransportKind, yamux: bool) -> Self {
+        self.listeners.push(ListenerSpec {
+            bind,
+            transport,
+            yamux,
+        });
+        self
+    }
+
+    /// 开启认证和访问控制
+    pub fn acl(mut self, acl: Acl) -> Self {
+        self.service = self.service.with_acl(acl);
+        self
+    }
+
+    /// 开启限流和配额，限流规则不合法时返回错误
+    pub fn limits(mut self, limits: LimitsConfig) -> Result<Self> {
+        self.service = self.service.with_limits(limits)?;
+        Ok(self)
+    }
+
+    /// 添加中间件，先添加的在外层
+    pub fn middleware(mut self, m: impl Middleware) -> Self {
+        self.service = self.service.middleware(m);
+        self
+    }
+
+    /// 连接数、每个连接的 stream 数和正在处理的请求数的上限，运行时可以用 CONFIG SET 修改
+    pub fn admission(mut self, config: AdmissionConfig) -> Self {
+        self.admission = config;
+        self
+    }
+
+    /// 慢命令的阈值和保留的条数，运行时可以用 CONFIG SET 修改
+    pub fn slowlog(mut self, config: SlowlogConfig) -> Self {
+        self.slowlog = config;
+        self
+    }
+
+    /// 主从复制，配置了 leader 时作为 follower 启动，从 leader 同步数据并拒绝写命令
+    pub fn replication(mut self, config: ReplicationConfig) -> Self {
+        self.service = self.service.with_replication(&config);
+        self.leader = config.leader;
+        self
+    }
+
+    /// 允许 Backup 把备份写到这个目录下，不设置时只能以流的形式备份
+    pub fn backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.service = self.service.with_backup_dir(dir);
+        self
+    }
+
+    /// 记录写命令的审计日志
+    pub fn audit(mut self, audit: AuditLog) -> Self {
+        self.service = self.service.with_audit(audit);
+        self
+    }
+
+    /// 在这个地址上提供 Prometheus 抓取
+    pub fn metrics_addr(mut self, addr: impl Into<String>) -> Self {
+        self.metrics_addr = Some(addr.into());
+        self
+    }
+
+    /// 关闭时等待正在处理的请求的最长时间
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// 连接上没有请求超过这个时间就关闭，None 表示不限制
+    pub fn idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.idle_timeout_ms = millis(timeout);
+        self
+    }
+
+    /// TLS 握手的最长时间，超时后关闭连接，None 表示不限制
+    pub fn handshake_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeouts.handshake_timeout_ms = millis(timeout);
+        self
+    }
+
+    /// 绑定所有地址并开始接受连接，任何一个地址绑定失败都不会启动
+    pub async fn start(self) -> Result<ServerHandle> {
+        if self.listeners.is_empty() {
+            return Err(anyhow!("No address to listen on"));
+        }
+
+        let mut listeners = Vec::with_capacity(self.listeners.len());
+        for spec in self.listeners {
+            let transport = match (spec.transport, &self.transport) {
+                (TransportKind::Plaintext, _) => Transport::Plaintext,
+                (TransportKind::Default, Some(transport)) => transport.clone(),
+                (TransportKind::Tls, Some(Transport::Tls(tls))) => Transport::Tls(tls.clone()),
+                (TransportKind::Default, None) => {
+                    return Err(anyhow!("Either TLS or plaintext must be chosen"))
+                }
+                (TransportKind::Tls, _) => {
+                    return Err(anyhow!("TLS listener requires a TLS acceptor"))
+                }
+            };
+            let framing = match spec.yamux {
+                true => Framing::Yamux(self.yamux.clone()),
+                false => Framing::Direct,
+            };
+            let listener = Listener::bind(spec.bind).await?;
+            info!("Start listening on {}", listener);
+            listeners.push((listener, transport, framing));
+        }
+        // 只有 TCP 监听有网络地址
+        let addrs = listeners
+            .iter()
+            .filter_map(|(listener, ..)| listener.local_addr())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // 超时和上限都可以在运行时修改，Service 处理 CONFIG 命令，连接从 Admission 读取
+        let settings = Arc::new(RuntimeSettings::new(
+            &self.timeouts,
+            &self.admission,
+            &self.slowlog,
+        ));
+        let admission = Admission::new(settings.clone());
+        let service: Service<Store> = self.service.with_settings(settings).into();
+
+        let metrics = match &self.metrics_addr {
+            Some(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let addr = listener.local_addr()?;
+                info!("Metrics listening on {}", addr);
+                let service = service.clone();
+                let task = tokio::spawn(async move {
+                    if let Err(e) = serve_metrics(listener, service).await {
+                        warn!("Metrics server stopped: {:?}", e);
+                    }
+                });
+                Some((addr, task))
+            }
+            None => None,
+        };
+        let metrics_addr = metrics.as_ref().map(|(addr, _)| *addr);
+
+        let (tx, rx) = watch::channel(false);
+        let follower = self.leader.map(|config| {
+            let service = service.clone();
+            let shutdown = Shutdown::new(rx.clone());
+            tokio::spawn(async move { service.follow(config, shutdown).await })
+        });
+        let drain_timeout = self.drain_timeout;
+        let task = tokio::spawn(async move {
+            let drain = Drain::default();
+            let loops = listeners.into_iter().map(|(listener, transport, framing)| {
+                accept_loop(
+                    listener,
+                    service.clone(),
+                    transport,
+                    framing,
+                    admission.clone(),
+                    Shutdown::new(rx.clone()),
+                    drain.clone(),
+                )
+            });
+            // 任何一个 listener 出错时其它的也不再接受新连接，整个服务器关闭
+            let res = future::try_join_all(loops).await.map(|_| ());
+
+            // 等正在处理的请求完成，订阅会在收到 going away 后结束
+            if time::timeout(drain_timeout, drain.wait()).await.is_err() {
+                warn!("Some requests are still running after {:?}", drain_timeout);
+            }
+            if let Some((_, task)) = metrics {
+                task.abort();
+            }
+            // 收到关闭信号后 follower 不再同步
+            if let Some(task) = follower {
+                let _ = task.await;
+            }
+            tokio::task::spawn_blocking(move || service.flush()).await??;
+            info!("Server is shut down");
+            res
+        });
+
+        Ok(ServerHandle::new(addrs, metrics_addr, tx, task))
+    }
+}
+
+// TCP 或者 Unix domain socket 上的 listener
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    async fn bind(bind: Bind) -> Result<Self> {
+        Ok(match bind {
+            Bind::Addr(addr) => Listener::Tcp(TcpListener::bind(addr).await?),
+            Bind::Tcp(listener) => Listener::Tcp(listener),
+            Bind::Unix { path, mode } => {
+                remove_stale_socket(&path)?;
+                let listener = UnixListener::bind(&path)?;
+                if let Some(mode) = mode {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+                }
+                Listener::Unix(listener, path)
+            }
+        })
+    }
+
+    fn local_addr(&self) -> Option<std::io::Result<std::net::SocketAddr>> {
+        match self {
+            Listener::Tcp(listener) => Some(listener.local_addr()),
+            Listener::Unix(..) => None,
+        }
+    }
+
+    async fn accept(&self) -> std::io::Result<(BoxedStream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            Listener::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), path.display().to_string()))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{}", addr),
+                Err(_) => write!(f, "tcp"),
+            },
+            Listener::Unix(_, path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+// 上次没有正常退出时 socket 文件还在，bind 会失败。只删除 socket，不碰普通文件
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => Ok(fs::remove_file(path)?),
+        Ok(_) => Err(anyhow!("{} exists and is not a socket", path.display())),
+        Err(_) => Ok(()),
+    }
+}
+
+// 一直接受新连接，直到收到关闭信号
+async fn accept_loop<Store: Storage>(
+    listener: Listener,
+    service: Service<Store>,
+    transport: Transport,
+    framing: Framing,
+    admission: Admission,
+    mut shutdown: Shutdown,
+    drain: Drain,
+) -> Result<()> {
+    loop {
+        let (stream, peer) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(res) => res,
+                Err(e) => {
+                    // 比如文件句柄用完了，等一会儿再接受，不让整个服务器退出
+                    metrics().record_connection_error("accept");
+                    warn!("Failed to accept connection on {}: {:?}", listener, e);
+                    time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = shutdown.wait() => return Ok(()),
+        };
+        info!("Client {} connected", peer);
+        let root = span!(tracing::Level::INFO, "server_process", peer = %peer);
+        // 在 accept 时就占用名额，握手中的连接也算在内
+        let admitted = admission.connection();
+
+        let svc = service.clone();
+        let transport = transport.clone();
+        let framing = framing.clone();
+        let admission = admission.clone();
+        let shutdown = shutdown.clone();
+        let drain = drain.clone();
+        tokio::spawn(
+            async move {
+                let (stream, identity): (BoxedStream, _) = match transport {
+                    Transport::Tls(tls) => {
+                        let handshake = tls.accept(stream);
+                        let res = match admission.settings().handshake_timeout() {
+                            Some(timeout) => time::timeout(timeout, handshake).await,
+                            None => Ok(handshake.await),
+                        };
+                        let stream = match res {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(e)) => {
+                                // 握手没有完成，没法回复错误，直接关闭连接
+                                metrics().record_connection_error("tls_handshake");
+                                warn!("TLS handshake with {} failed: {:?}", peer, e);
+                                return;
+                            }
+                            Err(_) => {
+                                // 只连上不握手的客户端不能一直占着连接
+                                metrics().record_connection_error("handshake_timeout");
+                                warn!("TLS handshake with {} timed out", peer);
+                                return;
+                            }
+                        };
+                        // 初始身份来自客户端证书
+                        let identity = peer_identity(&stream);
+                        (Box::new(stream), identity)
+                    }
+                    Transport::Plaintext => (stream, Identity::Anonymous),
+                };
+
+                match admitted {
+                    Ok(_permit) => {
+                        let session = Session::with_identity(identity).with_peer(peer);
+                        serve_connection(stream, session, svc, framing, admission, shutdown, drain)
+                            .await;
+                    }
+                    Err(e) => {
+                        warn!("Rejected connection from {}: {}", peer, e);
+                        reject_connection(stream, framing, e).await;
+                    }
+                }
+            }
+            .instrument(root),
+        );
+    }
+}
+
+// 使用 yamux 时每个 stream 单独处理命令，否则直接在连接上处理。连接关闭后返回
+async fn serve_connection<S, Store>(
+    stream: S,
+    session: Session,
+    service: Service<Store>,
+    framing: Framing,
+    admission: Admission,
+    shutdown: Shutdown,
+    drain: Drain,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    Store: Storage,
+{
+    // 这个连接上的所有 stream 共享一个 session
+    let session = Arc::new(session);
+    let _client = service.register_client(&session);
+    let guard = GaugeGuard::new(&metrics().connections);
+    let idle_timeout = admission.settings().idle_timeout();
+    let requests = admission.requests();
+    let config = match framing {
+        Framing::Yamux(config) => config,
+        Framing::Direct => {
+            let inflight = drain.guard();
+            let peer = session.peer().to_string();
+            let stream = ProstServerStream::with_session(stream, service, session)
+                .with_shutdown(shutdown)
+                .with_idle_timeout(idle_timeout)
+                .with_request_limit(requests);
+            if let Err(e) = stream.process().await {
+                warn!("Connection to {} closed with error: {:?}", peer, e);
+            }
+            drop(inflight);
+            drop(guard);
+            return;
+        }
+    };
+
+    // 连接关闭时 yamux 会 drop 这个闭包，guard 和 closed 也跟着释放
+    let (closed, on_closed) = oneshot::channel::<()>();
+    let conn_session = session.clone();
+    let streams = admission.streams();
+    let mut ctrl = YamuxCtrl::new_server(stream, config, move |stream| {
+        let _guard = &guard;
+        let _closed = &closed;
+        let svc = service.clone();
+        let session = session.clone();
+        let shutdown = shutdown.clone();
+        let requests = requests.clone();
+        let admitted = streams.try_acquire();
+        // 关闭时等每个 stream 处理完手上的请求
+        let inflight = drain.guard();
+        async move {
+            let peer = session.peer().to_string();
+            let _permit = match admitted {
+                Ok(permit) => permit,
+                Err(e) => {
+                    warn!("Rejected stream from {}: {}", peer, e);
+                    reject_stream(stream.compat(), e.into()).await;
+                    return Ok(());
+                }
+            };
+            // 空闲超时由下面的 watchdog 按整个连接处理
+            let stream = ProstServerStream::with_session(stream.compat(), svc, session)
+                .with_shutdown(shutdown)
+                .with_request_limit(requests);
+            if let Err(e) = stream.process().await {
+                warn!("Stream from {} closed with error: {:?}", peer, e);
+            }
+            drop(inflight);
+            Ok(())
+        }
+    });
+
+    // 没有打开任何 stream 的连接也要在空闲超时后关闭，CLIENT KILL 时关闭整个连接
+    tokio::select! {
+        _ = idle_expired(&conn_session, idle_timeout) => {
+            metrics().record_connection_error("idle_timeout");
+            info!("Closing idle connection to {}", conn_session.peer());
+            if let Err(e) = ctrl.close().await {
+                warn!("Failed to close connection to {}: {:?}", conn_session.peer(), e);
+            }
+        }
+        _ = conn_session.killed() => {
+            info!("Closing killed connection to {}", conn_session.peer());
+            // 每个 stream 发完手上请求的响应后自己结束，比如执行 CLIENT KILL 的 stream
+            let _ = time::timeout(REJECT_GRACE, async {
+                while conn_session.streams() > 0 {
+                    time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await;
+            if let Err(e) = ctrl.close().await {
+                warn!("Failed to close connection to {}: {:?}", conn_session.peer(), e);
+            }
+        }
+        _ = on_closed => {}
+    }
+}
+
+// 超过连接数上限的连接：回复错误后关闭。yamux 连接上客户端打开的每个 stream 都会收到这个错误
+async fn reject_connection<S>(stream: S, framing: Framing, error: KvError)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let res: CommandResponse = error.into();
+    match framing {
+        Framing::Direct => reject_stream(stream, res).await,
+        Framing::Yamux(config) => {
+            let mut ctrl = YamuxCtrl::new_server(stream, config, move |stream| {
+                let res = res.clone();
+                async move {
+                    reject_stream(stream.compat(), res).await;
+                    Ok(())
+                }
+            });
+            time::sleep(REJECT_GRACE).await;
+            let _ = ctrl.close().await;
+        }
+    }
+}
+
+// 不等请求就先回复错误，再等客户端发出第一个请求后关闭，避免还没读的数据让关闭变成 reset
+async fn reject_stream<S>(stream: S, res: CommandResponse)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let mut stream = ProstStream::<S, CommandRequest, CommandResponse>::new(stream);
+    if stream.send(&res).await.is_ok() {
+        let _ = time::timeout(REJECT_GRACE, stream.next()).await;
+    }
+    let _ = stream.close().await;
+}
+
+// 0 表示不限制，不足 1ms 的按 1ms 算
+fn millis(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(0, |t| (t.as_millis() as u64).max(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        start_client_with_config, start_server_with_config, ClientConfig, CommandRequest,
+        ConnectorConfig, KvClient, MemTable, Next, ProstClientStream, Request, ServerConfig,
+        StorageConfig, Value,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    #[tokio::test]
+    async fn builder_should_start_embedded_server() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let server = ServerBuilder::new(MemTable::new())
+            .plaintext()
+            .listen("127.0.0.1:0")
+            .listen("127.0.0.1:0")
+            .metrics_addr("127.0.0.1:0")
+            .middleware(move |req: Request, next: Next| {
+                counter.fetch_add(1, Ordering::SeqCst);
+                next.run(req)
+            })
+            .start()
+            .await
+            .unwrap();
+        assert_eq!(server.local_addrs().len(), 2);
+
+        // 两个地址用的是同一个存储
+        for (i, addr) in server.local_addrs().iter().enumerate() {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut ctrl = YamuxCtrl::new_client(stream, None);
+            let mut client = ctrl.open_stream().await.unwrap();
+            let cmd = CommandRequest::new_hset("t1", "k1", (i as i64).into());
+            client.execute_unary(&cmd).await.unwrap();
+            let res = client
+                .execute_unary(&CommandRequest::new_hget("t1", "k1"))
+                .await
+                .unwrap();
+            assert_eq!(res.values, vec![Value::from(i as i64)]);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        let mut stream = TcpStream::connect(server.metrics_addr().unwrap())
+            .await
+            .unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+        let mut text = String::new();
+        stream.read_to_string(&mut text).await.unwrap();
+        assert!(text.contains(r#"kv_storage_keys{table="t1"} 1"#));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn builder_should_require_transport_and_listener() {
+        let res = ServerBuilder::new(MemTable::new())
+            .listen("127.0.0.1:0")
+            .start()
+            .await;
+        assert!(res.is_err());
+
+        let res = ServerBuilder::new(MemTable::new())
+            .plaintext()
+            .start()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn builder_should_serve_unix_and_direct_listeners() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("kv.sock");
+        // 遗留的 socket 文件不影响启动
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let server = ServerBuilder::new(MemTable::new())
+            .listen_unix(&path, Some(0o600))
+            .add_listener(&ListenerConfig::Tcp {
+                addr: "127.0.0.1:0".into(),
+                allow_plaintext: true,
+                yamux: false,
+            })
+            .start()
+            .await
+            .unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let connectors = [
+            ConnectorConfig::Unix {
+                path: path.to_string_lossy().into(),
+                yamux: true,
+            },
+            ConnectorConfig::Tcp { yamux: false },
+        ];
+        for (i, connector) in connectors.into_iter().enumerate() {
+            let mut config = ClientConfig::default();
+            config.general.addr = server.local_addr().to_string();
+            config.connector = connector;
+            config.reconnect.max_attempts = Some(0);
+            let client = KvClient::connect(&config).await.unwrap();
+            let cmd = CommandRequest::new_hset("t1", "k1", (i as i64).into());
+            client.execute(&cmd).await.unwrap();
+            let res = client
+                .execute(&CommandRequest::new_hget("t1", "k1"))
+                .await
+                .unwrap();
+            assert_eq!(res.values, vec![Value::from(i as i64)]);
+        }
+
+        server.shutdown().await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn plaintext_listener_should_require_opt_in() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.storage = StorageConfig::MemTable;
+        config.listeners = vec![ListenerConfig::Tcp {
+            addr: "127.0.0.1:0".into(),
+            allow_plaintext: false,
+            yamux: true,
+        }];
+        assert!(start_server_with_config(&config).await.is_err());
+
+        // TLS 监听必须有证书
+        let res = ServerBuilder::new(MemTable::new())
+            .plaintext()
+            .add_listener(&ListenerConfig::Tls {
+                addr: "127.0.0.1:0".into(),
+                yamux: true,
+            })
+            .start()
+            .await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_should_not_stop_server() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.general.addr = "127.0.0.1:0".into();
+        config.storage = StorageConfig::MemTable;
+        let server = start_server_with_config(&config).await.unwrap();
+        let handshake_errors = metrics()
+            .connection_errors
+            .with_label_values(&["tls_handshake"]);
+        let before = handshake_errors.get();
+
+        // 不是 TLS 的客户端，握手失败后服务器关闭连接
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let mut buf = Vec::new();
+        let _ = stream.read_to_end(&mut buf).await;
+        assert!(handshake_errors.get() > before);
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        config.general.addr = server.local_addr().to_string();
+        let client = KvClient::connect(&config).await.unwrap();
+        client.set("t1", "k1", "v1").await.unwrap();
+        assert_eq!(client.get("t1", "k1").await.unwrap(), Some("v1".into()));
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn idle_connections_should_be_closed() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.general.addr = "127.0.0.1:0".into();
+        config.storage = StorageConfig::MemTable;
+        config.timeouts.idle_timeout_ms = 100;
+        config.timeouts.handshake_timeout_ms = 50;
+        let server = start_server_with_config(&config).await.unwrap();
+
+        // 连上后不握手的客户端会被关闭
+        let mut stream = TcpStream::connect(server.local_addr()).await.unwrap();
+        let mut buf = Vec::new();
+        let res = time::timeout(Duration::from_secs(1), stream.read_to_end(&mut buf)).await;
+        assert!(res.is_ok());
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        config.general.addr = server.local_addr().to_string();
+        let mut mux = start_client_with_config(&config).await.unwrap();
+        let mut client = mux.open_stream().await.unwrap();
+        // 一直有请求的连接不会被关闭
+        for _ in 0..4 {
+            time::sleep(Duration::from_millis(50)).await;
+            let res = client.execute_unary(&CommandRequest::new_ping("")).await;
+            assert_eq!(res.unwrap().status, 200);
+        }
+
+        time::sleep(Duration::from_millis(300)).await;
+        assert!(client
+            .execute_unary(&CommandRequest::new_ping(""))
+            .await
+            .is_err());
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_should_reject_with_error() {
+        let server = ServerBuilder::new(MemTable::new())
+            .plaintext()
+            .add_listener(&ListenerConfig::Tcp {
+                addr: "127.0.0.1:0".into(),
+                allow_plaintext: true,
+                yamux: false,
+            })
+            .listen("127.0.0.1:0")
+            .admission(AdmissionConfig {
+                max_connections: 2,
+                max_streams_per_connection: 1,
+                max_in_flight: 1,
+            })
+            .middleware(|req: Request, next: Next| async move {
+                if req.cmd.name() == "hgetall" {
+                    time::sleep(Duration::from_millis(200)).await;
+                }
+                next.run(req).await
+            })
+            .start()
+            .await
+            .unwrap();
+        let (direct, multiplexed) = (server.local_addrs()[0], server.local_addrs()[1]);
+        let ping = CommandRequest::new_ping("");
+
+        // yamux 连接上只能打开一个 stream
+        let mut ctrl = YamuxCtrl::new_client(TcpStream::connect(multiplexed).await.unwrap(), None);
+        let mut first = ctrl.open_stream().await.unwrap();
+        assert_eq!(first.execute_unary(&ping).await.unwrap().status, 200);
+        let mut second = ctrl.open_stream().await.unwrap();
+        let res = second.execute_unary(&ping).await.unwrap();
+        assert_eq!(res.status, 503);
+        assert!(res.message.contains("Too many concurrent streams"));
+
+        // 正在处理的请求数达到上限
+        let slow = tokio::spawn(async move {
+            let res = first
+                .execute_unary(&CommandRequest::new_hgetall("t1"))
+                .await;
+            res.unwrap().status
+        });
+        time::sleep(Duration::from_millis(50)).await;
+        let mut client = ProstClientStream::new(TcpStream::connect(direct).await.unwrap());
+        let res = client.execute_unary(&ping).await.unwrap();
+        assert_eq!(res.status, 503);
+        assert!(res.message.contains("Too many concurrent requests"));
+        assert_eq!(slow.await.unwrap(), 200);
+        assert_eq!(client.execute_unary(&ping).await.unwrap().status, 200);
+
+        // 已经有两个连接，第三个连接被拒绝
+        let mut rejected = ProstClientStream::new(TcpStream::connect(direct).await.unwrap());
+        let res = rejected.execute_unary(&ping).await.unwrap();
+        assert_eq!(res.status, 503);
+        assert!(res.message.contains("Too many concurrent connections"));
+
+        drop(client);
+        time::sleep(Duration::from_millis(50)).await;
+        let mut client = ProstClientStream::new(TcpStream::connect(direct).await.unwrap());
+        assert_eq!(client.execute_unary(&ping).await.unwrap().status, 200);
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn clients_should_be_listed_and_killed() {
+        let server = ServerBuilder::new(MemTable::new())
+            .plaintext()
+            .add_listener(&ListenerConfig::Tcp {
+                addr: "127.0.0.1:0".into(),
+                allow_plaintext: true,
+                yamux: false,
+            })
+            .listen("127.0.0.1:0")
+            .start()
+            .await
+            .unwrap();
+        let (direct, multiplexed) = (server.local_addrs()[0], server.local_addrs()[1]);
+        let ping = CommandRequest::new_ping("");
+
+        let mut victim = ProstClientStream::new(TcpStream::connect(direct).await.unwrap());
+        assert_eq!(victim.execute_unary(&ping).await.unwrap().status, 200);
+        let mut ctrl = YamuxCtrl::new_client(TcpStream::connect(multiplexed).await.unwrap(), None);
+        let mut admin = ctrl.open_stream().await.unwrap();
+
+        let res = admin
+            .execute_unary(&CommandRequest::new_client_list())
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 2);
+        let line = String::try_from(res.values[0].clone()).unwrap();
+        assert!(line.contains("identity=anonymous streams=1"), "{}", line);
+        assert!(line.ends_with("cmd=ping"), "{}", line);
+        let id: u64 = line["id=".len()..line.find(' ').unwrap()].parse().unwrap();
+
+        let res = admin
+            .execute_unary(&CommandRequest::new_client_kill(id))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+        assert!(victim.execute_unary(&ping).await.is_err());
+
+        // yamux 连接上所有的 stream 都被关闭
+        let res = admin
+            .execute_unary(&CommandRequest::new_client_list())
+            .await
+            .unwrap();
+        assert_eq!(res.values.len(), 1);
+        let line = String::try_from(res.values[0].clone()).unwrap();
+        let id: u64 = line["id=".len()..line.find(' ').unwrap()].parse().unwrap();
+        admin
+            .execute_unary(&CommandRequest::new_client_kill(id))
+            .await
+            .unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(admin.execute_unary(&ping).await.is_err());
+        assert!(ctrl.open_stream().await.is_err());
+
+        server.shutdown().await.unwrap();
+    }
+
+    #[test]
+    fn restore_should_only_fill_empty_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let backup = dir.path().join("backup.kvsnap");
+        let source = MemTable::new();
+        source.set("t1", "k1".into(), "old".into()).unwrap();
+        crate::Snapshot::capture(&source)
+            .unwrap()
+            .save(&backup)
+            .unwrap();
+        let path = backup.to_string_lossy();
+
+        // 第一次启动时恢复，之后的写入在重启后不会被旧的备份覆盖
+        let sled = crate::SledDb::new(dir.path().join("db"));
+        assert!(crate::restore_on_start(&path, &sled).unwrap());
+        assert_eq!(sled.get("t1", "k1").unwrap(), Some("old".into()));
+        sled.set("t1", "k1".into(), "new".into()).unwrap();
+        assert!(!crate::restore_on_start(&path, &sled).unwrap());
+        assert_eq!(sled.get("t1", "k1").unwrap(), Some("new".into()));
+    }
+}
@@ -0,0 +1,547 @@
+//! 命令行客户端用到的命令解析和输出格式
+//!
+//! 命令的写法和 abi.proto 中的命令一一对应，比如 `hset t1 k1 v1`、`hmget t1 k1 k2`。
+//! 值按照下面的规则解析：整数、浮点数、true/false、0x 开头的十六进制是二进制，
+//! 其它都是字符串，加上引号可以强制作为字符串。
+
+mod output;
+
+pub use output::*;
+
+use bytes::Bytes;
+
+use crate::{CommandRequest, KvError, Kvpair, Value};
+
+/// 一条命令的名字、参数和说明
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CommandHelp {
+    pub name: &'static str,
+    pub args: &'static str,
+    pub about: &'static str,
+}
+
+/// 支持的所有命令
+pub const COMMANDS: &[CommandHelp] = &[
+    CommandHelp {
+        name: "hget",
+        args: "<table> <key>",
+        about: "读取一个 key",
+    },
+    CommandHelp {
+        name: "hgetall",
+        args: "<table>",
+        about: "读取整个 table",
+    },
+    CommandHelp {
+        name: "hmget",
+        args: "<table> <key>...",
+        about: "读取多个 key",
+    },
+    CommandHelp {
+        name: "hset",
+        args: "<table> <key> <value>",
+        about: "写入一个 key，返回之前的值",
+    },
+    CommandHelp {
+        name: "hmset",
+        args: "<table> <key> <value> [<key> <value>]...",
+        about: "写入多个 key",
+    },
+    CommandHelp {
+        name: "hdel",
+        args: "<table> <key>",
+        about: "删除一个 key，返回被删掉的值",
+    },
+    CommandHelp {
+        name: "hmdel",
+        args: "<table> <key>...",
+        about: "删除多个 key",
+    },
+    CommandHelp {
+        name: "hexist",
+        args: "<table> <key>",
+        about: "检查 key 是否存在",
+    },
+    CommandHelp {
+        name: "hmexist",
+        args: "<table> <key>...",
+        about: "检查多个 key 是否存在",
+    },
+    CommandHelp {
+        name: "hscan",
+        args: "<table> [cursor] [count] [pattern]",
+        about: "按 key 的顺序分页读取 table，返回下一页的 cursor",
+    },
+    CommandHelp {
+        name: "tables",
+        args: "[pattern]",
+        about: "列出 table 和它们的 key 个数",
+    },
+    CommandHelp {
+        name: "subscribe",
+        args: "<topic>",
+        about: "订阅主题，持续输出收到的数据，Ctrl-C 结束",
+    },
+    CommandHelp {
+        name: "unsubscribe",
+        args: "<topic> <id>",
+        about: "取消订阅",
+    },
+    CommandHelp {
+        name: "publish",
+        args: "<topic> <value>...",
+        about: "往主题中发布数据",
+    },
+    CommandHelp {
+        name: "batch",
+        args: "[--stop-on-error] <command> [; <command>]...",
+        about: "一次提交多条命令，命令之间用 ; 分隔",
+    },
+    CommandHelp {
+        name: "track",
+        args: "",
+        about: "开启 key 跟踪，持续输出失效通知，Ctrl-C 结束",
+    },
+    CommandHelp {
+        name: "auth",
+        args: "<username> <password> | --token <token>",
+        about: "认证当前连接",
+    },
+    CommandHelp {
+        name: "setratelimit",
+        args: "<connection|identity|table> <target> <rate> <burst>",
+        about: "修改限流规则，rate 为 0 时删除",
+    },
+    CommandHelp {
+        name: "setquota",
+        args: "<table> <max_keys> <max_bytes>",
+        about: "修改 table 的配额，0 表示不限制",
+    },
+    CommandHelp {
+        name: "ping",
+        args: "[message]",
+        about: "检查连接是否存活，原样返回 message",
+    },
+    CommandHelp {
+        name: "info",
+        args: "",
+        about: "查看服务器的版本、运行时间、存储和连接数",
+    },
+    CommandHelp {
+        name: "client",
+        args: "list | kill <id>",
+        about: "列出当前的连接，或者关闭一个连接",
+    },
+    CommandHelp {
+        name: "config",
+        args: "get [pattern] | set <name> <value>",
+        about: "读取或修改运行时配置",
+    },
+    CommandHelp {
+        name: "flushall",
+        args: "[table]",
+        about: "清空所有 table，或者名字匹配 table 的 table",
+    },
+    CommandHelp {
+        name: "slowlog",
+        args: "get [count]",
+        about: "查看最近的慢命令，从新到旧排列",
+    },
+    CommandHelp {
+        name: "backup",
+        args: "<path>",
+        about: "在服务器上把整个数据库备份到 path，备份到本地用 kv-backup",
+    },
+];
+
+impl CommandRequest {
+    /// 是否是会持续返回数据的命令
+    pub fn is_streaming(&self) -> bool {
+        use crate::command_request::RequestData;
+        matches!(
+            self.request_data,
+            Some(RequestData::Subscribe(_)) | Some(RequestData::Track(_))
+        )
+    }
+}
+
+/// 分词的结果，带引号的词在解析值的时候总是当作字符串
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    pub text: String,
+    pub quoted: bool,
+}
+
+impl Word {
+    fn plain(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            quoted: false,
+        }
+    }
+
+    // 单独的 ; 用来分隔 batch 里的命令
+    fn is_separator(&self) -> bool {
+        !self.quoted && self.text == ";"
+    }
+
+    fn value(&self) -> Value {
+        match self.quoted {
+            true => self.text.as_str().into(),
+            false => parse_value(&self.text),
+        }
+    }
+}
+
+/// 解析一行命令
+pub fn parse_command(line: &str) -> Result<CommandRequest, KvError> {
+    parse_words(&tokenize(line)?)
+}
+
+/// 解析已经分好词的命令，比如命令行参数
+pub fn parse_tokens<S: AsRef<str>>(tokens: &[S]) -> Result<CommandRequest, KvError> {
+    let words: Vec<Word> = tokens.iter().map(|s| Word::plain(s.as_ref())).collect();
+    parse_words(&words)
+}
+
+fn parse_words(words: &[Word]) -> Result<CommandRequest, KvError> {
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (name.text.to_lowercase(), args),
+        None => return Err(KvError::InvalidCommand("Empty command".into())),
+    };
+    let s: Vec<&str> = args.iter().map(|w| w.text.as_str()).collect();
+
+    let cmd = match (name.as_str(), s.as_slice()) {
+        ("hget", [table, key]) => CommandRequest::new_hget(*table, *key),
+        ("hgetall", [table]) => CommandRequest::new_hgetall(*table),
+        ("hmget", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmget(*table, to_strings(keys))
+        }
+        ("hset", [table, key, _]) => CommandRequest::new_hset(*table, *key, args[2].value()),
+        ("hmset", [table, rest @ ..]) if !rest.is_empty() && rest.len().is_multiple_of(2) => {
+            let pairs = args[1..]
+                .chunks(2)
+                .map(|kv| Kvpair::new(kv[0].text.as_str(), kv[1].value()))
+                .collect();
+            CommandRequest::new_hmset(*table, pairs)
+        }
+        ("hdel", [table, key]) => CommandRequest::new_hdel(*table, *key),
+        ("hmdel", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmdel(*table, to_strings(keys))
+        }
+        ("hexist", [table, key]) => CommandRequest::new_hexist(*table, *key),
+        ("hmexist", [table, keys @ ..]) if !keys.is_empty() => {
+            CommandRequest::new_hmexist(*table, to_strings(keys))
+        }
+        ("hscan", [table, rest @ ..]) if rest.len() <= 3 => {
+            let count = match rest.get(1) {
+                Some(count) => parse_number(count)?,
+                None => 0,
+            };
+            CommandRequest::new_hscan(
+                *table,
+                rest.first().copied().unwrap_or_default(),
+                count,
+                rest.get(2).copied().unwrap_or_default(),
+            )
+        }
+        ("tables", []) => CommandRequest::new_list_tables(""),
+        ("tables", [pattern]) => CommandRequest::new_list_tables(*pattern),
+        ("subscribe", [topic]) => CommandRequest::new_subscribe(*topic),
+        ("unsubscribe", [topic, id]) => {
+            let id = id
+                .parse()
+                .map_err(|_| KvError::InvalidCommand(format!("Invalid subscription id: {}", id)))?;
+            CommandRequest::new_unsubscribe(*topic, id)
+        }
+        ("publish", [topic, data @ ..]) if !data.is_empty() => {
+            CommandRequest::new_publish(*topic, args[1..].iter().map(Word::value).collect())
+        }
+        ("batch", _) => parse_batch(args)?,
+        ("track", []) => CommandRequest::new_track(),
+        ("auth", ["--token", token]) => CommandRequest::new_auth_token(*token),
+        ("auth", [username, password]) => CommandRequest::new_auth(*username, *password),
+        ("setratelimit", [scope, target, rate, burst]) => CommandRequest::new_set_rate_limit(
+            *scope,
+            *target,
+            parse_number(rate)?,
+            parse_number(burst)?,
+        ),
+        ("setquota", [table, max_keys, max_bytes]) => {
+            CommandRequest::new_set_quota(*table, parse_number(max_keys)?, parse_number(max_bytes)?)
+        }
+        ("ping", []) => CommandRequest::new_ping(""),
+        ("ping", [message]) => CommandRequest::new_ping(*message),
+        ("info", []) => CommandRequest::new_info(),
+        ("client", ["list"]) => CommandRequest::new_client_list(),
+        ("client", ["kill", id]) => {
+            let id = id
+                .parse()
+                .map_err(|_| KvError::InvalidCommand(format!("Invalid client id: {}", id)))?;
+            CommandRequest::new_client_kill(id)
+        }
+        ("config", ["get"]) => CommandRequest::new_config_get(""),
+        ("config", ["get", pattern]) => CommandRequest::new_config_get(*pattern),
+        ("config", ["set", name, _]) => CommandRequest::new_config_set(*name, args[2].value()),
+        ("flushall", []) => CommandRequest::new_flush_all(""),
+        ("flushall", [table]) => CommandRequest::new_flush_all(*table),
+        ("slowlog", ["get"]) => CommandRequest::new_slowlog_get(0),
+        ("slowlog", ["get", count]) => CommandRequest::new_slowlog_get(parse_number(count)?),
+        ("backup", [path]) => CommandRequest::new_backup(*path),
+        (name, _) => {
+            return Err(match COMMANDS.iter().find(|c| c.name == name) {
+                Some(help) => {
+                    KvError::InvalidCommand(format!("Usage: {} {}", help.name, help.args))
+                }
+                None => KvError::InvalidCommand(format!("Unknown command: {}", name)),
+            })
+        }
+    };
+
+    Ok(cmd)
+}
+
+// batch [--stop-on-error] hset t1 k1 v1 ; hget t1 k1
+fn parse_batch(args: &[Word]) -> Result<CommandRequest, KvError> {
+    let (stop_on_error, args) = match args.split_first() {
+        Some((w, rest)) if w.text == "--stop-on-error" => (true, rest),
+        _ => (false, args),
+    };
+
+    let requests = args
+        .split(Word::is_separator)
+        .filter(|cmd| !cmd.is_empty())
+        .map(|cmd| match cmd[0].text.to_lowercase().as_str() {
+            "batch" => Err(KvError::InvalidCommand(
+                "Nested batch is not supported".into(),
+            )),
+            _ => parse_words(cmd),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if requests.is_empty() {
+        return Err(KvError::InvalidCommand("Batch is empty".into()));
+    }
+    Ok(CommandRequest::new_batch(requests, stop_on_error))
+}
+
+/// 把一个参数解析成 Value
+pub fn parse_value(s: &str) -> Value {
+    if let Some(hex) = s.strip_prefix("0x") {
+        if let Some(data) = decode_hex(hex) {
+            return Bytes::from(data).into();
+        }
+    }
+    if let Ok(i) = s.parse::<i64>() {
+        return i.into();
+    }
+    if let Ok(f) = s.parse::<f64>() {
+        return f.into();
+    }
+    match s {
+        "true" => true.into(),
+        "false" => false.into(),
+        _ => s.into(),
+    }
+}
+
+/// 按空白分词，支持单引号、双引号和反斜杠转义
+pub fn tokenize(line: &str) -> Result<Vec<Word>, KvError> {
+    let mut words = Vec::new();
+    let mut current: Option<Word> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' | '\'' => {
+                let word = current.get_or_insert_with(|| Word::plain(""));
+                word.quoted = true;
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') if c == '"' => match chars.next() {
+                            Some(e) => word.text.push(unescape(e)),
+                            None => break,
+                        },
+                        Some(ch) => word.text.push(ch),
+                        None => {
+                            return Err(KvError::InvalidCommand(format!(
+                                "Unterminated quote in: {}",
+                                line
+                            )))
+                        }
+                    }
+                }
+            }
+            '\\' => {
+                if let Some(e) = chars.next() {
+                    current.get_or_insert_with(|| Word::plain("")).text.push(e);
+                }
+            }
+            c if c.is_whitespace() => {
+                if let Some(word) = current.take() {
+                    words.push(word);
+                }
+            }
+            c => current.get_or_insert_with(|| Word::plain("")).text.push(c),
+        }
+    }
+    if let Some(word) = current {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        '0' => '\0',
+        c => c,
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(s: &str) -> Result<T, KvError> {
+    s.parse()
+        .map_err(|_| KvError::InvalidCommand(format!("Invalid number: {}", s)))
+}
+
+fn to_strings(keys: &[&str]) -> Vec<String> {
+    keys.iter().map(|k| k.to_string()).collect()
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if s.is_empty() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_should_handle_quotes_and_escapes() {
+        let words = tokenize(r#"hset t1 "k 1" 'it''s' a\ b\"c"#).unwrap();
+        let text: Vec<_> = words.iter().map(|w| w.text.as_str()).collect();
+        assert_eq!(text, ["hset", "t1", "k 1", "its", "a b\"c"]);
+        assert!(words[2].quoted && !words[4].quoted);
+        assert!(tokenize("hget t1 k1 ; hget t1 k2").unwrap()[3].is_separator());
+        assert!(!tokenize("\";\"").unwrap()[0].is_separator());
+        assert!(tokenize("hset t1 k1 \"v1").is_err());
+    }
+
+    #[test]
+    fn values_should_be_parsed() {
+        assert_eq!(parse_value("42"), 42i64.into());
+        assert_eq!(parse_value("1.5"), 1.5.into());
+        assert_eq!(parse_value("true"), true.into());
+        assert_eq!(parse_value("0x0aff"), Bytes::from(vec![0x0a, 0xff]).into());
+        assert_eq!(parse_value("hello"), "hello".into());
+        // 带引号的数字是字符串
+        assert_eq!(tokenize("\"42\"").unwrap()[0].value(), "42".into());
+    }
+
+    #[test]
+    fn commands_should_be_parsed() {
+        assert_eq!(
+            parse_command("HSET t1 k1 v1").unwrap(),
+            CommandRequest::new_hset("t1", "k1", "v1".into())
+        );
+        assert_eq!(
+            parse_command("hmset t1 k1 1 k2 \"two\"").unwrap(),
+            CommandRequest::new_hmset(
+                "t1",
+                vec![
+                    Kvpair::new("k1", 1i64.into()),
+                    Kvpair::new("k2", "two".into())
+                ]
+            )
+        );
+        assert_eq!(
+            parse_tokens(&["hmget", "t1", "k1", "k2"]).unwrap(),
+            CommandRequest::new_hmget("t1", vec!["k1".into(), "k2".into()])
+        );
+        assert_eq!(
+            parse_command("batch --stop-on-error hset t1 k1 v1 ; hget t1 k1").unwrap(),
+            CommandRequest::new_batch(
+                vec![
+                    CommandRequest::new_hset("t1", "k1", "v1".into()),
+                    CommandRequest::new_hget("t1", "k1"),
+                ],
+                true
+            )
+        );
+        assert!(parse_command("track").unwrap().is_streaming());
+        assert_eq!(
+            parse_command("client kill 3").unwrap(),
+            CommandRequest::new_client_kill(3)
+        );
+        assert_eq!(
+            parse_command("config set admission.max_in_flight 100").unwrap(),
+            CommandRequest::new_config_set("admission.max_in_flight", 100.into())
+        );
+        assert_eq!(
+            parse_command("flushall cache.*").unwrap(),
+            CommandRequest::new_flush_all("cache.*")
+        );
+        assert_eq!(
+            parse_command("hscan t1 \"\" 50 user:*").unwrap(),
+            CommandRequest::new_hscan("t1", "", 50, "user:*")
+        );
+    }
+
+    #[test]
+    fn invalid_commands_should_be_rejected() {
+        let err = parse_command("hget t1").unwrap_err();
+        assert!(err.to_string().contains("Usage: hget <table> <key>"));
+        assert!(parse_command("hmset t1 k1").is_err());
+        assert!(parse_command("unsubscribe lobby abc").is_err());
+        assert!(parse_command("client kill abc").is_err());
+        assert!(parse_command("config").is_err());
+        assert!(parse_command("foo").is_err());
+        assert!(parse_command("batch hget t1 k1 ; batch").is_err());
+        assert!(parse_command("").is_err());
+    }
+
+    #[test]
+    fn every_command_in_proto_should_have_help() {
+        // RequestData 中的每个命令都能在 COMMANDS 里找到
+        for name in [
+            "hget",
+            "hgetall",
+            "hmget",
+            "hset",
+            "hmset",
+            "hdel",
+            "hmdel",
+            "hexist",
+            "hmexist",
+            "subscribe",
+            "unsubscribe",
+            "publish",
+            "batch",
+            "track",
+            "auth",
+            "setratelimit",
+            "setquota",
+            "ping",
+            "info",
+            "client",
+            "config",
+            "flushall",
+            "slowlog",
+            "backup",
+            "hscan",
+            "tables",
+        ] {
+            assert!(COMMANDS.iter().any(|c| c.name == name), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prost::Message;
+use serde_json::{json, Map, Value as Json};
+
+use crate::{value, CommandResponse, ErrorCode, ErrorDetail, KvError, Value};
+
+/// 命令行客户端的输出格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    /// 适合人阅读的文本
+    #[default]
+    Human,
+    /// 每个响应一行 JSON，二进制数据用 base64 表示
+    Json,
+    /// 原始的 protobuf，每个响应前面带 varint 长度
+    Proto,
+}
+
+impl FromStr for OutputFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            "proto" => Ok(Self::Proto),
+            _ => Err(KvError::InvalidCommand(format!(
+                "Unknown output format: {} (expected human, json or proto)",
+                s
+            ))),
+        }
+    }
+}
+
+/// 按照输出格式把响应转换成可以直接写到 stdout 的数据
+pub fn render_response(res: &CommandResponse, format: OutputFormat) -> Vec<u8> {
+    match format {
+        OutputFormat::Human => {
+            let mut out = String::new();
+            write_human(&mut out, res, "");
+            out.into_bytes()
+        }
+        OutputFormat::Json => {
+            let mut out = response_to_json(res).to_string();
+            out.push('\n');
+            out.into_bytes()
+        }
+        OutputFormat::Proto => res.encode_length_delimited_to_vec(),
+    }
+}
+
+fn write_human(out: &mut String, res: &CommandResponse, indent: &str) {
+    if !(200..300).contains(&res.status) {
+        let _ = writeln!(out, "{}(error {}) {}", indent, res.status, res.message);
+        return;
+    }
+
+    if !res.responses.is_empty() {
+        for (i, r) in res.responses.iter().enumerate() {
+            let _ = writeln!(out, "{}{})", indent, i + 1);
+            write_human(out, r, &format!("{}   ", indent));
+        }
+        return;
+    }
+
+    match (res.values.as_slice(), res.pairs.as_slice()) {
+        ([], []) => {
+            let _ = writeln!(out, "{}OK", indent);
+        }
+        ([v], []) => {
+            let _ = writeln!(out, "{}{}", indent, value_to_text(v));
+        }
+        (values, pairs) => {
+            for (i, v) in values.iter().enumerate() {
+                let _ = writeln!(out, "{}{}) {}", indent, i + 1, value_to_text(v));
+            }
+            for pair in pairs {
+                let v = pair.value.clone().unwrap_or_default();
+                let _ = writeln!(out, "{}{} => {}", indent, pair.key, value_to_text(&v));
+            }
+        }
+    }
+}
+
+// 错误详情只输出有内容的字段
+fn error_to_json(code: ErrorCode, error: &ErrorDetail) -> Json {
+    let mut obj = Map::new();
+    obj.insert("code".into(), json!(format!("{:?}", code)));
+    obj.insert("retryable".into(), json!(error.retryable));
+    let fields = [
+        ("table", &error.table),
+        ("key", &error.key),
+        ("expected_type", &error.expected_type),
+        ("operation", &error.operation),
+        ("reason", &error.reason),
+    ];
+    for (name, value) in fields {
+        if !value.is_empty() {
+            obj.insert(name.into(), json!(value));
+        }
+    }
+    if let Some(actual) = &error.actual {
+        obj.insert("actual".into(), value_to_json(actual));
+    }
+    Json::Object(obj)
+}
+
+/// Value 的文本形式，字符串带引号，二进制用十六进制
+pub fn value_to_text(v: &Value) -> String {
+    match &v.value {
+        None => "(nil)".into(),
+        Some(value::Value::String(s)) => format!("{:?}", s),
+        Some(value::Value::Binary(b)) => {
+            let mut s = String::with_capacity(2 + b.len() * 2);
+            s.push_str("0x");
+            for byte in b.iter() {
+                let _ = write!(s, "{:02x}", byte);
+            }
+            s
+        }
+        Some(value::Value::Integer(i)) => i.to_string(),
+        Some(value::Value::Float(f)) => f.to_string(),
+        Some(value::Value::Bool(b)) => b.to_string(),
+    }
+}
+
+/// CommandResponse 的 JSON 形式，空的字段不输出
+pub fn response_to_json(res: &CommandResponse) -> Json {
+    let mut obj = Map::new();
+    obj.insert("status".into(), json!(res.status));
+    if !res.message.is_empty() {
+        obj.insert("message".into(), json!(res.message));
+    }
+    if res.retry_after_ms > 0 {
+        obj.insert("retry_after_ms".into(), json!(res.retry_after_ms));
+    }
+    if let Some(error) = &res.error {
+        obj.insert("error".into(), error_to_json(res.error_code(), error));
+    }
+    if !res.values.is_empty() {
+        let values = res.values.iter().map(value_to_json).collect();
+        obj.insert("values".into(), Json::Array(values));
+    }
+    if !res.pairs.is_empty() {
+        let pairs = res
+            .pairs
+            .iter()
+            .map(|p| {
+                let v = p.value.clone().unwrap_or_default();
+                json!({ "key": p.key, "value": value_to_json(&v) })
+            })
+            .collect();
+        obj.insert("pairs".into(), Json::Array(pairs));
+    }
+    if !res.responses.is_empty() {
+        let responses = res.responses.iter().map(response_to_json).collect();
+        obj.insert("responses".into(), Json::Array(responses));
+    }
+    Json::Object(obj)
+}
+
+/// Value 的 JSON 形式，二进制表示为 {"base64": "..."}，
+/// JSON 中没有的 NaN 和无穷大表示为 {"float": "NaN"}、{"float": "inf"}、{"float": "-inf"}
+pub fn value_to_json(v: &Value) -> Json {
+    match &v.value {
+        None => Json::Null,
+        Some(value::Value::String(s)) => json!(s),
+        Some(value::Value::Binary(b)) => json!({ "base64": STANDARD.encode(b) }),
+        Some(value::Value::Integer(i)) => json!(i),
+        Some(value::Value::Float(f)) if !f.is_finite() => json!({ "float": f.to_string() }),
+        Some(value::Value::Float(f)) => json!(f),
+        Some(value::Value::Bool(b)) => json!(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::Kvpair;
+
+    #[test]
+    fn human_output_should_work() {
+        let render = |res: &CommandResponse| {
+            String::from_utf8(render_response(res, OutputFormat::Human)).unwrap()
+        };
+
+        assert_eq!(render(&CommandResponse::ok()), "OK\n");
+        assert_eq!(render(&Value::from("v1").into()), "\"v1\"\n");
+        assert_eq!(
+            render(&vec![Value::from(1i64), Value::default()].into()),
+            "1) 1\n2) (nil)\n"
+        );
+        assert_eq!(
+            render(&vec![Kvpair::new("k1", Bytes::from_static(b"\x01\xff").into())].into()),
+            "k1 => 0x01ff\n"
+        );
+        let err: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        assert!(render(&err).starts_with("(error 404) "));
+        assert_eq!(
+            render(&vec![CommandResponse::ok(), Value::from(true).into()].into()),
+            "1)\n   OK\n2)\n   true\n"
+        );
+    }
+
+    #[test]
+    fn json_output_should_work() {
+        let res: CommandResponse = vec![
+            Value::from("v1"),
+            Bytes::from_static(b"hi").into(),
+            Value::default(),
+        ]
+        .into();
+        let out = String::from_utf8(render_response(&res, OutputFormat::Json)).unwrap();
+        assert_eq!(
+            out,
+            "{\"status\":200,\"values\":[\"v1\",{\"base64\":\"aGk=\"},null]}\n"
+        );
+    }
+
+    #[test]
+    fn json_output_should_include_error_detail() {
+        let res: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        let out = String::from_utf8(render_response(&res, OutputFormat::Json)).unwrap();
+        let json: Json = serde_json::from_str(&out).unwrap();
+        assert_eq!(json["status"], 404);
+        assert_eq!(
+            json["error"],
+            json!({"code": "NotFound", "retryable": false, "table": "t1", "key": "k1"})
+        );
+    }
+
+    #[test]
+    fn proto_output_should_be_decodable() {
+        let res: CommandResponse = Value::from(42i64).into();
+        let out = render_response(&res, OutputFormat::Proto);
+        assert_eq!(
+            CommandResponse::decode_length_delimited(&out[..]).unwrap(),
+            res
+        );
+    }
+
+    #[test]
+    fn output_format_should_be_parsed() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -1,42 +1,346 @@
-use anyhow::Result;
-use async_prost::AsyncProstStream;
-use db_server::CommandRequest;
-use db_server::ProstClientStream;
-use db_server::TlsClientConnector;
-use futures::*; //有些方法怎么感觉要显式引进
-use tokio::net::TcpStream;
-use tracing::info;
+use std::borrow::Cow;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use db_server::{
+    parse_command, parse_tokens, render_response, ClientConfig, CommandRequest, Credentials,
+    KvPool, OutputFormat, COMMANDS,
+};
+use futures::StreamExt;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use tokio::signal;
+
+/// KV server 命令行客户端
+///
+/// 不带命令时进入交互模式；带命令时执行完退出。
+/// 也可以用 -f 从文件（- 表示 stdin）读取命令，每行一条。
+#[derive(Parser, Debug)]
+#[command(name = "client", version)]
+struct Args {
+    /// 配置文件，命令行参数会覆盖其中的设置
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// 服务器地址
+    #[arg(long)]
+    addr: Option<String>,
+
+    /// 服务器证书的域名
+    #[arg(long)]
+    domain: Option<String>,
+
+    /// CA 证书文件
+    #[arg(long)]
+    ca: Option<PathBuf>,
+
+    /// 客户端证书文件，需要和 --key 一起使用
+    #[arg(long, requires = "key")]
+    cert: Option<PathBuf>,
+
+    /// 客户端私钥文件
+    #[arg(long, requires = "cert")]
+    key: Option<PathBuf>,
+
+    /// 连接后用这个用户名认证，需要和 --password 一起使用
+    #[arg(short, long, requires = "password", conflicts_with = "token")]
+    user: Option<String>,
+
+    /// 认证用的密码
+    #[arg(short, long, requires = "user")]
+    password: Option<String>,
+
+    /// 连接后用这个 token 认证
+    #[arg(long)]
+    token: Option<String>,
+
+    /// 输出格式：human、json 或 proto
+    #[arg(short, long, default_value = "human")]
+    output: OutputFormat,
+
+    /// 从文件读取命令，- 表示 stdin
+    #[arg(short, long, conflicts_with = "command")]
+    file: Option<PathBuf>,
+
+    /// 交互模式的历史记录文件
+    #[arg(long)]
+    history: Option<PathBuf>,
+
+    /// 要执行的命令，比如 hget t1 k1
+    command: Vec<String>,
+}
+
+const HISTORY_FILE: &str = ".kv_history";
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let addr = "127.0.0.1:9527";
+    let args = Args::parse();
+    // 日志输出到 stderr，不影响 stdout 上的结果
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
 
-    // 读取配置文件
-    let ca_cert = include_str!("../fixtures/ca.cert");
+    let config = load_config(&args)?;
+    let pool = KvPool::connect(&config).await?;
+    let mut cli = Cli {
+        pool,
+        output: args.output,
+        failed: false,
+    };
 
-    // 提供证书
-    let connector = TlsClientConnector::new("dbserver.acme.inc", None, Some(ca_cert))?;
+    if !args.command.is_empty() {
+        cli.run(parse_tokens(&args.command)).await?;
+    } else if let Some(path) = &args.file {
+        if path.as_os_str() == "-" {
+            cli.run_script(io::stdin().lock())?;
+        } else {
+            cli.run_script(io::BufReader::new(fs::File::open(path)?))?;
+        }
+    } else if io::stdin().is_terminal() {
+        cli.repl(args.history.unwrap_or_else(history_path))?;
+    } else {
+        cli.run_script(io::stdin().lock())?;
+    }
 
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+    if cli.failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
 
-    // let mut client =
-    //         AsyncProstStream::<_, CommandResponse, CommandRequest, _>::from(stream).for_async();
+// 配置文件加上命令行参数，都没有时使用默认值
+fn load_config(args: &Args) -> Result<ClientConfig> {
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(&path.to_string_lossy())?,
+        None => ClientConfig::default(),
+    };
 
-    let mut client = ProstClientStream::new(stream);
+    if let Some(addr) = &args.addr {
+        config.general.addr = addr.clone();
+    }
+    if let Some(domain) = &args.domain {
+        config.tls.domain = domain.clone();
+    }
+    if let Some(ca) = &args.ca {
+        config.tls.ca = Some(fs::read_to_string(ca)?);
+    }
+    if let (Some(cert), Some(key)) = (&args.cert, &args.key) {
+        config.tls.identity = Some((fs::read_to_string(cert)?, fs::read_to_string(key)?));
+    }
+    if let (Some(username), Some(password)) = (&args.user, &args.password) {
+        config.auth = Some(Credentials {
+            username: username.clone(),
+            password: password.clone(),
+            token: None,
+        });
+    }
+    if let Some(token) = &args.token {
+        config.auth = Some(Credentials {
+            token: Some(token.clone()),
+            ..Default::default()
+        });
+    }
+    Ok(config)
+}
 
-    let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+fn history_path() -> PathBuf {
+    match std::env::var_os("HOME") {
+        Some(home) => PathBuf::from(home).join(HISTORY_FILE),
+        None => PathBuf::from(HISTORY_FILE),
+    }
+}
 
-    //     client.send(cmd).await?;
+struct Cli {
+    pool: KvPool,
+    output: OutputFormat,
+    // 有命令失败时进程以非 0 退出
+    failed: bool,
+}
 
-    let data = client.execute_unary(&cmd).await?;
+impl Cli {
+    // 执行一条命令并输出结果，流式命令一直输出到 Ctrl-C
+    async fn run(&mut self, cmd: Result<CommandRequest, db_server::KvError>) -> Result<()> {
+        let cmd = match cmd {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                self.failed = true;
+                eprintln!("{}", e);
+                return Ok(());
+            }
+        };
 
-    info!("Got response {:?}", data);
+        if cmd.is_streaming() {
+            return self.run_streaming(&cmd).await;
+        }
 
-    //     if let Some(Ok(data)) = client.next().await {
-    //         info!("Got response {:?}", data);
-    //     }
+        let res = self.pool.execute(&cmd).await?;
+        if !(200..300).contains(&res.status) {
+            self.failed = true;
+        }
+        self.print(&render_response(&res, self.output))
+    }
 
-    Ok(())
+    async fn run_streaming(&mut self, cmd: &CommandRequest) -> Result<()> {
+        let mut stream = self.pool.get().await?.execute_streaming(cmd).await?;
+        if self.output == OutputFormat::Human {
+            eprintln!("Streaming (id {}), press Ctrl-C to stop", stream.id);
+        }
+
+        loop {
+            tokio::select! {
+                res = stream.next() => match res {
+                    Some(Ok(res)) => self.print(&render_response(&res, self.output))?,
+                    Some(Err(e)) => return Err(e.into()),
+                    None => return Ok(()),
+                },
+                _ = signal::ctrl_c() => return Ok(()),
+            }
+        }
+    }
+
+    fn run_script(&mut self, input: impl BufRead) -> Result<()> {
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.block_on(parse_command(line))?;
+        }
+        Ok(())
+    }
+
+    fn repl(&mut self, history: PathBuf) -> Result<()> {
+        let mut rl: Editor<CliHelper, DefaultHistory> = Editor::new()?;
+        rl.set_helper(Some(CliHelper));
+        // 第一次运行时还没有历史记录文件
+        let _ = rl.load_history(&history);
+
+        loop {
+            match rl.readline("kv> ") {
+                Ok(line) => {
+                    let line = line.trim();
+                    if line.is_empty() {
+                        continue;
+                    }
+                    let _ = rl.add_history_entry(line);
+                    match line {
+                        "exit" | "quit" => break,
+                        "help" => print_help(),
+                        line => {
+                            if let Err(e) = self.block_on(parse_command(line)) {
+                                eprintln!("Error: {}", e);
+                            }
+                        }
+                    }
+                }
+                // Ctrl-C 清空当前行，Ctrl-D 退出
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => break,
+                Err(e) => return Err(anyhow!(e)),
+            }
+        }
+
+        rl.save_history(&history)?;
+        Ok(())
+    }
+
+    // 读取输入是同步的，在当前线程上执行异步的命令
+    fn block_on(&mut self, cmd: Result<CommandRequest, db_server::KvError>) -> Result<()> {
+        tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(self.run(cmd)))
+    }
+
+    fn print(&self, data: &[u8]) -> Result<()> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(data)?;
+        stdout.flush()?;
+        Ok(())
+    }
+}
+
+fn print_help() {
+    let builtin = [("help", "显示帮助"), ("exit", "退出")];
+    let commands = COMMANDS.iter().map(|c| (c.name, c.args, c.about));
+    for (name, args, about) in commands.chain(builtin.map(|(name, about)| (name, "", about))) {
+        println!("  {:<12} {:<45} {}", name, args, about);
+    }
+}
+
+// 交互模式的补全：命令名，以及 batch 中 ; 后面的命令名
+struct CliHelper;
+
+impl Completer for CliHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let before = &line[..pos];
+        let start = before
+            .rfind(|c: char| c.is_whitespace())
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let word = before[start..].to_lowercase();
+        let previous = before[..start].split_whitespace().last();
+
+        // 只在命令的位置补全
+        if !matches!(previous, None | Some(";")) {
+            return Ok((pos, vec![]));
+        }
+
+        let names = COMMANDS
+            .iter()
+            .map(|c| c.name)
+            .chain(["help", "exit"])
+            .filter(|name| name.starts_with(&word))
+            .map(|name| Pair {
+                display: name.to_string(),
+                replacement: format!("{} ", name),
+            })
+            .collect();
+        Ok((start, names))
+    }
 }
+
+impl Hinter for CliHelper {
+    type Hint = String;
+
+    // 输入完命令名后提示参数
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos != line.len() {
+            return None;
+        }
+        let mut words = line.split_whitespace();
+        let name = words.next()?;
+        if words.next().is_some() || !line.ends_with(' ') {
+            return None;
+        }
+        COMMANDS
+            .iter()
+            .find(|c| c.name.eq_ignore_ascii_case(name))
+            .map(|c| c.args.to_string())
+    }
+}
+
+impl Highlighter for CliHelper {
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        // 提示显示成灰色
+        Cow::Owned(format!("\x1b[90m{}\x1b[0m", hint))
+    }
+}
+
+impl Validator for CliHelper {}
+
+impl Helper for CliHelper {}
@@ -8,12 +8,65 @@ pub struct ServerConfig {
     pub storage: StorageConfig,
     pub tls: ServerTlsConfig,
     pub log: LogConfig,
+    // 认证和访问控制，不配置时所有连接都有全部权限
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    // 限流和配额，不配置时没有限制
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    // Prometheus 监控指标，不配置时不开启 HTTP 服务
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    // 链路追踪和日志，不配置时不导出 span，日志输出 info 级别
+    #[serde(default)]
+    pub tracing: TracingConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    // 空闲连接和握手的超时
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    // 连接数、stream 数和请求数的上限
+    #[serde(default)]
+    pub admission: AdmissionConfig,
+    // 慢命令日志，用 SlowlogGet 查看
+    #[serde(default)]
+    pub slowlog: SlowlogConfig,
+    // 写命令的审计日志，写入 path 下按 rotation 滚动的 audit.log，不配置时不记录
+    #[serde(default)]
+    pub audit: Option<LogConfig>,
+    // Backup 命令把备份写到服务器上时所在的目录，path 只能是其中的相对路径。
+    // 不配置时只能以流的形式备份到客户端
+    #[serde(default)]
+    pub backup_dir: Option<String>,
+    // 启动时如果存储是空的，先从这个备份文件恢复数据。MemTable 没有持久化，只能用这种方式恢复；
+    // SledDb 已经有数据时不会再恢复，不会用旧的备份覆盖之后的写入
+    #[serde(default)]
+    pub restore: Option<String>,
+    // 监听的地址和传输方式，不配置时用 TLS 监听 general.addr
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    // 主从复制，不配置 leader 时作为 leader 运行
+    #[serde(default)]
+    pub replication: ReplicationConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct ClientConfig {
     pub general: GeneralConfig,
     pub tls: ClientTlsConfig,
+    #[serde(default)]
+    pub reconnect: ReconnectConfig,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    // 客户端缓存，不配置就不开启
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+    // 连接（包括重连）后用 Auth 命令认证，不配置时使用证书的身份或者匿名
+    #[serde(default)]
+    pub auth: Option<Credentials>,
+    // 连接服务器的方式，需要和服务器的 listener 一致
+    #[serde(default)]
+    pub connector: ConnectorConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -55,6 +108,413 @@ pub struct ClientTlsConfig {
     pub ca: Option<String>,
 }
 
+/// 断线重连的退避策略，时间单位都是毫秒
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ReconnectConfig {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    pub multiplier: f64,
+    // 随机抖动的比例，0.2 表示在 ±20% 范围内浮动
+    pub jitter: f64,
+    // 最多重试次数，用完后等待连接的调用会返回错误，下一次调用再重新开始重连
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 100,
+            max_delay_ms: 10_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: Some(8),
+        }
+    }
+}
+
+/// 客户端连接池配置，时间单位都是毫秒
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PoolConfig {
+    // TLS 连接个数
+    pub connections: usize,
+    // 同时借出的 yamux stream 上限
+    pub max_streams: usize,
+    // stream 空闲超过这个时间就关掉
+    pub max_idle_ms: u64,
+    // stream 存在超过这个时间就关掉
+    pub max_lifetime_ms: u64,
+    // 后台检查连接是否健康的间隔
+    pub health_check_interval_ms: u64,
+    // 等待可用 stream 的超时时间
+    pub checkout_timeout_ms: u64,
+    // 健康检查时等待 ping 响应的超时时间
+    pub ping_timeout_ms: u64,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            connections: 1,
+            max_streams: 128,
+            max_idle_ms: 60_000,
+            max_lifetime_ms: 30 * 60_000,
+            health_check_interval_ms: 10_000,
+            checkout_timeout_ms: 5_000,
+            ping_timeout_ms: 5_000,
+        }
+    }
+}
+
+/// 客户端缓存配置，缓存的 key 被修改时服务器会通知客户端
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct CacheConfig {
+    // 最多缓存的 key 个数，满了之后淘汰最早缓存的
+    pub capacity: usize,
+    // 缓存的有效期，错过失效通知时最多读到这么久以前的数据
+    pub ttl_ms: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            ttl_ms: 60_000,
+        }
+    }
+}
+
+/// 认证和访问控制配置
+///
+/// 连接的身份来自客户端证书（subject 的 CN 和 SAN），或者 Auth 命令认证的用户，
+/// 每个命令需要的权限由 rules 授予，没有规则授予的命令都会被拒绝
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub users: Vec<UserConfig>,
+    pub rules: Vec<AclRule>,
+}
+
+/// 可以通过 Auth 命令认证的用户，password 和 token 至少配置一个
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct UserConfig {
+    pub name: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+}
+
+/// 一条授权规则
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct AclRule {
+    // 用户名或证书中的名字，* 匹配所有认证过的身份，anonymous 匹配未认证的连接
+    pub identity: String,
+    // table 名字的 glob，支持 * 和 ?，read/write/admin 权限作用在这些 table 上。
+    // 调整限流等服务器级别的命令需要 tables 中有 "*" 的规则授予 admin 权限
+    #[serde(default)]
+    pub tables: Vec<String>,
+    // 主题名字的 glob，publish/subscribe/admin 权限作用在这些主题上
+    #[serde(default)]
+    pub topics: Vec<String>,
+    pub permissions: Vec<Permission>,
+}
+
+/// 权限，admin 包含其它所有权限
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+    Publish,
+    Subscribe,
+}
+
+/// 限流和存储配额，运行时可以通过 SetRateLimit/SetQuota 命令调整
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub rate: Vec<RateLimitConfig>,
+    pub quotas: Vec<QuotaConfig>,
+}
+
+/// 令牌桶限流规则，每条命令消耗一个令牌，Batch 中的每条命令各算一个
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RateLimitConfig {
+    pub scope: LimitScope,
+    // 身份或 table 名字的 glob，connection 范围的规则不使用
+    #[serde(default = "match_all")]
+    pub target: String,
+    // 每秒补充的令牌数
+    pub rate: f64,
+    // 桶的容量，也就是允许的突发请求数
+    pub burst: u32,
+}
+
+/// 限流的范围，每个连接、身份或 table 各自有一个桶
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    Connection,
+    Identity,
+    Table,
+}
+
+/// table 的存储配额，key 和 value 编码后的大小都计入 max_bytes
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct QuotaConfig {
+    // table 名字的 glob，匹配的每个 table 各自计算
+    pub table: String,
+    #[serde(default)]
+    pub max_keys: Option<u64>,
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// 监控指标的 HTTP 服务
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct MetricsConfig {
+    // 监听地址，GET /metrics 返回指标
+    pub addr: String,
+}
+
+/// 服务器的一个监听地址
+///
+/// tls 使用 [tls] 中的证书；tcp 不加密，只能用在可信的网络里，需要显式设置
+/// allow_plaintext；unix 是本机的 Unix domain socket，mode 是 socket 文件的权限，比如 0o660。
+/// yamux 为 false 时每个连接上只有一个 stream，适合不支持 yamux 的客户端
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum ListenerConfig {
+    Tls {
+        addr: String,
+        #[serde(default = "default_yamux")]
+        yamux: bool,
+    },
+    Tcp {
+        addr: String,
+        #[serde(default)]
+        allow_plaintext: bool,
+        #[serde(default = "default_yamux")]
+        yamux: bool,
+    },
+    Unix {
+        path: String,
+        #[serde(default)]
+        mode: Option<u32>,
+        #[serde(default = "default_yamux")]
+        yamux: bool,
+    },
+}
+
+/// 客户端连接服务器的方式，tls 和 tcp 连接 general.addr，unix 连接 path
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "transport", rename_all = "lowercase")]
+pub enum ConnectorConfig {
+    Tls {
+        #[serde(default = "default_yamux")]
+        yamux: bool,
+    },
+    Tcp {
+        #[serde(default = "default_yamux")]
+        yamux: bool,
+    },
+    Unix {
+        path: String,
+        #[serde(default = "default_yamux")]
+        yamux: bool,
+    },
+}
+
+impl Default for ConnectorConfig {
+    fn default() -> Self {
+        ConnectorConfig::Tls { yamux: true }
+    }
+}
+
+fn default_yamux() -> bool {
+    true
+}
+
+/// 优雅关闭的配置，时间单位是毫秒
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // 等待正在处理的请求完成的最长时间，超时后不再等待
+    pub drain_timeout_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// 连接超时的配置，时间单位是毫秒，0 表示不限制
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    // 连接上没有收到任何请求超过这个时间就关闭
+    pub idle_timeout_ms: u64,
+    // TLS 握手的最长时间
+    pub handshake_timeout_ms: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout_ms: 300_000,
+            handshake_timeout_ms: 10_000,
+        }
+    }
+}
+
+/// 准入控制的上限，超过时回复 503，0 表示不限制
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AdmissionConfig {
+    // 所有监听上同时打开的连接数
+    pub max_connections: usize,
+    // 每个 yamux 连接上同时打开的 stream 数
+    pub max_streams_per_connection: usize,
+    // 所有连接上正在处理（还没有返回第一个响应）的请求数
+    pub max_in_flight: usize,
+}
+
+impl Default for AdmissionConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            max_streams_per_connection: 256,
+            max_in_flight: 4096,
+        }
+    }
+}
+
+/// 慢命令日志的配置，运行时可以用 CONFIG SET 修改
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SlowlogConfig {
+    // 处理时间不少于这个值（微秒）的命令会被记录，0 表示记录所有命令
+    pub threshold_us: u64,
+    // 最多保留的条数，超过时丢弃最旧的，0 表示关闭慢命令日志
+    pub max_len: usize,
+}
+
+impl Default for SlowlogConfig {
+    fn default() -> Self {
+        Self {
+            threshold_us: 10_000,
+            max_len: 128,
+        }
+    }
+}
+
+/// 主从复制的配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    // 作为 leader 时为每个 follower 缓存的写操作条数，落后更多的 follower 会被断开，重连后全量同步
+    pub backlog: usize,
+    // 作为 leader 时发给 follower 的心跳间隔（毫秒）
+    pub heartbeat_ms: u64,
+    // 配置后作为 follower 运行：用这个客户端配置连接 leader 同步数据，拒绝所有写命令
+    pub leader: Option<ClientConfig>,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        Self {
+            backlog: 10_000,
+            heartbeat_ms: 1000,
+            leader: None,
+        }
+    }
+}
+
+/// 链路追踪和日志配置
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct TracingConfig {
+    pub exporter: TraceExporter,
+    // jaeger agent 的 UDP 地址或 OTLP collector 的 gRPC 地址，不配置时使用各自的默认地址
+    pub endpoint: Option<String>,
+    // 采样比例，1.0 表示全部采样，父 span 采样时子 span 总是采样
+    pub sample_ratio: f64,
+    // EnvFilter 的语法，比如 "info,sled=warn"，设置了 RUST_LOG 时以 RUST_LOG 为准
+    pub level: String,
+    // 日志文件的格式
+    pub format: LogFormat,
+}
+
+impl Default for TracingConfig {
+    fn default() -> Self {
+        Self {
+            exporter: TraceExporter::None,
+            endpoint: None,
+            sample_ratio: 1.0,
+            level: "info".into(),
+            format: LogFormat::Compact,
+        }
+    }
+}
+
+/// span 导出到哪里
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    None,
+    Stdout,
+    Jaeger,
+    Otlp,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Compact,
+    Json,
+}
+
+fn match_all() -> String {
+    "*".into()
+}
+
+/// 客户端的认证信息，有 token 时使用 token
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+    pub token: Option<String>,
+}
+
+impl Default for ClientConfig {
+    /// 连接本机的默认端口，使用系统的根证书
+    fn default() -> Self {
+        Self {
+            general: GeneralConfig {
+                addr: "127.0.0.1:9527".into(),
+            },
+            tls: ClientTlsConfig {
+                domain: "dbserver.acme.inc".into(),
+                identity: None,
+                ca: None,
+            },
+            reconnect: Default::default(),
+            pool: Default::default(),
+            cache: None,
+            auth: None,
+            connector: Default::default(),
+        }
+    }
+}
+
 impl ServerConfig {
     pub fn load(path: &str) -> Result<Self, KvError> {
         let config = fs::read_to_string(path)?;
@@ -88,4 +548,71 @@ mod tests {
             toml::from_str(include_str!("../fixtures/client.conf"));
         assert!(result.is_ok());
     }
+
+    #[test]
+    fn tracing_config_should_be_loaded() {
+        let config: TracingConfig = toml::from_str(
+            r#"
+            exporter = "otlp"
+            endpoint = "http://127.0.0.1:4317"
+            sample_ratio = 0.1
+            format = "json"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.exporter, TraceExporter::Otlp);
+        assert_eq!(config.endpoint.as_deref(), Some("http://127.0.0.1:4317"));
+        assert_eq!(config.sample_ratio, 0.1);
+        assert_eq!(config.level, "info");
+        assert_eq!(config.format, LogFormat::Json);
+
+        let config: ServerConfig = toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        assert_eq!(config.tracing, TracingConfig::default());
+    }
+
+    #[test]
+    fn listener_config_should_be_loaded() {
+        #[derive(Deserialize)]
+        struct Listeners {
+            listeners: Vec<ListenerConfig>,
+        }
+        let config: Listeners = toml::from_str(
+            r#"
+            [[listeners]]
+            transport = "tls"
+            addr = "0.0.0.0:9527"
+
+            [[listeners]]
+            transport = "tcp"
+            addr = "127.0.0.1:9528"
+            allow_plaintext = true
+            yamux = false
+
+            [[listeners]]
+            transport = "unix"
+            path = "/tmp/kv.sock"
+            mode = 0o660
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listeners,
+            vec![
+                ListenerConfig::Tls {
+                    addr: "0.0.0.0:9527".into(),
+                    yamux: true
+                },
+                ListenerConfig::Tcp {
+                    addr: "127.0.0.1:9528".into(),
+                    allow_plaintext: true,
+                    yamux: false
+                },
+                ListenerConfig::Unix {
+                    path: "/tmp/kv.sock".into(),
+                    mode: Some(0o660),
+                    yamux: true
+                },
+            ]
+        );
+    }
 }
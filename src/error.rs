@@ -4,9 +4,7 @@
 // 使用thiserror定义自己的错误类型,用Error宏,新的Error是枚举，包含了所有可能的错误
 // 注意用法
 // 使用实现
-use crate::Value;
-use sled;
-use std::io::Error as IoError;
+use crate::{ErrorCode, ErrorDetail, Value};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -16,9 +14,9 @@ pub enum KvError {
     NotFound(String, String),
     #[error("Cannot parse command: `{0}`")]
     InvalidCommand(String),
-    #[error("Cannot convert value {:0} to {1}")]
+    #[error("Cannot convert value {0:?} to {1}")]
     ConvertError(Value, &'static str),
-    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {}")]
+    #[error("Cannot process command {0} with table: {1}, key: {2}. Error: {3}")]
     StorageError(&'static str, String, String, String),
 
     #[error("I/O error")]
@@ -49,10 +47,135 @@ pub enum KvError {
     #[error("TLS error")]
     TlsError(#[from] tokio_rustls::rustls::TLSError),
 
-    // #[error("Yamux Connection error")]
-    // YamuxConnectionError(#[from] yamux::ConnectionError),
+    #[error("Yamux Connection error")]
+    YamuxConnectionError(#[from] yamux::ConnectionError),
     #[error("Parse config error")]
     ConfigError(#[from] toml::de::Error),
+
+    #[error("Authentication failed: {0}")]
+    Unauthenticated(String),
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Rate limited: {0}, retry after {1:?}")]
+    RateLimited(String, std::time::Duration),
+    #[error("Quota exceeded: {0}")]
+    QuotaExceeded(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+
+    #[error("Server returned status {0}: {1}")]
+    ServerError(u32, String),
+
+    #[error("Corrupt snapshot: {0}")]
+    CorruptSnapshot(String),
+
+    #[error("Invalid record: {0}")]
+    InvalidRecord(String),
+
+    #[error("Read-only follower, send writes to the leader at {0}")]
+    ReadOnly(String),
+}
+
+impl KvError {
+    /// 机器可读的错误码
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvError::NotFound(..) => ErrorCode::NotFound,
+            KvError::InvalidCommand(_) => ErrorCode::InvalidCommand,
+            KvError::ConvertError(..) => ErrorCode::TypeMismatch,
+            KvError::StorageError(..) | KvError::SledError(_) => ErrorCode::Storage,
+            KvError::IoError(_) | KvError::YamuxConnectionError(_) => ErrorCode::Io,
+            KvError::EncodeError(_) => ErrorCode::Encode,
+            KvError::DecodeError(_) | KvError::CorruptSnapshot(_) | KvError::InvalidRecord(_) => {
+                ErrorCode::Decode
+            }
+            KvError::FrameError => ErrorCode::FrameTooLarge,
+            KvError::Internal(_)
+            | KvError::FmtError(_)
+            | KvError::CertificateParseError(..)
+            | KvError::TlsError(_)
+            | KvError::ConfigError(_) => ErrorCode::Internal,
+            KvError::Unauthenticated(_) => ErrorCode::Unauthenticated,
+            KvError::PermissionDenied(_) => ErrorCode::PermissionDenied,
+            KvError::RateLimited(..) => ErrorCode::RateLimited,
+            KvError::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            KvError::Timeout(_) => ErrorCode::Timeout,
+            KvError::Unavailable(_) => ErrorCode::Unavailable,
+            KvError::ReadOnly(_) => ErrorCode::ReadOnly,
+            // 不认识错误码的旧服务器只有状态码
+            KvError::ServerError(status, _) => match status {
+                400 => ErrorCode::InvalidCommand,
+                401 => ErrorCode::Unauthenticated,
+                403 => ErrorCode::PermissionDenied,
+                404 => ErrorCode::NotFound,
+                413 => ErrorCode::FrameTooLarge,
+                429 => ErrorCode::RateLimited,
+                503 => ErrorCode::Unavailable,
+                504 => ErrorCode::Timeout,
+                _ => ErrorCode::Internal,
+            },
+        }
+    }
+
+    /// 相同的请求稍后重试可能会成功
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self.code(),
+            ErrorCode::Io | ErrorCode::RateLimited | ErrorCode::Timeout | ErrorCode::Unavailable
+        )
+    }
+
+    /// 发给客户端的错误详情
+    pub fn detail(&self) -> ErrorDetail {
+        let mut detail = ErrorDetail {
+            code: self.code() as i32,
+            retryable: self.is_retryable(),
+            ..Default::default()
+        };
+        match self {
+            KvError::NotFound(table, key) => {
+                detail.table = table.clone();
+                detail.key = key.clone();
+            }
+            KvError::ReadOnly(leader) => detail.leader = leader.clone(),
+            KvError::ConvertError(value, expected) => {
+                detail.expected_type = expected.to_string();
+                detail.actual = Some(value.clone());
+            }
+            KvError::StorageError(operation, table, key, reason) => {
+                detail.operation = operation.to_string();
+                detail.table = table.clone();
+                detail.key = key.clone();
+                detail.reason = reason.clone();
+            }
+            KvError::InvalidCommand(reason)
+            | KvError::Internal(reason)
+            | KvError::Unauthenticated(reason)
+            | KvError::PermissionDenied(reason)
+            | KvError::RateLimited(reason, _)
+            | KvError::QuotaExceeded(reason)
+            | KvError::Timeout(reason)
+            | KvError::Unavailable(reason)
+            | KvError::ServerError(_, reason)
+            | KvError::CorruptSnapshot(reason)
+            | KvError::InvalidRecord(reason) => detail.reason = reason.clone(),
+            KvError::IoError(e) => detail.reason = e.to_string(),
+            KvError::EncodeError(e) => detail.reason = e.to_string(),
+            KvError::DecodeError(e) => detail.reason = e.to_string(),
+            KvError::SledError(e) => detail.reason = e.to_string(),
+            KvError::TlsError(e) => detail.reason = e.to_string(),
+            KvError::YamuxConnectionError(e) => detail.reason = e.to_string(),
+            KvError::ConfigError(e) => detail.reason = e.to_string(),
+            KvError::FmtError(_) | KvError::FrameError | KvError::CertificateParseError(..) => {
+                detail.reason = self.to_string()
+            }
+        }
+        detail
+    }
 }
 
 // impl From<FmtError> for KvError {
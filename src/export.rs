@@ -0,0 +1,202 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use db_server::{
+    checkpoint_path, glob_match, load_checkpoint, save_checkpoint, with_retry, ClientConfig,
+    DataFormat, ExportCheckpoint, KvClient, Progress, Record, RecordWriter,
+};
+
+/// 把 KV server 中的 table 导出成 JSON Lines 或 CSV
+///
+/// 按 table 名字的顺序用 HSCAN 分页读取。导出到文件时每页之后在 <file>.checkpoint 中记录进度，
+/// 中途失败后加上 --resume 重新运行，从上次的位置接着导出。
+#[derive(Parser, Debug)]
+#[command(name = "kv-export", version)]
+struct Args {
+    /// 客户端配置文件
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// 服务器地址，覆盖配置文件中的设置
+    #[arg(long)]
+    addr: Option<String>,
+
+    /// 文件格式：jsonl 或 csv，默认根据文件扩展名判断
+    #[arg(short, long)]
+    format: Option<DataFormat>,
+
+    /// 只导出名字匹配的 table，可以指定多次，支持 * 和 ?
+    #[arg(short, long = "table")]
+    tables: Vec<String>,
+
+    /// 只导出匹配的 key，支持 * 和 ?
+    #[arg(short = 'm', long = "match")]
+    pattern: Option<String>,
+
+    /// 每页读取的 key 个数
+    #[arg(long, default_value_t = 1000)]
+    page_size: u32,
+
+    /// 可以重试的错误（断线、限流、超时）最多重试的次数
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// 从上次失败的位置继续导出
+    #[arg(long)]
+    resume: bool,
+
+    /// 不输出进度
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// 输出文件，- 表示 stdout
+    output: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
+
+    let stdout = args.output.as_os_str() == "-";
+    if stdout && args.resume {
+        bail!("--resume requires an output file");
+    }
+    let format = args
+        .format
+        .unwrap_or_else(|| DataFormat::from_path(&args.output));
+
+    let client = KvClient::connect(&load_config(&args)?).await?;
+    let tables: Vec<(String, u64)> = with_retry(args.retries, || client.tables(""))
+        .await?
+        .into_iter()
+        .filter(|(table, _)| {
+            args.tables.is_empty() || args.tables.iter().any(|p| glob_match(p, table))
+        })
+        .collect();
+    let total = tables.iter().map(|(_, keys)| keys).sum();
+
+    if stdout {
+        let writer = RecordWriter::new(io::stdout().lock(), format, 0)?;
+        let mut progress = Progress::new("Exported", Some(total), 0, args.quiet);
+        export(&client, &args, &tables, writer, None, None, &mut progress).await?;
+        progress.finish();
+        return Ok(());
+    }
+
+    let checkpoint_file = checkpoint_path(&args.output);
+    let checkpoint: Option<ExportCheckpoint> = match args.resume {
+        true => load_checkpoint(&checkpoint_file)?,
+        false => None,
+    };
+    let file = match &checkpoint {
+        // 截掉上次最后一个断点之后写了一半的数据
+        Some(checkpoint) => {
+            let mut file = OpenOptions::new().write(true).open(&args.output)?;
+            file.set_len(checkpoint.offset)?;
+            file.seek(SeekFrom::End(0))?;
+            file
+        }
+        None => File::create(&args.output)?,
+    };
+    let offset = checkpoint.as_ref().map_or(0, |c| c.offset);
+    let resumed = checkpoint.as_ref().map_or(0, |c| c.records);
+    if checkpoint.is_some() && !args.quiet {
+        eprintln!("Resuming after {} records", resumed);
+    }
+
+    let writer = RecordWriter::new(file, format, offset)?;
+    let mut progress = Progress::new("Exported", Some(total), resumed, args.quiet);
+    let file = Some(checkpoint_file.as_path());
+    export(
+        &client,
+        &args,
+        &tables,
+        writer,
+        checkpoint,
+        file,
+        &mut progress,
+    )
+    .await?;
+    progress.finish();
+    // 没有任何 table 时不会写断点
+    if let Err(e) = fs::remove_file(&checkpoint_file) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+// 按顺序导出所有 table，resume 是上次的断点。导出到 stdout 时 checkpoint_file 为 None，不记录断点
+async fn export<W: Write>(
+    client: &KvClient,
+    args: &Args,
+    tables: &[(String, u64)],
+    mut writer: RecordWriter<W>,
+    resume: Option<ExportCheckpoint>,
+    checkpoint_file: Option<&Path>,
+    progress: &mut Progress,
+) -> Result<()> {
+    let pattern = args.pattern.as_deref().unwrap_or_default();
+
+    for (table, _) in tables {
+        let mut cursor = match &resume {
+            Some(resume) if *table < resume.table => continue,
+            Some(resume) if *table == resume.table => match &resume.cursor {
+                Some(cursor) => cursor.clone(),
+                None => continue,
+            },
+            _ => String::new(),
+        };
+
+        loop {
+            let (pairs, next) = with_retry(args.retries, || {
+                client.scan(table.as_str(), cursor.as_str(), args.page_size, pattern)
+            })
+            .await?;
+            let records = pairs.len() as u64;
+            for pair in pairs {
+                writer.write(&Record {
+                    table: table.clone(),
+                    key: pair.key,
+                    value: pair.value.unwrap_or_default(),
+                })?;
+            }
+            let offset = writer.flush()?;
+            progress.add(records);
+
+            if let Some(path) = checkpoint_file {
+                let checkpoint = ExportCheckpoint {
+                    table: table.clone(),
+                    cursor: (!next.is_empty()).then(|| next.clone()),
+                    records: progress.done(),
+                    offset,
+                };
+                save_checkpoint(path, &checkpoint)?;
+            }
+            if next.is_empty() {
+                break;
+            }
+            cursor = next;
+        }
+    }
+    Ok(())
+}
+
+fn load_config(args: &Args) -> Result<ClientConfig> {
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(&path.to_string_lossy())?,
+        None => ClientConfig::default(),
+    };
+    if let Some(addr) = &args.addr {
+        config.general.addr = addr.clone();
+    }
+    Ok(config)
+}
@@ -0,0 +1,167 @@
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use clap::Parser;
+use db_server::{
+    checkpoint_path, glob_match, load_checkpoint, save_checkpoint, with_retry, ClientConfig,
+    CommandRequest, DataFormat, ImportCheckpoint, KvClient, KvError, Kvpair, Progress, Record,
+    RecordReader,
+};
+
+/// 把 JSON Lines 或 CSV 文件导入 KV server，格式和 kv-export 的输出一致
+///
+/// 记录按 --batch-size 分批，每批用一个 BATCH 命令写入。从文件导入时每批之后在
+/// <file>.checkpoint 中记录进度，中途失败后加上 --resume 重新运行，跳过已经写入的记录。
+#[derive(Parser, Debug)]
+#[command(name = "kv-import", version)]
+struct Args {
+    /// 客户端配置文件
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// 服务器地址，覆盖配置文件中的设置
+    #[arg(long)]
+    addr: Option<String>,
+
+    /// 文件格式：jsonl 或 csv，默认根据文件扩展名判断
+    #[arg(short, long)]
+    format: Option<DataFormat>,
+
+    /// 只导入名字匹配的 table，可以指定多次，支持 * 和 ?
+    #[arg(short, long = "table")]
+    tables: Vec<String>,
+
+    /// 每批写入的记录数
+    #[arg(short, long, default_value_t = 500)]
+    batch_size: usize,
+
+    /// 可以重试的错误（断线、限流、超时）最多重试的次数
+    #[arg(long, default_value_t = 5)]
+    retries: u32,
+
+    /// 跳过上次已经导入的记录
+    #[arg(long)]
+    resume: bool,
+
+    /// 不输出进度
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// 输入文件，- 表示 stdin
+    input: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    tracing_subscriber::fmt()
+        .with_writer(io::stderr)
+        .with_max_level(tracing::Level::WARN)
+        .init();
+
+    let stdin = args.input.as_os_str() == "-";
+    if stdin && args.resume {
+        bail!("--resume requires an input file");
+    }
+    if args.batch_size == 0 {
+        bail!("--batch-size must be positive");
+    }
+    let format = args
+        .format
+        .unwrap_or_else(|| DataFormat::from_path(&args.input));
+    let checkpoint_file = (!stdin).then(|| checkpoint_path(&args.input));
+    let skip = match (&checkpoint_file, args.resume) {
+        (Some(path), true) => load_checkpoint::<ImportCheckpoint>(path)?.map_or(0, |c| c.records),
+        _ => 0,
+    };
+    if skip > 0 && !args.quiet {
+        eprintln!("Resuming after {} records", skip);
+    }
+
+    let reader: Box<dyn Read> = match stdin {
+        true => Box::new(io::stdin().lock()),
+        false => Box::new(File::open(&args.input)?),
+    };
+    let records = RecordReader::new(reader, format)?;
+    let client = KvClient::connect(&load_config(&args)?).await?;
+    let mut progress = Progress::new("Imported", None, skip, args.quiet);
+
+    // position 是已经读过的记录数，包括跳过的和没有匹配 table 的
+    let mut position = 0;
+    let mut saved = skip;
+    let mut batch = Vec::with_capacity(args.batch_size);
+    for record in records {
+        let record = record?;
+        position += 1;
+        if position <= skip
+            || !(args.tables.is_empty() || args.tables.iter().any(|p| glob_match(p, &record.table)))
+        {
+            continue;
+        }
+        batch.push(record);
+        if batch.len() == args.batch_size {
+            write_batch(&client, &batch, args.retries).await?;
+            batch.clear();
+            progress.add(position - saved);
+            saved = position;
+            if let Some(path) = &checkpoint_file {
+                save_checkpoint(path, &ImportCheckpoint { records: position })?;
+            }
+        }
+    }
+    if !batch.is_empty() {
+        write_batch(&client, &batch, args.retries).await?;
+    }
+    progress.add(position.saturating_sub(saved));
+    progress.finish();
+
+    if let Some(path) = &checkpoint_file {
+        if let Err(e) = fs::remove_file(path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// 连续的同一个 table 的记录合并成一个 HMSET，所有 HMSET 放在一个 BATCH 中写入
+async fn write_batch(client: &KvClient, records: &[Record], retries: u32) -> Result<(), KvError> {
+    let mut requests: Vec<CommandRequest> = Vec::new();
+    let mut table = "";
+    let mut pairs = Vec::new();
+    for record in records {
+        if record.table != table && !pairs.is_empty() {
+            requests.push(CommandRequest::new_hmset(table, std::mem::take(&mut pairs)));
+        }
+        table = &record.table;
+        pairs.push(Kvpair::new(record.key.as_str(), record.value.clone()));
+    }
+    if !pairs.is_empty() {
+        requests.push(CommandRequest::new_hmset(table, pairs));
+    }
+
+    let cmd = CommandRequest::new_batch(requests, true);
+    let res = with_retry(retries, || client.execute(&cmd)).await?;
+    match res
+        .responses
+        .into_iter()
+        .find(|res| !(200..300).contains(&res.status))
+    {
+        Some(res) => Err(res.into()),
+        None => Ok(()),
+    }
+}
+
+fn load_config(args: &Args) -> Result<ClientConfig> {
+    let mut config = match &args.config {
+        Some(path) => ClientConfig::load(&path.to_string_lossy())?,
+        None => ClientConfig::default(),
+    };
+    if let Some(addr) = &args.addr {
+        config.general.addr = addr.clone();
+    }
+    Ok(config)
+}
@@ -0,0 +1,315 @@
+//! 客户端缓存：缓存 get 的结果，服务器通过 Track 命令打开的 stream 通知哪些 key 失效
+//!
+//! 只有在连接的跟踪 stream 正常工作时才会写入缓存，跟踪中断就清空整个缓存。
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, warn};
+
+use super::reconnect::Connection;
+use crate::{CacheConfig, CommandRequest, CommandResponse, KvError, Value};
+
+// 连接没有在跟踪时的 generation
+const NOT_TRACKING: u64 = u64::MAX;
+
+// 打开跟踪 stream 失败后的重试间隔
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+type CacheKey = (String, String);
+
+/// 本地缓存，key 不存在的结果也会缓存
+pub(crate) struct LocalCache {
+    capacity: usize,
+    ttl: Duration,
+    state: StdMutex<CacheState>,
+    // 每个连接当前跟踪的 generation
+    tracking: Vec<AtomicU64>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, Entry>,
+    // 按写入顺序排列，用来淘汰最早的缓存。seq 和 entries 里对不上的是已经被覆盖的旧记录
+    order: VecDeque<(CacheKey, u64)>,
+    next_seq: u64,
+    // 每次失效或清空都加一，读取期间有变化的结果不能缓存
+    epoch: u64,
+}
+
+struct Entry {
+    value: Option<Value>,
+    inserted: Instant,
+    seq: u64,
+}
+
+impl LocalCache {
+    pub fn new(config: &CacheConfig, connections: usize) -> Self {
+        Self {
+            capacity: config.capacity.max(1),
+            ttl: Duration::from_millis(config.ttl_ms),
+            state: Default::default(),
+            tracking: (0..connections)
+                .map(|_| AtomicU64::new(NOT_TRACKING))
+                .collect(),
+        }
+    }
+
+    /// 查找缓存，没有或者过期了返回 None
+    pub fn get(&self, table: &str, key: &str) -> Option<Option<Value>> {
+        let mut state = self.state.lock().unwrap();
+        let k = (table.to_string(), key.to_string());
+        match state.entries.get(&k) {
+            Some(entry) if entry.inserted.elapsed() < self.ttl => Some(entry.value.clone()),
+            Some(_) => {
+                state.entries.remove(&k);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// 读取前记下当前的 epoch，写入缓存时用来判断期间有没有失效通知
+    pub fn epoch(&self) -> u64 {
+        self.state.lock().unwrap().epoch
+    }
+
+    /// 缓存从 conn 连接的 generation 读到的值。连接没有在跟踪，或者读取期间有失效通知时不缓存
+    pub fn insert(
+        &self,
+        conn: usize,
+        generation: u64,
+        epoch: u64,
+        table: String,
+        key: String,
+        value: Option<Value>,
+    ) {
+        if self.tracking[conn].load(Ordering::Acquire) != generation {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        let k = (table, key);
+        state.order.push_back((k.clone(), seq));
+        state.entries.insert(
+            k,
+            Entry {
+                value,
+                inserted: Instant::now(),
+                seq,
+            },
+        );
+        self.evict(&mut state);
+    }
+
+    /// key 被修改了
+    pub fn invalidate(&self, table: &str, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.entries.remove(&(table.to_string(), key.to_string()));
+    }
+
+    /// 清空所有缓存
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch += 1;
+        state.entries.clear();
+        state.order.clear();
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_tracking(&self, conn: usize, generation: u64) -> bool {
+        self.tracking[conn].load(Ordering::Acquire) == generation
+    }
+
+    fn start_tracking(&self, conn: usize, generation: u64) {
+        self.tracking[conn].store(generation, Ordering::Release);
+    }
+
+    // 收不到失效通知了，缓存的数据都不再可信
+    fn stop_tracking(&self, conn: usize) {
+        self.tracking[conn].store(NOT_TRACKING, Ordering::Release);
+        self.clear();
+    }
+
+    fn evict(&self, state: &mut CacheState) {
+        while state.entries.len() > self.capacity {
+            let (k, seq) = match state.order.pop_front() {
+                Some(v) => v,
+                None => break,
+            };
+            if matches!(state.entries.get(&k), Some(entry) if entry.seq == seq) {
+                state.entries.remove(&k);
+            }
+        }
+
+        // 同一个 key 反复写入会留下很多旧记录，太多时整理一下
+        if state.order.len() > self.capacity * 2 {
+            let CacheState { entries, order, .. } = state;
+            order.retain(|(k, seq)| matches!(entries.get(k), Some(entry) if entry.seq == *seq));
+        }
+    }
+}
+
+/// KvClient 持有的缓存，最后一个 KvClient 释放时停止后台的跟踪任务
+pub(crate) struct ClientCache {
+    cache: Arc<LocalCache>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl ClientCache {
+    /// 在每个连接上打开跟踪 stream
+    pub fn start(config: &CacheConfig, conns: &[Arc<Connection>]) -> Self {
+        let cache = Arc::new(LocalCache::new(config, conns.len()));
+        let tasks = conns
+            .iter()
+            .enumerate()
+            .map(|(i, conn)| tokio::spawn(track(conn.clone(), i, cache.clone())))
+            .collect();
+        Self { cache, tasks }
+    }
+}
+
+impl Deref for ClientCache {
+    type Target = LocalCache;
+
+    fn deref(&self) -> &Self::Target {
+        &self.cache
+    }
+}
+
+impl Drop for ClientCache {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+// 接收一个连接上的失效通知，跟踪 stream 断开后清空缓存，等连接恢复后重新开启跟踪
+async fn track(conn: Arc<Connection>, index: usize, cache: Arc<LocalCache>) {
+    loop {
+        let (generation, mut stream) =
+            match conn.execute_streaming(&CommandRequest::new_track()).await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Failed to enable tracking: {:?}", e);
+                    time::sleep(RETRY_INTERVAL).await;
+                    continue;
+                }
+            };
+        debug!("Tracking is enabled on connection {}", stream.id);
+        cache.start_tracking(index, generation);
+
+        while let Some(Ok(res)) = stream.next().await {
+            match parse_invalidation(res) {
+                Ok((table, key)) => cache.invalidate(&table, &key),
+                Err(e) => warn!("Invalid invalidation message: {:?}", e),
+            }
+        }
+
+        warn!("Tracking stream is closed, clearing the cache");
+        cache.stop_tracking(index);
+        if !conn.is_alive(generation).await {
+            if let Err(e) = conn
+                .invalidate(generation, "tracking stream closed".into())
+                .await
+            {
+                warn!("Failed to reconnect: {:?}", e);
+                time::sleep(RETRY_INTERVAL).await;
+            }
+        }
+    }
+}
+
+// 失效通知的 values 是 [table, key]
+fn parse_invalidation(res: CommandResponse) -> Result<(String, String), KvError> {
+    if res.values.len() != 2 {
+        return Err(KvError::Internal(format!("Unexpected response: {:?}", res)));
+    }
+    let mut values = res.values.into_iter();
+    let table = values.next().unwrap().try_into()?;
+    let key = values.next().unwrap().try_into()?;
+    Ok((table, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_should_only_insert_when_tracking() {
+        let cache = cache(10, 1000);
+        cache.insert(0, 0, cache.epoch(), "t1".into(), "k1".into(), None);
+        assert_eq!(cache.get("t1", "k1"), None);
+
+        cache.start_tracking(0, 0);
+        // generation 不对说明是重连之前读到的
+        cache.insert(0, 1, cache.epoch(), "t1".into(), "k1".into(), None);
+        assert_eq!(cache.get("t1", "k1"), None);
+        cache.insert(0, 0, cache.epoch(), "t1".into(), "k1".into(), None);
+        assert_eq!(cache.get("t1", "k1"), Some(None));
+
+        cache.stop_tracking(0);
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn cache_should_skip_values_read_before_invalidation() {
+        let cache = cache(10, 1000);
+        cache.start_tracking(0, 0);
+        let epoch = cache.epoch();
+        cache.invalidate("t1", "k1");
+        cache.insert(0, 0, epoch, "t1".into(), "k1".into(), Some("v1".into()));
+        assert_eq!(cache.get("t1", "k1"), None);
+    }
+
+    #[test]
+    fn cache_should_evict_oldest_and_expire() {
+        let cache = cache(2, 1000);
+        cache.start_tracking(0, 0);
+        for k in ["k1", "k2", "k1", "k3"] {
+            cache.insert(0, 0, cache.epoch(), "t1".into(), k.into(), Some(k.into()));
+        }
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("t1", "k2"), None);
+        assert_eq!(cache.get("t1", "k1"), Some(Some("k1".into())));
+
+        let cache = self::cache(2, 0);
+        cache.start_tracking(0, 0);
+        cache.insert(0, 0, cache.epoch(), "t1".into(), "k1".into(), None);
+        assert_eq!(cache.get("t1", "k1"), None);
+    }
+
+    fn cache(capacity: usize, ttl_ms: u64) -> LocalCache {
+        LocalCache::new(&CacheConfig { capacity, ttl_ms }, 1)
+    }
+
+    #[test]
+    fn invalidation_should_be_parsed() {
+        let res: CommandResponse = vec![Value::from("t1"), Value::from("k1")].into();
+        assert_eq!(
+            parse_invalidation(res).unwrap(),
+            ("t1".to_string(), "k1".to_string())
+        );
+        assert!(parse_invalidation(CommandResponse::ok()).is_err());
+    }
+}
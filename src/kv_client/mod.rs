@@ -0,0 +1,672 @@
+//! 高层异步客户端，把 CommandRequest/CommandResponse 封装成带类型的方法
+//!
+
+mod cache;
+mod pool;
+mod reconnect;
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::{Stream, StreamExt};
+use http::StatusCode;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, warn};
+
+use crate::{
+    value, CacheConfig, ClientConfig, CommandRequest, CommandResponse, ErrorCode, KvError, Kvpair,
+    StreamResult, Value,
+};
+use cache::ClientCache;
+pub use pool::{KvPool, PooledStream};
+use reconnect::Connection;
+pub use reconnect::ConnectionEvent;
+
+// 订阅数据在客户端缓存的条数
+const SUBSCRIPTION_CAPACITY: usize = 128;
+
+/// 带类型的 KV 客户端，底层是连接池，断线后自动重连，clone 是轻量级的
+#[derive(Clone)]
+pub struct KvClient {
+    pool: KvPool,
+    cache: Option<Arc<ClientCache>>,
+}
+
+impl KvClient {
+    /// 通过配置连接服务器，连接池和重连策略来自 config.pool 和 config.reconnect，
+    /// 配置了 config.cache 时开启客户端缓存
+    pub async fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        let pool = KvPool::connect(config).await?;
+        Ok(match &config.cache {
+            Some(cache) => Self::with_cache(pool, cache),
+            None => Self::from_pool(pool),
+        })
+    }
+
+    /// 使用已有的连接池
+    pub fn from_pool(pool: KvPool) -> Self {
+        Self { pool, cache: None }
+    }
+
+    /// 使用已有的连接池，并开启客户端缓存
+    ///
+    /// get 的结果会缓存在本地，服务器在这些 key 被修改时通知客户端删除缓存。
+    /// 收不到通知（比如断线）时会清空整个缓存，重连后重新开启跟踪。
+    pub fn with_cache(pool: KvPool, config: &CacheConfig) -> Self {
+        let cache = ClientCache::start(config, pool.connections());
+        Self {
+            pool,
+            cache: Some(Arc::new(cache)),
+        }
+    }
+
+    /// 连接状态变化的通知
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.pool.events()
+    }
+
+    /// 执行任意一条命令，非 2xx 的响应会转换成 KvError
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        check_response(self.pool.execute(cmd).await?)
+    }
+
+    /// 执行一条命令，超过 timeout 没有响应时返回超时错误。服务器也会按这个期限放弃处理
+    pub async fn execute_timeout(
+        &self,
+        cmd: &CommandRequest,
+        timeout: Duration,
+    ) -> Result<CommandResponse, KvError> {
+        let cmd = cmd.clone().with_deadline(timeout);
+        match time::timeout(timeout, self.pool.execute(&cmd)).await {
+            Ok(res) => check_response(res?),
+            Err(_) => Err(KvError::Timeout(format!(
+                "{} did not respond within {:?}",
+                cmd.name(),
+                timeout
+            ))),
+        }
+    }
+
+    /// HGET，key 不存在时返回 None。开启了缓存时优先读缓存
+    pub async fn get(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<Value>, KvError> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return get_value(self.execute(&CommandRequest::new_hget(table, key)).await),
+        };
+
+        let (table, key) = (table.into(), key.into());
+        if let Some(value) = cache.get(&table, &key) {
+            return Ok(value);
+        }
+
+        let epoch = cache.epoch();
+        let cmd = CommandRequest::new_hget(table.clone(), key.clone());
+        let (conn, generation, res) = self.pool.execute_on(&cmd).await?;
+        let value = get_value(check_response(res))?;
+        cache.insert(conn, generation, epoch, table, key, value.clone());
+        Ok(value)
+    }
+
+    /// HSET，返回之前的值
+    pub async fn set(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+        value: impl Into<Value>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.into());
+        self.invalidate(&table, &key);
+        let cmd = CommandRequest::new_hset(table, key, value.into());
+        Ok(first_value(self.execute(&cmd).await?))
+    }
+
+    /// HMGET，不存在的 key 对应 None
+    pub async fn mget(
+        &self,
+        table: impl Into<String>,
+        keys: Vec<String>,
+    ) -> Result<Vec<Option<Value>>, KvError> {
+        let cmd = CommandRequest::new_hmget(table, keys);
+        let res = self.execute(&cmd).await?;
+        Ok(res.values.into_iter().map(non_empty).collect())
+    }
+
+    /// HDEL，返回被删掉的值
+    pub async fn del(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<Option<Value>, KvError> {
+        let (table, key) = (table.into(), key.into());
+        self.invalidate(&table, &key);
+        let cmd = CommandRequest::new_hdel(table, key);
+        Ok(first_value(self.execute(&cmd).await?))
+    }
+
+    /// HEXIST
+    pub async fn exists(
+        &self,
+        table: impl Into<String>,
+        key: impl Into<String>,
+    ) -> Result<bool, KvError> {
+        let cmd = CommandRequest::new_hexist(table, key);
+        match first_value(self.execute(&cmd).await?) {
+            Some(v) => v.try_into(),
+            None => Err(KvError::Internal("Didn't get any value".into())),
+        }
+    }
+
+    /// HGETALL
+    pub async fn get_all(&self, table: impl Into<String>) -> Result<Vec<Kvpair>, KvError> {
+        let cmd = CommandRequest::new_hgetall(table);
+        Ok(self.execute(&cmd).await?.pairs)
+    }
+
+    /// HSCAN，从大于 cursor 的 key 开始读一页，返回匹配 pattern 的 kvpair 和下一页的 cursor，
+    /// cursor 为空字符串时表示已经读完
+    pub async fn scan(
+        &self,
+        table: impl Into<String>,
+        cursor: impl Into<String>,
+        count: u32,
+        pattern: impl Into<String>,
+    ) -> Result<(Vec<Kvpair>, String), KvError> {
+        let cmd = CommandRequest::new_hscan(table, cursor, count, pattern);
+        let res = self.execute(&cmd).await?;
+        let cursor = match first_value(res.clone()) {
+            Some(v) => v.try_into()?,
+            None => String::new(),
+        };
+        Ok((res.pairs, cursor))
+    }
+
+    /// 名字匹配 pattern 的 table 和它们的 key 个数，按名字排序
+    pub async fn tables(&self, pattern: impl Into<String>) -> Result<Vec<(String, u64)>, KvError> {
+        let res = self
+            .execute(&CommandRequest::new_list_tables(pattern))
+            .await?;
+        res.pairs
+            .into_iter()
+            .map(|pair| {
+                let keys: i64 = pair.value.unwrap_or_default().try_into()?;
+                Ok((pair.key, keys as u64))
+            })
+            .collect()
+    }
+
+    /// 订阅一个主题，返回的 Subscription 是一个 Stream
+    ///
+    /// 连接断开后会自动重连并重新订阅，服务器会分配新的 id。
+    /// 服务器目前没有消息序号，断线期间发布的数据会丢失。
+    pub async fn subscribe(&self, topic: impl Into<String>) -> Result<Subscription, KvError> {
+        let topic = topic.into();
+        // 订阅会一直占用这个 stream，所以单独打开一个
+        let cmd = CommandRequest::new_subscribe(topic.clone());
+        let conn = self.pool.connection();
+        let (generation, stream) = conn.execute_streaming(&cmd).await?;
+        debug!("Subscription {} is created", stream.id);
+
+        let id = Arc::new(AtomicU32::new(stream.id));
+        let (tx, rx) = mpsc::channel(SUBSCRIPTION_CAPACITY);
+        tokio::spawn(forward_subscription(
+            conn,
+            topic,
+            generation,
+            stream,
+            id.clone(),
+            tx,
+        ));
+
+        Ok(Subscription {
+            id,
+            inner: ReceiverStream::new(rx),
+        })
+    }
+
+    /// 取消订阅
+    pub async fn unsubscribe(&self, topic: impl Into<String>, id: u32) -> Result<(), KvError> {
+        self.execute(&CommandRequest::new_unsubscribe(topic, id))
+            .await?;
+        Ok(())
+    }
+
+    /// 往主题中发布数据
+    pub async fn publish(&self, topic: impl Into<String>, data: Vec<Value>) -> Result<(), KvError> {
+        self.execute(&CommandRequest::new_publish(topic, data))
+            .await?;
+        Ok(())
+    }
+
+    /// 备份整个数据库，备份文件的内容按顺序写入 writer，返回写入的字节数
+    pub async fn backup(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<u64, KvError> {
+        let cmd = CommandRequest::new_backup("");
+        let mut stream = self.pool.get().await?.execute_streaming(&cmd).await?;
+        debug!("Backup {} is started", stream.id);
+
+        let mut written = 0;
+        while let Some(res) = stream.next().await {
+            for data in check_response(res?)?.values {
+                match data.value {
+                    Some(value::Value::Binary(data)) => {
+                        writer.write_all(&data).await?;
+                        written += data.len() as u64;
+                    }
+                    _ => return Err(KvError::Internal("Unexpected data in backup".into())),
+                }
+            }
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+
+    // 自己的修改不用等服务器通知，先删掉缓存，保证之后能读到自己写的数据
+    fn invalidate(&self, table: &str, key: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(table, key);
+        }
+    }
+}
+
+/// 订阅返回的数据流，每一项是 publish 的数据
+pub struct Subscription {
+    id: Arc<AtomicU32>,
+    inner: ReceiverStream<Result<Vec<Value>, KvError>>,
+}
+
+impl Subscription {
+    /// 当前的订阅 id，重新订阅后会变化
+    pub fn id(&self) -> u32 {
+        self.id.load(Ordering::Relaxed)
+    }
+}
+
+impl Stream for Subscription {
+    type Item = Result<Vec<Value>, KvError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+// 把订阅数据转发给 Subscription，stream 因为断线结束时重新订阅
+async fn forward_subscription(
+    conn: Arc<Connection>,
+    topic: String,
+    mut generation: u64,
+    mut stream: StreamResult,
+    id: Arc<AtomicU32>,
+    tx: mpsc::Sender<Result<Vec<Value>, KvError>>,
+) {
+    loop {
+        while let Some(Ok(res)) = stream.next().await {
+            let data = check_response(res).map(|res| res.values);
+            if tx.send(data).await.is_err() {
+                // Subscription 已经被丢弃
+                return;
+            }
+        }
+
+        // 连接还活着说明是取消了订阅，正常结束
+        if tx.is_closed() || conn.is_alive(generation).await {
+            return;
+        }
+
+        warn!("Subscription to {} is interrupted, resubscribing", topic);
+        let resubscribed = match conn
+            .invalidate(generation, "subscription stream closed".into())
+            .await
+        {
+            Ok(()) => {
+                conn.execute_streaming(&CommandRequest::new_subscribe(topic.clone()))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+
+        match resubscribed {
+            Ok((g, s)) => {
+                generation = g;
+                stream = s;
+                id.store(stream.id, Ordering::Relaxed);
+                conn.notify(ConnectionEvent::Resubscribed {
+                    topic: topic.clone(),
+                    id: stream.id,
+                });
+            }
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        }
+    }
+}
+
+// 把非 2xx 的响应转换成错误
+fn check_response(res: CommandResponse) -> Result<CommandResponse, KvError> {
+    match StatusCode::from_u16(res.status as _) {
+        Ok(status) if status.is_success() => Ok(res),
+        _ => Err(res.into()),
+    }
+}
+
+// HGET 的结果，NotFound 表示 key 不存在
+fn get_value(res: Result<CommandResponse, KvError>) -> Result<Option<Value>, KvError> {
+    match res {
+        Ok(res) => Ok(first_value(res)),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// 服务器用 Value::default() 表示没有值
+fn non_empty(v: Value) -> Option<Value> {
+    v.value.is_some().then_some(v)
+}
+
+fn first_value(res: CommandResponse) -> Option<Value> {
+    res.values.into_iter().next().and_then(non_empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        start_server_with_config, ConnectorConfig, MemTable, Next, Request, ServerBuilder,
+        ServerConfig, StorageConfig,
+    };
+    use futures::future;
+    use std::sync::Mutex as StdMutex;
+    use std::time::Duration;
+    use tokio::io::copy_bidirectional;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::task::JoinHandle;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn kv_client_should_work() {
+        let client = start().await;
+
+        assert_eq!(client.get("t1", "k1").await.unwrap(), None);
+        assert_eq!(client.set("t1", "k1", "v1").await.unwrap(), None);
+        assert_eq!(
+            client.set("t1", "k1", "v2").await.unwrap(),
+            Some("v1".into())
+        );
+        assert_eq!(client.get("t1", "k1").await.unwrap(), Some("v2".into()));
+        assert!(client.exists("t1", "k1").await.unwrap());
+        assert_eq!(
+            client
+                .mget("t1", vec!["k1".into(), "k2".into()])
+                .await
+                .unwrap(),
+            vec![Some("v2".into()), None]
+        );
+        assert_eq!(
+            client.get_all("t1").await.unwrap(),
+            vec![Kvpair::new("k1", "v2".into())]
+        );
+        assert_eq!(client.del("t1", "k1").await.unwrap(), Some("v2".into()));
+        assert!(!client.exists("t1", "k1").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_scan_tables() {
+        let client = start().await;
+        for i in 0..5 {
+            client.set("t1", format!("k{}", i), i as i64).await.unwrap();
+        }
+        client.set("t2", "k1", "v1").await.unwrap();
+        assert_eq!(
+            client.tables("").await.unwrap(),
+            vec![("t1".to_string(), 5), ("t2".to_string(), 1)]
+        );
+
+        let mut keys = Vec::new();
+        let mut cursor = String::new();
+        loop {
+            let (pairs, next) = client.scan("t1", cursor, 2, "").await.unwrap();
+            keys.extend(pairs.into_iter().map(|p| p.key));
+            if next.is_empty() {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(keys, ["k0", "k1", "k2", "k3", "k4"]);
+    }
+
+    #[tokio::test]
+    async fn kv_client_pub_sub_should_work() {
+        let client = start().await;
+
+        let mut sub = client.subscribe("lobby").await.unwrap();
+        client.publish("lobby", vec!["hello".into()]).await.unwrap();
+
+        let data = sub.next().await.unwrap().unwrap();
+        assert_eq!(data, vec!["hello".into()]);
+
+        client.unsubscribe("lobby", sub.id()).await.unwrap();
+        assert!(sub.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn kv_client_should_reconnect_and_resubscribe() {
+        let upstream = start_server().await;
+        let (addr, conns) = start_proxy(upstream).await;
+        let client = connect(&addr).await;
+        let mut events = client.events();
+
+        client.set("t1", "k1", "v1").await.unwrap();
+        let mut sub = client.subscribe("news").await.unwrap();
+
+        // 断开所有连接，后续请求应该自动重连。写命令可能已经执行过了，不会自动重试
+        for handle in conns.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+        assert!(client.set("t1", "k2", "v2").await.is_err());
+        assert_eq!(client.get("t1", "k1").await.unwrap(), Some("v1".into()));
+
+        // 等订阅恢复后再发布
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let ConnectionEvent::Resubscribed { .. } = events.recv().await.unwrap() {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        client.publish("news", vec!["hi".into()]).await.unwrap();
+        assert_eq!(sub.next().await.unwrap().unwrap(), vec!["hi".into()]);
+    }
+
+    #[tokio::test]
+    async fn calls_should_fail_when_reconnect_gives_up() {
+        // 服务器在单独的 runtime 里，关掉 runtime 时监听和所有连接都会断开
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.spawn(
+            ServerBuilder::new(MemTable::new())
+                .plaintext()
+                .listen("127.0.0.1:0")
+                .start(),
+        );
+        let server = server.await.unwrap().unwrap();
+        let mut config = ClientConfig::default();
+        config.general.addr = server.local_addr().to_string();
+        config.connector = ConnectorConfig::Tcp { yamux: true };
+        config.reconnect.initial_delay_ms = 10;
+        config.reconnect.max_attempts = Some(2);
+        let client = KvClient::connect(&config).await.unwrap();
+        client.set("t1", "k1", "v1").await.unwrap();
+        runtime.shutdown_background();
+
+        // 同时等待重连的调用都拿到错误，不会一直等下去
+        let calls = (0..4).map(|_| client.get("t1", "k1"));
+        let results = time::timeout(Duration::from_secs(5), future::join_all(calls))
+            .await
+            .unwrap();
+        assert!(results.iter().all(|res| res.is_err()));
+    }
+
+    #[tokio::test]
+    async fn cached_keys_should_be_invalidated_by_server() {
+        let addr = start_server().await;
+        let cached = connect_with_cache(&addr).await;
+        let other = connect(&addr).await;
+
+        other.set("t1", "k1", "v1").await.unwrap();
+        assert_eq!(cached.get("t1", "k1").await.unwrap(), Some("v1".into()));
+        assert_eq!(cached.get("t1", "k2").await.unwrap(), None);
+        let cache = cached.cache.as_ref().unwrap();
+        assert_eq!(cache.get("t1", "k1"), Some(Some("v1".into())));
+        assert_eq!(cache.get("t1", "k2"), Some(None));
+
+        // 别的客户端修改后，服务器通知缓存失效
+        other.set("t1", "k1", "v2").await.unwrap();
+        other
+            .execute(&CommandRequest::new_batch(
+                vec![CommandRequest::new_hset("t1", "k2", "v3".into())],
+                false,
+            ))
+            .await
+            .unwrap();
+        wait_until(|| cache.len() == 0).await;
+        assert_eq!(cached.get("t1", "k1").await.unwrap(), Some("v2".into()));
+        assert_eq!(cached.get("t1", "k2").await.unwrap(), Some("v3".into()));
+
+        // 自己的修改马上可以读到
+        cached.del("t1", "k1").await.unwrap();
+        assert_eq!(cached.get("t1", "k1").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn cache_should_be_cleared_when_tracking_is_lost() {
+        let upstream = start_server().await;
+        let (addr, conns) = start_proxy(upstream).await;
+        let cached = connect_with_cache(&addr).await;
+        let cache = cached.cache.as_ref().unwrap();
+
+        cached.set("t1", "k1", "v1").await.unwrap();
+        wait_until(|| cache.is_tracking(0, 0)).await;
+        cached.get("t1", "k1").await.unwrap();
+        assert_eq!(cache.len(), 1);
+
+        for handle in conns.lock().unwrap().drain(..) {
+            handle.abort();
+        }
+        wait_until(|| cache.len() == 0).await;
+
+        // 重连后重新开启跟踪，缓存可以继续使用
+        assert_eq!(cached.get("t1", "k1").await.unwrap(), Some("v1".into()));
+        wait_until(|| cache.is_tracking(0, 1)).await;
+        cached.get("t1", "k1").await.unwrap();
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn execute_timeout_should_give_up_slow_commands() {
+        let server = ServerBuilder::new(MemTable::new())
+            .plaintext()
+            .listen("127.0.0.1:0")
+            .middleware(|req: Request, next: Next| async move {
+                if req.cmd.name() == "hgetall" {
+                    time::sleep(Duration::from_millis(200)).await;
+                }
+                next.run(req).await
+            })
+            .start()
+            .await
+            .unwrap();
+        let mut config = ClientConfig::default();
+        config.general.addr = server.local_addr().to_string();
+        config.connector = ConnectorConfig::Tcp { yamux: true };
+        config.pool.max_streams = 1;
+        let client = KvClient::connect(&config).await.unwrap();
+
+        let cmd = CommandRequest::new_hgetall("t1");
+        let res = client
+            .execute_timeout(&cmd, Duration::from_millis(50))
+            .await;
+        assert!(matches!(res, Err(KvError::Timeout(_))));
+
+        // 超时的 stream 没有归还，后面的命令不会读到迟到的响应
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let res = client.execute_timeout(&cmd, Duration::from_secs(1)).await;
+        assert_eq!(res.unwrap().values, vec![Value::default()]);
+
+        server.shutdown().await.unwrap();
+    }
+
+    async fn start() -> KvClient {
+        let addr = start_server().await;
+        connect(&addr).await
+    }
+
+    // 在随机端口上启动服务器，返回监听的地址
+    async fn start_server() -> String {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        config.general.addr = "127.0.0.1:0".into();
+        config.storage = StorageConfig::MemTable;
+        let server = start_server_with_config(&config).await.unwrap();
+        server.local_addr().to_string()
+    }
+
+    async fn connect(addr: &str) -> KvClient {
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.reconnect.initial_delay_ms = 10;
+        KvClient::connect(&config).await.unwrap()
+    }
+
+    async fn connect_with_cache(addr: &str) -> KvClient {
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        config.reconnect.initial_delay_ms = 10;
+        config.cache = Some(CacheConfig::default());
+        KvClient::connect(&config).await.unwrap()
+    }
+
+    async fn wait_until(f: impl Fn() -> bool) {
+        time::timeout(Duration::from_secs(5), async {
+            while !f() {
+                time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    // 一个可以随时断开所有连接的 TCP 代理，用来模拟断线
+    async fn start_proxy(upstream: String) -> (String, Arc<StdMutex<Vec<JoinHandle<()>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let conns = Arc::new(StdMutex::new(Vec::new()));
+
+        let conns1 = conns.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut inbound, _) = listener.accept().await.unwrap();
+                let upstream = upstream.clone();
+                let handle = tokio::spawn(async move {
+                    let mut outbound = TcpStream::connect(upstream).await.unwrap();
+                    let _ = copy_bidirectional(&mut inbound, &mut outbound).await;
+                });
+                conns1.lock().unwrap().push(handle);
+            }
+        });
+
+        (addr, conns)
+    }
+}
@@ -0,0 +1,398 @@
+//! 客户端连接池：多个 TLS 连接，借出的 yamux stream 数量有上限，等待的调用方按先后顺序拿到 stream
+//!
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex, Weak};
+use std::time::{Duration, Instant};
+
+use futures::future;
+use tokio::sync::{broadcast, OwnedSemaphorePermit, Semaphore};
+use tokio::time;
+use tracing::{debug, warn};
+
+use super::reconnect::{is_connection_error, is_idempotent, ClientStream, Connection};
+use super::ConnectionEvent;
+use crate::{ClientConfig, CommandRequest, CommandResponse, KvError, PoolConfig, StreamResult};
+
+// 连接事件的缓存个数，慢的接收方会丢掉旧事件
+const EVENT_CAPACITY: usize = 64;
+// 健康检查的最小间隔
+const MIN_HEALTH_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 可以在多个任务间共享的连接池，clone 是轻量级的
+#[derive(Clone)]
+pub struct KvPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    conns: Vec<Arc<Connection>>,
+    // 轮流在各个连接上打开新的 stream
+    next: AtomicUsize,
+    idle: StdMutex<Vec<IdleStream>>,
+    // tokio 的 Semaphore 是公平的，先等待的先拿到
+    permits: Arc<Semaphore>,
+    config: PoolConfig,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+struct IdleStream {
+    conn: usize,
+    generation: u64,
+    stream: ClientStream,
+    created: Instant,
+    last_used: Instant,
+}
+
+/// 从连接池借出的 stream，drop 时自动归还
+pub struct PooledStream {
+    pool: Arc<PoolInner>,
+    conn: usize,
+    generation: u64,
+    stream: Option<ClientStream>,
+    created: Instant,
+    // 出过连接错误的 stream 不再归还
+    broken: bool,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl KvPool {
+    /// 按照 config.pool 建立连接
+    pub async fn connect(config: &ClientConfig) -> Result<Self, KvError> {
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let pool = config.pool.clone();
+
+        let conns = future::try_join_all(
+            (0..pool.connections.max(1))
+                .map(|_| Connection::connect(config.clone(), events.clone())),
+        )
+        .await?;
+
+        let inner = Arc::new(PoolInner {
+            conns: conns.into_iter().map(Arc::new).collect(),
+            next: AtomicUsize::new(0),
+            idle: StdMutex::new(Vec::new()),
+            permits: Arc::new(Semaphore::new(pool.max_streams.max(1))),
+            config: pool,
+            events,
+        });
+        tokio::spawn(health_check(Arc::downgrade(&inner)));
+
+        Ok(Self { inner })
+    }
+
+    /// 连接状态变化的通知
+    pub fn events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.inner.events.subscribe()
+    }
+
+    /// 借出一个 stream，没有可用的就等待，超过 checkout_timeout_ms 返回错误
+    pub async fn get(&self) -> Result<PooledStream, KvError> {
+        let timeout = Duration::from_millis(self.inner.config.checkout_timeout_ms);
+        let permit = time::timeout(timeout, self.inner.permits.clone().acquire_owned())
+            .await
+            .map_err(|_| KvError::Internal("Timed out waiting for a pooled stream".into()))?
+            .map_err(|_| KvError::Internal("Connection pool is closed".into()))?;
+
+        if let Some(idle) = self.inner.take_idle() {
+            return Ok(PooledStream {
+                pool: self.inner.clone(),
+                conn: idle.conn,
+                generation: idle.generation,
+                stream: Some(idle.stream),
+                created: idle.created,
+                broken: false,
+                _permit: permit,
+            });
+        }
+
+        let conn = self.inner.next.fetch_add(1, Ordering::Relaxed) % self.inner.conns.len();
+        let (generation, stream) = self.inner.conns[conn].open_stream().await?;
+        Ok(PooledStream {
+            pool: self.inner.clone(),
+            conn,
+            generation,
+            stream: Some(stream),
+            created: Instant::now(),
+            broken: false,
+            _permit: permit,
+        })
+    }
+
+    /// 借一个 stream 执行命令，连接断开时重连。只读的命令会在新连接上重试一次，
+    /// 写命令可能已经执行过了，直接返回连接错误
+    pub async fn execute(&self, cmd: &CommandRequest) -> Result<CommandResponse, KvError> {
+        Ok(self.execute_on(cmd).await?.2)
+    }
+
+    // 和 execute 一样，同时返回执行命令的连接和它的 generation
+    pub(crate) async fn execute_on(
+        &self,
+        cmd: &CommandRequest,
+    ) -> Result<(usize, u64, CommandResponse), KvError> {
+        let mut stream = self.get().await?;
+        let res = match stream.execute_unary(cmd).await {
+            Err(e) if is_connection_error(&e) && is_idempotent(cmd) => {
+                // 先归还名额，否则 max_streams 为 1 时会等不到新的 stream
+                drop(stream);
+                stream = self.get().await?;
+                stream.execute_unary(cmd).await
+            }
+            res => res,
+        }?;
+        Ok((stream.conn, stream.generation, res))
+    }
+
+    // 流式命令独占一个连接上的 stream，不占用连接池的名额
+    pub(crate) fn connection(&self) -> Arc<Connection> {
+        let conn = self.inner.next.fetch_add(1, Ordering::Relaxed) % self.inner.conns.len();
+        self.inner.conns[conn].clone()
+    }
+
+    pub(crate) fn connections(&self) -> &[Arc<Connection>] {
+        &self.inner.conns
+    }
+}
+
+impl PooledStream {
+    /// 执行一条命令，出现连接错误时这个 stream 作废并触发重连
+    pub async fn execute_unary(
+        &mut self,
+        cmd: &CommandRequest,
+    ) -> Result<CommandResponse, KvError> {
+        let stream = self.stream.as_mut().expect("stream is only taken in drop");
+        // 调用被取消（比如超时）时响应可能还没读完，这样的 stream 不能再归还
+        self.broken = true;
+        match stream.execute_unary(cmd).await {
+            Err(e) if is_connection_error(&e) => {
+                warn!("Pooled stream is broken: {:?}", e);
+                self.broken = true;
+                self.pool.conns[self.conn]
+                    .invalidate(self.generation, e.to_string())
+                    .await?;
+                Err(e)
+            }
+            res => {
+                self.broken = false;
+                res
+            }
+        }
+    }
+
+    /// 在这个 stream 上执行流式命令，stream 不再归还
+    pub async fn execute_streaming(
+        mut self,
+        cmd: &CommandRequest,
+    ) -> Result<StreamResult, KvError> {
+        let stream = self.stream.take().expect("stream is only taken in drop");
+        stream.execute_streaming(cmd).await
+    }
+
+    /// 丢弃这个 stream，不再归还
+    pub fn discard(mut self) {
+        self.broken = true;
+    }
+}
+
+impl Drop for PooledStream {
+    fn drop(&mut self) {
+        if self.broken {
+            return;
+        }
+        if let Some(stream) = self.stream.take() {
+            self.pool.idle.lock().unwrap().push(IdleStream {
+                conn: self.conn,
+                generation: self.generation,
+                stream,
+                created: self.created,
+                last_used: Instant::now(),
+            });
+        }
+    }
+}
+
+impl PoolInner {
+    // 后进先出，最近用过的 stream 最可能是好的
+    fn take_idle(&self) -> Option<IdleStream> {
+        let mut idle = self.idle.lock().unwrap();
+        while let Some(stream) = idle.pop() {
+            if self.is_usable(&stream) {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    fn prune_idle(&self) {
+        self.idle.lock().unwrap().retain(|s| self.is_usable(s));
+    }
+
+    fn is_usable(&self, stream: &IdleStream) -> bool {
+        let max_idle = Duration::from_millis(self.config.max_idle_ms);
+        let max_lifetime = Duration::from_millis(self.config.max_lifetime_ms);
+
+        stream.generation == self.conns[stream.conn].generation()
+            && stream.last_used.elapsed() < max_idle
+            && stream.created.elapsed() < max_lifetime
+    }
+}
+
+// 定期检查每个连接，断开的连接在后台重连，连接池被释放后退出
+// 检查和重连期间只持有连接，不持有连接池，不影响连接池的释放
+async fn health_check(pool: Weak<PoolInner>) {
+    let interval = match pool.upgrade() {
+        Some(pool) => health_check_interval(&pool.config),
+        None => return,
+    };
+
+    loop {
+        time::sleep(interval).await;
+        let conns = match pool.upgrade() {
+            Some(pool) => {
+                pool.prune_idle();
+                pool.conns.clone()
+            }
+            None => return,
+        };
+
+        for conn in conns {
+            let generation = conn.generation();
+            if !conn.is_alive(generation).await {
+                debug!("Health check failed, reconnecting");
+                if let Err(e) = conn
+                    .invalidate(generation, "health check failed".into())
+                    .await
+                {
+                    warn!("Failed to reconnect: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+// 间隔太小时健康检查会占满 CPU
+fn health_check_interval(config: &PoolConfig) -> Duration {
+    let interval = Duration::from_millis(config.health_check_interval_ms);
+    if interval < MIN_HEALTH_CHECK_INTERVAL {
+        warn!(
+            "health_check_interval_ms {} is too small, using {:?}",
+            config.health_check_interval_ms, MIN_HEALTH_CHECK_INTERVAL
+        );
+        return MIN_HEALTH_CHECK_INTERVAL;
+    }
+    interval
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        start_server_with_config, ConnectorConfig, MemTable, ServerBuilder, ServerConfig,
+        StorageConfig,
+    };
+
+    #[tokio::test]
+    async fn pool_should_bound_and_reuse_streams() {
+        let addr = start_server().await;
+        let mut config = client_config(&addr);
+        config.pool.connections = 2;
+        config.pool.max_streams = 2;
+        config.pool.checkout_timeout_ms = 50;
+        let pool = KvPool::connect(&config).await.unwrap();
+
+        // 最多同时借出两个
+        let s1 = pool.get().await.unwrap();
+        let s2 = pool.get().await.unwrap();
+        assert_ne!(s1.conn, s2.conn);
+        assert!(pool.get().await.is_err());
+
+        // 归还后可以复用
+        drop(s1);
+        let mut s3 = pool.get().await.unwrap();
+        let res = s3
+            .execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+        assert_eq!(pool.inner.idle.lock().unwrap().len(), 0);
+        drop(s3);
+        drop(s2);
+        assert_eq!(pool.inner.idle.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pool_should_be_shared_by_many_tasks() {
+        let addr = start_server().await;
+        let mut config = client_config(&addr);
+        config.pool.max_streams = 4;
+        let pool = KvPool::connect(&config).await.unwrap();
+
+        let tasks = (0..32).map(|i| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let cmd = CommandRequest::new_hset("t1", format!("k{}", i), (i as i64).into());
+                pool.execute(&cmd).await.unwrap()
+            })
+        });
+        for res in future::join_all(tasks).await {
+            assert_eq!(res.unwrap().status, 200);
+        }
+        assert!(pool.inner.idle.lock().unwrap().len() <= 4);
+    }
+
+    #[tokio::test]
+    async fn expired_streams_should_not_be_reused() {
+        let addr = start_server().await;
+        let mut config = client_config(&addr);
+        config.pool.max_idle_ms = 10;
+        let pool = KvPool::connect(&config).await.unwrap();
+
+        drop(pool.get().await.unwrap());
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(pool.inner.take_idle().is_none());
+    }
+
+    #[tokio::test]
+    async fn pool_should_be_released_while_reconnecting() {
+        // 服务器在单独的 runtime 里，关掉 runtime 时监听和所有连接都会断开
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let server = runtime.spawn(
+            ServerBuilder::new(MemTable::new())
+                .plaintext()
+                .listen("127.0.0.1:0")
+                .start(),
+        );
+        let server = server.await.unwrap().unwrap();
+        let mut config = ClientConfig::default();
+        config.general.addr = server.local_addr().to_string();
+        config.connector = ConnectorConfig::Tcp { yamux: true };
+        // 间隔为 0 时不能变成忙循环
+        config.pool.health_check_interval_ms = 0;
+        config.reconnect.initial_delay_ms = 60_000;
+        let pool = KvPool::connect(&config).await.unwrap();
+        let inner = Arc::downgrade(&pool.inner);
+        runtime.shutdown_background();
+
+        // 健康检查发现连接断开后一直在等待重连，这时释放连接池
+        time::sleep(Duration::from_millis(200)).await;
+        drop(pool);
+        assert!(inner.upgrade().is_none());
+    }
+
+    // 在随机端口上启动服务器，返回监听的地址
+    async fn start_server() -> String {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        config.general.addr = "127.0.0.1:0".into();
+        config.storage = StorageConfig::MemTable;
+        let server = start_server_with_config(&config).await.unwrap();
+        server.local_addr().to_string()
+    }
+
+    fn client_config(addr: &str) -> ClientConfig {
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.into();
+        config
+    }
+}
@@ -0,0 +1,270 @@
+//! 断线重连：指数退避加随机抖动，重连成功后旧的 stream 全部作废
+//!
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+use tokio::sync::{broadcast, Mutex};
+use tokio::time;
+use tracing::{info, warn};
+
+use crate::pb::command_request::RequestData;
+use crate::{
+    BoxedStream, ClientConfig, ClientConnector, ClientMux, CommandRequest, KvError,
+    ProstClientStream, ReconnectConfig, StreamResult,
+};
+
+// 每个 stream 上的客户端，不使用 yamux 时一个 stream 就是一个连接
+pub(crate) type ClientStream = ProstClientStream<BoxedStream>;
+
+/// 连接状态变化，通过 KvClient::events() 获取
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// 连接（或重连）成功，generation 每次重连加一
+    Connected { generation: u64 },
+    /// 连接断开
+    Disconnected { reason: String },
+    /// 第 attempt 次重连，会先等待 delay
+    Reconnecting { attempt: u32, delay: Duration },
+    /// 重试次数用完，放弃重连
+    GaveUp { attempts: u32 },
+    /// 重连后重新订阅了主题，服务器分配了新的 id
+    Resubscribed { topic: String, id: u32 },
+}
+
+impl ReconnectConfig {
+    /// 第 attempt 次（从 1 开始）重连前需要等待的时间
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.multiplier.powi(attempt.saturating_sub(1) as i32);
+        let base = (self.initial_delay_ms as f64 * exp).min(self.max_delay_ms as f64);
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            0.0
+        };
+        Duration::from_millis((base * (1.0 + jitter)).max(0.0) as u64)
+    }
+}
+
+struct ConnState {
+    generation: u64,
+    ctrl: ClientMux,
+}
+
+/// 会自动重连的连接，stream 都带着创建时的 generation，重连后旧的不再复用
+pub(crate) struct Connection {
+    config: ClientConfig,
+    // 只在打开 stream 和替换连接时短暂持有
+    state: Mutex<ConnState>,
+    // state.generation 的副本，不用拿锁就能读
+    generation: AtomicU64,
+    // 同一时间只有一个任务在重连，其它任务等它的结果。记录最近一次放弃重连的时间
+    reconnecting: Mutex<Option<Instant>>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+impl Connection {
+    pub async fn connect(
+        config: ClientConfig,
+        events: broadcast::Sender<ConnectionEvent>,
+    ) -> Result<Self, KvError> {
+        let ctrl = connect(&config).await?;
+
+        Ok(Self {
+            config,
+            state: Mutex::new(ConnState {
+                generation: 0,
+                ctrl,
+            }),
+            generation: AtomicU64::new(0),
+            reconnecting: Mutex::new(None),
+            events,
+        })
+    }
+
+    /// 当前连接的 generation
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// 在独占的 stream 上执行流式命令，返回 stream 所属的 generation
+    pub async fn execute_streaming(
+        &self,
+        cmd: &CommandRequest,
+    ) -> Result<(u64, StreamResult), KvError> {
+        let (generation, stream) = self.open_stream().await?;
+        Ok((generation, stream.execute_streaming(cmd).await?))
+    }
+
+    /// 打开一个新的 stream，打不开说明连接断了，需要重连
+    pub async fn open_stream(&self) -> Result<(u64, ClientStream), KvError> {
+        let (generation, reason) = {
+            let mut state = self.state.lock().await;
+            match state.ctrl.open_stream().await {
+                Ok(stream) => return Ok((state.generation, stream)),
+                Err(e) => (state.generation, e.to_string()),
+            }
+        };
+        self.reconnect(generation, reason).await?;
+
+        let mut state = self.state.lock().await;
+        let stream = state.ctrl.open_stream().await?;
+        Ok((state.generation, stream))
+    }
+
+    /// 检查 generation 对应的连接是否还活着：在新的 stream 上发送 ping，同时让服务器知道连接还在使用
+    pub async fn is_alive(&self, generation: u64) -> bool {
+        // 只在打开 stream 时持有锁，等待响应时不阻塞其它任务
+        let mut stream = {
+            let mut state = self.state.lock().await;
+            if state.generation != generation {
+                return false;
+            }
+            match state.ctrl.open_stream().await {
+                Ok(stream) => stream,
+                Err(_) => return false,
+            }
+        };
+        let timeout = Duration::from_millis(self.config.pool.ping_timeout_ms);
+        let ping = CommandRequest::new_ping("");
+        matches!(time::timeout(timeout, stream.execute_unary(&ping)).await, Ok(Ok(res)) if res.status == 200)
+    }
+
+    /// generation 对应的连接已经断开，如果还没有人重连过就重连
+    pub async fn invalidate(&self, generation: u64, reason: String) -> Result<(), KvError> {
+        self.reconnect(generation, reason).await
+    }
+
+    pub fn notify(&self, event: ConnectionEvent) {
+        // 没有接收方时会返回错误，忽略即可
+        let _ = self.events.send(event);
+    }
+
+    // 退避等待时不持有 state 的锁。等待别人重连的任务直接使用它的结果，
+    // 它放弃了的话也返回错误，不会每个任务各自再等一轮
+    async fn reconnect(&self, generation: u64, reason: String) -> Result<(), KvError> {
+        let waiting_since = Instant::now();
+        let mut gave_up = self.reconnecting.lock().await;
+        if self.generation() != generation {
+            return Ok(());
+        }
+        if matches!(*gave_up, Some(at) if at >= waiting_since) {
+            return Err(KvError::Unavailable(format!(
+                "Failed to reconnect to {}",
+                self.config.general.addr
+            )));
+        }
+        self.notify(ConnectionEvent::Disconnected { reason });
+
+        let policy = &self.config.reconnect;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            if matches!(policy.max_attempts, Some(max) if attempt > max) {
+                *gave_up = Some(Instant::now());
+                self.notify(ConnectionEvent::GaveUp {
+                    attempts: attempt - 1,
+                });
+                return Err(KvError::Unavailable(format!(
+                    "Failed to reconnect after {} attempts",
+                    attempt - 1
+                )));
+            }
+
+            let delay = policy.delay(attempt);
+            self.notify(ConnectionEvent::Reconnecting { attempt, delay });
+            time::sleep(delay).await;
+
+            match connect(&self.config).await {
+                Ok(ctrl) => {
+                    let mut state = self.state.lock().await;
+                    state.ctrl = ctrl;
+                    state.generation += 1;
+                    self.generation.store(state.generation, Ordering::Release);
+                    info!("Reconnected to {}", self.config.general.addr);
+                    self.notify(ConnectionEvent::Connected {
+                        generation: state.generation,
+                    });
+                    return Ok(());
+                }
+                Err(e) => warn!("Reconnect attempt {} failed: {:?}", attempt, e),
+            }
+        }
+    }
+}
+
+// 建立连接，配置了认证信息时先在连接上认证
+async fn connect(config: &ClientConfig) -> Result<ClientMux, KvError> {
+    ClientConnector::new(config)?.open().await
+}
+
+pub(crate) fn is_connection_error(e: &KvError) -> bool {
+    matches!(e, KvError::IoError(_) | KvError::YamuxConnectionError(_))
+}
+
+// 连接断开时不知道服务器有没有执行，只有重复执行没有影响的命令才能在新连接上重试
+pub(crate) fn is_idempotent(cmd: &CommandRequest) -> bool {
+    match &cmd.request_data {
+        Some(RequestData::Hget(_))
+        | Some(RequestData::Hgetall(_))
+        | Some(RequestData::Hmget(_))
+        | Some(RequestData::Hexist(_))
+        | Some(RequestData::Hmexist(_))
+        | Some(RequestData::Hscan(_))
+        | Some(RequestData::Ping(_))
+        | Some(RequestData::Info(_))
+        | Some(RequestData::ListTables(_))
+        | Some(RequestData::ClientList(_))
+        | Some(RequestData::ConfigGet(_))
+        | Some(RequestData::SlowlogGet(_)) => true,
+        Some(RequestData::Batch(v)) => v.requests.iter().all(is_idempotent),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_should_grow_exponentially_and_be_capped() {
+        let policy = ReconnectConfig {
+            initial_delay_ms: 100,
+            max_delay_ms: 1000,
+            multiplier: 2.0,
+            jitter: 0.0,
+            max_attempts: None,
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(10), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn only_idempotent_commands_should_be_retried() {
+        assert!(is_idempotent(&CommandRequest::new_hget("t1", "k1")));
+        assert!(!is_idempotent(&CommandRequest::new_hset(
+            "t1",
+            "k1",
+            "v1".into()
+        )));
+        let reads = vec![CommandRequest::new_hget("t1", "k1")];
+        assert!(is_idempotent(&CommandRequest::new_batch(reads, false)));
+        let writes = vec![CommandRequest::new_hdel("t1", "k1")];
+        assert!(!is_idempotent(&CommandRequest::new_batch(writes, false)));
+    }
+
+    #[test]
+    fn delay_should_stay_within_jitter() {
+        let policy = ReconnectConfig {
+            jitter: 0.5,
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(1).as_millis();
+            assert!((50..=150).contains(&delay));
+        }
+    }
+}
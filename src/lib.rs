@@ -1,93 +1,136 @@
+mod admission;
+mod builder;
+mod cli;
 mod config;
 mod error;
+mod kv_client;
+mod metrics;
 mod network;
 mod pb;
 mod service;
+mod settings;
+mod shutdown;
+mod snapshot;
 mod storage;
+mod telemetry;
+mod transfer;
 
+pub use admission::*;
+pub use builder::*;
+pub use cli::*;
 pub use config::*;
 pub use error::*;
+pub use kv_client::*;
+pub use metrics::*;
 pub use network::*;
 pub use pb::*;
 pub use service::*;
+pub use settings::*;
+pub use shutdown::*;
+pub use snapshot::*;
 pub use storage::*;
+pub use telemetry::*;
+pub use transfer::*;
 
-use bytes::{Bytes, BytesMut};
+use bytes::BytesMut;
 use futures::prelude::*;
 use futures::{Sink, Stream};
-use std::net::SocketAddr;
-use std::time::Duration;
 use std::{
-    marker::PhantomData,
     pin::Pin,
-    task::{ready, Context, Poll},
+    task::{ready, Poll},
+    time::Duration,
 };
-use tokio::time;
-use tracing::{info, instrument, span};
+use tracing::{info, instrument};
 
 use anyhow::Result;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_rustls::client;
-use tokio_util::compat::FuturesAsyncReadCompatExt;
 
-/// 通过配置创建 db 服务器
-pub async fn start_server_with_config(config: &ServerConfig) -> Result<()> {
-    let acceptor =
-        TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, config.tls.ca.as_deref())?;
-
-    let addr = &config.general.addr;
+/// 通过配置启动 db 服务器，开始监听后返回，用返回的 ServerHandle 关闭服务器
+pub async fn start_server_with_config(config: &ServerConfig) -> Result<ServerHandle> {
     match &config.storage {
-        StorageConfig::MemTable => start_tls_server(addr, MemTable::new(), acceptor).await?,
-        StorageConfig::SledDb(path) => start_tls_server(addr, SledDb::new(path), acceptor).await?,
-    };
-
-    Ok(())
+        StorageConfig::MemTable => builder(MemTable::new(), config)?.start().await,
+        StorageConfig::SledDb(path) => builder(SledDb::new(path), config)?.start().await,
+    }
 }
 
-/// 通过配置创建 KV 客户端
-pub async fn start_client_with_config(
-    config: &ClientConfig,
-) -> Result<YamuxCtrl<client::TlsStream<TcpStream>>> {
-    let addr = &config.general.addr;
-    let tls = &config.tls;
-
-    let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
-    let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
-    let stream = TcpStream::connect(addr).await?;
-    let stream = connector.connect(stream).await?;
+// 启动时从备份恢复，只恢复到空的存储中。SledDb 重启后还有自己的数据，
+// 再恢复一次会用旧的备份覆盖之后的写入。返回是否恢复了数据
+pub(crate) fn restore_on_start<Store: Storage>(path: &str, store: &Store) -> Result<bool> {
+    if !store.stats()?.tables.is_empty() {
+        info!("Storage is not empty, skipped restoring from {}", path);
+        return Ok(false);
+    }
+    let manifest = restore_snapshot(path, store)?;
+    let keys: u64 = manifest.tables.iter().map(|t| t.keys).sum();
+    info!("Restored {} keys from {}", keys, path);
+    Ok(true)
+}
 
-    // 打开一个 stream
-    Ok(YamuxCtrl::new_client(stream, None))
+/// 通过配置创建 KV 客户端，配置了认证信息时会先完成认证
+pub async fn start_client_with_config(config: &ClientConfig) -> Result<ClientMux> {
+    Ok(ClientConnector::new(config)?.open().await?)
 }
 
-async fn start_tls_server<Store: Storage>(
-    addr: &str,
-    store: Store,
-    acceptor: TlsServerAcceptor,
-) -> Result<()> {
-    let service: Service<Store> = ServiceInner::new(store).into();
-    let listener = TcpListener::bind(addr).await?;
-    info!("Start listening on {}", addr);
-    loop {
-        let root = span!(tracing::Level::INFO, "server_process");
-        let tls = acceptor.clone();
-        let (stream, addr) = listener.accept().await?;
-        info!("Client {:?} connected", addr);
+fn builder<Store: Storage>(store: Store, config: &ServerConfig) -> Result<ServerBuilder<Store>> {
+    if let Some(path) = &config.restore {
+        restore_on_start(path, &store)?;
+    }
+    let mut builder = ServerBuilder::new(store)
+        .limits(config.limits.clone())?
+        .drain_timeout(Duration::from_millis(config.shutdown.drain_timeout_ms))
+        .idle_timeout(millis(config.timeouts.idle_timeout_ms))
+        .handshake_timeout(millis(config.timeouts.handshake_timeout_ms))
+        .admission(config.admission.clone())
+        .slowlog(config.slowlog.clone())
+        .replication(config.replication.clone());
 
-        let svc = service.clone();
-        tokio::spawn(async move {
-            let stream = tls.accept(stream).await.unwrap();
-            // 延迟 100ms 处理
-            // time::sleep(Duration::from_millis(100)).await;
+    // 没有配置 listeners 时在 general.addr 上使用 TLS
+    let needs_tls = config.listeners.is_empty()
+        || config
+            .listeners
+            .iter()
+            .any(|l| matches!(l, ListenerConfig::Tls { .. }));
+    if needs_tls {
+        let tls = &config.tls;
+        builder = builder.tls(TlsServerAcceptor::new(
+            &tls.cert,
+            &tls.key,
+            tls.ca.as_deref(),
+        )?);
+    }
+    if config.listeners.is_empty() {
+        builder = builder.listen(&config.general.addr);
+    }
+    for listener in &config.listeners {
+        if let ListenerConfig::Tcp {
+            addr,
+            allow_plaintext: false,
+            ..
+        } = listener
+        {
+            return Err(anyhow::anyhow!(
+                "Plaintext listener on {} requires allow_plaintext = true",
+                addr
+            ));
+        }
+        builder = builder.add_listener(listener);
+    }
 
-            YamuxCtrl::new_server(stream, None, move |stream| {
-                let svc1 = svc.clone();
-                async move {
-                    let stream = ProstServerStream::new(stream.compat(), svc1.clone());
-                    stream.process().await.unwrap();
-                    Ok(())
-                }
-            });
-        });
+    if let Some(auth) = &config.auth {
+        builder = builder.acl(auth.clone().into());
+    }
+    if let Some(dir) = &config.backup_dir {
+        builder = builder.backup_dir(dir);
+    }
+    if let Some(audit) = &config.audit {
+        builder = builder.audit(AuditLog::open(audit));
     }
+    if let Some(metrics) = &config.metrics {
+        builder = builder.metrics_addr(&metrics.addr);
+    }
+    Ok(builder)
+}
+
+// 配置中 0 表示不限制
+fn millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
 }
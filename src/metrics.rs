@@ -0,0 +1,480 @@
+//! 监控指标：记录请求、连接、frame、pub/sub 和存储的状态，通过 HTTP 暴露给 Prometheus 抓取
+//!
+
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use crate::{Limit, Service, Storage, StorageStats};
+
+// 请求头最大长度，抓取请求不会很大
+const MAX_REQUEST_HEADER: usize = 8 * 1024;
+// 读取请求头和写出响应的超时，防止慢连接一直占着名额
+const SCRAPE_IO_TIMEOUT: Duration = Duration::from_secs(5);
+// 同时处理的抓取连接数上限，超过时直接关闭连接
+const MAX_SCRAPE_CONNECTIONS: u64 = 16;
+// 统计存储需要遍历所有数据，结果在这段时间内复用
+const STORAGE_STATS_TTL: Duration = Duration::from_secs(30);
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// 进程内全局的监控指标
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+/// frame 的方向：in 是解码收到的，out 是编码发出的
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::In => "in",
+            Direction::Out => "out",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    // 按命令和状态码统计的请求数和延迟（到第一个响应为止）
+    pub requests: IntCounterVec,
+    pub request_duration: HistogramVec,
+    // 当前的 TLS 连接数和 yamux stream 数
+    pub connections: IntGauge,
+    pub streams: IntGauge,
+    // 连接和 stream 上出错的次数，按出错的阶段分类
+    pub connection_errors: IntCounterVec,
+    // 超过连接数、stream 数或者请求数上限被拒绝的次数
+    pub rejections: IntCounterVec,
+    // frame 在网络上的字节数（含长度头，压缩后）和 protobuf 编码后的字节数
+    pub frame_bytes: IntCounterVec,
+    pub frame_payload_bytes: IntCounterVec,
+    // 压缩过的 frame 的压缩比：压缩后 / 压缩前
+    pub compression_ratio: Histogram,
+    pub topics: IntGauge,
+    pub subscribers: IntGauge,
+    pub published_messages: IntCounter,
+    // 订阅者已经断开，没有送达的消息
+    pub dropped_messages: IntCounter,
+    // 存储的状态在抓取时更新，最多每 STORAGE_STATS_TTL 遍历一次存储
+    pub storage_keys: IntGaugeVec,
+    pub storage_bytes: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let requests = IntCounterVec::new(
+            Opts::new(
+                "kv_requests_total",
+                "Number of requests by command and status",
+            ),
+            &["command", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "kv_request_duration_seconds",
+                "Time until the first response by command and status",
+            )
+            .buckets(prometheus::exponential_buckets(0.0001, 4.0, 10).unwrap()),
+            &["command", "status"],
+        )
+        .unwrap();
+        let connections = IntGauge::new("kv_connections", "Number of open connections").unwrap();
+        let streams = IntGauge::new("kv_streams", "Number of open yamux streams").unwrap();
+        let connection_errors = IntCounterVec::new(
+            Opts::new(
+                "kv_connection_errors_total",
+                "Number of connection and stream errors by kind",
+            ),
+            &["kind"],
+        )
+        .unwrap();
+        let rejections = IntCounterVec::new(
+            Opts::new(
+                "kv_admission_rejected_total",
+                "Number of connections, streams and requests rejected by limit",
+            ),
+            &["limit"],
+        )
+        .unwrap();
+        let frame_bytes = IntCounterVec::new(
+            Opts::new("kv_frame_bytes_total", "Bytes of frames on the wire"),
+            &["direction"],
+        )
+        .unwrap();
+        let frame_payload_bytes = IntCounterVec::new(
+            Opts::new(
+                "kv_frame_payload_bytes_total",
+                "Bytes of frame payloads before compression",
+            ),
+            &["direction"],
+        )
+        .unwrap();
+        let compression_ratio = Histogram::with_opts(
+            HistogramOpts::new(
+                "kv_frame_compression_ratio",
+                "Compressed size divided by original size of compressed frames",
+            )
+            .buckets(prometheus::linear_buckets(0.1, 0.1, 10).unwrap()),
+        )
+        .unwrap();
+        let topics = IntGauge::new("kv_pubsub_topics", "Number of topics").unwrap();
+        let subscribers =
+            IntGauge::new("kv_pubsub_subscribers", "Number of subscriptions").unwrap();
+        let published_messages = IntCounter::new(
+            "kv_pubsub_published_messages_total",
+            "Number of published messages",
+        )
+        .unwrap();
+        let dropped_messages = IntCounter::new(
+            "kv_pubsub_dropped_messages_total",
+            "Number of messages not delivered to a subscriber",
+        )
+        .unwrap();
+        let storage_keys = IntGaugeVec::new(
+            Opts::new("kv_storage_keys", "Number of keys by table"),
+            &["table"],
+        )
+        .unwrap();
+        let storage_bytes =
+            IntGauge::new("kv_storage_bytes", "Bytes of all keys and values").unwrap();
+
+        let registry = Registry::new();
+        let collectors: [Box<dyn prometheus::core::Collector>; 15] = [
+            Box::new(requests.clone()),
+            Box::new(request_duration.clone()),
+            Box::new(connections.clone()),
+            Box::new(streams.clone()),
+            Box::new(connection_errors.clone()),
+            Box::new(rejections.clone()),
+            Box::new(frame_bytes.clone()),
+            Box::new(frame_payload_bytes.clone()),
+            Box::new(compression_ratio.clone()),
+            Box::new(topics.clone()),
+            Box::new(subscribers.clone()),
+            Box::new(published_messages.clone()),
+            Box::new(dropped_messages.clone()),
+            Box::new(storage_keys.clone()),
+            Box::new(storage_bytes.clone()),
+        ];
+        for c in collectors {
+            registry.register(c).unwrap();
+        }
+
+        Self {
+            registry,
+            requests,
+            request_duration,
+            connections,
+            streams,
+            connection_errors,
+            rejections,
+            frame_bytes,
+            frame_payload_bytes,
+            compression_ratio,
+            topics,
+            subscribers,
+            published_messages,
+            dropped_messages,
+            storage_keys,
+            storage_bytes,
+        }
+    }
+
+    /// 记录一次连接或 stream 上的错误，kind 是出错的阶段，比如 tls_handshake、decode
+    pub fn record_connection_error(&self, kind: &str) {
+        self.connection_errors.with_label_values(&[kind]).inc();
+    }
+
+    /// 记录一次因为达到上限被拒绝，limit 是 connections、streams 或 requests
+    pub fn record_rejection(&self, limit: &str) {
+        self.rejections.with_label_values(&[limit]).inc();
+    }
+
+    /// 记录一个请求
+    pub fn record_request(&self, command: &str, status: u32, elapsed: Duration) {
+        let status = status.to_string();
+        let labels = [command, status.as_str()];
+        self.requests.with_label_values(&labels).inc();
+        self.request_duration
+            .with_label_values(&labels)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    /// 记录一个 frame，wire 是网络上的字节数，payload 是压缩前的字节数
+    pub fn record_frame(
+        &self,
+        direction: Direction,
+        wire: usize,
+        payload: usize,
+        compressed: bool,
+    ) {
+        let direction = direction.as_str();
+        self.frame_bytes
+            .with_label_values(&[direction])
+            .inc_by(wire as u64);
+        self.frame_payload_bytes
+            .with_label_values(&[direction])
+            .inc_by(payload as u64);
+        if compressed && payload > 0 {
+            self.compression_ratio
+                .observe((wire - crate::LEN_LEN) as f64 / payload as f64);
+        }
+    }
+
+    /// 用最新的存储统计替换之前的值，已经删掉的 table 不再输出
+    pub fn update_storage(&self, stats: &StorageStats) {
+        self.storage_keys.reset();
+        for (table, keys) in &stats.tables {
+            self.storage_keys
+                .with_label_values(&[table])
+                .set(*keys as i64);
+        }
+        self.storage_bytes.set(stats.bytes as i64);
+    }
+
+    /// 输出 Prometheus 文本格式
+    pub fn gather(&self) -> String {
+        let mut buf = Vec::new();
+        let encoder = TextEncoder::new();
+        // 写入 Vec 不会失败
+        let _ = encoder.encode(&self.registry.gather(), &mut buf);
+        String::from_utf8_lossy(&buf).into_owned()
+    }
+}
+
+/// 创建时加一，drop 时减一，用来统计连接和 stream
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// 在 addr 上启动 HTTP 服务，GET /metrics 返回所有指标
+pub async fn start_metrics_server<Store: Storage>(
+    addr: &str,
+    service: Service<Store>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("Metrics listening on {}", addr);
+    serve_metrics(listener, service).await
+}
+
+/// 在已经绑定的 listener 上提供 HTTP 抓取
+pub async fn serve_metrics<Store: Storage>(
+    listener: TcpListener,
+    service: Service<Store>,
+) -> Result<()> {
+    let connections = Limit::new("metrics_connections", MAX_SCRAPE_CONNECTIONS);
+    // 上次更新存储统计的时间，同时只有一个抓取在遍历存储
+    let refreshed = Arc::new(Mutex::new(None));
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let permit = match connections.try_acquire() {
+            Ok(permit) => permit,
+            Err(e) => {
+                warn!("Rejected metrics scrape from {}: {}", peer, e);
+                continue;
+            }
+        };
+        let svc = service.clone();
+        let refreshed = refreshed.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_scrape(stream, svc, refreshed).await {
+                warn!("Failed to serve metrics to {}: {:?}", peer, e);
+            }
+            drop(permit);
+        });
+    }
+}
+
+// 只处理一个请求，响应后关闭连接
+async fn handle_scrape<Store: Storage>(
+    mut stream: TcpStream,
+    service: Service<Store>,
+    refreshed: Arc<Mutex<Option<Instant>>>,
+) -> Result<()> {
+    let buf = match timeout(SCRAPE_IO_TIMEOUT, read_header(&mut stream)).await {
+        Ok(buf) => buf?,
+        Err(_) => return Ok(()),
+    };
+    let buf = match buf {
+        Some(buf) => buf,
+        None => return Ok(()),
+    };
+
+    let line = String::from_utf8_lossy(&buf);
+    let mut parts = line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            refresh_storage(&service, &refreshed).await?;
+            ("200 OK", metrics().gather())
+        }
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let write = async {
+        stream.write_all(header.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        stream.shutdown().await
+    };
+    timeout(SCRAPE_IO_TIMEOUT, write).await??;
+    Ok(())
+}
+
+// 读到请求头结束为止，连接关闭或者请求头太长时返回 None
+async fn read_header(stream: &mut TcpStream) -> Result<Option<Vec<u8>>> {
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 || buf.len() + n > MAX_REQUEST_HEADER {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+    Ok(Some(buf))
+}
+
+// 存储统计过期时重新统计，并发的抓取等待同一次统计的结果
+async fn refresh_storage<Store: Storage>(
+    service: &Service<Store>,
+    refreshed: &Mutex<Option<Instant>>,
+) -> Result<()> {
+    let mut refreshed = refreshed.lock().await;
+    if matches!(*refreshed, Some(at) if at.elapsed() < STORAGE_STATS_TTL) {
+        return Ok(());
+    }
+    // 统计存储需要遍历数据，放到阻塞线程上执行
+    let svc = service.clone();
+    match tokio::task::spawn_blocking(move || svc.storage_stats()).await? {
+        Ok(stats) => metrics().update_storage(&stats),
+        Err(e) => warn!("Failed to get storage stats: {:?}", e),
+    }
+    *refreshed = Some(Instant::now());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CommandRequest, MemTable, ServiceInner, Value};
+
+    #[test]
+    fn record_request_should_work() {
+        let m = metrics();
+        let before = m.requests.with_label_values(&["hget", "404"]).get();
+        m.record_request("hget", 404, Duration::from_millis(1));
+        assert_eq!(
+            m.requests.with_label_values(&["hget", "404"]).get(),
+            before + 1
+        );
+
+        let text = m.gather();
+        assert!(text.contains(r#"kv_requests_total{command="hget",status="404"}"#));
+        assert!(text.contains("kv_request_duration_seconds_bucket"));
+    }
+
+    #[tokio::test]
+    async fn metrics_server_should_work() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let cmd = CommandRequest::new_hset("metrics", "k1", Value::from("v1"));
+        futures::StreamExt::next(&mut service.execute(cmd))
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve_metrics(listener, service.clone()));
+
+        let text = http_get(&addr, "/metrics").await;
+        assert!(text.starts_with("HTTP/1.1 200 OK"));
+        assert!(text.contains(r#"kv_storage_keys{table="metrics"} 1"#));
+        assert!(text.contains(r#"kv_requests_total{command="hset",status="200"}"#));
+
+        // 存储统计在 STORAGE_STATS_TTL 内复用，不会每次抓取都遍历存储
+        let cmd = CommandRequest::new_hset("metrics", "k2", Value::from("v2"));
+        futures::StreamExt::next(&mut service.execute(cmd))
+            .await
+            .unwrap();
+        let text = http_get(&addr, "/metrics").await;
+        assert!(text.contains(r#"kv_storage_keys{table="metrics"} 1"#));
+
+        let text = http_get(&addr, "/").await;
+        assert!(text.starts_with("HTTP/1.1 404"));
+    }
+
+    #[tokio::test]
+    async fn metrics_server_should_limit_connections() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve_metrics(listener, service));
+
+        // 占满名额的连接不发请求
+        let mut idle = Vec::new();
+        for _ in 0..MAX_SCRAPE_CONNECTIONS {
+            idle.push(TcpStream::connect(&addr).await.unwrap());
+        }
+        let before = metrics()
+            .rejections
+            .with_label_values(&["metrics_connections"])
+            .get();
+        // 超过上限的连接直接被关闭
+        let mut stream = TcpStream::connect(&addr).await.unwrap();
+        let mut buf = Vec::new();
+        let n = stream.read_to_end(&mut buf).await.unwrap_or(0);
+        assert_eq!(n, 0);
+        assert_eq!(
+            metrics()
+                .rejections
+                .with_label_values(&["metrics_connections"])
+                .get(),
+            before + 1
+        );
+
+        drop(idle);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let text = http_get(&addr, "/metrics").await;
+        assert!(text.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    async fn http_get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let req = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        stream.write_all(req.as_bytes()).await.unwrap();
+        let mut buf = String::new();
+        stream.read_to_string(&mut buf).await.unwrap();
+        buf
+    }
+}
@@ -1,7 +1,7 @@
 use crate::error::KvError;
-use crate::pb::Value;
+use crate::metrics::{metrics, Direction};
 use crate::pb::{CommandRequest, CommandResponse};
-use bytes::{Buf,Bytes, BufMut, BytesMut};
+use bytes::{Buf, BufMut, BytesMut};
 use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use prost::Message;
 use std::io::{Read, Write};
@@ -71,6 +71,8 @@ where
             // 重写长度
             buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
 
+            metrics().record_frame(Direction::Out, LEN_LEN + payload.len(), size, true);
+
             // 拼接
             buf.unsplit(payload);
             Ok(())
@@ -78,6 +80,7 @@ where
             // 压缩完成后
         } else {
             self.encode(buf)?;
+            metrics().record_frame(Direction::Out, LEN_LEN + size, size, false);
 
             Ok(())
         }
@@ -86,33 +89,38 @@ where
     // 把一个完整的 frame decode成一个Message
     // 1. 解头
     // 2. 解内容
+    // 解码失败时整个 frame 也会被取走，后面的 frame 还能正常解码
     fn decode_frame(buf: &mut BytesMut) -> Result<Self, KvError> {
         // 先拿到头 4 字节
         let header = buf.get_u32() as usize;
 
         // 解码头
         let (len, compressed) = decode_header(header);
+        if len > buf.len() {
+            return Err(prost::DecodeError::new("frame is truncated").into());
+        }
+        let payload = buf.split_to(len);
 
         // 根据是否压缩继续解码
         if compressed {
             // 解码
-            let mut decoder = GzDecoder::new(&buf[..len]);
+            let mut decoder = GzDecoder::new(&payload[..]);
             // 新建空buf
             let mut buf1 = Vec::with_capacity(len * 2);
 
-            // 全部读到空 buf中
-            decoder.read_to_end(&mut buf1)?;
+            // 全部读到空 buf中，解压失败说明 frame 的内容是坏的
+            decoder
+                .read_to_end(&mut buf1)
+                .map_err(|e| prost::DecodeError::new(e.to_string()))?;
 
-            // 取长度
-            buf.advance(len);
+            metrics().record_frame(Direction::In, LEN_LEN + len, buf1.len(), true);
 
             // 拿到消息
             Ok(Self::decode(&buf1[..buf1.len()])?)
         } else {
             // 直接解码
-            let msg = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(msg)
+            metrics().record_frame(Direction::In, LEN_LEN + len, len, false);
+            Ok(Self::decode(payload)?)
         }
     }
 }
@@ -137,6 +145,10 @@ where
 
     // 检查是否压缩
     let (len, _compressed) = decode_header(header);
+    // 太大的 frame 不读内容，连接上后面的数据已经没法按 frame 解析了
+    if len >= MAX_FRAME {
+        return Err(KvError::FrameError);
+    }
 
     // 分配内存
     buf.reserve(LEN_LEN + len);
@@ -159,6 +171,8 @@ where
 mod tests {
 
     use super::*;
+    use crate::pb::Value;
+    use bytes::Bytes;
 
     #[test]
     fn command_request_encode_decode_should_work() {
@@ -170,7 +184,7 @@ mod tests {
 
         println!("buf {:?}", buf);
 
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
         let cmd1 = CommandRequest::decode_frame(&mut buf).unwrap();
 
         assert_eq!(cmd, cmd1)
@@ -185,7 +199,7 @@ mod tests {
 
         res.encode_frame(&mut buf).unwrap();
 
-        assert_eq!(is_compressed(&buf), false);
+        assert!(!is_compressed(&buf));
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
 
         assert_eq!(res, res1)
@@ -200,7 +214,7 @@ mod tests {
 
         res.encode_frame(&mut buf).unwrap();
 
-        assert_eq!(is_compressed(&buf), true);
+        assert!(is_compressed(&buf));
 
         let res1 = CommandResponse::decode_frame(&mut buf).unwrap();
 
@@ -2,20 +2,29 @@ mod frame;
 mod multiplex;
 mod stream;
 mod tls;
+mod transport;
 use super::*;
 use crate::error::KvError;
 pub use frame::*;
 pub use multiplex::*;
-use prost::Message;
 pub use stream::*;
 
 mod stream_result;
-use stream_result::StreamResult;
+pub use stream_result::StreamResult;
 pub use tls::*;
-use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
-
-use crate::service::Service;
-use tracing::info;
+use tokio::io::{AsyncRead, AsyncWrite};
+pub use transport::*;
+
+use crate::metrics::{metrics, GaugeGuard};
+use crate::service::{Service, Session};
+use crate::shutdown::Shutdown;
+use crate::telemetry::{current_trace_context, set_trace_parent};
+use std::borrow::Cow;
+use std::future;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tracing::{info, info_span, warn, Instrument};
 
 // pub struct ProstServerStream<S> {
 //     inner: S,
@@ -24,6 +33,13 @@ use tracing::info;
 pub struct ProstServerStream<S, Store> {
     inner: ProstStream<S, CommandRequest, CommandResponse>,
     service: Service<Store>,
+    // 同一个连接上的 stream 共享一个 session
+    session: Arc<Session>,
+    shutdown: Shutdown,
+    // 连接上没有请求超过这个时间就关闭
+    idle_timeout: Option<Duration>,
+    // 正在处理的请求数的上限，所有连接共享
+    requests: Limit,
 }
 
 // pub struct ProstClientStream<S> {
@@ -39,12 +55,39 @@ where
     Store: Storage,
 {
     pub fn new(stream: S, service: Service<Store>) -> Self {
+        Self::with_session(stream, service, Default::default())
+    }
+
+    /// 创建属于某个连接的 stream，多路复用时每个 yamux stream 都使用连接的 session
+    pub fn with_session(stream: S, service: Service<Store>, session: Arc<Session>) -> Self {
         Self {
             inner: ProstStream::new(stream),
             service,
+            session,
+            shutdown: Default::default(),
+            idle_timeout: None,
+            requests: Limit::default(),
         }
     }
 
+    /// 收到关闭信号后不再读取新的命令，订阅等长时间的 stream 收到 going away 后结束
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// 整个连接空闲超过 timeout 后关闭 stream，None 表示不限制
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// 正在处理的请求达到上限时，新的请求直接收到 503
+    pub fn with_request_limit(mut self, limit: Limit) -> Self {
+        self.requests = limit;
+        self
+    }
+
     pub async fn process(mut self) -> Result<(), KvError> {
         // while let Ok(cmd) = self.recv().await {
         //     info!("Got a command {:?}", cmd);
@@ -53,13 +96,105 @@ where
         //     self.send(res).await?;
         // }
 
+        let _guard = GaugeGuard::new(&metrics().streams);
+        let _stream = self.session.enter_stream();
         let stream = &mut self.inner;
-
-        while let Some(Ok(cmd)) = stream.next().await {
-            info!("Got a new command {:?}", cmd);
-            let mut res = self.service.execute(cmd);
-            let data = res.next().await.unwrap();
-            stream.send(&data).await.unwrap();
+        let service = &self.service;
+        let session = &self.session;
+        let shutdown = &mut self.shutdown;
+        let idle_timeout = self.idle_timeout;
+        let requests = &self.requests;
+
+        loop {
+            let cmd = tokio::select! {
+                cmd = stream.next() => match cmd {
+                    Some(Ok(cmd)) => cmd,
+                    Some(Err(e)) => {
+                        metrics().record_connection_error(read_error_kind(&e));
+                        // I/O 出错说明连接已经断了，没法再回复
+                        if let KvError::IoError(_) = e {
+                            warn!(peer = session.peer(), "Failed to read command: {:?}", e);
+                            return Err(e);
+                        }
+                        warn!(peer = session.peer(), "Received malformed frame: {:?}", e);
+                        // frame 太大时没有读取内容，后面的数据没法再按 frame 解析，回复后关闭
+                        let fatal = matches!(e, KvError::FrameError);
+                        if let Err(e) = stream.send(&e.into()).await {
+                            metrics().record_connection_error("send");
+                            return Err(e);
+                        }
+                        if fatal {
+                            break;
+                        }
+                        continue;
+                    }
+                    None => break,
+                },
+                _ = shutdown.wait() => break,
+                _ = session.killed() => {
+                    info!(peer = session.peer(), "Connection killed");
+                    break;
+                }
+                _ = idle_expired(session, idle_timeout) => {
+                    metrics().record_connection_error("idle_timeout");
+                    info!(peer = session.peer(), "Closing idle connection");
+                    break;
+                }
+            };
+            let _in_flight = session.begin_request(cmd.name());
+            let permit = match requests.try_acquire() {
+                Ok(permit) => permit,
+                Err(e) => {
+                    warn!(peer = session.peer(), "Rejected {}: {}", cmd.name(), e);
+                    if let Err(e) = stream.send(&e.into()).await {
+                        metrics().record_connection_error("send");
+                        return Err(e);
+                    }
+                    continue;
+                }
+            };
+            info!("Got a new command {:?}", cmd.redacted());
+            // 客户端带了 trace context 时，这个命令的 span 接在客户端的 span 下面
+            let span = info_span!("process_command", command = cmd.name());
+            if let Some(trace) = &cmd.trace {
+                set_trace_parent(&span, trace);
+            }
+            let res = async {
+                let mut res = service.execute_in(cmd, session);
+                let mut sent = false;
+                // 第一个响应发出后请求就处理完了，订阅后续的数据不占用名额
+                let mut permit = Some(permit);
+                // 流式响应（比如 subscribe）要把所有数据都发出去
+                loop {
+                    let data = tokio::select! {
+                        // 先发出已经准备好的响应，CLIENT KILL 关闭自己的连接时也能收到回复
+                        biased;
+                        data = res.next() => match data {
+                            Some(data) => data,
+                            None => break,
+                        },
+                        // 已经发出过响应的是订阅这类长时间的 stream，正在处理的请求要等它完成
+                        _ = shutdown.wait(), if sent => {
+                            stream.send(&CommandResponse::going_away()).await?;
+                            break;
+                        }
+                        // 外层循环会在读取下一个命令前发现连接已被关闭
+                        _ = session.killed() => break,
+                    };
+                    stream.send(&data).await?;
+                    sent = true;
+                    permit.take();
+                }
+                Ok::<_, KvError>(())
+            }
+            .instrument(span)
+            .await;
+            if let Err(e) = res {
+                // 客户端已经关闭了 stream
+                metrics().record_connection_error("send");
+                warn!(peer = session.peer(), "Failed to send response: {:?}", e);
+                return Err(e);
+            }
         }
 
         Ok(())
@@ -83,6 +218,31 @@ where
     // }
 }
 
+/// 连接空闲超过 timeout 后返回，timeout 为 None 时永远不返回
+pub(crate) async fn idle_expired(session: &Session, timeout: Option<Duration>) {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return future::pending().await,
+    };
+    loop {
+        let idle = session.idle_for();
+        if idle >= timeout {
+            return;
+        }
+        time::sleep(timeout - idle).await;
+    }
+}
+
+// 读取命令出错时记录到监控指标里的分类
+fn read_error_kind(e: &KvError) -> &'static str {
+    match e {
+        KvError::IoError(_) => "io",
+        KvError::DecodeError(_) => "decode",
+        KvError::FrameError => "frame",
+        _ => "other",
+    }
+}
+
 impl<S> ProstClientStream<S>
 where
     S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
@@ -102,20 +262,42 @@ where
 
         let stream = &mut self.inner;
 
-        stream.send(cmd).await?;
+        stream.send(&with_trace(cmd)).await?;
 
         match stream.next().await {
             Some(v) => v,
-            None => Err(KvError::Internal("Didn't get any response".into())),
+            // 连接已经断开
+            None => Err(KvError::IoError(std::io::ErrorKind::UnexpectedEof.into())),
+        }
+    }
+    /// 一次发送多条命令，再按顺序读取所有响应。服务器按顺序处理同一个 stream 上的命令
+    pub async fn execute_pipeline(
+        &mut self,
+        cmds: &[CommandRequest],
+    ) -> Result<Vec<CommandResponse>, KvError> {
+        let stream = &mut self.inner;
+        for cmd in cmds {
+            stream.feed(&with_trace(cmd)).await?;
+        }
+        stream.flush().await?;
+
+        let mut responses = Vec::with_capacity(cmds.len());
+        for _ in cmds {
+            match stream.next().await {
+                Some(res) => responses.push(res?),
+                None => return Err(KvError::IoError(std::io::ErrorKind::UnexpectedEof.into())),
+            }
         }
+        Ok(responses)
     }
+
     pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult, KvError> {
         // self.send(cmd).await?;
         // Ok(self.recv().await?)
 
         let mut stream = self.inner;
 
-        stream.send(cmd).await?;
+        stream.send(&with_trace(cmd)).await?;
         stream.close().await?;
 
         StreamResult::new(stream).await
@@ -144,14 +326,26 @@ where
     // }
 }
 
-#[cfg(test)]
+// 当前在一个被追踪的 span 里时，给命令带上 trace context，否则直接发送原来的命令
+fn with_trace(cmd: &CommandRequest) -> Cow<'_, CommandRequest> {
+    match current_trace_context() {
+        Some(trace) if cmd.trace.is_none() => Cow::Owned(CommandRequest {
+            trace: Some(trace),
+            ..cmd.clone()
+        }),
+        _ => Cow::Borrowed(cmd),
+    }
+}
 
+#[cfg(test)]
 mod tests {
 
     use anyhow::Result;
 
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
+    use std::net::SocketAddr;
     use tokio::io::AsyncRead;
+    use tokio::net::{TcpListener, TcpStream};
 
     use crate::CommandRequest;
 
@@ -164,7 +358,7 @@ mod tests {
     impl AsyncRead for DummyStream {
         fn poll_read(
             self: std::pin::Pin<&mut Self>,
-            cx: &mut std::task::Context<'_>,
+            _cx: &mut std::task::Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> std::task::Poll<std::io::Result<()>> {
             let len = buf.capacity();
@@ -222,12 +416,87 @@ mod tests {
         let stream = TcpStream::connect(addr).await?;
         let mut client = ProstClientStream::new(stream);
         let v: Value = Bytes::from(vec![0u8; 16384]).into();
-        let cmd = CommandRequest::new_hset("t2", "k2", v.clone().into());
+        let cmd = CommandRequest::new_hset("t2", "k2", v.clone());
         let res = client.execute_unary(&cmd).await?;
         assert_res_ok(&res, &[Value::default()], &[]);
         let cmd = CommandRequest::new_hget("t2", "k2");
         let res = client.execute_unary(&cmd).await?;
-        assert_res_ok(&res, &[v.into()], &[]);
+        assert_res_ok(&res, &[v], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn client_server_pipeline_should_work() -> anyhow::Result<()> {
+        let addr = start_server().await?;
+        let stream = TcpStream::connect(addr).await?;
+        let mut client = ProstClientStream::new(stream);
+
+        let cmds = [
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hset("t1", "k1", "v2".into()),
+            CommandRequest::new_hget("t1", "k1"),
+        ];
+        let res = client.execute_pipeline(&cmds).await?;
+        assert_eq!(res.len(), 3);
+        assert_res_ok(&res[0], &[Value::default()], &[]);
+        assert_res_ok(&res[1], &["v1".into()], &[]);
+        assert_res_ok(&res[2], &["v2".into()], &[]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_should_get_error_response() -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let addr = start_server().await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        // 长度正确，但内容不是合法的 protobuf
+        stream.write_all(&[0, 0, 0, 3, 0xff, 0xff, 0xff]).await?;
+
+        let mut client = ProstClientStream::new(stream);
+        let res = client.inner.next().await.unwrap()?;
+        assert_eq!(res.status, 400);
+
+        // 坏的 frame 被跳过，stream 还能继续用
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let res = client.execute_unary(&cmd).await?;
+        assert_eq!(res.status, 404);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_should_close_stream() -> anyhow::Result<()> {
+        use tokio::io::AsyncWriteExt;
+
+        let addr = start_server().await?;
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(&0x7fff_ffffu32.to_be_bytes()).await?;
+
+        let mut client = ProstClientStream::new(stream);
+        let res = client.inner.next().await.unwrap()?;
+        assert_eq!(res.status, 413);
+        assert!(client.inner.next().await.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn idle_stream_should_be_closed() -> anyhow::Result<()> {
+        let (client, server) = tokio::io::duplex(4096);
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let server = ProstServerStream::new(server, service)
+            .with_idle_timeout(Some(Duration::from_millis(50)));
+        let handle = tokio::spawn(server.process());
+
+        // 有请求时不会关闭
+        let mut client = ProstClientStream::new(client);
+        for _ in 0..3 {
+            time::sleep(Duration::from_millis(30)).await;
+            let res = client.execute_unary(&CommandRequest::new_ping("")).await?;
+            assert_res_ok(&res, &["PONG".into()], &[]);
+        }
+
+        time::timeout(Duration::from_secs(1), handle).await???;
+        assert!(client.inner.next().await.is_none());
         Ok(())
     }
 
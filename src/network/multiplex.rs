@@ -1,11 +1,10 @@
 // 使用 yamux 做多路复用
 
-use crate::ProstClientStream;
+use crate::{BoxedStream, ProstClientStream};
 use futures::{future, Future, TryStreamExt};
 use std::marker::PhantomData;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt, TokioAsyncReadCompatExt};
-use tracing::info;
 use yamux::{Config, Connection, ConnectionError, Control, Mode, WindowUpdateMode};
 // yamux 的控制结构
 pub struct YamuxCtrl<S> {
@@ -59,7 +58,7 @@ where
 
         Self {
             ctrl,
-            _conn: PhantomData::default(),
+            _conn: PhantomData,
         }
     }
 
@@ -71,4 +70,15 @@ where
 
         Ok(ProstClientStream::new(stream.compat()))
     }
+
+    // 关闭整个连接，所有 stream 都会结束
+    pub async fn close(&mut self) -> Result<(), ConnectionError> {
+        self.ctrl.close().await
+    }
+
+    // 打开一个 stream，统一成 BoxedStream，方便和其它传输方式共用客户端
+    pub(crate) async fn open_raw_stream(&mut self) -> Result<BoxedStream, ConnectionError> {
+        let stream = self.ctrl.open_stream().await?;
+        Ok(Box::new(stream.compat()))
+    }
 }
@@ -27,13 +27,20 @@ where
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         // 上次调用结束后，rbuf应为空
-        assert!(self.rbuf.len() == 0);
+        assert!(self.rbuf.is_empty());
 
         // 分离 rbuf，摆脱 self引用
         let mut rest = self.rbuf.split_off(0);
 
         let fut = read_frame(&mut self.stream, &mut rest);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
+        match ready!(Box::pin(fut).poll_unpin(cx)) {
+            Ok(()) => {}
+            // 对端关闭了连接，stream 结束
+            Err(KvError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Poll::Ready(None)
+            }
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        }
 
         //拿到frame 数据,把buffer合并回去
         self.rbuf.unsplit(rest);
@@ -50,7 +57,7 @@ where
     Out: Unpin + Send + FrameCoder,
 {
     type Error = KvError;
-    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    fn poll_ready(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Poll::Ready(Ok(()))
     }
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
@@ -96,8 +103,8 @@ where
             written: 0,
             wbuf: BytesMut::new(),
             rbuf: BytesMut::new(),
-            _in: PhantomData::default(),
-            _out: PhantomData::default(),
+            _in: PhantomData,
+            _out: PhantomData,
         }
     }
 }
@@ -124,7 +131,7 @@ mod tests {
     impl AsyncRead for DummyStream {
         fn poll_read(
             self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
+            _cx: &mut Context<'_>,
             buf: &mut tokio::io::ReadBuf<'_>,
         ) -> Poll<std::io::Result<()>> {
             let len = buf.capacity();
@@ -137,7 +144,7 @@ mod tests {
     impl AsyncWrite for DummyStream {
         fn poll_write(
             self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
+            _cx: &mut Context<'_>,
             buf: &[u8],
         ) -> Poll<Result<usize, std::io::Error>> {
             self.get_mut().buf.put_slice(buf);
@@ -145,14 +152,14 @@ mod tests {
         }
         fn poll_flush(
             self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
+            _cx: &mut Context<'_>,
         ) -> Poll<Result<(), std::io::Error>> {
             Poll::Ready(Ok(()))
         }
 
         fn poll_shutdown(
             self: Pin<&mut Self>,
-            cx: &mut Context<'_>,
+            _cx: &mut Context<'_>,
         ) -> Poll<Result<(), std::io::Error>> {
             Poll::Ready(Ok(()))
         }
@@ -173,7 +180,7 @@ mod tests {
         if let Some(Ok(s)) = stream.next().await {
             assert_eq!(s, cmd);
         } else {
-            assert!(false);
+            panic!("should get a command");
         }
 
         Ok(())
@@ -28,9 +28,11 @@ impl StreamResult {
                 if v.is_empty() {
                     return Err(KvError::Internal("Invalid stream".into()));
                 }
-                let id: i64 = (&v[0]).try_into().unwrap();
+                let id: i64 = (&v[0]).try_into()?;
                 Ok(id as u32)
             }
+            // 服务器拒绝了这个命令，比如没有权限
+            Some(Ok(res)) => Err(res.into()),
             _ => Err(KvError::Internal("Invalid stream".into())),
         };
 
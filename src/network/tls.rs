@@ -1,6 +1,7 @@
 use std::io::Cursor;
 use std::sync::Arc;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::Session as _;
 use tokio_rustls::rustls::{internal::pemfile, Certificate, ClientConfig, ServerConfig};
 use tokio_rustls::rustls::{AllowAnyAuthenticatedClient, NoClientAuth, PrivateKey, RootCertStore};
 use tokio_rustls::webpki::DNSNameRef;
@@ -8,6 +9,9 @@ use tokio_rustls::TlsConnector;
 use tokio_rustls::{
     client::TlsStream as ClientTlsStream, server::TlsStream as ServerTlsStream, TlsAcceptor,
 };
+use x509_parser::extensions::GeneralName;
+
+use crate::Identity;
 
 use super::*;
 
@@ -50,7 +54,10 @@ impl TlsClientConnector {
         // 但是这个 CA 证书能验证它，也可以
         if let Some(cert) = server_ca {
             let mut buf = Cursor::new(cert);
-            config.root_store.add_pem_file(&mut buf).unwrap();
+            config
+                .root_store
+                .add_pem_file(&mut buf)
+                .map_err(|_| KvError::CertificateParseError("CA", "cert"))?;
         }
         Ok(Self {
             config: Arc::new(config),
@@ -102,7 +109,7 @@ impl TlsServerAcceptor {
         config
             .set_single_cert(certs, key)
             .map_err(|_| KvError::CertificateParseError("server", "cert"))?;
-        config.set_protocols(&[Vec::from(&ALPN_DB_SERVER[..])]);
+        config.set_protocols(&[Vec::from(ALPN_DB_SERVER)]);
 
         Ok(Self {
             inner: Arc::new(config),
@@ -122,6 +129,42 @@ impl TlsServerAcceptor {
     }
 }
 
+/// 客户端证书对应的身份，没有证书时是匿名的
+pub fn peer_identity<S>(stream: &ServerTlsStream<S>) -> Identity {
+    let (_, session) = stream.get_ref();
+    let certs = session.get_peer_certificates().unwrap_or_default();
+    match certs.first().map(|cert| certificate_names(&cert.0)) {
+        Some(names) if !names.is_empty() => Identity::Certificate(names),
+        _ => Identity::Anonymous,
+    }
+}
+
+// 证书 subject 中的 CN，以及 SAN 中的 DNS 名字、邮件地址和 URI
+fn certificate_names(der: &[u8]) -> Vec<String> {
+    let cert = match x509_parser::parse_x509_certificate(der) {
+        Ok((_, cert)) => cert,
+        Err(_) => return vec![],
+    };
+
+    let mut names: Vec<String> = cert
+        .subject()
+        .iter_common_name()
+        .filter_map(|cn| cn.as_str().ok())
+        .map(String::from)
+        .collect();
+    if let Some((_, san)) = cert.tbs_certificate.subject_alternative_name() {
+        for name in &san.general_names {
+            match name {
+                GeneralName::DNSName(s) | GeneralName::RFC822Name(s) | GeneralName::URI(s) => {
+                    names.push(s.to_string())
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
 // 加载证书（内部进行了解析）
 
 fn load_certs(cert: &str) -> Result<Vec<Certificate>, KvError> {
@@ -156,7 +199,8 @@ fn load_key(key: &str) -> Result<PrivateKey, KvError> {
 mod tests {
 
     use anyhow::Result;
-    use tokio::io::AsyncReadExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
 
     use super::*;
     use std::net::SocketAddr;
@@ -195,7 +239,7 @@ mod tests {
         let client_identity = Some((CLIENT_CERT, CLIENT_KEY));
         let ca = Some(CA_CERT);
 
-        let addr = start_server(ca.clone()).await?;
+        let addr = start_server(ca).await?;
         let connector = TlsClientConnector::new("dbserver.acme.inc", client_identity, ca)?;
 
         let stream = TcpStream::connect(addr).await?;
@@ -226,6 +270,32 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn peer_identity_should_come_from_client_cert() -> Result<()> {
+        let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, Some(CA_CERT))?;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = acceptor.accept(stream).await.unwrap();
+            peer_identity(&stream)
+        });
+
+        let connector = TlsClientConnector::new(
+            "dbserver.acme.inc",
+            Some((CLIENT_CERT, CLIENT_KEY)),
+            Some(CA_CERT),
+        )?;
+        let _stream = connector.connect(TcpStream::connect(addr).await?).await?;
+
+        assert_eq!(
+            server.await?,
+            Identity::Certificate(vec!["awesome-device-id".into()])
+        );
+        Ok(())
+    }
+
     async fn start_server(ca: Option<&str>) -> Result<SocketAddr> {
         let acceptor = TlsServerAcceptor::new(SERVER_CERT, SERVER_KEY, ca)?;
 
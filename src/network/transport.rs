@@ -0,0 +1,128 @@
+// 客户端的传输层：TLS、明文 TCP 或者 Unix domain socket，连接上可以使用 yamux 多路复用
+
+use tokio::net::{TcpStream, UnixStream};
+
+use super::*;
+use crate::{ClientConfig, ConnectorConfig, Credentials};
+
+/// 可以在上面读写 frame 的连接
+pub trait AsyncStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> AsyncStream for T {}
+
+/// 各种传输方式统一成一个类型
+pub type BoxedStream = Box<dyn AsyncStream>;
+
+#[derive(Clone)]
+enum Target {
+    Tls {
+        addr: String,
+        connector: TlsClientConnector,
+    },
+    Tcp {
+        addr: String,
+    },
+    Unix {
+        path: String,
+    },
+}
+
+/// 按 ClientConfig 建立到服务器的连接
+#[derive(Clone)]
+pub struct ClientConnector {
+    target: Target,
+    yamux: bool,
+    auth: Option<Credentials>,
+}
+
+impl ClientConnector {
+    pub fn new(config: &ClientConfig) -> Result<Self, KvError> {
+        let addr = config.general.addr.clone();
+        let (target, yamux) = match &config.connector {
+            ConnectorConfig::Tls { yamux } => {
+                let tls = &config.tls;
+                let identity = tls.identity.as_ref().map(|(c, k)| (c.as_str(), k.as_str()));
+                let connector = TlsClientConnector::new(&tls.domain, identity, tls.ca.as_deref())?;
+                (Target::Tls { addr, connector }, *yamux)
+            }
+            ConnectorConfig::Tcp { yamux } => (Target::Tcp { addr }, *yamux),
+            ConnectorConfig::Unix { path, yamux } => (Target::Unix { path: path.clone() }, *yamux),
+        };
+        Ok(Self {
+            target,
+            yamux,
+            auth: config.auth.clone(),
+        })
+    }
+
+    /// 建立一个新的连接，还没有认证
+    pub async fn connect(&self) -> Result<BoxedStream, KvError> {
+        Ok(match &self.target {
+            Target::Tls { addr, connector } => {
+                let stream = TcpStream::connect(addr).await?;
+                Box::new(connector.connect(stream).await?)
+            }
+            Target::Tcp { addr } => Box::new(TcpStream::connect(addr).await?),
+            Target::Unix { path } => Box::new(UnixStream::connect(path).await?),
+        })
+    }
+
+    /// 建立连接并认证，不使用 yamux 时之后每个 stream 都会单独建立连接
+    pub async fn open(&self) -> Result<ClientMux, KvError> {
+        if !self.yamux {
+            // 先连一次，服务器连不上时直接报错
+            self.open_direct().await?;
+            return Ok(ClientMux::Direct(self.clone()));
+        }
+
+        let mut ctrl = YamuxCtrl::new_client(self.connect().await?, None);
+        if self.auth.is_some() {
+            // 认证的是整个连接，之后打开的 stream 都使用认证后的身份
+            let mut stream = ProstClientStream::new(ctrl.open_raw_stream().await?);
+            self.authenticate(&mut stream).await?;
+        }
+        Ok(ClientMux::Yamux(ctrl))
+    }
+
+    async fn open_direct(&self) -> Result<ProstClientStream<BoxedStream>, KvError> {
+        let mut stream = ProstClientStream::new(self.connect().await?);
+        self.authenticate(&mut stream).await?;
+        Ok(stream)
+    }
+
+    // 配置了认证信息时用 Auth 命令认证
+    async fn authenticate<S>(&self, stream: &mut ProstClientStream<S>) -> Result<(), KvError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let credentials = match &self.auth {
+            Some(credentials) => credentials,
+            None => return Ok(()),
+        };
+        let cmd = match &credentials.token {
+            Some(token) => CommandRequest::new_auth_token(token),
+            None => CommandRequest::new_auth(&credentials.username, &credentials.password),
+        };
+        let res = stream.execute_unary(&cmd).await?;
+        if res.status != 200 {
+            return Err(res.into());
+        }
+        Ok(())
+    }
+}
+
+/// 客户端连接，使用 yamux 时所有 stream 共用一个连接，否则每个 stream 单独建立连接
+pub enum ClientMux {
+    Yamux(YamuxCtrl<BoxedStream>),
+    Direct(ClientConnector),
+}
+
+impl ClientMux {
+    /// 打开一个新的 stream
+    pub async fn open_stream(&mut self) -> Result<ProstClientStream<BoxedStream>, KvError> {
+        match self {
+            ClientMux::Yamux(ctrl) => Ok(ProstClientStream::new(ctrl.open_raw_stream().await?)),
+            ClientMux::Direct(connector) => connector.open_direct().await,
+        }
+    }
+}
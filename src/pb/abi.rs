@@ -1,17 +1,23 @@
 /// 来自客户端的命令请求命令，共9个
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandRequest {
+    /// 可选的 W3C trace context，服务器处理命令的 span 作为客户端 span 的子节点
+    #[prost(message, optional, tag = "32")]
+    pub trace: ::core::option::Option<TraceContext>,
+    /// 从服务器收到请求开始计算的期限，超过后服务器放弃处理并返回 504，0 表示不限制
+    #[prost(uint64, tag = "33")]
+    pub deadline_ms: u64,
     /// 互斥字段，同时只支持一个命令
     #[prost(
         oneof = "command_request::RequestData",
-        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29"
     )]
     pub request_data: ::core::option::Option<command_request::RequestData>,
 }
 /// Nested message and enum types in `CommandRequest`.
 pub mod command_request {
     /// 互斥字段，同时只支持一个命令
-    #[derive(PartialOrd, Clone, PartialEq, ::prost::Oneof)]
+    #[derive(Clone, PartialEq, ::prost::Oneof)]
     pub enum RequestData {
         #[prost(message, tag = "1")]
         Hget(super::Hget),
@@ -37,23 +43,243 @@ pub mod command_request {
         Unsubscribe(super::Unsubscribe),
         #[prost(message, tag = "12")]
         Publish(super::Publish),
+        #[prost(message, tag = "13")]
+        Batch(super::Batch),
+        #[prost(message, tag = "14")]
+        Track(super::Track),
+        #[prost(message, tag = "15")]
+        Auth(super::Auth),
+        #[prost(message, tag = "16")]
+        SetRateLimit(super::SetRateLimit),
+        #[prost(message, tag = "17")]
+        SetQuota(super::SetQuota),
+        #[prost(message, tag = "18")]
+        Ping(super::Ping),
+        #[prost(message, tag = "19")]
+        Info(super::Info),
+        #[prost(message, tag = "20")]
+        ClientList(super::ClientList),
+        #[prost(message, tag = "21")]
+        ClientKill(super::ClientKill),
+        #[prost(message, tag = "22")]
+        ConfigGet(super::ConfigGet),
+        #[prost(message, tag = "23")]
+        ConfigSet(super::ConfigSet),
+        #[prost(message, tag = "24")]
+        FlushAll(super::FlushAll),
+        #[prost(message, tag = "25")]
+        SlowlogGet(super::SlowlogGet),
+        #[prost(message, tag = "26")]
+        Backup(super::Backup),
+        #[prost(message, tag = "27")]
+        Hscan(super::Hscan),
+        #[prost(message, tag = "28")]
+        ListTables(super::ListTables),
+        #[prost(message, tag = "29")]
+        Replicate(super::Replicate),
     }
 }
+/// 保活用的 ping，服务器原样返回 message，message 为空时返回 PONG
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Ping {
+    #[prost(string, tag = "1")]
+    pub message: ::prost::alloc::string::String,
+}
+/// 列出名字匹配 pattern 这个 glob 的 table，为空时列出全部，pairs 中是 table 名和 key 个数。
+/// 开启访问控制时只返回有 read 权限的 table
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ListTables {
+    #[prost(string, tag = "1")]
+    pub pattern: ::prost::alloc::string::String,
+}
+// 以下是运维命令，都需要 admin 权限
+
+/// 服务器的状态，pairs 中有 version、uptime_secs、storage、tables、keys、bytes、
+/// clients、memory_rss_bytes（只在 Linux 上提供）和复制的状态：role 是 leader 或 follower，
+/// leader 上有 replication_seq 和 connected_followers，follower 上有 leader、link_status、
+/// replication_seq、replication_lag_ms 和 replication_full_syncs
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Info {}
+/// 列出当前的连接，每个连接是 values 中的一个字符串，
+/// 格式为 id=1 peer=127.0.0.1:5000 identity=alice streams=1 age=10 idle=0 cmd=hget
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientList {}
+/// 关闭 id 对应的连接，连接上所有的 stream 都会结束
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ClientKill {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+}
+/// 读取运行时可以修改的配置，pattern 是名字的 glob，为空时返回全部，pairs 中是名字和值
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfigGet {
+    #[prost(string, tag = "1")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 修改运行时配置，名字和 ConfigGet 返回的一致
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConfigSet {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "2")]
+    pub value: ::core::option::Option<Value>,
+}
+/// 删除名字匹配 table 这个 glob 的所有 table 中的数据，为空时删除全部，返回删除的 key 个数
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct FlushAll {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+}
+/// 最近的慢命令，从新到旧最多返回 count 条，为 0 时返回全部。每条是 values 中的一个字符串，
+/// 格式为 id=1 time=1700000000 duration_us=12000 identity=alice peer=127.0.0.1:5000 cmd=hget t1 k1
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SlowlogGet {
+    #[prost(uint32, tag = "1")]
+    pub count: u32,
+}
+/// 备份整个数据库，备份期间不暂停写入，备份是读完数据那一刻的状态。
+/// path 不为空时服务器把备份写到 backup_dir 中的这个相对路径，没有配置 backup_dir 时返回 403，
+/// pairs 中返回每个 table 的 key 个数；为空时以流的形式返回备份文件：第一个响应的 values 中是备份的 id，
+/// 之后每个响应的 values 中是一段二进制数据，按顺序拼起来就是备份文件
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Backup {
+    #[prost(string, tag = "1")]
+    pub path: ::prost::alloc::string::String,
+}
+/// follower 从 leader 同步数据。返回一个流：第一个响应的 values 中是流的 id，第二个的 values 中是
+/// 快照之后的写操作序号和快照的字节数，之后是按顺序拼起来的快照（格式和备份文件一样），
+/// 再之后每个响应的 replication 中是 leader 按顺序执行的一次写入，没有写入时也会定期发送心跳。
+/// follower 跟不上时 leader 返回 503 并结束这个流，follower 需要重新同步
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Replicate {}
+/// 复制流中的一条记录，writes 为空时是心跳
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicationEntry {
+    /// 写操作的序号，每次写入加一，心跳为 0
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+    /// leader 生成这条记录时的 Unix 时间，单位毫秒，follower 用来计算延迟
+    #[prost(uint64, tag = "2")]
+    pub timestamp_ms: u64,
+    /// 一次写入（包括 batch 中所有成功的写命令）修改的 key，按执行的顺序排列
+    #[prost(message, repeated, tag = "3")]
+    pub writes: ::prost::alloc::vec::Vec<ReplicatedWrite>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ReplicatedWrite {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub key: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "3")]
+    pub value: ::core::option::Option<Value>,
+    /// 删除这个 key，此时没有 value
+    #[prost(bool, tag = "4")]
+    pub deleted: bool,
+}
+/// 备份文件的格式：8 字节的 KVSNAP01，然后是长度前缀编码的 SnapshotManifest，
+/// 之后按 manifest 中 table 的顺序是每个 table 的若干个长度前缀编码的 SnapshotChunk
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotManifest {
+    #[prost(uint32, tag = "1")]
+    pub version: u32,
+    /// 备份的 Unix 时间，单位毫秒
+    #[prost(uint64, tag = "2")]
+    pub created_ms: u64,
+    /// 做备份的存储后端，只用于显示，可以恢复到任意的存储
+    #[prost(string, tag = "3")]
+    pub storage: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "4")]
+    pub tables: ::prost::alloc::vec::Vec<SnapshotTable>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotTable {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub keys: u64,
+    /// 这个 table 所有 chunk 编码后（包括长度前缀）的 SHA-256
+    #[prost(bytes = "bytes", tag = "3")]
+    pub sha256: ::prost::bytes::Bytes,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SnapshotChunk {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(message, repeated, tag = "2")]
+    pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+}
+/// W3C Trace Context 的两个 header，格式见 <https://www.w3.org/TR/trace-context/>
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct TraceContext {
+    #[prost(string, tag = "1")]
+    pub traceparent: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub tracestate: ::prost::alloc::string::String,
+}
+/// 运行时增加或修改一条限流规则（需要 admin 权限），scope 和 target 相同的规则会被替换，
+/// rate 为 0 时删除这条规则。scope 是 connection、identity 或 table
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetRateLimit {
+    #[prost(string, tag = "1")]
+    pub scope: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub target: ::prost::alloc::string::String,
+    #[prost(double, tag = "3")]
+    pub rate: f64,
+    #[prost(uint32, tag = "4")]
+    pub burst: u32,
+}
+/// 运行时设置 table 的存储配额（需要 admin 权限），table 相同的配额会被替换，
+/// max_keys 和 max_bytes 都为 0 时删除这个配额，只有一个为 0 表示这一项不限制
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct SetQuota {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub max_keys: u64,
+    #[prost(uint64, tag = "3")]
+    pub max_bytes: u64,
+}
+/// 认证当前连接，之后这个连接上的命令都以认证的用户身份检查权限。
+/// 有 token 时使用 token，否则使用 username 和 password，成功时返回用户名
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Auth {
+    #[prost(string, tag = "1")]
+    pub username: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub password: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub token: ::prost::alloc::string::String,
+}
+/// 开启客户端缓存的 key 跟踪。返回一个流，第一条是连接 id，
+/// 之后同一个连接上读过的 key 被修改时，会收到 values 为 [table, key] 的通知
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Track {}
+/// 一次提交一组命令，按顺序（非原子）执行，可以跨 table
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Batch {
+    #[prost(message, repeated, tag = "1")]
+    pub requests: ::prost::alloc::vec::Vec<CommandRequest>,
+    /// 某条命令出错时是否停止执行后面的命令
+    #[prost(bool, tag = "2")]
+    pub stop_on_error: bool,
+}
 // subscribe 某个主题，任何发布到这个主题的数据都会被收到
 
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Subscribe {
     #[prost(string, tag = "1")]
     pub topic: ::prost::alloc::string::String,
 }
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Unsubscribe {
     #[prost(string, tag = "1")]
     pub topic: ::prost::alloc::string::String,
     #[prost(uint32, tag = "2")]
     pub id: u32,
 }
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Publish {
     #[prost(string, tag = "1")]
     pub topic: ::prost::alloc::string::String,
@@ -61,7 +287,7 @@ pub struct Publish {
     pub data: ::prost::alloc::vec::Vec<Value>,
 }
 /// 服务器的响应
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CommandResponse {
     /// 状态码；复用 HTTP 2xx/4xx/5xx 状态码
     #[prost(uint32, tag = "1")]
@@ -75,9 +301,51 @@ pub struct CommandResponse {
     /// 成功返回的 kv pairs
     #[prost(message, repeated, tag = "4")]
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
+    /// Batch 中每条命令各自的响应，顺序和请求一致
+    #[prost(message, repeated, tag = "5")]
+    pub responses: ::prost::alloc::vec::Vec<CommandResponse>,
+    /// 被限流（429）时，建议客户端等待多少毫秒后重试
+    #[prost(uint64, tag = "6")]
+    pub retry_after_ms: u64,
+    /// 订阅者收到的消息带上发布消息时的 trace context
+    #[prost(message, optional, tag = "7")]
+    pub trace: ::core::option::Option<TraceContext>,
+    /// 不是 2xx 时，机器可读的错误信息
+    #[prost(message, optional, tag = "8")]
+    pub error: ::core::option::Option<ErrorDetail>,
+    /// 复制流中的写操作，见 Replicate
+    #[prost(message, optional, tag = "9")]
+    pub replication: ::core::option::Option<ReplicationEntry>,
+}
+/// 错误的详细信息，只填写和错误码相关的字段
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ErrorDetail {
+    #[prost(enumeration = "ErrorCode", tag = "1")]
+    pub code: i32,
+    /// 相同的请求稍后重试可能会成功
+    #[prost(bool, tag = "2")]
+    pub retryable: bool,
+    #[prost(string, tag = "3")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "4")]
+    pub key: ::prost::alloc::string::String,
+    /// 类型不一致时期望的类型和实际的值
+    #[prost(string, tag = "5")]
+    pub expected_type: ::prost::alloc::string::String,
+    #[prost(message, optional, tag = "6")]
+    pub actual: ::core::option::Option<Value>,
+    /// 存储出错时正在执行的操作
+    #[prost(string, tag = "7")]
+    pub operation: ::prost::alloc::string::String,
+    /// 错误的原因，不包含错误类型的前缀
+    #[prost(string, tag = "8")]
+    pub reason: ::prost::alloc::string::String,
+    /// follower 拒绝写命令时 leader 的地址
+    #[prost(string, tag = "9")]
+    pub leader: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一个 key，返回 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -85,13 +353,13 @@ pub struct Hget {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中获取所有的 Kvpair
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hgetall {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
 }
 /// 从 table 中获取一组 key，返回它们的 value
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmget {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -130,7 +398,7 @@ pub struct Kvpair {
 }
 /// 往 table 里存一个 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -139,7 +407,7 @@ pub struct Hset {
 }
 /// 往 table 中存一组 kvpair，
 /// 如果 table 不存在就创建这个 table
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmset {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -147,7 +415,7 @@ pub struct Hmset {
     pub pairs: ::prost::alloc::vec::Vec<Kvpair>,
 }
 /// 从 table 中删除一个 key，返回它之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -155,7 +423,7 @@ pub struct Hdel {
     pub key: ::prost::alloc::string::String,
 }
 /// 从 table 中删除一组 key，返回它们之前的值
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmdel {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -163,7 +431,7 @@ pub struct Hmdel {
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
 /// 查看 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
@@ -171,10 +439,50 @@ pub struct Hexist {
     pub key: ::prost::alloc::string::String,
 }
 /// 查看一组 key 是否存在
-#[derive(PartialOrd, Clone, PartialEq, ::prost::Message)]
+#[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Hmexist {
     #[prost(string, tag = "1")]
     pub table: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "2")]
     pub keys: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
 }
+/// 按 key 的顺序分页读取 table，从大于 cursor 的 key 开始最多检查 count 个 key（为 0 时是 100，最多 10000），
+/// 返回其中匹配 pattern 这个 glob 的 kvpair（为空时全部返回）。
+/// values 中是下一页的 cursor，为空字符串时表示已经读完
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Hscan {
+    #[prost(string, tag = "1")]
+    pub table: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub cursor: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "3")]
+    pub count: u32,
+    #[prost(string, tag = "4")]
+    pub pattern: ::prost::alloc::string::String,
+}
+/// 错误码，数值一旦发布就不再改变，只会新增
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ErrorCode {
+    /// 没有错误，或者是不认识错误码的旧服务器
+    Unspecified = 0,
+    NotFound = 1,
+    InvalidCommand = 2,
+    /// 值的类型和期望的不一致
+    TypeMismatch = 3,
+    Storage = 4,
+    Io = 5,
+    Encode = 6,
+    Decode = 7,
+    FrameTooLarge = 8,
+    Internal = 9,
+    Unauthenticated = 10,
+    PermissionDenied = 11,
+    RateLimited = 12,
+    QuotaExceeded = 13,
+    Timeout = 14,
+    /// 服务器正在关闭
+    Unavailable = 15,
+    /// 只读的 follower 不处理写命令，需要发给 ErrorDetail 中的 leader
+    ReadOnly = 16,
+}
@@ -7,9 +7,10 @@ use crate::error::*;
 pub use abi::*;
 
 use bytes::Bytes;
-use bytes::BytesMut;
 use http::StatusCode; // 使用状态码
+use std::fmt;
 use std::str;
+use std::time::Duration;
 
 // 类型的生成，两种方式，一种是直接创建，另一种是由其他类型转换而来
 impl CommandRequest {
@@ -19,6 +20,8 @@ impl CommandRequest {
                 table: table.into(),
                 pair: Some(Kvpair::new(key, value)),
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
     /// 创建 HGET 命令,代表了一种可以转为字String的类型
@@ -28,6 +31,8 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -37,6 +42,19 @@ impl CommandRequest {
             request_data: Some(RequestData::Hgetall(Hgetall {
                 table: table.into(),
             })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    pub fn new_hmget(table: impl Into<String>, keys: Vec<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hmget(Hmget {
+                table: table.into(),
+                keys,
+            })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -46,6 +64,8 @@ impl CommandRequest {
                 table: table.into(),
                 pairs,
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -55,6 +75,8 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -64,6 +86,8 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -73,6 +97,8 @@ impl CommandRequest {
                 table: table.into(),
                 key: key.into(),
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -82,11 +108,38 @@ impl CommandRequest {
                 table: table.into(),
                 keys,
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
+
+    /// 创建 HSCAN 命令，从大于 cursor 的 key 开始读一页，cursor 为空时从头开始
+    pub fn new_hscan(
+        table: impl Into<String>,
+        cursor: impl Into<String>,
+        count: u32,
+        pattern: impl Into<String>,
+    ) -> Self {
+        Self::with_data(RequestData::Hscan(Hscan {
+            table: table.into(),
+            cursor: cursor.into(),
+            count,
+            pattern: pattern.into(),
+        }))
+    }
+
+    /// 创建 LIST TABLES 命令，pattern 为空时列出所有 table
+    pub fn new_list_tables(pattern: impl Into<String>) -> Self {
+        Self::with_data(RequestData::ListTables(ListTables {
+            pattern: pattern.into(),
+        }))
+    }
+
     pub fn new_subscribe(name: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Subscribe(Subscribe { topic: name.into() })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -96,6 +149,8 @@ impl CommandRequest {
                 topic: name.into(),
                 id,
             })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
 
@@ -105,8 +160,249 @@ impl CommandRequest {
                 topic: name.into(),
                 data,
             })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 创建 TRACK 命令，开启客户端缓存的 key 跟踪
+    pub fn new_track() -> Self {
+        Self {
+            request_data: Some(RequestData::Track(Track {})),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 创建 PING 命令，message 为空时服务器返回 PONG
+    pub fn new_ping(message: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Ping(Ping {
+                message: message.into(),
+            })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 设置服务器处理这个请求的期限，超过后服务器返回 504
+    pub fn with_deadline(mut self, timeout: Duration) -> Self {
+        // 不足 1ms 的按 1ms 算，0 表示不限制
+        self.deadline_ms = (timeout.as_millis() as u64).max(1);
+        self
+    }
+
+    /// 请求里带的期限
+    pub fn deadline(&self) -> Option<Duration> {
+        (self.deadline_ms > 0).then(|| Duration::from_millis(self.deadline_ms))
+    }
+
+    /// 创建 AUTH 命令，用用户名和密码认证
+    pub fn new_auth(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                username: username.into(),
+                password: password.into(),
+                ..Default::default()
+            })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 创建 AUTH 命令，用 token 认证
+    pub fn new_auth_token(token: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                token: token.into(),
+                ..Default::default()
+            })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 创建 SETRATELIMIT 命令，rate 为 0 时删除规则
+    pub fn new_set_rate_limit(
+        scope: impl Into<String>,
+        target: impl Into<String>,
+        rate: f64,
+        burst: u32,
+    ) -> Self {
+        Self {
+            request_data: Some(RequestData::SetRateLimit(SetRateLimit {
+                scope: scope.into(),
+                target: target.into(),
+                rate,
+                burst,
+            })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 创建 SETQUOTA 命令，0 表示不限制
+    pub fn new_set_quota(table: impl Into<String>, max_keys: u64, max_bytes: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::SetQuota(SetQuota {
+                table: table.into(),
+                max_keys,
+                max_bytes,
+            })),
+            trace: None,
+            deadline_ms: 0,
         }
     }
+
+    /// 创建 INFO 命令，返回服务器的状态
+    pub fn new_info() -> Self {
+        Self::with_data(RequestData::Info(Info {}))
+    }
+
+    /// 创建 CLIENT LIST 命令，列出当前的连接
+    pub fn new_client_list() -> Self {
+        Self::with_data(RequestData::ClientList(ClientList {}))
+    }
+
+    /// 创建 CLIENT KILL 命令，关闭 id 对应的连接
+    pub fn new_client_kill(id: u64) -> Self {
+        Self::with_data(RequestData::ClientKill(ClientKill { id }))
+    }
+
+    /// 创建 CONFIG GET 命令，pattern 为空时返回所有运行时配置
+    pub fn new_config_get(pattern: impl Into<String>) -> Self {
+        Self::with_data(RequestData::ConfigGet(ConfigGet {
+            pattern: pattern.into(),
+        }))
+    }
+
+    /// 创建 CONFIG SET 命令
+    pub fn new_config_set(name: impl Into<String>, value: Value) -> Self {
+        Self::with_data(RequestData::ConfigSet(ConfigSet {
+            name: name.into(),
+            value: Some(value),
+        }))
+    }
+
+    /// 创建 FLUSHALL 命令，table 是要清空的 table 的 glob，为空时清空所有 table
+    pub fn new_flush_all(table: impl Into<String>) -> Self {
+        Self::with_data(RequestData::FlushAll(FlushAll {
+            table: table.into(),
+        }))
+    }
+
+    /// 创建 SLOWLOG GET 命令，count 为 0 时返回所有记录的慢命令
+    pub fn new_slowlog_get(count: u32) -> Self {
+        Self::with_data(RequestData::SlowlogGet(SlowlogGet { count }))
+    }
+
+    /// 创建 BACKUP 命令，path 为空时备份以流的形式返回给客户端
+    pub fn new_backup(path: impl Into<String>) -> Self {
+        Self::with_data(RequestData::Backup(Backup { path: path.into() }))
+    }
+
+    /// 创建 REPLICATE 命令，follower 用它从 leader 同步数据
+    pub fn new_replicate() -> Self {
+        Self::with_data(RequestData::Replicate(Replicate {}))
+    }
+
+    fn with_data(data: RequestData) -> Self {
+        Self {
+            request_data: Some(data),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 命令的名字，用于日志和统计
+    pub fn name(&self) -> &'static str {
+        match &self.request_data {
+            Some(RequestData::Hget(_)) => "hget",
+            Some(RequestData::Hgetall(_)) => "hgetall",
+            Some(RequestData::Hmget(_)) => "hmget",
+            Some(RequestData::Hset(_)) => "hset",
+            Some(RequestData::Hmset(_)) => "hmset",
+            Some(RequestData::Hdel(_)) => "hdel",
+            Some(RequestData::Hmdel(_)) => "hmdel",
+            Some(RequestData::Hexist(_)) => "hexist",
+            Some(RequestData::Hmexist(_)) => "hmexist",
+            Some(RequestData::Hscan(_)) => "hscan",
+            Some(RequestData::ListTables(_)) => "list_tables",
+            Some(RequestData::Subscribe(_)) => "subscribe",
+            Some(RequestData::Unsubscribe(_)) => "unsubscribe",
+            Some(RequestData::Publish(_)) => "publish",
+            Some(RequestData::Batch(_)) => "batch",
+            Some(RequestData::Track(_)) => "track",
+            Some(RequestData::Auth(_)) => "auth",
+            Some(RequestData::SetRateLimit(_)) => "setratelimit",
+            Some(RequestData::SetQuota(_)) => "setquota",
+            Some(RequestData::Ping(_)) => "ping",
+            Some(RequestData::Info(_)) => "info",
+            Some(RequestData::ClientList(_)) => "client_list",
+            Some(RequestData::ClientKill(_)) => "client_kill",
+            Some(RequestData::ConfigGet(_)) => "config_get",
+            Some(RequestData::ConfigSet(_)) => "config_set",
+            Some(RequestData::FlushAll(_)) => "flushall",
+            Some(RequestData::SlowlogGet(_)) => "slowlog_get",
+            Some(RequestData::Backup(_)) => "backup",
+            Some(RequestData::Replicate(_)) => "replicate",
+            None => "unknown",
+        }
+    }
+
+    /// 创建 BATCH 命令，requests 会按顺序执行
+    pub fn new_batch(requests: Vec<CommandRequest>, stop_on_error: bool) -> Self {
+        Self {
+            request_data: Some(RequestData::Batch(Batch {
+                requests,
+                stop_on_error,
+            })),
+            trace: None,
+            deadline_ms: 0,
+        }
+    }
+
+    /// 用于日志输出，Auth 中的密码和 token 会被隐藏
+    pub fn redacted(&self) -> Redacted<'_> {
+        Redacted(self)
+    }
+}
+
+/// 隐藏了密码和 token 的命令，只实现 Debug
+pub struct Redacted<'a>(&'a CommandRequest);
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 大部分命令没有密码，不用复制
+        if !has_secret(self.0) {
+            return self.0.fmt(f);
+        }
+        let mut cmd = self.0.clone();
+        hide_secret(&mut cmd);
+        cmd.fmt(f)
+    }
+}
+
+fn has_secret(cmd: &CommandRequest) -> bool {
+    match &cmd.request_data {
+        Some(RequestData::Auth(_)) => true,
+        Some(RequestData::Batch(v)) => v.requests.iter().any(has_secret),
+        _ => false,
+    }
+}
+
+fn hide_secret(cmd: &mut CommandRequest) {
+    match &mut cmd.request_data {
+        Some(RequestData::Auth(v)) => {
+            for secret in [&mut v.password, &mut v.token] {
+                if !secret.is_empty() {
+                    *secret = "***".into();
+                }
+            }
+        }
+        Some(RequestData::Batch(v)) => v.requests.iter_mut().for_each(hide_secret),
+        _ => {}
+    }
 }
 
 impl Kvpair {
@@ -150,13 +446,32 @@ impl From<KvError> for CommandResponse {
         let mut result = Self {
             status: StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             message: e.to_string(),
-            values: vec![],
-            pairs: vec![],
+            error: Some(e.detail()),
+            ..Default::default()
         };
 
         match e {
             KvError::NotFound(_, _) => result.status = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::ConvertError(..) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::DecodeError(_) => result.status = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::FrameError => result.status = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _,
+            KvError::Unauthenticated(_) => result.status = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::PermissionDenied(_) => result.status = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::Timeout(_) => result.status = StatusCode::GATEWAY_TIMEOUT.as_u16() as _,
+            KvError::Unavailable(_) => {
+                result.status = StatusCode::SERVICE_UNAVAILABLE.as_u16() as _
+            }
+            KvError::RateLimited(_, retry_after) => {
+                result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _;
+                // 至少等 1ms，0 表示没有建议的重试时间
+                result.retry_after_ms = (retry_after.as_millis() as u64).max(1);
+            }
+            KvError::QuotaExceeded(_) => {
+                result.status = StatusCode::TOO_MANY_REQUESTS.as_u16() as _
+            }
+            KvError::ReadOnly(_) => result.status = StatusCode::MISDIRECTED_REQUEST.as_u16() as _,
+            KvError::ServerError(status, _) => result.status = status,
             _ => {}
         }
 
@@ -164,6 +479,70 @@ impl From<KvError> for CommandResponse {
     }
 }
 
+/// 客户端把出错的响应还原成 KvError，没有错误详情时只能得到状态码和消息
+impl From<CommandResponse> for KvError {
+    fn from(res: CommandResponse) -> Self {
+        let detail = match res.error {
+            Some(detail) => detail,
+            None if res.status == StatusCode::BAD_REQUEST.as_u16() as u32 => {
+                return KvError::InvalidCommand(res.message)
+            }
+            None => return KvError::ServerError(res.status, res.message),
+        };
+        let reason = detail.reason;
+        match ErrorCode::from_i32(detail.code) {
+            Some(ErrorCode::NotFound) => KvError::NotFound(detail.table, detail.key),
+            Some(ErrorCode::InvalidCommand) => KvError::InvalidCommand(reason),
+            Some(ErrorCode::TypeMismatch) => KvError::ConvertError(
+                detail.actual.unwrap_or_default(),
+                known_name(&detail.expected_type, TYPE_NAMES),
+            ),
+            Some(ErrorCode::Storage) => KvError::StorageError(
+                known_name(&detail.operation, STORAGE_OPERATIONS),
+                detail.table,
+                detail.key,
+                reason,
+            ),
+            Some(ErrorCode::Internal) => KvError::Internal(reason),
+            Some(ErrorCode::Unauthenticated) => KvError::Unauthenticated(reason),
+            Some(ErrorCode::PermissionDenied) => KvError::PermissionDenied(reason),
+            Some(ErrorCode::RateLimited) => {
+                KvError::RateLimited(reason, Duration::from_millis(res.retry_after_ms))
+            }
+            Some(ErrorCode::QuotaExceeded) => KvError::QuotaExceeded(reason),
+            Some(ErrorCode::Timeout) => KvError::Timeout(reason),
+            Some(ErrorCode::Unavailable) => KvError::Unavailable(reason),
+            Some(ErrorCode::ReadOnly) => KvError::ReadOnly(detail.leader),
+            // 服务器那边的 I/O、编解码错误，不能当成客户端自己的连接错误
+            _ => KvError::ServerError(res.status, res.message),
+        }
+    }
+}
+
+// ConvertError 里期望的类型
+const TYPE_NAMES: &[&str] = &["Integer", "Bool", "String", "CommandResponse"];
+// StorageError 里的操作
+const STORAGE_OPERATIONS: &[&str] = &[
+    "get",
+    "set",
+    "contains",
+    "del",
+    "get_all",
+    "get_iter",
+    "stats",
+    "flush",
+    "apply_batch",
+];
+
+// KvError 里的名字是 &'static str，从网络上收到的只能对应到已知的名字
+fn known_name(name: &str, known: &[&'static str]) -> &'static str {
+    known
+        .iter()
+        .find(|n| **n == name)
+        .copied()
+        .unwrap_or("unknown")
+}
+
 /// 从 Vec<Kvpair> 转换成 CommandResponse
 impl From<Vec<Kvpair>> for CommandResponse {
     fn from(v: Vec<Kvpair>) -> Self {
@@ -184,6 +563,15 @@ impl From<i64> for Value {
     }
 }
 
+/// 从 f64 转换成 Value
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Self {
+            value: Some(value::Value::Float(f)),
+        }
+    }
+}
+
 impl TryFrom<&[u8]> for Value {
     type Error = KvError;
 
@@ -232,11 +620,36 @@ impl From<Vec<Value>> for CommandResponse {
     }
 }
 
+/// 从 Batch 中每条命令的响应转换成 CommandResponse
+impl From<Vec<CommandResponse>> for CommandResponse {
+    fn from(v: Vec<CommandResponse>) -> Self {
+        Self {
+            status: StatusCode::OK.as_u16() as _,
+            responses: v,
+            ..Default::default()
+        }
+    }
+}
+
 impl CommandResponse {
     pub fn ok() -> Self {
-        let mut result = CommandResponse::default();
-        result.status = StatusCode::OK.as_u16() as _;
-        result
+        CommandResponse {
+            status: StatusCode::OK.as_u16() as _,
+            ..Default::default()
+        }
+    }
+
+    /// 服务器关闭时发给订阅等长时间 stream 的最后一个响应
+    pub fn going_away() -> Self {
+        KvError::Unavailable("Server is shutting down".into()).into()
+    }
+
+    /// 响应的错误码，成功或者旧服务器的响应是 Unspecified
+    pub fn error_code(&self) -> ErrorCode {
+        self.error
+            .as_ref()
+            .and_then(|e| ErrorCode::from_i32(e.code))
+            .unwrap_or(ErrorCode::Unspecified)
     }
 }
 
@@ -259,6 +672,28 @@ impl TryFrom<Value> for i64 {
     }
 }
 
+impl TryFrom<Value> for bool {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::Bool(b)) => Ok(b),
+            _ => Err(KvError::ConvertError(v, "Bool")),
+        }
+    }
+}
+
+impl TryFrom<Value> for String {
+    type Error = KvError;
+
+    fn try_from(v: Value) -> Result<Self, Self::Error> {
+        match v.value {
+            Some(value::Value::String(s)) => Ok(s),
+            _ => Err(KvError::ConvertError(v, "String")),
+        }
+    }
+}
+
 impl Value {
     /// 转换成 string 做错误处理
     pub fn format(&self) -> String {
@@ -283,16 +718,87 @@ impl TryFrom<&CommandResponse> for i64 {
     fn try_from(value: &CommandResponse) -> Result<Self, Self::Error> {
         if value.status != StatusCode::OK.as_u16() as u32 {
             return Err(KvError::ConvertError(
-                value.values.get(0).unwrap().clone(),
+                value.values.first().unwrap().clone(),
                 "CommandResponse",
             ));
         }
-        match value.values.get(0) {
+        match value.values.first() {
             Some(v) => v.try_into(),
             None => Err(KvError::ConvertError(
-                value.values.get(0).unwrap().clone(),
+                value.values.first().unwrap().clone(),
                 "CommandResponse",
             )),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted_should_hide_secrets() {
+        let auth = CommandRequest::new_auth("alice", "secret");
+        let batch = CommandRequest::new_batch(vec![auth.clone()], false);
+        for cmd in [auth, batch] {
+            let s = format!("{:?}", cmd.redacted());
+            assert!(s.contains("alice"));
+            assert!(!s.contains("secret"));
+        }
+        let hget = CommandRequest::new_hget("t1", "k1");
+        assert_eq!(format!("{:?}", hget.redacted()), format!("{:?}", hget));
+    }
+
+    #[test]
+    fn error_detail_should_rebuild_typed_error() {
+        let res: CommandResponse = KvError::NotFound("t1".into(), "k1".into()).into();
+        assert_eq!(res.status, 404);
+        assert_eq!(res.error_code(), ErrorCode::NotFound);
+        assert!(matches!(KvError::from(res), KvError::NotFound(t, k) if t == "t1" && k == "k1"));
+
+        let res: CommandResponse = KvError::ConvertError(Value::from(true), "Integer").into();
+        assert_eq!(res.status, 400);
+        match KvError::from(res) {
+            KvError::ConvertError(v, expected) => {
+                assert_eq!(v, Value::from(true));
+                assert_eq!(expected, "Integer");
+            }
+            e => panic!("expect ConvertError, got {:?}", e),
+        }
+
+        let res: CommandResponse =
+            KvError::RateLimited("too fast".into(), Duration::from_millis(50)).into();
+        let detail = res.error.clone().unwrap();
+        assert!(detail.retryable);
+        assert_eq!(detail.reason, "too fast");
+        assert!(matches!(
+            KvError::from(res),
+            KvError::RateLimited(r, d) if r == "too fast" && d == Duration::from_millis(50)
+        ));
+
+        // follower 拒绝写命令时带上 leader 的地址
+        let res: CommandResponse = KvError::ReadOnly("127.0.0.1:9527".into()).into();
+        assert_eq!(res.status, 421);
+        assert!(!res.error.as_ref().unwrap().retryable);
+        assert!(matches!(KvError::from(res), KvError::ReadOnly(addr) if addr == "127.0.0.1:9527"));
+    }
+
+    #[test]
+    fn server_side_io_error_should_not_look_like_connection_error() {
+        let e = KvError::IoError(std::io::ErrorKind::PermissionDenied.into());
+        let res: CommandResponse = e.into();
+        assert_eq!(res.error_code(), ErrorCode::Io);
+        assert!(matches!(KvError::from(res), KvError::ServerError(500, _)));
+
+        // 没有错误详情的旧服务器
+        let res = CommandResponse {
+            status: 404,
+            message: "not found".into(),
+            ..Default::default()
+        };
+        assert_eq!(res.error_code(), ErrorCode::Unspecified);
+        let e = KvError::from(res);
+        assert_eq!(e.code(), ErrorCode::NotFound);
+        assert!(!e.is_retryable());
+    }
+}
@@ -1,15 +1,10 @@
 use std::env;
 
 use anyhow::Result;
-use db_server::{start_server_with_config, RotationConfig, ServerConfig};
+use db_server::{init_tracing, start_server_with_config, ServerConfig};
 use tokio::fs;
-use tracing::span;
-use tracing_subscriber::{
-    fmt::{self, format},
-    layer::SubscriberExt,
-    prelude::*,
-    EnvFilter,
-};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{info, span};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -19,34 +14,25 @@ async fn main() -> Result<()> {
     };
     let config: ServerConfig = toml::from_str(&config)?;
 
-    let tracer = opentelemetry_jaeger::new_pipeline()
-        .with_service_name("kv-server")
-        .install_simple()?;
-    let opentelemetry = tracing_opentelemetry::layer().with_tracer(tracer);
-
-    // 添加
-    let log = &config.log;
-    let file_appender = match log.rotation {
-        RotationConfig::Hourly => tracing_appender::rolling::hourly(&log.path, "server.log"),
-        RotationConfig::Daily => tracing_appender::rolling::daily(&log.path, "server.log"),
-        RotationConfig::Never => tracing_appender::rolling::never(&log.path, "server.log"),
-    };
-
-    let (non_blocking, _guard1) = tracing_appender::non_blocking(file_appender);
-    let fmt_layer = fmt::layer()
-        .event_format(format().compact())
-        .with_writer(non_blocking);
-
-    tracing_subscriber::registry()
-        .with(EnvFilter::from_default_env())
-        .with(fmt_layer)
-        .with(opentelemetry)
-        .init();
+    let _guard = init_tracing(&config)?;
 
     let root = span!(tracing::Level::INFO, "app_start", work_units = 2);
     let _enter = root.enter();
 
-    start_server_with_config(&config).await?;
+    let server = start_server_with_config(&config).await?;
+    shutdown_signal().await?;
+    info!("Shutting down");
+    server.shutdown().await?;
+
+    Ok(())
+}
 
+// 等待 Ctrl-C 或者 SIGTERM
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        res = tokio::signal::ctrl_c() => res?,
+        _ = terminate.recv() => {}
+    }
     Ok(())
 }
@@ -0,0 +1,373 @@
+//! 认证和访问控制：连接的身份来自客户端证书或者 Auth 命令，每个命令执行前按规则检查权限
+//!
+
+use std::fmt;
+
+use ring::{constant_time, digest};
+
+use crate::pb::command_request::RequestData;
+use crate::{AclRule, Auth, AuthConfig, CommandRequest, KvError, Permission, UserConfig};
+
+// 匹配未认证连接的规则身份
+const ANONYMOUS: &str = "anonymous";
+
+/// 连接的身份
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Identity {
+    /// 没有客户端证书，也没有执行过 Auth
+    #[default]
+    Anonymous,
+    /// 客户端证书 subject 中的 CN，以及 SAN 中的名字
+    Certificate(Vec<String>),
+    /// 通过 Auth 命令认证的用户
+    User(String),
+}
+
+impl Identity {
+    // 规则里可以用来匹配这个身份的名字
+    pub(crate) fn names(&self) -> &[String] {
+        match self {
+            Identity::Anonymous => &[],
+            Identity::Certificate(names) => names,
+            Identity::User(name) => std::slice::from_ref(name),
+        }
+    }
+
+    // 日志和 CLIENT LIST 中显示的名字，多个名字用逗号分隔
+    pub(crate) fn label(&self) -> String {
+        match self {
+            Identity::Anonymous => ANONYMOUS.to_string(),
+            identity => identity.names().join(","),
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Anonymous => write!(f, "{}", ANONYMOUS),
+            Identity::Certificate(names) => write!(f, "certificate {:?}", names),
+            Identity::User(name) => write!(f, "user {}", name),
+        }
+    }
+}
+
+/// 命令要访问的资源
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource<'a> {
+    Table(&'a str),
+    Topic(&'a str),
+    // 整个服务器，比如调整限流
+    Server,
+}
+
+// 比较密码或 token，耗时和内容无关。先做哈希，长度也不会泄露
+fn secret_eq(expected: &str, actual: &str) -> bool {
+    let expected = digest::digest(&digest::SHA256, expected.as_bytes());
+    let actual = digest::digest(&digest::SHA256, actual.as_bytes());
+    constant_time::verify_slices_are_equal(expected.as_ref(), actual.as_ref()).is_ok()
+}
+
+impl fmt::Display for Resource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resource::Table(name) => write!(f, "table {}", name),
+            Resource::Topic(name) => write!(f, "topic {}", name),
+            Resource::Server => write!(f, "server"),
+        }
+    }
+}
+
+/// 用户和授权规则
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    users: Vec<UserConfig>,
+    rules: Vec<AclRule>,
+}
+
+impl From<AuthConfig> for Acl {
+    fn from(config: AuthConfig) -> Self {
+        Self {
+            users: config.users,
+            rules: config.rules,
+        }
+    }
+}
+
+impl Acl {
+    /// 检查 Auth 命令中的 token 或者用户名密码，成功时返回用户的身份
+    pub fn authenticate(&self, auth: &Auth) -> Result<Identity, KvError> {
+        let user = if !auth.token.is_empty() {
+            self.users.iter().find(|u| match &u.token {
+                Some(token) => secret_eq(token, &auth.token),
+                None => false,
+            })
+        } else {
+            self.users.iter().find(|u| match &u.password {
+                Some(password) => u.name == auth.username && secret_eq(password, &auth.password),
+                None => false,
+            })
+        };
+
+        match user {
+            Some(user) => Ok(Identity::User(user.name.clone())),
+            None => Err(KvError::Unauthenticated(
+                "Invalid username, password or token".into(),
+            )),
+        }
+    }
+
+    /// 检查身份是否有执行命令需要的所有权限
+    pub fn check(&self, identity: &Identity, cmd: &CommandRequest) -> Result<(), KvError> {
+        for (permission, resource) in required_permissions(cmd) {
+            if !self.is_allowed(identity, permission, resource) {
+                return Err(KvError::PermissionDenied(format!(
+                    "{} has no {:?} permission on {}",
+                    identity, permission, resource
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// 是否有规则授予身份在资源上的权限
+    pub fn is_allowed(
+        &self,
+        identity: &Identity,
+        permission: Permission,
+        resource: Resource,
+    ) -> bool {
+        self.rules.iter().any(|rule| {
+            rule_matches_identity(rule, identity)
+                && rule_matches_resource(rule, resource)
+                && rule
+                    .permissions
+                    .iter()
+                    .any(|p| *p == permission || *p == Permission::Admin)
+        })
+    }
+}
+
+fn rule_matches_identity(rule: &AclRule, identity: &Identity) -> bool {
+    match identity {
+        Identity::Anonymous => rule.identity == ANONYMOUS,
+        identity => rule.identity == "*" || identity.names().contains(&rule.identity),
+    }
+}
+
+fn rule_matches_resource(rule: &AclRule, resource: Resource) -> bool {
+    let (patterns, name) = match resource {
+        Resource::Table(name) => (&rule.tables, name),
+        Resource::Topic(name) => (&rule.topics, name),
+        // 只有能访问所有 table 的规则才能管理服务器
+        Resource::Server => return rule.tables.iter().any(|p| p == "*"),
+    };
+    patterns.iter().any(|p| glob_match(p, name))
+}
+
+/// 执行命令需要的权限，Batch 需要其中每条命令的权限
+pub fn required_permissions(cmd: &CommandRequest) -> Vec<(Permission, Resource<'_>)> {
+    let mut result = Vec::new();
+    collect_permissions(cmd, &mut result);
+    result
+}
+
+fn collect_permissions<'a>(cmd: &'a CommandRequest, result: &mut Vec<(Permission, Resource<'a>)>) {
+    let (permission, resource) = match &cmd.request_data {
+        Some(RequestData::Hget(p)) => (Permission::Read, Resource::Table(&p.table)),
+        Some(RequestData::Hgetall(p)) => (Permission::Read, Resource::Table(&p.table)),
+        Some(RequestData::Hmget(p)) => (Permission::Read, Resource::Table(&p.table)),
+        Some(RequestData::Hexist(p)) => (Permission::Read, Resource::Table(&p.table)),
+        Some(RequestData::Hmexist(p)) => (Permission::Read, Resource::Table(&p.table)),
+        Some(RequestData::Hscan(p)) => (Permission::Read, Resource::Table(&p.table)),
+        Some(RequestData::Hset(p)) => (Permission::Write, Resource::Table(&p.table)),
+        Some(RequestData::Hmset(p)) => (Permission::Write, Resource::Table(&p.table)),
+        Some(RequestData::Hdel(p)) => (Permission::Write, Resource::Table(&p.table)),
+        Some(RequestData::Hmdel(p)) => (Permission::Write, Resource::Table(&p.table)),
+        Some(RequestData::Publish(p)) => (Permission::Publish, Resource::Topic(&p.topic)),
+        Some(RequestData::Subscribe(p)) => (Permission::Subscribe, Resource::Topic(&p.topic)),
+        Some(RequestData::Unsubscribe(p)) => (Permission::Subscribe, Resource::Topic(&p.topic)),
+        Some(RequestData::SetRateLimit(_))
+        | Some(RequestData::SetQuota(_))
+        | Some(RequestData::Info(_))
+        | Some(RequestData::ClientList(_))
+        | Some(RequestData::ClientKill(_))
+        | Some(RequestData::ConfigGet(_))
+        | Some(RequestData::ConfigSet(_))
+        | Some(RequestData::FlushAll(_))
+        | Some(RequestData::SlowlogGet(_))
+        | Some(RequestData::Backup(_))
+        | Some(RequestData::Replicate(_)) => (Permission::Admin, Resource::Server),
+        Some(RequestData::Batch(p)) => {
+            for cmd in &p.requests {
+                collect_permissions(cmd, result);
+            }
+            return;
+        }
+        // Track 只会通知读过的 key，Auth 本身就是用来认证的，Ping 用来保活，都不需要权限。
+        // ListTables 只返回有 read 权限的 table
+        Some(RequestData::Track(_))
+        | Some(RequestData::Auth(_))
+        | Some(RequestData::Ping(_))
+        | Some(RequestData::ListTables(_))
+        | None => return,
+    };
+    result.push((permission, resource));
+}
+
+/// 简单的 glob 匹配，* 匹配任意个字符，? 匹配一个字符
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // 最近一个 * 的位置，以及它当时匹配到的 name 位置
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // 让上一个 * 多匹配一个字符再试
+                Some((sp, sn)) => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl() -> Acl {
+        let config: AuthConfig = toml::from_str(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "secret"
+
+            [[users]]
+            name = "bot"
+            token = "t0ken"
+
+            [[rules]]
+            identity = "alice"
+            tables = ["user_*"]
+            topics = ["news.*"]
+            permissions = ["read", "write", "publish"]
+
+            [[rules]]
+            identity = "*"
+            tables = ["public"]
+            permissions = ["read"]
+
+            [[rules]]
+            identity = "anonymous"
+            topics = ["lobby"]
+            permissions = ["subscribe"]
+
+            [[rules]]
+            identity = "awesome-device-id"
+            tables = ["*"]
+            topics = ["*"]
+            permissions = ["admin"]
+            "#,
+        )
+        .unwrap();
+        config.into()
+    }
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user_*", "user_1"));
+        assert!(glob_match("user_*", "user_"));
+        assert!(!glob_match("user_*", "users"));
+        assert!(glob_match("a?c*d", "abcxxd"));
+        assert!(glob_match("*.log.*", "app.log.1"));
+        assert!(!glob_match("a?c", "ac"));
+        assert!(!glob_match("abc", "abcd"));
+    }
+
+    #[test]
+    fn authenticate_should_work() {
+        let acl = acl();
+        let identity = acl.authenticate(&Auth {
+            username: "alice".into(),
+            password: "secret".into(),
+            ..Default::default()
+        });
+        assert_eq!(identity.unwrap(), Identity::User("alice".into()));
+
+        let identity = acl.authenticate(&Auth {
+            token: "t0ken".into(),
+            ..Default::default()
+        });
+        assert_eq!(identity.unwrap(), Identity::User("bot".into()));
+
+        let wrong = Auth {
+            username: "alice".into(),
+            password: "wrong".into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            acl.authenticate(&wrong),
+            Err(KvError::Unauthenticated(_))
+        ));
+        // 没有配置密码的用户不能用空密码认证
+        let empty = Auth {
+            username: "bot".into(),
+            ..Default::default()
+        };
+        assert!(acl.authenticate(&empty).is_err());
+    }
+
+    #[test]
+    fn check_should_follow_rules() {
+        let acl = acl();
+        let alice = Identity::User("alice".into());
+        let bot = Identity::User("bot".into());
+        let device = Identity::Certificate(vec!["awesome-device-id".into()]);
+        let anonymous = Identity::Anonymous;
+
+        let set = CommandRequest::new_hset("user_1", "k", "v".into());
+        assert!(acl.check(&alice, &set).is_ok());
+        assert!(acl.check(&bot, &set).is_err());
+        assert!(acl.check(&device, &set).is_ok());
+
+        let get = CommandRequest::new_hget("public", "k");
+        assert!(acl.check(&bot, &get).is_ok());
+        assert!(acl.check(&anonymous, &get).is_err());
+
+        let publish = CommandRequest::new_publish("news.tech", vec![]);
+        assert!(acl.check(&alice, &publish).is_ok());
+        assert!(acl
+            .check(&alice, &CommandRequest::new_subscribe("news.tech"))
+            .is_err());
+        assert!(acl
+            .check(&anonymous, &CommandRequest::new_subscribe("lobby"))
+            .is_ok());
+
+        // batch 里任何一条没有权限都会拒绝
+        let batch = CommandRequest::new_batch(vec![set, get.clone()], false);
+        assert!(acl.check(&alice, &batch).is_ok());
+        let batch =
+            CommandRequest::new_batch(vec![get, CommandRequest::new_hdel("other", "k")], false);
+        assert!(matches!(
+            acl.check(&alice, &batch),
+            Err(KvError::PermissionDenied(_))
+        ));
+    }
+}
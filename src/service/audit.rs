@@ -0,0 +1,141 @@
+//! 审计日志：成功的写命令按 key 追加一行 JSON，记录谁在什么时候改了哪个 key，以及改之前的值的哈希
+//!
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use ring::digest::{digest, SHA256};
+use serde::Serialize;
+use tracing::warn;
+use tracing_appender::non_blocking::{NonBlockingBuilder, WorkerGuard};
+
+use crate::{file_appender, LogConfig, Session, Value};
+
+/// 审计日志的写入端
+pub struct AuditLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+    // 文件由后台线程写入，drop 时把缓存的记录写完
+    _guard: Option<WorkerGuard>,
+}
+
+// {"timestamp_ms":1700000000000,"identity":"alice","peer":"127.0.0.1:5000","command":"hset",
+//  "table":"t1","key":"k1","old_value_sha256":"9f86d0..."}
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp_ms: u64,
+    identity: String,
+    peer: &'a str,
+    command: &'a str,
+    table: &'a str,
+    key: &'a str,
+    // key 之前不存在时为 null
+    old_value_sha256: Option<String>,
+}
+
+impl AuditLog {
+    /// 写入任意的 writer，比如测试中的内存 buffer
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            _guard: None,
+        }
+    }
+
+    /// 写入 config.path 下按 config.rotation 滚动的 audit.log
+    pub fn open(config: &LogConfig) -> Self {
+        // 默认的 non_blocking 在后台线程跟不上时会丢掉记录，审计日志宁可让写命令等一等
+        let (writer, guard) = NonBlockingBuilder::default()
+            .lossy(false)
+            .finish(file_appender(config, "audit.log"));
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            _guard: Some(guard),
+        }
+    }
+
+    /// 记录一个 key 的修改，old 是修改之前的值
+    pub fn record(
+        &self,
+        session: &Session,
+        command: &str,
+        table: &str,
+        key: &str,
+        old: Option<&Value>,
+    ) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let entry = AuditEntry {
+            timestamp_ms,
+            identity: session.identity().label(),
+            peer: session.peer(),
+            command,
+            table,
+            key,
+            old_value_sha256: old.map(hash_value),
+        };
+        // 整行一次写入，并发的记录不会交错
+        let mut line = match serde_json::to_vec(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode audit entry: {:?}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writer.write_all(&line) {
+            warn!("Failed to write audit log: {:?}", e);
+        }
+    }
+}
+
+// 值的 protobuf 编码的 SHA-256，和存储后端无关
+fn hash_value(value: &Value) -> String {
+    let hash = digest(&SHA256, &value.encode_to_vec());
+    hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn audit_log_should_write_json_lines() {
+        let buffer = Buffer::default();
+        let audit = AuditLog::new(buffer.clone());
+        let session = Session::new().with_peer("127.0.0.1:5000");
+        audit.record(&session, "hset", "t1", "k1", None);
+        audit.record(&session, "hdel", "t1", "k1", Some(&"v1".into()));
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["identity"], "anonymous");
+        assert_eq!(lines[0]["peer"], "127.0.0.1:5000");
+        assert_eq!(lines[0]["old_value_sha256"], serde_json::Value::Null);
+        assert_eq!(lines[1]["command"], "hdel");
+        assert_eq!(lines[1]["old_value_sha256"].as_str().unwrap().len(), 64);
+    }
+}
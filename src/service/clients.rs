@@ -0,0 +1,108 @@
+//! 当前的客户端连接，用于 CLIENT LIST 和 CLIENT KILL
+//!
+
+use std::sync::Arc;
+
+use dashmap::DashMap;
+
+use crate::Session;
+
+/// 登记的连接，clone 之后共享
+#[derive(Clone, Default)]
+pub struct Clients {
+    sessions: Arc<DashMap<u64, Arc<Session>>>,
+}
+
+impl Clients {
+    /// 登记一个连接，返回的 Registration 释放时注销
+    pub fn register(&self, session: Arc<Session>) -> Registration {
+        let id = session.id;
+        self.sessions.insert(id, session);
+        Registration {
+            sessions: self.sessions.clone(),
+            id,
+        }
+    }
+
+    /// 登记的连接个数
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    /// 每个连接一行，按 id 排序
+    pub fn list(&self) -> Vec<String> {
+        let mut sessions: Vec<_> = self.sessions.iter().map(|e| e.value().clone()).collect();
+        sessions.sort_by_key(|s| s.id);
+        sessions.iter().map(|s| describe(s)).collect()
+    }
+
+    /// 关闭 id 对应的连接，没有这个连接时返回 false
+    pub fn kill(&self, id: u64) -> bool {
+        match self.sessions.get(&id) {
+            Some(session) => {
+                session.kill();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// 连接的登记，drop 时注销
+pub struct Registration {
+    sessions: Arc<DashMap<u64, Arc<Session>>>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.sessions.remove(&self.id);
+    }
+}
+
+// id=1 peer=127.0.0.1:5000 identity=alice streams=1 age=10 idle=0 cmd=hget
+fn describe(session: &Session) -> String {
+    let cmd = match session.last_command() {
+        "" => "none",
+        cmd => cmd,
+    };
+    format!(
+        "id={} peer={} identity={} streams={} age={} idle={} cmd={}",
+        session.id,
+        session.peer(),
+        session.identity().label(),
+        session.streams(),
+        session.age().as_secs(),
+        session.idle_for().as_secs(),
+        cmd
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+
+    #[test]
+    fn clients_should_be_listed_and_killed() {
+        let clients = Clients::default();
+        let session = Arc::new(Session::new().with_peer("127.0.0.1:5000"));
+        let registration = clients.register(session.clone());
+        session.set_identity(Identity::User("alice".into()));
+        drop(session.begin_request("hget"));
+
+        let list = clients.list();
+        assert_eq!(list.len(), 1);
+        assert!(list[0].contains("peer=127.0.0.1:5000 identity=alice streams=0"));
+        assert!(list[0].ends_with("cmd=hget"));
+
+        assert!(clients.kill(session.id));
+        assert!(!clients.kill(u64::MAX));
+        drop(registration);
+        assert!(clients.is_empty());
+    }
+}
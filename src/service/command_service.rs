@@ -3,25 +3,17 @@
 //! 验证并迭代接口，完善产品需求
 //! 通过使用核心逻辑，思考外围逻辑并反推实现
 
+use super::{dispatch, glob_match};
+use crate::command_request::RequestData;
 use crate::error::*;
 use crate::pb::*;
 use crate::storage::*;
+use http::StatusCode;
 
 pub trait CommandService {
     fn execute(self, store: &impl Storage) -> CommandResponse;
 }
 
-use std::pin::Pin;
-use std::sync::Arc;
-
-use super::top::Topic;
-use futures::Stream;
-
-pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
-pub trait TopicService {
-    fn execute(self, chan: &impl Topic) -> StreamingResponse;
-}
-
 // 为指令实现trait中定义的方法
 
 // 根据数据表和key，返回数据
@@ -134,8 +126,162 @@ impl CommandService for Hmexist {
     }
 }
 
-#[cfg(test)]
+// HSCAN 没有指定 count 时每页检查的 key 个数，以及 count 的上限
+const DEFAULT_SCAN_COUNT: usize = 100;
+const MAX_SCAN_COUNT: usize = 10_000;
+
+impl CommandService for Hscan {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let count = match self.count as usize {
+            0 => DEFAULT_SCAN_COUNT,
+            n => n.min(MAX_SCAN_COUNT),
+        };
+        let pairs = match store.scan(&self.table, &self.cursor, count) {
+            Ok(pairs) => pairs,
+            Err(e) => return e.into(),
+        };
+        // 检查了 count 个 key 时后面可能还有，用最后一个 key 作为下一页的 cursor
+        let cursor = match pairs.last() {
+            Some(pair) if pairs.len() == count => pair.key.clone(),
+            _ => String::new(),
+        };
+        let pairs = pairs
+            .into_iter()
+            .filter(|pair| self.pattern.is_empty() || glob_match(&self.pattern, &pair.key))
+            .collect();
+        CommandResponse {
+            values: vec![cursor.into()],
+            pairs,
+            ..CommandResponse::ok()
+        }
+    }
+}
+
+// Batch 中的命令按顺序执行，每条写命令单独调用一次 apply_batch，响应和实际写入一致
+impl CommandService for Batch {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let mut responses = Vec::with_capacity(self.requests.len());
+
+        for cmd in self.requests {
+            let res = match cmd.request_data {
+                Some(RequestData::Hset(param)) => {
+                    let ops = param
+                        .pair
+                        .map(|pair| WriteOp::Set {
+                            table: param.table,
+                            key: pair.key,
+                            value: pair.value.unwrap_or_default(),
+                        })
+                        .into_iter()
+                        .collect();
+                    apply_writes(store, ops, false)
+                }
+                Some(RequestData::Hmset(param)) => {
+                    let table = param.table;
+                    let ops = param
+                        .pairs
+                        .into_iter()
+                        .map(|pair| WriteOp::Set {
+                            table: table.clone(),
+                            key: pair.key,
+                            value: pair.value.unwrap_or_default(),
+                        })
+                        .collect();
+                    apply_writes(store, ops, true)
+                }
+                Some(RequestData::Hdel(param)) => {
+                    let ops = vec![WriteOp::Del {
+                        table: param.table,
+                        key: param.key,
+                    }];
+                    apply_writes(store, ops, false)
+                }
+                Some(RequestData::Hmdel(param)) => {
+                    let table = param.table;
+                    let ops = param
+                        .keys
+                        .into_iter()
+                        .map(|key| WriteOp::Del {
+                            table: table.clone(),
+                            key,
+                        })
+                        .collect();
+                    apply_writes(store, ops, true)
+                }
+                Some(RequestData::Batch(_)) => {
+                    KvError::InvalidCommand("Nested batch is not supported".into()).into()
+                }
+                Some(RequestData::Subscribe(_))
+                | Some(RequestData::Unsubscribe(_))
+                | Some(RequestData::Publish(_)) => {
+                    KvError::InvalidCommand("Pub/sub is not supported in batch".into()).into()
+                }
+                Some(RequestData::Track(_)) => {
+                    KvError::InvalidCommand("Track is not supported in batch".into()).into()
+                }
+                data @ (Some(RequestData::Auth(_))
+                | Some(RequestData::SetRateLimit(_))
+                | Some(RequestData::SetQuota(_))
+                | Some(RequestData::Ping(_))
+                | Some(RequestData::Info(_))
+                | Some(RequestData::ClientList(_))
+                | Some(RequestData::ClientKill(_))
+                | Some(RequestData::ConfigGet(_))
+                | Some(RequestData::ConfigSet(_))
+                | Some(RequestData::FlushAll(_))
+                | Some(RequestData::SlowlogGet(_))
+                | Some(RequestData::Backup(_))
+                | Some(RequestData::Replicate(_))
+                | Some(RequestData::ListTables(_))) => {
+                    let cmd = CommandRequest {
+                        request_data: data,
+                        ..Default::default()
+                    };
+                    KvError::InvalidCommand(format!("{} is not supported in batch", cmd.name()))
+                        .into()
+                }
+                data => dispatch(
+                    CommandRequest {
+                        request_data: data,
+                        ..Default::default()
+                    },
+                    store,
+                ),
+            };
+            let failed = !is_success(&res);
+            responses.push(res);
+            if failed && self.stop_on_error {
+                break;
+            }
+        }
+
+        responses.into()
+    }
+}
+
+// 执行一条写命令的所有操作，multi 表示它是否返回多个值（Hmset/Hmdel）
+fn apply_writes(store: &impl Storage, ops: Vec<WriteOp>, multi: bool) -> CommandResponse {
+    match store.apply_batch(ops) {
+        Ok(values) => {
+            let mut values: Vec<Value> =
+                values.into_iter().map(|v| v.unwrap_or_default()).collect();
+            if multi {
+                values.into()
+            } else {
+                values.pop().unwrap_or_default().into()
+            }
+        }
+        Err(e) => e.into(),
+    }
+}
 
+pub(crate) fn is_success(res: &CommandResponse) -> bool {
+    StatusCode::from_u16(res.status as _)
+        .map(|s| s.is_success())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::command_request::RequestData;
@@ -192,12 +338,103 @@ mod tests {
         assert_res_ok(res, &[], pairs);
     }
 
+    #[test]
+    fn batch_should_work() {
+        let store = MemTable::new();
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hmset(
+                    "t2",
+                    vec![Kvpair::new("k1", 1.into()), Kvpair::new("k2", 2.into())],
+                ),
+                CommandRequest::new_hset("t1", "k1", "v2".into()),
+                CommandRequest::new_hget("t1", "k1"),
+                CommandRequest::new_hdel("t2", "k2"),
+                CommandRequest::new_hget("t2", "k2"),
+                CommandRequest::new_hget("t2", "k1"),
+            ],
+            false,
+        );
+        let res = dispatch(cmd, &store);
+        assert_eq!(res.status, 200);
+        assert_eq!(res.responses.len(), 7);
+
+        let mut responses = res.responses.into_iter();
+        assert_res_ok(responses.next().unwrap(), &[Value::default()], &[]);
+        assert_res_ok(
+            responses.next().unwrap(),
+            &[Value::default(), Value::default()],
+            &[],
+        );
+        assert_res_ok(responses.next().unwrap(), &["v1".into()], &[]);
+        assert_res_ok(responses.next().unwrap(), &["v2".into()], &[]);
+        assert_res_ok(responses.next().unwrap(), &[2.into()], &[]);
+        assert_res_error(responses.next().unwrap(), 404, "Not found");
+        assert_res_ok(responses.next().unwrap(), &[1.into()], &[]);
+    }
+
+    #[test]
+    fn batch_should_stop_on_error() {
+        let store = MemTable::new();
+        let cmds = vec![
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hget("t1", "k2"),
+            CommandRequest::new_hset("t1", "k3", "v3".into()),
+        ];
+
+        let res = dispatch(CommandRequest::new_batch(cmds.clone(), true), &store);
+        assert_eq!(res.responses.len(), 2);
+        assert_res_error(res.responses[1].clone(), 404, "Not found");
+        assert!(!store.contains("t1", "k3").unwrap());
+
+        let res = dispatch(CommandRequest::new_batch(cmds, false), &store);
+        assert_eq!(res.responses.len(), 3);
+        assert!(store.contains("t1", "k3").unwrap());
+    }
+
+    #[test]
+    fn hscan_should_page_through_table() {
+        let store = MemTable::new();
+        for i in 0..5 {
+            store
+                .set("t1", format!("k{}", i), Value::from(i as i64))
+                .unwrap();
+        }
+        store.set("t1", "other".into(), "v".into()).unwrap();
+
+        let res = dispatch(CommandRequest::new_hscan("t1", "", 3, "k*"), &store);
+        let pairs: Vec<Kvpair> = (0..3)
+            .map(|i| Kvpair::new(format!("k{}", i), Value::from(i as i64)))
+            .collect();
+        assert_res_ok(res, &["k2".into()], &pairs);
+
+        // 不匹配 pattern 的 key 也算在 count 里
+        let res = dispatch(CommandRequest::new_hscan("t1", "k2", 3, "k*"), &store);
+        let pairs = vec![Kvpair::new("k3", 3.into()), Kvpair::new("k4", 4.into())];
+        assert_res_ok(res, &["other".into()], &pairs);
+
+        // 检查的 key 不够 count 个时已经读完，cursor 为空
+        let res = dispatch(CommandRequest::new_hscan("t1", "other", 3, ""), &store);
+        assert_res_ok(res, &["".into()], &[]);
+    }
+
+    #[test]
+    fn nested_batch_should_be_rejected() {
+        let store = MemTable::new();
+        let inner = CommandRequest::new_batch(vec![], false);
+        let res = dispatch(CommandRequest::new_batch(vec![inner], false), &store);
+        assert_res_error(res.responses[0].clone(), 400, "Nested batch");
+    }
+
     // 从 Request 中得到 Response，目前处理 HGET/HGETALL/HSET
     fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         match cmd.request_data.unwrap() {
             RequestData::Hget(v) => v.execute(store),
             RequestData::Hgetall(v) => v.execute(store),
             RequestData::Hset(v) => v.execute(store),
+            RequestData::Hscan(v) => v.execute(store),
+            RequestData::Batch(v) => v.execute(store),
             _ => todo!(),
         }
     }
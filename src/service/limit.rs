@@ -0,0 +1,723 @@
+//! 限流和存储配额：令牌桶按连接、身份或 table 限制请求速率，配额限制 table 的 key 个数和大小
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use prost::Message;
+use tracing::info;
+
+use super::acl::{glob_match, required_permissions, Identity, Resource};
+use super::Session;
+use crate::pb::command_request::RequestData;
+use crate::{
+    CommandRequest, CommandResponse, KvError, LimitScope, QuotaConfig, RateLimitConfig, SetQuota,
+    SetRateLimit, Storage, Value,
+};
+
+// 每检查这么多次清理一次已经装满的桶，装满的桶和新建的桶没有区别
+const CLEANUP_INTERVAL: u64 = 1024;
+// 告诉客户端的等待时间的上限，rate 很小时算出来的时间可能大到没有意义
+const MAX_WAIT: Duration = Duration::from_secs(3600);
+// 每个客户端地址允许连续认证失败的次数，之后每 5 秒恢复一次机会
+const AUTH_FAILURE_BURST: u32 = 5;
+const AUTH_FAILURE_RATE: f64 = 0.2;
+
+impl FromStr for LimitScope {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "connection" => Ok(Self::Connection),
+            "identity" => Ok(Self::Identity),
+            "table" => Ok(Self::Table),
+            _ => Err(KvError::InvalidCommand(format!(
+                "Unknown rate limit scope: {} (expected connection, identity or table)",
+                s
+            ))),
+        }
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimitConfig {
+    /// rate 必须是正数，burst 至少为 1
+    pub fn validate(&self) -> Result<(), KvError> {
+        if !self.rate.is_finite() || self.rate <= 0.0 {
+            return Err(KvError::InvalidCommand(format!(
+                "Rate must be a positive number, got {}",
+                self.rate
+            )));
+        }
+        if self.burst == 0 {
+            return Err(KvError::InvalidCommand("Burst must be at least 1".into()));
+        }
+        Ok(())
+    }
+}
+
+impl TokenBucket {
+    fn new(rule: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            tokens: rule.burst as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, rule: &RateLimitConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rule.rate).min(rule.burst as f64);
+        self.last = now;
+    }
+
+    // 令牌不够时返回还要等多久
+    fn take(&mut self, n: f64, rule: &RateLimitConfig, now: Instant) -> Result<(), Duration> {
+        self.refill(rule, now);
+        if self.tokens >= n {
+            self.tokens -= n;
+            Ok(())
+        } else {
+            let wait = Duration::try_from_secs_f64((n - self.tokens) / rule.rate);
+            Err(wait.map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT)))
+        }
+    }
+
+    fn is_full(&self, rule: &RateLimitConfig, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens + elapsed * rule.rate >= rule.burst as f64
+    }
+}
+
+/// 令牌桶限流
+#[derive(Default)]
+pub struct RateLimiter {
+    rules: RwLock<Vec<RateLimitConfig>>,
+    // (规则序号, 连接 id、身份或 table) -> 令牌桶
+    buckets: DashMap<(usize, String), TokenBucket>,
+    checks: AtomicU64,
+}
+
+impl RateLimiter {
+    pub fn new(rules: Vec<RateLimitConfig>) -> Result<Self, KvError> {
+        for rule in &rules {
+            rule.validate()?;
+        }
+        Ok(Self {
+            rules: RwLock::new(rules),
+            ..Default::default()
+        })
+    }
+
+    /// 当前的规则
+    pub fn rules(&self) -> Vec<RateLimitConfig> {
+        self.rules.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// 从所有匹配的桶里取令牌，任何一个桶不够时都不取，返回需要等待的时间
+    pub fn check(&self, session: &Session, cmd: &CommandRequest) -> Result<(), KvError> {
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        if rules.is_empty() {
+            return Ok(());
+        }
+
+        let identity = session.identity();
+        let (cost, tables) = command_cost(cmd);
+        let now = Instant::now();
+        let mut taken: Vec<((usize, String), f64)> = Vec::new();
+
+        for (i, rule) in rules.iter().enumerate() {
+            let subjects = match rule.scope {
+                LimitScope::Connection => vec![(format!("connection {}", session.id), cost)],
+                LimitScope::Identity if identity_matches(&rule.target, &identity) => {
+                    vec![(identity.to_string(), cost)]
+                }
+                LimitScope::Identity => vec![],
+                LimitScope::Table => tables
+                    .iter()
+                    .filter(|(table, _)| glob_match(&rule.target, table))
+                    .map(|(table, n)| (format!("table {}", table), *n))
+                    .collect(),
+            };
+
+            for (subject, n) in subjects {
+                // 比桶还大的 batch 只要求桶是满的
+                let n = (n as f64).min(rule.burst as f64);
+                let key = (i, subject);
+                let result = self
+                    .buckets
+                    .entry(key.clone())
+                    .or_insert_with(|| TokenBucket::new(rule, now))
+                    .take(n, rule, now);
+
+                if let Err(wait) = result {
+                    for (key, n) in taken {
+                        if let Some(mut bucket) = self.buckets.get_mut(&key) {
+                            bucket.tokens += n;
+                        }
+                    }
+                    return Err(KvError::RateLimited(
+                        format!("{} exceeded {} requests/s", key.1, rule.rate),
+                        wait,
+                    ));
+                }
+                taken.push((key, n));
+            }
+        }
+
+        if self
+            .checks
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(CLEANUP_INTERVAL)
+        {
+            self.buckets.retain(|(i, _), bucket| match rules.get(*i) {
+                Some(rule) => !bucket.is_full(rule, now),
+                None => false,
+            });
+        }
+        Ok(())
+    }
+
+    /// 增加、替换或删除（rate 为 0）一条规则，所有的桶都会重置
+    pub fn set(&self, param: &SetRateLimit) -> Result<(), KvError> {
+        let scope: LimitScope = param.scope.parse()?;
+        let target = match (scope, param.target.is_empty()) {
+            (LimitScope::Connection, _) | (_, true) => "*".to_string(),
+            _ => param.target.clone(),
+        };
+        let rule = RateLimitConfig {
+            scope,
+            target,
+            rate: param.rate,
+            burst: param.burst,
+        };
+        let remove = rule.rate == 0.0;
+        if !remove {
+            rule.validate()?;
+        }
+
+        let mut rules = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        rules.retain(|r| !(r.scope == rule.scope && r.target == rule.target));
+        if !remove {
+            rules.push(rule);
+        }
+        // 规则的序号变了，旧的桶不能再用
+        self.buckets.clear();
+        info!("Rate limits changed: {:?}", *rules);
+        Ok(())
+    }
+}
+
+/// 认证失败的限流，每个客户端地址一个令牌桶，只有失败的认证消耗令牌
+pub struct AuthLimiter {
+    rule: RateLimitConfig,
+    buckets: DashMap<String, TokenBucket>,
+    checks: AtomicU64,
+}
+
+impl Default for AuthLimiter {
+    fn default() -> Self {
+        Self {
+            rule: RateLimitConfig {
+                scope: LimitScope::Connection,
+                target: "*".into(),
+                rate: AUTH_FAILURE_RATE,
+                burst: AUTH_FAILURE_BURST,
+            },
+            buckets: DashMap::new(),
+            checks: AtomicU64::new(0),
+        }
+    }
+}
+
+impl AuthLimiter {
+    /// 地址的失败次数用完时返回需要等待的时间，这时不应该再检查密码
+    pub fn check(&self, peer: &str) -> Result<(), KvError> {
+        let now = Instant::now();
+        if self
+            .checks
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(CLEANUP_INTERVAL)
+        {
+            self.buckets
+                .retain(|_, bucket| !bucket.is_full(&self.rule, now));
+        }
+
+        let host = peer_host(peer);
+        let mut bucket = match self.buckets.get_mut(&host) {
+            Some(bucket) => bucket,
+            None => return Ok(()),
+        };
+        bucket.refill(&self.rule, now);
+        if bucket.tokens >= 1.0 {
+            return Ok(());
+        }
+        let wait = Duration::try_from_secs_f64((1.0 - bucket.tokens) / self.rule.rate);
+        Err(KvError::RateLimited(
+            format!("Too many failed authentications from {}", host),
+            wait.map_or(MAX_WAIT, |wait| wait.min(MAX_WAIT)),
+        ))
+    }
+
+    /// 记录一次失败的认证
+    pub fn failed(&self, peer: &str) {
+        let now = Instant::now();
+        let mut bucket = self
+            .buckets
+            .entry(peer_host(peer))
+            .or_insert_with(|| TokenBucket::new(&self.rule, now));
+        // 令牌不够时 check 已经拒绝了，这里不用处理
+        let _ = bucket.take(1.0, &self.rule, now);
+    }
+}
+
+// 同一个主机的不同端口共享失败次数
+fn peer_host(peer: &str) -> String {
+    match peer.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => peer.to_string(),
+    }
+}
+
+// 命令消耗的令牌数，以及每个 table 上消耗的令牌数
+fn command_cost(cmd: &CommandRequest) -> (u32, BTreeMap<&str, u32>) {
+    let cost = match &cmd.request_data {
+        Some(RequestData::Batch(p)) => (p.requests.len() as u32).max(1),
+        _ => 1,
+    };
+    let mut tables = BTreeMap::new();
+    for (_, resource) in required_permissions(cmd) {
+        if let Resource::Table(table) = resource {
+            *tables.entry(table).or_default() += 1;
+        }
+    }
+    (cost, tables)
+}
+
+fn identity_matches(target: &str, identity: &Identity) -> bool {
+    match identity {
+        Identity::Anonymous => glob_match(target, "anonymous"),
+        identity => identity.names().iter().any(|name| glob_match(target, name)),
+    }
+}
+
+/// table 当前的 key 个数和大小
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TableUsage {
+    pub keys: u64,
+    pub bytes: u64,
+}
+
+#[derive(Default)]
+struct UsageState {
+    usage: TableUsage,
+    // 没有加载或者可能不准确时，下次使用前重新扫描 table
+    loaded: bool,
+}
+
+/// table 的存储配额
+#[derive(Default)]
+pub struct Quotas {
+    rules: RwLock<Vec<QuotaConfig>>,
+    // 有配额的 table 的使用量，修改同一个 table 的写命令会持有锁，保证统计准确
+    usage: DashMap<String, Arc<Mutex<UsageState>>>,
+}
+
+impl Quotas {
+    pub fn new(rules: Vec<QuotaConfig>) -> Self {
+        Self {
+            rules: RwLock::new(rules),
+            ..Default::default()
+        }
+    }
+
+    /// 当前的配额
+    pub fn rules(&self) -> Vec<QuotaConfig> {
+        self.rules.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// 替换或删除（两个限制都为 0）一个 table 的配额
+    pub fn set(&self, param: &SetQuota) -> Result<(), KvError> {
+        if param.table.is_empty() {
+            return Err(KvError::InvalidCommand("Quota table is empty".into()));
+        }
+        let limit = |v: u64| if v == 0 { None } else { Some(v) };
+
+        let mut rules = self.rules.write().unwrap_or_else(|e| e.into_inner());
+        rules.retain(|r| r.table != param.table);
+        if param.max_keys > 0 || param.max_bytes > 0 {
+            rules.push(QuotaConfig {
+                table: param.table.clone(),
+                max_keys: limit(param.max_keys),
+                max_bytes: limit(param.max_bytes),
+            });
+        }
+        // 之前没有配额的 table 没有统计，全部重新加载
+        self.usage.clear();
+        info!("Quotas changed: {:?}", *rules);
+        Ok(())
+    }
+
+    /// 检查写命令是否会超出配额，没有超出时用 f 执行命令并更新使用量
+    pub fn execute(
+        &self,
+        cmd: &CommandRequest,
+        store: &impl Storage,
+        f: impl FnOnce() -> CommandResponse,
+    ) -> CommandResponse {
+        let rules = self.rules.read().unwrap_or_else(|e| e.into_inner());
+        let writes: Vec<_> = write_ops(cmd)
+            .into_iter()
+            .filter(|(table, _, _)| rules.iter().any(|r| glob_match(&r.table, table)))
+            .collect();
+        if writes.is_empty() {
+            return f();
+        }
+
+        // 按 table 名字的顺序加锁，避免死锁
+        let states: BTreeMap<&str, Arc<Mutex<UsageState>>> = writes
+            .iter()
+            .map(|(table, _, _)| {
+                let state = self.usage.entry(table.to_string()).or_default().clone();
+                (*table, state)
+            })
+            .collect();
+        let mut guards: BTreeMap<&str, _> = states
+            .iter()
+            .map(|(table, state)| (*table, state.lock().unwrap_or_else(|e| e.into_inner())))
+            .collect();
+
+        let result: Result<_, KvError> = (|| {
+            for (table, state) in guards.iter_mut() {
+                if !state.loaded {
+                    state.usage = scan_table(store, table)?;
+                    state.loaded = true;
+                }
+            }
+            let deltas = usage_deltas(store, &writes)?;
+            for (table, (keys, bytes)) in &deltas {
+                let usage = guards[table].usage;
+                for rule in rules.iter().filter(|r| glob_match(&r.table, table)) {
+                    check_limit(table, "keys", usage.keys, *keys, rule.max_keys)?;
+                    check_limit(table, "bytes", usage.bytes, *bytes, rule.max_bytes)?;
+                }
+            }
+            Ok(deltas)
+        })();
+
+        let deltas = match result {
+            Ok(deltas) => deltas,
+            Err(e) => return e.into(),
+        };
+        let res = f();
+        for (table, state) in guards.iter_mut() {
+            match deltas.get(table) {
+                // batch 中有失败的命令时不知道哪些写入了，下次重新扫描
+                _ if !fully_succeeded(&res) => state.loaded = false,
+                Some((keys, bytes)) => {
+                    state.usage.keys = state.usage.keys.saturating_add_signed(*keys);
+                    state.usage.bytes = state.usage.bytes.saturating_add_signed(*bytes);
+                }
+                None => {}
+            }
+        }
+        res
+    }
+
+    /// 丢弃所有统计，下次写入时重新扫描，比如 table 被清空之后
+    pub fn reset(&self) {
+        self.usage.clear();
+    }
+
+    /// table 的使用量，没有配额或者还没有统计过时返回 None
+    pub fn usage(&self, table: &str) -> Option<TableUsage> {
+        let state = self.usage.get(table)?.clone();
+        let state = state.lock().unwrap_or_else(|e| e.into_inner());
+        state.loaded.then_some(state.usage)
+    }
+}
+
+fn check_limit(
+    table: &str,
+    what: &str,
+    current: u64,
+    delta: i64,
+    limit: Option<u64>,
+) -> Result<(), KvError> {
+    match limit {
+        // 只拒绝让使用量增加的写入，超出配额时仍然可以删除
+        Some(limit) if delta > 0 && current.saturating_add_signed(delta) > limit => {
+            Err(KvError::QuotaExceeded(format!(
+                "table {} would have {} {}, limit is {}",
+                table,
+                current.saturating_add_signed(delta),
+                what,
+                limit
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+// 写命令按顺序执行后每个 table 的 key 个数和大小的变化
+fn usage_deltas<'a>(
+    store: &impl Storage,
+    writes: &[(&'a str, &'a str, Option<&Value>)],
+) -> Result<HashMap<&'a str, (i64, i64)>, KvError> {
+    // 同一条命令里写过的 key 的最新大小
+    let mut sizes: HashMap<(&str, &str), Option<u64>> = HashMap::new();
+    let mut deltas: HashMap<&str, (i64, i64)> = HashMap::new();
+
+    for (table, key, value) in writes {
+        let old = match sizes.get(&(*table, *key)) {
+            Some(size) => *size,
+            None => store.get(table, key)?.map(|v| entry_size(key, &v)),
+        };
+        let new = value.map(|v| entry_size(key, v));
+        sizes.insert((*table, *key), new);
+
+        let delta = deltas.entry(*table).or_default();
+        delta.0 += new.is_some() as i64 - old.is_some() as i64;
+        delta.1 += new.unwrap_or(0) as i64 - old.unwrap_or(0) as i64;
+    }
+    Ok(deltas)
+}
+
+fn scan_table(store: &impl Storage, table: &str) -> Result<TableUsage, KvError> {
+    let mut usage = TableUsage::default();
+    for pair in store.get_iter(table)? {
+        usage.keys += 1;
+        usage.bytes += entry_size(&pair.key, &pair.value.unwrap_or_default());
+    }
+    Ok(usage)
+}
+
+// key 加上 value 编码后的大小
+fn entry_size(key: &str, value: &Value) -> u64 {
+    (key.len() + value.encoded_len()) as u64
+}
+
+// 命令中的写操作：(table, key, 新的值)，删除时没有值
+fn write_ops(cmd: &CommandRequest) -> Vec<(&str, &str, Option<&Value>)> {
+    let mut ops = Vec::new();
+    collect_write_ops(cmd, &mut ops);
+    ops
+}
+
+fn collect_write_ops<'a>(
+    cmd: &'a CommandRequest,
+    ops: &mut Vec<(&'a str, &'a str, Option<&'a Value>)>,
+) {
+    // Hset 没有 value 时按空值计算
+    static EMPTY: Value = Value { value: None };
+    match &cmd.request_data {
+        Some(RequestData::Hset(p)) => {
+            if let Some(pair) = &p.pair {
+                ops.push((
+                    &p.table,
+                    &pair.key,
+                    Some(pair.value.as_ref().unwrap_or(&EMPTY)),
+                ));
+            }
+        }
+        Some(RequestData::Hmset(p)) => {
+            for pair in &p.pairs {
+                ops.push((
+                    &p.table,
+                    &pair.key,
+                    Some(pair.value.as_ref().unwrap_or(&EMPTY)),
+                ));
+            }
+        }
+        Some(RequestData::Hdel(p)) => ops.push((&p.table, &p.key, None)),
+        Some(RequestData::Hmdel(p)) => {
+            for key in &p.keys {
+                ops.push((&p.table, key, None));
+            }
+        }
+        Some(RequestData::Batch(p)) => {
+            for cmd in &p.requests {
+                collect_write_ops(cmd, ops);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn fully_succeeded(res: &CommandResponse) -> bool {
+    (200..300).contains(&res.status) && res.responses.iter().all(fully_succeeded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MemTable;
+
+    fn rule(scope: LimitScope, target: &str, rate: f64, burst: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            scope,
+            target: target.into(),
+            rate,
+            burst,
+        }
+    }
+
+    #[test]
+    fn token_bucket_should_refill_over_time() {
+        let rule = rule(LimitScope::Connection, "*", 10.0, 2);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&rule, now);
+        assert!(bucket.take(1.0, &rule, now).is_ok());
+        assert!(bucket.take(1.0, &rule, now).is_ok());
+        let wait = bucket.take(1.0, &rule, now).unwrap_err();
+        assert_eq!(wait, Duration::from_millis(100));
+        assert!(bucket
+            .take(1.0, &rule, now + Duration::from_millis(100))
+            .is_ok());
+        assert!(bucket.is_full(&rule, now + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn invalid_rates_should_be_rejected() {
+        for rate in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            assert!(RateLimiter::new(vec![rule(LimitScope::Connection, "*", rate, 1)]).is_err());
+        }
+        let limiter = RateLimiter::default();
+        for rate in [-1.0, f64::NAN, f64::INFINITY] {
+            let param = SetRateLimit {
+                scope: "connection".into(),
+                target: "".into(),
+                rate,
+                burst: 1,
+            };
+            assert!(limiter.set(&param).is_err());
+        }
+        assert!(limiter.rules().is_empty());
+    }
+
+    #[test]
+    fn wait_should_be_clamped_for_tiny_rates() {
+        let rule = rule(LimitScope::Connection, "*", 1e-300, 1);
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(&rule, now);
+        assert!(bucket.take(1.0, &rule, now).is_ok());
+        assert_eq!(bucket.take(1.0, &rule, now).unwrap_err(), MAX_WAIT);
+    }
+
+    #[test]
+    fn rate_limiter_should_limit_by_scope() {
+        let limiter = RateLimiter::new(vec![
+            rule(LimitScope::Table, "hot*", 1.0, 2),
+            rule(LimitScope::Identity, "bot", 1.0, 1),
+        ])
+        .unwrap();
+        let session = Session::new();
+        let hot = CommandRequest::new_hget("hot1", "k");
+        assert!(limiter.check(&session, &hot).is_ok());
+        assert!(limiter.check(&session, &hot).is_ok());
+        let err = limiter.check(&session, &hot).unwrap_err();
+        assert!(matches!(err, KvError::RateLimited(_, wait) if wait > Duration::ZERO));
+        // 其它 table 不受影响
+        let cold = CommandRequest::new_hget("cold", "k");
+        assert!(limiter.check(&session, &cold).is_ok());
+
+        let bot = Session::with_identity(Identity::User("bot".into()));
+        assert!(limiter.check(&bot, &cold).is_ok());
+        assert!(limiter.check(&bot, &cold).is_err());
+
+        // 运行时修改规则后桶会重置
+        limiter
+            .set(&SetRateLimit {
+                scope: "identity".into(),
+                target: "bot".into(),
+                rate: 0.0,
+                burst: 0,
+            })
+            .unwrap();
+        assert!(limiter.check(&bot, &cold).is_ok());
+        assert!(limiter.check(&bot, &cold).is_ok());
+        assert_eq!(limiter.rules().len(), 1);
+    }
+
+    #[test]
+    fn rejected_request_should_not_consume_tokens() {
+        let limiter = RateLimiter::new(vec![
+            rule(LimitScope::Connection, "*", 1.0, 2),
+            rule(LimitScope::Table, "t1", 1.0, 1),
+        ])
+        .unwrap();
+        let session = Session::new();
+        let t1 = CommandRequest::new_hget("t1", "k");
+        assert!(limiter.check(&session, &t1).is_ok());
+        // t1 的桶空了，连接的令牌要还回去
+        assert!(limiter.check(&session, &t1).is_err());
+        assert!(limiter
+            .check(&session, &CommandRequest::new_hget("t2", "k"))
+            .is_ok());
+    }
+
+    #[test]
+    fn failed_authentications_should_be_limited_by_host() {
+        let limiter = AuthLimiter::default();
+        for _ in 0..AUTH_FAILURE_BURST {
+            assert!(limiter.check("10.0.0.1:1000").is_ok());
+            limiter.failed("10.0.0.1:1000");
+        }
+        // 换一个端口重连也不行
+        let err = limiter.check("10.0.0.1:2000").unwrap_err();
+        assert!(matches!(err, KvError::RateLimited(_, wait) if wait > Duration::ZERO));
+        assert!(limiter.check("10.0.0.2:1000").is_ok());
+        assert!(limiter.check("local").is_ok());
+    }
+
+    #[test]
+    fn quotas_should_limit_keys_and_bytes() {
+        let store = MemTable::new();
+        store.set("q1", "old".into(), "v".into()).unwrap();
+        let quotas = Quotas::new(vec![QuotaConfig {
+            table: "q*".into(),
+            max_keys: Some(2),
+            max_bytes: Some(100),
+        }]);
+        let run = |cmd: CommandRequest| {
+            quotas.execute(&cmd, &store, || crate::dispatch(cmd.clone(), &store))
+        };
+
+        assert_eq!(
+            run(CommandRequest::new_hset("q1", "k1", "v1".into())).status,
+            200
+        );
+        // 已经有 2 个 key 了，新的 key 会超出配额
+        let res = run(CommandRequest::new_hset("q1", "k2", "v2".into()));
+        assert_eq!(res.status, 429);
+        assert!(res.message.contains("keys"));
+        // 覆盖已有的 key 不增加 key 的个数
+        assert_eq!(
+            run(CommandRequest::new_hset("q1", "k1", "v3".into())).status,
+            200
+        );
+        let big = "x".repeat(200);
+        let res = run(CommandRequest::new_hset("q1", "k1", big.into()));
+        assert!(res.message.contains("bytes"));
+        // 删除后又可以写入
+        run(CommandRequest::new_hdel("q1", "old"));
+        assert_eq!(
+            run(CommandRequest::new_hset("q1", "k2", "v2".into())).status,
+            200
+        );
+        assert_eq!(quotas.usage("q1").map(|u| u.keys), Some(2));
+        // 没有配额的 table 不受限制
+        assert_eq!(
+            run(CommandRequest::new_hset("t1", "k3", "v".into())).status,
+            200
+        );
+    }
+}
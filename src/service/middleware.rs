@@ -0,0 +1,475 @@
+//! 中间件：在命令执行前后检查、修改、拦截请求和响应，以及计时
+//!
+//! 中间件按注册的顺序嵌套，先注册的在外层。每个中间件拿到请求和 Next，
+//! 调用 next.run 把请求交给后面的中间件，最后由 Service 执行；也可以不调用直接返回响应。
+//! 返回的是响应流，流式命令（比如 subscribe）的每条响应都会经过中间件。
+//!
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use futures::future::BoxFuture;
+use futures::{stream, Future, Stream, StreamExt};
+use tokio::time;
+use tracing::info;
+
+use super::{Notify, NotifyMut, Session, StreamingResponse};
+use crate::{CommandRequest, CommandResponse, KvError};
+
+/// 在中间件之间传递的请求
+pub struct Request {
+    pub cmd: CommandRequest,
+    // 请求所在的连接
+    pub session: Arc<Session>,
+    // 过了这个时间还没开始执行的命令不再执行，中间件可以把它提前
+    pub deadline: Option<time::Instant>,
+}
+
+/// 中间件
+///
+/// 闭包 `|req: Request, next: Next| async move { next.run(req).await }` 也是中间件，可以捕获状态
+pub trait Middleware: Send + Sync + 'static {
+    fn call<'a>(&'a self, req: Request, next: Next) -> BoxFuture<'a, StreamingResponse>;
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = StreamingResponse> + Send + 'static,
+{
+    fn call<'a>(&'a self, req: Request, next: Next) -> BoxFuture<'a, StreamingResponse> {
+        Box::pin(self(req, next))
+    }
+}
+
+// 中间件链的最后，真正执行命令
+pub(crate) type Endpoint = Box<dyn FnOnce(Request) -> StreamingResponse + Send>;
+
+/// 中间件链中剩下的部分
+pub struct Next {
+    chain: Arc<[Arc<dyn Middleware>]>,
+    index: usize,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    pub(crate) fn new(chain: Arc<[Arc<dyn Middleware>]>, endpoint: Endpoint) -> Self {
+        Self {
+            chain,
+            index: 0,
+            endpoint,
+        }
+    }
+
+    /// 把请求交给下一个中间件，没有中间件了就执行命令
+    pub fn run(mut self, req: Request) -> BoxFuture<'static, StreamingResponse> {
+        match self.chain.get(self.index).cloned() {
+            Some(middleware) => {
+                self.index += 1;
+                Box::pin(async move { middleware.call(req, self).await })
+            }
+            None => {
+                let res = (self.endpoint)(req);
+                Box::pin(async move { res })
+            }
+        }
+    }
+}
+
+/// 等到第一条响应，返回它以及包含它在内的完整响应流
+pub async fn peek_response(
+    mut res: StreamingResponse,
+) -> (Option<Arc<CommandResponse>>, StreamingResponse) {
+    match res.next().await {
+        Some(first) => {
+            let rest = stream::once(futures::future::ready(first.clone())).chain(res);
+            (Some(first), Box::pin(rest))
+        }
+        None => (None, res),
+    }
+}
+
+/// 响应流被释放（正常结束或者连接断开）时调用 f
+pub fn on_stream_end(
+    res: StreamingResponse,
+    f: impl FnOnce() + Send + 'static,
+) -> StreamingResponse {
+    let guard = Finally(Some(f));
+    Box::pin(res.map(move |data| {
+        let _ = &guard;
+        data
+    }))
+}
+
+struct Finally<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> Drop for Finally<F> {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            f()
+        }
+    }
+}
+
+/// 记录每个命令的结果和耗时，流式命令结束时再记录一次
+#[derive(Debug, Default)]
+pub struct LoggingMiddleware;
+
+impl Middleware for LoggingMiddleware {
+    fn call<'a>(&'a self, req: Request, next: Next) -> BoxFuture<'a, StreamingResponse> {
+        Box::pin(async move {
+            let (name, session) = (req.cmd.name(), req.session.id);
+            let start = Instant::now();
+            let (first, res) = peek_response(next.run(req).await).await;
+            let status = first.map(|r| r.status).unwrap_or_default();
+            info!(
+                "Session {} {} -> {} in {:?}",
+                session,
+                name,
+                status,
+                start.elapsed()
+            );
+
+            if !matches!(name, "subscribe" | "track") {
+                return res;
+            }
+            on_stream_end(res, move || {
+                info!(
+                    "Session {} {} stream closed after {:?}",
+                    session,
+                    name,
+                    start.elapsed()
+                )
+            })
+        })
+    }
+}
+
+/// 一个命令在某个状态码下的统计
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandMetrics {
+    pub count: u64,
+    // 到第一条响应的耗时，单位微秒
+    pub total_us: u64,
+    pub max_us: u64,
+}
+
+/// 按命令和状态码统计请求个数和耗时
+#[derive(Debug, Default)]
+pub struct RequestMetrics {
+    stats: DashMap<(&'static str, u32), CommandMetrics>,
+}
+
+impl RequestMetrics {
+    pub fn record(&self, command: &'static str, status: u32, elapsed: Duration) {
+        let us = elapsed.as_micros() as u64;
+        let mut entry = self.stats.entry((command, status)).or_default();
+        entry.count += 1;
+        entry.total_us += us;
+        entry.max_us = entry.max_us.max(us);
+    }
+
+    /// 当前的统计，按命令和状态码排序
+    pub fn snapshot(&self) -> Vec<(&'static str, u32, CommandMetrics)> {
+        let mut result: Vec<_> = self
+            .stats
+            .iter()
+            .map(|entry| (entry.key().0, entry.key().1, *entry.value()))
+            .collect();
+        result.sort_by_key(|(command, status, _)| (*command, *status));
+        result
+    }
+}
+
+/// 把请求统计到 RequestMetrics 里，流式命令只统计第一条响应
+#[derive(Debug, Default, Clone)]
+pub struct MetricsMiddleware {
+    metrics: Arc<RequestMetrics>,
+}
+
+impl MetricsMiddleware {
+    pub fn new(metrics: Arc<RequestMetrics>) -> Self {
+        Self { metrics }
+    }
+
+    pub fn metrics(&self) -> Arc<RequestMetrics> {
+        self.metrics.clone()
+    }
+}
+
+impl Middleware for MetricsMiddleware {
+    fn call<'a>(&'a self, req: Request, next: Next) -> BoxFuture<'a, StreamingResponse> {
+        Box::pin(async move {
+            let name = req.cmd.name();
+            let start = Instant::now();
+            let (first, res) = peek_response(next.run(req).await).await;
+            let status = first.map(|r| r.status).unwrap_or_default();
+            self.metrics.record(name, status, start.elapsed());
+            res
+        })
+    }
+}
+
+/// 第一条响应超时后返回 504，流式命令后续的响应不受限制
+#[derive(Debug, Clone)]
+pub struct TimeoutMiddleware {
+    timeout: Duration,
+}
+
+impl TimeoutMiddleware {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl Middleware for TimeoutMiddleware {
+    fn call<'a>(&'a self, mut req: Request, next: Next) -> BoxFuture<'a, StreamingResponse> {
+        Box::pin(async move {
+            let name = req.cmd.name();
+            // 让 Service 知道期限，超时后不再开始执行命令
+            let deadline = time::Instant::now() + self.timeout;
+            req.deadline = Some(req.deadline.map_or(deadline, |d| d.min(deadline)));
+            let res = async { peek_response(next.run(req).await).await };
+            match time::timeout(self.timeout, res).await {
+                Ok((_, res)) => res,
+                Err(_) => {
+                    let msg = format!("{} did not respond within {:?}", name, self.timeout);
+                    let res: CommandResponse = KvError::Timeout(msg).into();
+                    Box::pin(stream::once(async { Arc::new(res) }))
+                }
+            }
+        })
+    }
+}
+
+/// 请求自带的期限：第一条响应在期限内没有出来时返回 504，期限从服务器收到请求开始计算
+pub(crate) fn with_deadline(
+    res: StreamingResponse,
+    name: &'static str,
+    timeout: Duration,
+    deadline: time::Instant,
+) -> StreamingResponse {
+    let res = async move {
+        match time::timeout_at(deadline, peek_response(res)).await {
+            Ok((_, res)) => res,
+            Err(_) => {
+                let msg = format!("{} exceeded its deadline of {:?}", name, timeout);
+                let res: CommandResponse = KvError::Timeout(msg).into();
+                Box::pin(stream::once(async { Arc::new(res) })) as StreamingResponse
+            }
+        }
+    };
+    Box::pin(stream::once(res).flatten())
+}
+
+/// 把 ServiceInner 上注册的函数指针包装成中间件
+#[derive(Default)]
+pub(crate) struct HookMiddleware {
+    pub on_received: Vec<fn(&CommandRequest)>,
+    pub on_executed: Vec<fn(&CommandResponse)>,
+    pub on_before_send: Vec<fn(&mut CommandResponse)>,
+    pub on_after_send: Vec<fn()>,
+}
+
+impl HookMiddleware {
+    pub fn is_empty(&self) -> bool {
+        self.on_received.is_empty()
+            && self.on_executed.is_empty()
+            && self.on_before_send.is_empty()
+            && self.on_after_send.is_empty()
+    }
+}
+
+impl Middleware for HookMiddleware {
+    fn call<'a>(&'a self, req: Request, next: Next) -> BoxFuture<'a, StreamingResponse> {
+        Box::pin(async move {
+            self.on_received.notify(&req.cmd);
+            let res = next.run(req).await;
+
+            let (on_executed, on_before_send) =
+                (self.on_executed.clone(), self.on_before_send.clone());
+            let res = res.map(move |mut data| {
+                on_executed.notify(&data);
+                if !on_before_send.is_empty() {
+                    on_before_send.notify(Arc::make_mut(&mut data));
+                }
+                data
+            });
+            Box::pin(AfterSend {
+                inner: Box::pin(res),
+                on_after_send: self.on_after_send.clone(),
+                sent: false,
+            }) as StreamingResponse
+        })
+    }
+}
+
+// 发送方发完一条响应之后才会取下一条，所以取下一条时说明上一条已经发出
+struct AfterSend {
+    inner: StreamingResponse,
+    on_after_send: Vec<fn()>,
+    sent: bool,
+}
+
+impl Stream for AfterSend {
+    type Item = Arc<CommandResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.sent {
+            self.sent = false;
+            for f in &self.on_after_send {
+                f()
+            }
+        }
+        let res = self.inner.as_mut().poll_next(cx);
+        if let Poll::Ready(Some(_)) = res {
+            self.sent = true;
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{MemTable, Service, ServiceInner, Value};
+
+    #[tokio::test]
+    async fn middlewares_should_run_in_order_and_modify_requests() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let service: Service = ServiceInner::new(MemTable::default())
+            // 闭包可以捕获状态
+            .middleware(move |req: Request, next: Next| {
+                let counter = counter.clone();
+                async move {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    next.run(req).await
+                }
+            })
+            // 把所有写入都改到 t2
+            .middleware(|mut req: Request, next: Next| async move {
+                if let Some(crate::command_request::RequestData::Hset(p)) =
+                    &mut req.cmd.request_data
+                {
+                    p.table = "t2".into();
+                }
+                next.run(req).await
+            })
+            .into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+        let res = service
+            .execute(CommandRequest::new_hget("t2", "k1"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values, vec![Value::from("v1")]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn middleware_should_short_circuit() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .middleware(|req: Request, next: Next| async move {
+                if req.cmd.name() == "hdel" {
+                    let res: CommandResponse =
+                        KvError::PermissionDenied("hdel is disabled".into()).into();
+                    return Box::pin(stream::once(async { Arc::new(res) })) as StreamingResponse;
+                }
+                next.run(req).await
+            })
+            .into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+        let res = service
+            .execute(CommandRequest::new_hdel("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 403);
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.values, vec![Value::from("v1")]);
+    }
+
+    #[tokio::test]
+    async fn metrics_middleware_should_count_streaming_requests() {
+        let metrics = Arc::new(RequestMetrics::default());
+        let service: Service = ServiceInner::new(MemTable::default())
+            .middleware(LoggingMiddleware)
+            .middleware(MetricsMiddleware::new(metrics.clone()))
+            .into();
+
+        service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await;
+        let mut sub = service.execute(CommandRequest::new_subscribe("lobby"));
+        sub.next().await.unwrap();
+        let mut publish = service.execute(CommandRequest::new_publish("lobby", vec!["hi".into()]));
+        publish.next().await;
+        assert_eq!(sub.next().await.unwrap().values, vec![Value::from("hi")]);
+
+        let stats = metrics.snapshot();
+        let names: Vec<_> = stats.iter().map(|(c, s, m)| (*c, *s, m.count)).collect();
+        assert_eq!(
+            names,
+            vec![("hget", 404, 1), ("publish", 200, 1), ("subscribe", 200, 1)]
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_middleware_should_return_504() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .middleware(TimeoutMiddleware::new(Duration::from_millis(10)))
+            .middleware(|req: Request, next: Next| async move {
+                if req.cmd.name() == "hgetall" {
+                    time::sleep(Duration::from_millis(100)).await;
+                }
+                next.run(req).await
+            })
+            .into();
+
+        let res = service
+            .execute(CommandRequest::new_hgetall("t1"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 504);
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn after_send_should_be_called_for_each_response() {
+        static SENT: AtomicUsize = AtomicUsize::new(0);
+        fn sent() {
+            SENT.fetch_add(1, Ordering::SeqCst);
+        }
+
+        let service: Service = ServiceInner::new(MemTable::default())
+            .fn_after_send(sent)
+            .into();
+        let mut res = service.execute(CommandRequest::new_hget("t1", "k1"));
+        res.next().await.unwrap();
+        assert_eq!(SENT.load(Ordering::SeqCst), 0);
+        // 取下一条时上一条已经发出
+        assert!(res.next().await.is_none());
+        assert_eq!(SENT.load(Ordering::SeqCst), 1);
+    }
+}
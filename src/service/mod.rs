@@ -1,27 +1,61 @@
 //! 服务模块，将外部网络请求转换为内部数据库指令
 //!
 
+mod acl;
+mod audit;
+mod clients;
 mod command_service;
+mod limit;
+mod middleware;
+mod replication;
+mod slowlog;
 mod top;
 mod topic_service;
+mod tracking;
 use crate::error::KvError;
 use crate::pb::command_request::RequestData;
-use crate::storage::SledDb;
-use crate::storage::Storage;
-use futures::stream;
-use http::StatusCode;
+use crate::storage::{Storage, StorageStats, WriteOp};
+use bytes::Bytes;
+use futures::{stream, Stream, StreamExt};
 
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tracing::debug;
-use tracing::info;
+use std::time::Instant;
+use tokio::{task, time};
+use tracing::{debug, info, warn};
 
-use crate::{pb::*, MemTable};
+use crate::{
+    current_trace_context, metrics, pb::*, LimitsConfig, MemTable, Permission, ReplicationConfig,
+    RuntimeSettings, Snapshot, SnapshotPatch,
+};
+pub use acl::{glob_match, required_permissions, Acl, Identity, Resource};
+pub use audit::AuditLog;
+pub use clients::{Clients, Registration};
 pub use command_service::*;
+pub use limit::{AuthLimiter, Quotas, RateLimiter, TableUsage};
+pub use middleware::{
+    on_stream_end, peek_response, CommandMetrics, LoggingMiddleware, MetricsMiddleware, Middleware,
+    Next, Request, RequestMetrics, TimeoutMiddleware,
+};
+pub use replication::{replicated_writes, ChangeSet, ReplicaState, ReplicationLog, Sequencer};
+pub use slowlog::{summarize, SlowLog, SlowlogEntry};
+pub use tracking::{
+    read_keys, written_keys, InFlight, OpenStream, Session, Tracker, TrackingStream,
+};
+
+// 流式备份和复制时每个响应中的快照数据大小
+const BACKUP_CHUNK_SIZE: usize = 64 * 1024;
 
 // 让数据对象能够多线程访问
 pub struct Service<Store = MemTable> {
     inner: Arc<ServiceInner<Store>>,
     broadcaster: Arc<Broadcaster>,
+    tracker: Arc<Tracker>,
+    // 中间件链，先注册的在外层
+    middlewares: Arc<[Arc<dyn Middleware>]>,
 }
 
 // 手动实现clone
@@ -30,6 +64,8 @@ impl<Store> Clone for Service<Store> {
         Self {
             inner: Arc::clone(&self.inner),
             broadcaster: Arc::clone(&self.broadcaster),
+            tracker: Arc::clone(&self.tracker),
+            middlewares: Arc::clone(&self.middlewares),
         }
     }
 }
@@ -60,40 +96,116 @@ impl<Store> Clone for Service<Store> {
 
 pub struct ServiceInner<Store> {
     store: Store,
-    on_received: Vec<fn(&CommandRequest)>, // 函数指针，服务器收到请求时触发
-    on_executed: Vec<fn(&CommandResponse)>, // 处理完请求时触发
-    on_before_send: Vec<fn(&mut CommandResponse)>, // 在发送之前修改
-    on_after_send: Vec<fn()>,              // 发送完响应后触发
+    acl: Option<Acl>, // 不设置时不检查权限
+    limiter: RateLimiter,
+    quotas: Quotas,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    hooks: HookMiddleware, // 函数指针形式的事件，会包装成最外层的中间件
+    settings: Arc<RuntimeSettings>,
+    clients: Clients,
+    started: Instant,
+    slowlog: SlowLog,
+    audit: Option<AuditLog>, // 不设置时不记录审计日志
+    auth_failures: AuthLimiter,
+    next_backup: AtomicU64,
+    // 备份写到服务器上时所在的目录，不设置时只能以流的形式备份
+    backup_dir: Option<PathBuf>,
+    // 写操作的序号，以及发给 follower 的复制流
+    replication: ReplicationLog,
+    // 作为 follower 运行时的同步状态，不设置时是 leader
+    replica: Option<ReplicaState>,
 }
 
 impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
-            on_received: Vec::new(),
-            on_executed: Vec::new(),
-            on_before_send: Vec::new(),
-            on_after_send: Vec::new(),
+            acl: None,
+            limiter: RateLimiter::default(),
+            quotas: Quotas::default(),
+            middlewares: Vec::new(),
+            hooks: HookMiddleware::default(),
+            settings: Default::default(),
+            clients: Clients::default(),
+            started: Instant::now(),
+            slowlog: SlowLog::default(),
+            audit: None,
+            auth_failures: AuthLimiter::default(),
+            next_backup: AtomicU64::new(1),
+            backup_dir: None,
+            replication: ReplicationLog::default(),
+            replica: None,
         }
     }
 
+    /// 开启认证和访问控制
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// 开启限流和配额，限流规则不合法时返回错误
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Result<Self, KvError> {
+        self.limiter = RateLimiter::new(limits.rate)?;
+        self.quotas = Quotas::new(limits.quotas);
+        Ok(self)
+    }
+
+    /// 使用和服务器共享的运行时配置，CONFIG GET/SET 读写的就是它
+    pub fn with_settings(mut self, settings: Arc<RuntimeSettings>) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// 记录写命令的审计日志
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// 允许 Backup 把备份写到这个目录下
+    pub fn with_backup_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.backup_dir = Some(dir.into());
+        self
+    }
+
+    /// 复制的配置，配置了 leader 时作为只读的 follower，数据由 Service::follow 从 leader 同步
+    pub fn with_replication(mut self, config: &ReplicationConfig) -> Self {
+        self.replication = ReplicationLog::new(config);
+        self.replica = config
+            .leader
+            .as_ref()
+            .map(|leader| ReplicaState::new(leader.general.addr.as_str()));
+        self
+    }
+
+    /// 添加中间件，先添加的在外层
+    pub fn middleware(mut self, m: impl Middleware) -> Self {
+        self.middlewares.push(Arc::new(m));
+        self
+    }
+
+    // 服务器收到请求时触发
     pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
-        self.on_received.push(f);
+        self.hooks.on_received.push(f);
         self
     }
 
+    // 每条响应生成后触发
     pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
-        self.on_executed.push(f);
+        self.hooks.on_executed.push(f);
         self
     }
 
+    // 在发送之前修改响应
     pub fn fn_before_send(mut self, f: fn(&mut CommandResponse)) -> Self {
-        self.on_before_send.push(f);
+        self.hooks.on_before_send.push(f);
         self
     }
 
+    // 每条响应发送完后触发
     pub fn fn_after_send(mut self, f: fn()) -> Self {
-        self.on_after_send.push(f);
+        self.hooks.on_after_send.push(f);
         self
     }
 }
@@ -137,15 +249,79 @@ pub fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         Some(RequestData::Hmdel(param)) => param.execute(store),
         Some(RequestData::Hexist(param)) => param.execute(store),
         Some(RequestData::Hmexist(param)) => param.execute(store),
+        Some(RequestData::Hscan(param)) => param.execute(store),
+        Some(RequestData::Batch(param)) => param.execute(store),
         None => KvError::InvalidCommand("Request has no data".into()).into(), // 处理不了的返回一个啥都不包括的 Response，这样后续可以用 dispatch_stream 处理
         _ => CommandResponse::default(),
     }
 }
 
+// 会修改存储的命令，备份期间需要等待，follower 上会被拒绝
+fn is_write(cmd: &CommandRequest) -> bool {
+    matches!(
+        cmd.request_data,
+        Some(RequestData::Hset(_))
+            | Some(RequestData::Hmset(_))
+            | Some(RequestData::Hdel(_))
+            | Some(RequestData::Hmdel(_))
+            | Some(RequestData::Batch(_))
+    )
+}
+
+// 进程占用的物理内存，只在 Linux 上能拿到
+fn resident_memory() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// 把编码后的快照分成若干段，每段是一个响应。快照在临时文件中，在阻塞线程上读
+fn snapshot_chunks(snapshot: Snapshot) -> impl Stream<Item = Arc<CommandResponse>> {
+    stream::unfold(Some(snapshot.into_reader()), |reader| async move {
+        let mut reader = reader?;
+        let res = task::spawn_blocking(move || {
+            let mut buf = Vec::with_capacity(BACKUP_CHUNK_SIZE);
+            (&mut reader)
+                .take(BACKUP_CHUNK_SIZE as u64)
+                .read_to_end(&mut buf)
+                .map(|_| (reader, buf))
+        })
+        .await;
+        let res: CommandResponse = match res {
+            Ok(Ok((_, buf))) if buf.is_empty() => return None,
+            Ok(Ok((reader, buf))) => {
+                return Some((Arc::new(Value::from(Bytes::from(buf)).into()), Some(reader)))
+            }
+            Ok(Err(e)) => KvError::from(e).into(),
+            Err(e) => KvError::Internal(format!("Failed to read snapshot: {}", e)).into(),
+        };
+        Some((Arc::new(res), None))
+    })
+}
+
+// ping 的响应，原样返回 message
+fn pong(param: &Ping) -> CommandResponse {
+    match param.message.is_empty() {
+        true => Value::from("PONG").into(),
+        false => Value::from(param.message.as_str()).into(),
+    }
+}
+
+fn ok_or_error(result: Result<(), KvError>) -> CommandResponse {
+    match result {
+        Ok(()) => CommandResponse::ok(),
+        Err(e) => e.into(),
+    }
+}
+
 // 重新做一个
 pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
     match cmd.request_data {
-        Some(RequestData::Publish(param)) => param.execute(topic),
+        // 服务器在追踪时用处理这个命令的 span，否则原样转发发布者的 trace context
+        Some(RequestData::Publish(param)) => {
+            param.execute_traced(topic, current_trace_context().or(cmd.trace))
+        }
         Some(RequestData::Subscribe(param)) => param.execute(topic),
         Some(RequestData::Unsubscribe(param)) => param.execute(topic),
 
@@ -154,43 +330,551 @@ pub fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingRespo
 }
 
 impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
-    fn from(inner: ServiceInner<Store>) -> Self {
+    fn from(mut inner: ServiceInner<Store>) -> Self {
+        let mut middlewares: Vec<Arc<dyn Middleware>> = Vec::new();
+        let hooks = std::mem::take(&mut inner.hooks);
+        if !hooks.is_empty() {
+            middlewares.push(Arc::new(hooks));
+        }
+        middlewares.append(&mut inner.middlewares);
+
         Self {
             inner: Arc::new(inner),
             broadcaster: Default::default(),
+            tracker: Default::default(),
+            middlewares: middlewares.into(),
         }
     }
 }
 
 impl<Store: Storage> Service<Store> {
+    /// 在一个单独的连接上执行命令
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
-        debug!("Got a result: {:?}", cmd);
+        self.execute_in(cmd, &Arc::new(Session::new()))
+    }
 
-        self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+    /// 在 session 对应的连接上执行命令，同一个连接的命令共享 key 跟踪等状态
+    pub fn execute_in(&self, cmd: CommandRequest, session: &Arc<Session>) -> StreamingResponse {
+        debug!("Got a result: {:?}", cmd.redacted());
 
-        if res == CommandResponse::default() {
-            dispatch_stream(cmd, Arc::clone(&self.broadcaster))
+        let name = cmd.name();
+        let timeout = cmd.deadline();
+        let deadline = timeout.map(|timeout| time::Instant::now() + timeout);
+        let start = Instant::now();
+        // 慢命令日志开启时才生成命令的摘要
+        let mut slowlog = self
+            .inner
+            .settings
+            .slowlog_threshold()
+            .map(|threshold| (threshold, summarize(&cmd), self.inner.clone()));
+        let req = Request {
+            cmd,
+            session: session.clone(),
+            deadline,
+        };
+        let res = if self.middlewares.is_empty() {
+            self.handle(req)
         } else {
-            debug!("Executed response: {:?}", res);
-            self.inner.on_executed.notify(&res);
-            self.inner.on_before_send.notify(&mut res);
-            if !self.inner.on_before_send.is_empty() {
-                debug!("Modified response: {:?}", res);
+            // 中间件是异步的，等它们处理完再开始输出响应
+            let service = self.clone();
+            let next = Next::new(
+                self.middlewares.clone(),
+                Box::new(move |req| service.handle(req)),
+            );
+            Box::pin(stream::once(next.run(req)).flatten())
+        };
+        let res = match (timeout, deadline) {
+            (Some(timeout), Some(deadline)) => {
+                middleware::with_deadline(res, name, timeout, deadline)
+            }
+            _ => res,
+        };
+
+        // 第一个响应出来时记录请求数和延迟，流式命令后续的数据不算
+        let mut recorded = false;
+        let session = session.clone();
+        Box::pin(res.map(move |res| {
+            if !recorded {
+                recorded = true;
+                let elapsed = start.elapsed();
+                metrics().record_request(name, res.status, elapsed);
+                if let Some((threshold, command, inner)) = slowlog.take() {
+                    if elapsed >= threshold {
+                        let max_len = inner.settings.slowlog_max_len();
+                        inner.slowlog.record(&session, command, elapsed, max_len);
+                    }
+                }
+            }
+            res
+        }))
+    }
+
+    /// 登记一个网络连接，返回的 Registration 释放前它会出现在 CLIENT LIST 中
+    pub fn register_client(&self, session: &Arc<Session>) -> Registration {
+        self.inner.clients.register(session.clone())
+    }
+
+    /// 存储中每个 table 的 key 个数和总大小
+    pub fn storage_stats(&self) -> Result<StorageStats, KvError> {
+        self.inner.store.stats()
+    }
+
+    /// 把存储中缓存的写入刷到磁盘
+    pub fn flush(&self) -> Result<(), KvError> {
+        self.inner.store.flush()
+    }
+
+    // 经过所有中间件之后执行命令
+    fn handle(&self, req: Request) -> StreamingResponse {
+        let Request {
+            cmd,
+            session: shared,
+            deadline,
+        } = req;
+        let session = shared.as_ref();
+        match &cmd.request_data {
+            Some(RequestData::Auth(param)) => {
+                return self.respond(self.authenticate(param, session));
+            }
+            // 保活不受权限和限流的影响
+            Some(RequestData::Ping(param)) => return self.respond(pong(param)),
+            _ => {}
+        }
+        if let Some(acl) = &self.inner.acl {
+            if let Err(e) = acl.check(&session.identity(), &cmd) {
+                warn!("Session {} rejected: {}", session.id, e);
+                return self.respond(e.into());
             }
-            Box::pin(stream::once(async { Arc::new(res) }))
+        }
+        if let Err(e) = self.inner.limiter.check(session, &cmd) {
+            warn!("Session {} rejected: {}", session.id, e);
+            return self.respond(e.into());
+        }
+        // follower 的数据只来自 leader，修改数据的命令要发给 leader
+        if let Some(replica) = &self.inner.replica {
+            let modifies = is_write(&cmd)
+                || matches!(
+                    cmd.request_data,
+                    Some(RequestData::FlushAll(_)) | Some(RequestData::Replicate(_))
+                );
+            if modifies {
+                return self.respond(KvError::ReadOnly(replica.leader().into()).into());
+            }
+        }
+
+        match &cmd.request_data {
+            Some(RequestData::Track(_)) => {
+                return Box::pin(self.tracker.clone().enable(session.id));
+            }
+            Some(RequestData::SetRateLimit(param)) => {
+                return self.respond(ok_or_error(self.inner.limiter.set(param)));
+            }
+            Some(RequestData::SetQuota(param)) => {
+                return self.respond(ok_or_error(self.inner.quotas.set(param)));
+            }
+            Some(RequestData::Info(_)) => return self.respond(self.info()),
+            Some(RequestData::ListTables(param)) => {
+                let res = self
+                    .list_tables(&param.pattern, session)
+                    .unwrap_or_else(|e| e.into());
+                return self.respond(res);
+            }
+            Some(RequestData::ClientList(_)) => {
+                let clients: Vec<Value> = self
+                    .inner
+                    .clients
+                    .list()
+                    .into_iter()
+                    .map(Value::from)
+                    .collect();
+                return self.respond(clients.into());
+            }
+            Some(RequestData::ClientKill(param)) => {
+                let res = match self.inner.clients.kill(param.id) {
+                    true => CommandResponse::ok(),
+                    false => {
+                        KvError::InvalidCommand(format!("No client with id {}", param.id)).into()
+                    }
+                };
+                return self.respond(res);
+            }
+            Some(RequestData::ConfigGet(param)) => {
+                let pairs: Vec<Kvpair> = self
+                    .inner
+                    .settings
+                    .get(&param.pattern)
+                    .into_iter()
+                    .map(|(name, value)| Kvpair::new(name, (value as i64).into()))
+                    .collect();
+                return self.respond(pairs.into());
+            }
+            Some(RequestData::ConfigSet(param)) => {
+                let value = param.value.clone().unwrap_or_default();
+                return self.respond(ok_or_error(self.inner.settings.set(&param.name, &value)));
+            }
+            Some(RequestData::FlushAll(param)) => {
+                let res = self
+                    .flush_all(&param.table, session)
+                    .unwrap_or_else(|e| e.into());
+                return self.respond(res);
+            }
+            Some(RequestData::Backup(param)) => return self.backup(param),
+            Some(RequestData::Replicate(_)) => return self.replicate(),
+            Some(RequestData::SlowlogGet(param)) => {
+                let entries: Vec<Value> = self
+                    .inner
+                    .slowlog
+                    .get(param.count as usize)
+                    .iter()
+                    .map(|e| e.to_string().into())
+                    .collect();
+                return self.respond(entries.into());
+            }
+            _ => {}
+        }
+
+        // 发布订阅不访问存储
+        if matches!(
+            cmd.request_data,
+            Some(RequestData::Publish(_))
+                | Some(RequestData::Subscribe(_))
+                | Some(RequestData::Unsubscribe(_))
+        ) {
+            return dispatch_stream(cmd, Arc::clone(&self.broadcaster));
+        }
+
+        match deadline {
+            // 有期限的命令在阻塞线程上访问存储，存储太慢时到了期限可以先返回，不占用异步线程
+            Some(deadline) => {
+                let service = self.clone();
+                let res = async move {
+                    task::spawn_blocking(move || {
+                        service.execute_storage(cmd, &shared, Some(deadline))
+                    })
+                    .await
+                    .unwrap_or_else(|e| KvError::Internal(format!("Command failed: {}", e)).into())
+                };
+                Box::pin(stream::once(res).map(Arc::new))
+            }
+            None => self.respond(self.execute_storage(cmd, session, None)),
         }
     }
+
+    // 访问存储的命令，期限在开始执行之前已经过了时不执行
+    fn execute_storage(
+        &self,
+        cmd: CommandRequest,
+        session: &Session,
+        deadline: Option<time::Instant>,
+    ) -> CommandResponse {
+        // 先记录要读的 key 再读取，读取期间的修改也会发出失效通知
+        for (table, key) in read_keys(&cmd) {
+            self.tracker.track(session.id, table, key);
+        }
+
+        let store = &self.inner.store;
+        let writing = is_write(&cmd);
+        // 写入期间持有序号，follower 按照和这里相同的顺序应用写入
+        let sequencer = writing.then(|| self.inner.replication.begin());
+        // 等待其它写入的时候可能已经过了期限，这时客户端已经收到了 504，不能再修改数据
+        if deadline.is_some_and(|d| time::Instant::now() >= d) {
+            let msg = format!(
+                "{} exceeded its deadline before it was executed",
+                cmd.name()
+            );
+            return KvError::Timeout(msg).into();
+        }
+        // 审计日志需要写入之前的值，batch 中多次修改的 key 记录 batch 之前的值
+        let old_values = self.inner.audit.as_ref().map(|_| {
+            let mut old_values = HashMap::new();
+            for (table, key) in written_keys(&cmd) {
+                old_values
+                    .entry((table, key))
+                    .or_insert_with(|| store.get(table, key).ok().flatten());
+            }
+            old_values
+        });
+        let res = self
+            .inner
+            .quotas
+            .execute(&cmd, store, || dispatch(cmd.clone(), store));
+        // batch 中失败或者没有执行的命令不算
+        let applied = replicated_writes(&cmd, &res);
+        if let (Some(audit), Some(old_values)) = (&self.inner.audit, old_values) {
+            for op in &applied {
+                let (table, key) = op.target();
+                let old = old_values.get(&(table, key)).and_then(|v| v.as_ref());
+                audit.record(session, cmd.name(), table, key, old);
+            }
+        }
+        if let Some(sequencer) = sequencer {
+            sequencer.record(applied);
+        }
+
+        for (table, key) in written_keys(&cmd) {
+            self.tracker.invalidate(table, key);
+        }
+        // 其它命令在前面都处理过了
+        if res == CommandResponse::default() {
+            return KvError::InvalidCommand(format!("{} is not supported", cmd.name())).into();
+        }
+        res
+    }
+
+    // 服务器的状态，统计存储失败时不返回存储相关的项
+    fn info(&self) -> CommandResponse {
+        let inner = &self.inner;
+        let mut pairs = vec![
+            Kvpair::new("version", env!("CARGO_PKG_VERSION").into()),
+            Kvpair::new(
+                "uptime_secs",
+                (inner.started.elapsed().as_secs() as i64).into(),
+            ),
+            Kvpair::new("storage", inner.store.name().into()),
+            Kvpair::new("clients", (inner.clients.len() as i64).into()),
+        ];
+        match inner.store.stats() {
+            Ok(stats) => {
+                let keys: u64 = stats.tables.values().sum();
+                pairs.push(Kvpair::new("tables", (stats.tables.len() as i64).into()));
+                pairs.push(Kvpair::new("keys", (keys as i64).into()));
+                pairs.push(Kvpair::new("bytes", (stats.bytes as i64).into()));
+            }
+            Err(e) => warn!("Failed to collect storage stats: {:?}", e),
+        }
+        if let Some(rss) = resident_memory() {
+            pairs.push(Kvpair::new("memory_rss_bytes", (rss as i64).into()));
+        }
+        pairs.extend(self.replication_info());
+        pairs.into()
+    }
+
+    // 名字匹配 pattern 并且有 read 权限的 table，以及它们的 key 个数
+    fn list_tables(&self, pattern: &str, session: &Session) -> Result<CommandResponse, KvError> {
+        let identity = session.identity();
+        let pairs: Vec<Kvpair> = self
+            .inner
+            .store
+            .stats()?
+            .tables
+            .into_iter()
+            .filter(|(table, _)| pattern.is_empty() || glob_match(pattern, table))
+            .filter(|(table, _)| match &self.inner.acl {
+                Some(acl) => acl.is_allowed(&identity, Permission::Read, Resource::Table(table)),
+                None => true,
+            })
+            .map(|(table, keys)| Kvpair::new(table, (keys as i64).into()))
+            .collect();
+        Ok(pairs.into())
+    }
+
+    // 清空名字匹配 pattern 的 table，读过这些 key 的客户端会收到失效通知
+    fn flush_all(&self, pattern: &str, session: &Session) -> Result<CommandResponse, KvError> {
+        let store = &self.inner.store;
+        let sequencer = self.inner.replication.begin();
+        // 中途出错时已经清空的 table 也要发给 follower
+        let mut flushed = Vec::new();
+        let result = (|| {
+            let tables = store.stats()?.tables;
+            let mut removed = 0;
+            for table in tables
+                .keys()
+                .filter(|t| pattern.is_empty() || glob_match(pattern, t))
+            {
+                let keys: Vec<String> = store.get_all(table)?.into_iter().map(|p| p.key).collect();
+                let ops: Vec<WriteOp> = keys
+                    .iter()
+                    .map(|key| WriteOp::Del {
+                        table: table.clone(),
+                        key: key.clone(),
+                    })
+                    .collect();
+                store.apply_batch(ops.clone())?;
+                flushed.extend(ops);
+                for key in &keys {
+                    self.tracker.invalidate(table, key);
+                }
+                if let Some(audit) = &self.inner.audit {
+                    audit.record(session, "flushall", table, "*", None);
+                }
+                removed += keys.len();
+            }
+            Ok::<_, KvError>(removed)
+        })();
+        sequencer.record(flushed);
+        let removed = result?;
+        // 配额的使用量下次写入时重新统计
+        self.inner.quotas.reset();
+        info!(
+            "Flushed {} keys from tables matching {:?}",
+            removed, pattern
+        );
+        Ok(Value::from(removed as i64).into())
+    }
+
+    // 不暂停写入读出所有数据，然后写到服务器上的文件或者分段返回给客户端，读写都在阻塞线程上
+    fn backup(&self, param: &Backup) -> StreamingResponse {
+        let target = match param.path.is_empty() {
+            true => None,
+            false => match self.backup_path(&param.path) {
+                Ok(target) => Some(target),
+                Err(e) => return self.respond(e.into()),
+            },
+        };
+        let service = self.clone();
+        let capture = task::spawn_blocking(move || {
+            let (snapshot, _) = service.capture_snapshot(|_| ())?;
+            if let Some(target) = &target {
+                if let Some(parent) = target.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                snapshot.save(target)?;
+            }
+            Ok::<_, KvError>(snapshot)
+        });
+        let service = self.clone();
+        let path = param.path.clone();
+        let res = stream::once(capture).flat_map(move |res| {
+            let res =
+                res.unwrap_or_else(|e| Err(KvError::Internal(format!("Backup failed: {}", e))));
+            let snapshot = match res {
+                Ok(snapshot) => snapshot,
+                Err(e) => return service.respond(e.into()),
+            };
+            let tables = &snapshot.manifest().tables;
+            let keys: u64 = tables.iter().map(|t| t.keys).sum();
+            if !path.is_empty() {
+                info!("Backed up {} keys to {}", keys, path);
+                let pairs: Vec<Kvpair> = tables
+                    .iter()
+                    .map(|t| Kvpair::new(t.name.as_str(), (t.keys as i64).into()))
+                    .collect();
+                return service.respond(pairs.into());
+            }
+
+            let id = service.inner.next_backup.fetch_add(1, Ordering::Relaxed);
+            info!(
+                "Streaming backup {} with {} keys ({} bytes)",
+                id,
+                keys,
+                snapshot.size()
+            );
+            let header: CommandResponse = Value::from(id as i64).into();
+            Box::pin(stream::once(async { Arc::new(header) }).chain(snapshot_chunks(snapshot)))
+        });
+        Box::pin(res)
+    }
+
+    // 客户端给出的备份路径只能是备份目录中的相对路径，不能用 .. 跳出这个目录
+    fn backup_path(&self, path: &str) -> Result<PathBuf, KvError> {
+        let dir = self.inner.backup_dir.as_ref().ok_or_else(|| {
+            KvError::PermissionDenied(
+                "backup_dir is not configured, backups can only be streamed to the client".into(),
+            )
+        })?;
+        let path = Path::new(path);
+        let valid = path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !valid || path.file_name().is_none() {
+            return Err(KvError::InvalidCommand(format!(
+                "Backup path {} must be a relative path inside backup_dir",
+                path.display()
+            )));
+        }
+        Ok(dir.join(path))
+    }
+
+    // 不暂停写入读出一致的快照，要在阻塞线程上调用。读取期间收集被修改过的 key，读完后短暂持有
+    // 写入序号，读出这些 key 的当前值合并进去。cut 在持有序号时调用，返回值和快照一起返回
+    fn capture_snapshot<T>(
+        &self,
+        cut: impl FnOnce(&Sequencer) -> T,
+    ) -> Result<(Snapshot, T), KvError> {
+        let log = &self.inner.replication;
+        let changes = log.track_changes();
+        Snapshot::capture_with(&self.inner.store, |store| {
+            let mut sequencer = log.begin();
+            let mut patch = SnapshotPatch::new();
+            for (table, key) in changes.finish(&mut sequencer) {
+                let value = store.get(&table, &key)?;
+                patch.entry(table).or_default().insert(key, value);
+            }
+            Ok((patch, cut(&sequencer)))
+        })
+    }
+
+    // 认证成功后 session 换成用户的身份，返回用户名
+    fn authenticate(&self, param: &Auth, session: &Session) -> CommandResponse {
+        let acl = match &self.inner.acl {
+            Some(acl) => acl,
+            None => return KvError::InvalidCommand("Authentication is not enabled".into()).into(),
+        };
+        // 失败太多次的地址暂时不检查密码，防止暴力猜测
+        let failures = &self.inner.auth_failures;
+        if let Err(e) = failures.check(session.peer()) {
+            warn!("Session {} rejected: {}", session.id, e);
+            return e.into();
+        }
+        match acl.authenticate(param) {
+            Ok(identity) => {
+                let res = match &identity {
+                    Identity::User(name) => Value::from(name.as_str()).into(),
+                    _ => CommandResponse::ok(),
+                };
+                session.set_identity(identity);
+                res
+            }
+            Err(e) => {
+                warn!("Session {} failed to authenticate: {}", session.id, e);
+                failures.failed(session.peer());
+                e.into()
+            }
+        }
+    }
+
+    fn respond(&self, res: CommandResponse) -> StreamingResponse {
+        debug!("Executed response: {:?}", res);
+        Box::pin(stream::once(async { Arc::new(res) }))
+    }
 }
 #[cfg(test)]
+use crate::{Kvpair, Value};
 
-mod tests {
-    use std::thread;
+use self::middleware::HookMiddleware;
+use self::top::Broadcaster;
+use self::top::Topic;
+pub use self::topic_service::{StreamingResponse, TopicService};
 
+// 测试成功返回的结果
+#[cfg(test)]
+pub fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
+    let mut sorted_pairs = res.pairs.clone();
+    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    assert_eq!(res.status, 200);
+    assert_eq!(res.message, "");
+    assert_eq!(res.values, values);
+    assert_eq!(sorted_pairs, pairs);
+}
+
+// 测试失败返回的结果
+#[cfg(test)]
+pub fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
+    assert_eq!(res.status, code);
+    assert!(res.message.contains(msg));
+    assert_eq!(res.values, &[]);
+    assert_eq!(res.pairs, &[]);
+}
+
+#[cfg(test)]
+mod tests {
     use futures::StreamExt;
 
     use super::*;
     use crate::{storage::MemTable, Value};
+    use http::StatusCode;
+    use std::collections::HashMap;
+    use tracing::info;
 
     #[tokio::test]
     async fn service_should_works() {
@@ -215,6 +899,597 @@ mod tests {
         assert_res_ok(&data, &["v1".into()], &[]);
     }
 
+    // 读取时先拿到值，再执行 hook 模拟读写之间插入的写入
+    #[derive(Default)]
+    struct WriteDuringRead {
+        store: MemTable,
+        hook: std::sync::Mutex<Option<Box<dyn FnOnce() + Send>>>,
+    }
+
+    impl Storage for WriteDuringRead {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            let value = self.store.get(table, key);
+            self.run_hook();
+            value
+        }
+        fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+            self.store.set(table, key, value)
+        }
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.store.contains(table, key)
+        }
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.store.del(table, key)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            let pairs = self.store.get_all(table);
+            self.run_hook();
+            pairs
+        }
+        fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+            self.store.get_iter(table)
+        }
+        fn stats(&self) -> Result<StorageStats, KvError> {
+            self.store.stats()
+        }
+    }
+
+    impl WriteDuringRead {
+        // 第一次读取之后执行 hook
+        fn run_hook(&self) {
+            let hook = self.hook.lock().unwrap().take();
+            if let Some(hook) = hook {
+                hook();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn write_during_tracked_read_should_invalidate() {
+        let service: Service<WriteDuringRead> =
+            ServiceInner::new(WriteDuringRead::default()).into();
+        service
+            .inner
+            .store
+            .set("t1", "k1".into(), "v1".into())
+            .unwrap();
+
+        let reader = Arc::new(Session::new());
+        let mut tracking = service.execute_in(CommandRequest::new_track(), &reader);
+        tracking.next().await.unwrap();
+
+        // 读到 v1 之后，返回之前另一个连接写入了 v2
+        let writer = service.clone();
+        *service.inner.store.hook.lock().unwrap() = Some(Box::new(move || {
+            let hset = CommandRequest::new_hset("t1", "k1", "v2".into());
+            drop(writer.execute(hset));
+        }));
+        let res = service
+            .execute_in(CommandRequest::new_hget("t1", "k1"), &reader)
+            .next()
+            .await
+            .unwrap();
+        assert_res_ok(&res, &["v1".into()], &[]);
+
+        // 读到的是旧值，客户端必须收到失效通知
+        let res = tracking.next().await.unwrap();
+        assert_eq!(res.values, vec!["t1".into(), "k1".into()]);
+    }
+
+    #[tokio::test]
+    async fn acl_should_be_enforced_per_session() {
+        let config: crate::AuthConfig = toml::from_str(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "secret"
+
+            [[rules]]
+            identity = "alice"
+            tables = ["t*"]
+            permissions = ["read", "write"]
+            "#,
+        )
+        .unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_acl(config.into())
+            .into();
+        let session = Arc::new(Session::new());
+        let hset = CommandRequest::new_hset("t1", "k1", "v1".into());
+
+        // 匿名连接没有权限
+        let res = service
+            .execute_in(hset.clone(), &session)
+            .next()
+            .await
+            .unwrap();
+        assert_res_error(&res, 403, "anonymous");
+
+        let auth = CommandRequest::new_auth("alice", "wrong");
+        let res = service.execute_in(auth, &session).next().await.unwrap();
+        assert_res_error(&res, 401, "Authentication failed");
+
+        let auth = CommandRequest::new_auth("alice", "secret");
+        let res = service.execute_in(auth, &session).next().await.unwrap();
+        assert_res_ok(&res, &["alice".into()], &[]);
+
+        let res = service.execute_in(hset, &session).next().await.unwrap();
+        assert_res_ok(&res, &[Value::default()], &[]);
+        let hset = CommandRequest::new_hset("other", "k1", "v1".into());
+        let res = service.execute_in(hset, &session).next().await.unwrap();
+        assert_res_error(&res, 403, "no Write permission on table other");
+
+        // 只列出有 read 权限的 table
+        let store = &service.inner.store;
+        store.set("other", "k1".into(), "v1".into()).unwrap();
+        store.set("t2", "k1".into(), "v1".into()).unwrap();
+        let list = CommandRequest::new_list_tables("");
+        let res = service.execute_in(list, &session).next().await.unwrap();
+        let tables = [Kvpair::new("t1", 1.into()), Kvpair::new("t2", 1.into())];
+        assert_res_ok(&res, &[], &tables);
+        let list = CommandRequest::new_list_tables("*2");
+        let res = service.execute_in(list, &session).next().await.unwrap();
+        assert_res_ok(&res, &[], &tables[1..]);
+
+        // 其它连接不受影响
+        let res = service
+            .execute_in(
+                CommandRequest::new_hget("t1", "k1"),
+                &Arc::new(Session::new()),
+            )
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 403);
+    }
+
+    #[tokio::test]
+    async fn failed_authentications_should_be_rate_limited() {
+        let config: crate::AuthConfig = toml::from_str(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "secret"
+            "#,
+        )
+        .unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_acl(config.into())
+            .into();
+        let session = Arc::new(Session::new().with_peer("10.0.0.1:1000"));
+        let auth = |password: &str| CommandRequest::new_auth("alice", password);
+
+        for _ in 0..5 {
+            let res = service.execute_in(auth("wrong"), &session).next().await;
+            assert_res_error(&res.unwrap(), 401, "Authentication failed");
+        }
+        // 失败次数用完后正确的密码也不会被检查
+        let res = service.execute_in(auth("secret"), &session).next().await;
+        assert_res_error(&res.unwrap(), 429, "Too many failed authentications");
+
+        let other = Arc::new(Session::new().with_peer("10.0.0.2:1000"));
+        let res = service.execute_in(auth("secret"), &other).next().await;
+        assert_res_ok(&res.unwrap(), &["alice".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn ping_should_not_require_permission() {
+        let config: crate::AuthConfig = toml::from_str("").unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_acl(config.into())
+            .into();
+
+        let res = service.execute(CommandRequest::new_ping("")).next().await;
+        assert_res_ok(&res.unwrap(), &["PONG".into()], &[]);
+        let res = service.execute(CommandRequest::new_ping("hi")).next().await;
+        assert_res_ok(&res.unwrap(), &["hi".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn admin_commands_should_work() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cmds = [
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hset("t1", "k2", "v2".into()),
+            CommandRequest::new_hset("cache", "k1", "v1".into()),
+        ];
+        for cmd in cmds {
+            service.execute(cmd).next().await.unwrap();
+        }
+
+        let res = service
+            .execute(CommandRequest::new_info())
+            .next()
+            .await
+            .unwrap();
+        assert_eq!(res.status, 200);
+        let info: HashMap<_, _> = res
+            .pairs
+            .iter()
+            .map(|p| (p.key.as_str(), p.value.clone()))
+            .collect();
+        assert_eq!(info["storage"], Some("memtable".into()));
+        assert_eq!(info["tables"], Some(2.into()));
+        assert_eq!(info["keys"], Some(3.into()));
+
+        let cmd = CommandRequest::new_config_set("admission.max_in_flight", 8.into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[], &[]);
+        let cmd = CommandRequest::new_config_get("admission.max_in*");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(
+            &res,
+            &[],
+            &[Kvpair::new("admission.max_in_flight", 8.into())],
+        );
+        let cmd = CommandRequest::new_config_set("unknown", 8.into());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&res, 400, "Unknown config");
+
+        let res = service
+            .execute(CommandRequest::new_client_kill(42))
+            .next()
+            .await;
+        assert_res_error(&res.unwrap(), 400, "No client with id 42");
+
+        // 只清空匹配的 table
+        let res = service
+            .execute(CommandRequest::new_flush_all("t*"))
+            .next()
+            .await;
+        assert_res_ok(&res.unwrap(), &[2.into()], &[]);
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await;
+        assert_eq!(res.unwrap().status, 404);
+        let res = service
+            .execute(CommandRequest::new_hget("cache", "k1"))
+            .next()
+            .await;
+        assert_res_ok(&res.unwrap(), &["v1".into()], &[]);
+    }
+
+    #[tokio::test]
+    async fn admin_commands_should_require_admin_permission() {
+        let config: crate::AuthConfig = toml::from_str(
+            r#"
+            [[users]]
+            name = "alice"
+            password = "secret"
+
+            [[rules]]
+            identity = "alice"
+            tables = ["*"]
+            permissions = ["read", "write"]
+            "#,
+        )
+        .unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_acl(config.into())
+            .into();
+        let session = Arc::new(Session::new());
+        let auth = CommandRequest::new_auth("alice", "secret");
+        service.execute_in(auth, &session).next().await.unwrap();
+
+        for cmd in [
+            CommandRequest::new_info(),
+            CommandRequest::new_client_list(),
+            CommandRequest::new_config_set("admission.max_in_flight", 1.into()),
+            CommandRequest::new_flush_all(""),
+            CommandRequest::new_slowlog_get(0),
+        ] {
+            let res = service.execute_in(cmd, &session).next().await.unwrap();
+            assert_eq!(res.status, 403);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_commands_should_be_logged() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .middleware(|req: Request, next: Next| async move {
+                if req.cmd.name() == "hgetall" {
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                next.run(req).await
+            })
+            .into();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        service.execute(cmd).next().await.unwrap();
+        let cmd = CommandRequest::new_hgetall("t1");
+        service.execute(cmd).next().await.unwrap();
+
+        let res = service
+            .execute(CommandRequest::new_slowlog_get(0))
+            .next()
+            .await;
+        let res = res.unwrap();
+        assert_eq!(res.values.len(), 1);
+        let line = String::try_from(res.values[0].clone()).unwrap();
+        assert!(line.contains("identity=anonymous peer=local cmd=hgetall t1"));
+
+        // 阈值为 0 时记录所有命令
+        let cmd = CommandRequest::new_config_set("slowlog.threshold_us", 0.into());
+        service.execute(cmd).next().await.unwrap();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        service.execute(cmd).next().await.unwrap();
+        let res = service
+            .execute(CommandRequest::new_slowlog_get(1))
+            .next()
+            .await;
+        let line = String::try_from(res.unwrap().values[0].clone()).unwrap();
+        assert!(line.ends_with("cmd=hget t1 k1"));
+    }
+
+    #[tokio::test]
+    async fn writes_should_be_audited() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = crate::LogConfig {
+            path: dir.path().to_string_lossy().into(),
+            rotation: crate::RotationConfig::Never,
+        };
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_audit(AuditLog::open(&config))
+            .into();
+        // batch 中失败的命令不记录，嵌套的 batch 会被拒绝
+        let nested = CommandRequest::new_batch(
+            vec![CommandRequest::new_hset("t2", "k3", "v3".into())],
+            false,
+        );
+        let batch = CommandRequest::new_batch(
+            vec![CommandRequest::new_hset("t2", "k2", "v2".into()), nested],
+            false,
+        );
+        let cmds = [
+            CommandRequest::new_hset("t1", "k1", "v1".into()),
+            CommandRequest::new_hset("t1", "k1", "v2".into()),
+            CommandRequest::new_hget("t1", "k1"),
+            CommandRequest::new_hdel("t1", "k1"),
+            batch,
+        ];
+        for cmd in cmds {
+            service.execute(cmd).next().await.unwrap();
+        }
+        // drop 之后后台线程把记录都写到文件里
+        drop(service);
+
+        let log = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let entries: Vec<serde_json::Value> = log
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let commands: Vec<_> = entries.iter().map(|e| e["command"].clone()).collect();
+        assert_eq!(commands, ["hset", "hset", "hdel", "batch"]);
+        assert_eq!(entries[3]["key"], "k2");
+        assert_eq!(entries[0]["old_value_sha256"], serde_json::Value::Null);
+        assert_eq!(entries[0]["table"], "t1");
+        assert_eq!(entries[1]["key"], "k1");
+        // 第二次 hset 和 hdel 之前的值不同
+        assert_ne!(
+            entries[1]["old_value_sha256"],
+            entries[2]["old_value_sha256"]
+        );
+    }
+
+    #[tokio::test]
+    async fn backup_should_be_restorable() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_backup_dir(dir.path())
+            .into();
+        for i in 0..100 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), i.into());
+            service.execute(cmd).next().await.unwrap();
+        }
+        let cmd = CommandRequest::new_hset("t2", "k1", "v1".into());
+        service.execute(cmd).next().await.unwrap();
+
+        // 写到服务器上备份目录中的文件
+        let path = dir.path().join("server.kvsnap");
+        let cmd = CommandRequest::new_backup("server.kvsnap");
+        let res = service.execute(cmd).next().await.unwrap();
+        let expected = [Kvpair::new("t1", 100.into()), Kvpair::new("t2", 1.into())];
+        assert_res_ok(&res, &[], &expected);
+        let store = MemTable::new();
+        crate::restore_snapshot(&path, &store).unwrap();
+        assert_eq!(store.get("t1", "k42").unwrap(), Some(42.into()));
+
+        // 分段返回给客户端
+        let responses: Vec<_> = service
+            .execute(CommandRequest::new_backup(""))
+            .collect()
+            .await;
+        assert_eq!(responses[0].values, [Value::from(1)]);
+        let mut data = Vec::new();
+        for res in &responses[1..] {
+            match &res.values[0].value {
+                Some(crate::value::Value::Binary(chunk)) => data.extend_from_slice(chunk),
+                v => panic!("unexpected value {:?}", v),
+            }
+        }
+        let path = dir.path().join("client.kvsnap");
+        std::fs::write(&path, data).unwrap();
+        let manifest = crate::verify_snapshot(&path).unwrap();
+        assert_eq!(manifest.storage, "memtable");
+        assert_eq!(manifest.tables.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn backup_path_should_stay_in_backup_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service = ServiceInner::new(MemTable::default())
+            .with_backup_dir(dir.path().join("backups"))
+            .into();
+        let outside = dir.path().join("outside.kvsnap");
+        for path in [
+            outside.to_string_lossy().as_ref(),
+            "../outside.kvsnap",
+            "a/../../x",
+            "..",
+        ] {
+            let res = service
+                .execute(CommandRequest::new_backup(path))
+                .next()
+                .await
+                .unwrap();
+            assert_res_error(&res, 400, "must be a relative path inside backup_dir");
+        }
+        assert!(!outside.exists());
+
+        // 没有配置备份目录时只能流式备份
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let cmd = CommandRequest::new_backup("server.kvsnap");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&res, 403, "backup_dir is not configured");
+    }
+
+    #[tokio::test]
+    async fn backup_should_not_pause_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let service: Service<WriteDuringRead> = ServiceInner::new(WriteDuringRead::default())
+            .with_backup_dir(dir.path())
+            .into();
+        let store = &service.inner.store;
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+
+        // 读出 t1 之后，备份完成之前另一个连接修改了数据
+        let writer = service.clone();
+        let (tx, rx) = std::sync::mpsc::channel();
+        *store.hook.lock().unwrap() = Some(Box::new(move || {
+            let cmds = [
+                CommandRequest::new_hset("t1", "k1", "v3".into()),
+                CommandRequest::new_hdel("t1", "k2"),
+                CommandRequest::new_hset("t2", "k1", "v1".into()),
+            ];
+            for cmd in cmds {
+                let res = futures::executor::block_on(writer.execute(cmd).next()).unwrap();
+                tx.send(res.status).unwrap();
+            }
+        }));
+        let path = dir.path().join("online.kvsnap");
+        let cmd = CommandRequest::new_backup("online.kvsnap");
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(rx.try_iter().collect::<Vec<_>>(), [200, 200, 200]);
+        let expected = [Kvpair::new("t1", 1.into()), Kvpair::new("t2", 1.into())];
+        assert_res_ok(&res, &[], &expected);
+
+        // 备份中是写入之后的数据
+        let restored = MemTable::new();
+        crate::restore_snapshot(&path, &restored).unwrap();
+        assert_eq!(restored.get("t1", "k1").unwrap(), Some("v3".into()));
+        assert_eq!(restored.get("t1", "k2").unwrap(), None);
+        assert_eq!(restored.get("t2", "k1").unwrap(), Some("v1".into()));
+    }
+
+    #[tokio::test]
+    async fn deadline_should_return_504() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .middleware(|req: Request, next: Next| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                next.run(req).await
+            })
+            .into();
+
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let timeout = std::time::Duration::from_millis(10);
+        let mut res = service.execute(cmd.clone().with_deadline(timeout));
+        let res = res.next().await.unwrap();
+        assert_res_error(&res, 504, "exceeded its deadline");
+        assert_eq!(res.error_code(), ErrorCode::Timeout);
+
+        // 没有期限时等到处理完
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.status, 404);
+    }
+
+    #[tokio::test]
+    async fn expired_writes_should_not_be_applied() {
+        let service: Service = ServiceInner::new(MemTable::default())
+            .middleware(|req: Request, next: Next| async move {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                next.run(req).await
+            })
+            .into();
+
+        let cmd = CommandRequest::new_hset("t1", "k1", "v1".into());
+        let timeout = std::time::Duration::from_millis(10);
+        let res = service.execute(cmd.with_deadline(timeout)).next().await;
+        assert_res_error(&res.unwrap(), 504, "exceeded its deadline");
+
+        // 中间件处理完之后期限已经过了，写入不会执行
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let res = service
+            .execute(CommandRequest::new_hget("t1", "k1"))
+            .next()
+            .await;
+        assert_eq!(res.unwrap().status, 404);
+    }
+
+    #[tokio::test]
+    async fn deadline_should_cover_slow_storage() {
+        let service: Service<WriteDuringRead> =
+            ServiceInner::new(WriteDuringRead::default()).into();
+        *service.inner.store.hook.lock().unwrap() = Some(Box::new(|| {
+            std::thread::sleep(std::time::Duration::from_millis(500));
+        }));
+
+        let start = Instant::now();
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let timeout = std::time::Duration::from_millis(20);
+        let res = service.execute(cmd.with_deadline(timeout)).next().await;
+        assert_res_error(&res.unwrap(), 504, "exceeded its deadline");
+        assert!(start.elapsed() < std::time::Duration::from_millis(400));
+    }
+
+    #[tokio::test]
+    async fn limits_should_be_adjustable_at_runtime() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let hget = CommandRequest::new_hget("t1", "k1");
+        let session = Arc::new(Session::new());
+
+        let cmd = CommandRequest::new_set_rate_limit("table", "t*", 1.0, 1);
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[], &[]);
+        service.execute_in(hget.clone(), &session).next().await;
+        let res = service.execute_in(hget, &session).next().await.unwrap();
+        assert_eq!(res.status, 429);
+        assert!(res.retry_after_ms > 0);
+
+        let cmd = CommandRequest::new_set_quota("q1", 1, 0);
+        service.execute(cmd).next().await;
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("q1", "k1", "v1".into()),
+                CommandRequest::new_hset("q1", "k2", "v2".into()),
+            ],
+            false,
+        );
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_error(&res, 429, "Quota exceeded");
+    }
+
+    #[tokio::test]
+    async fn publish_should_carry_trace_context() {
+        let service: Service = ServiceInner::new(MemTable::default()).into();
+        let mut sub = service.execute(CommandRequest::new_subscribe("traced"));
+        // 第一个响应是 subscription id
+        sub.next().await.unwrap();
+
+        let trace = TraceContext {
+            traceparent: "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01".into(),
+            tracestate: "congo=t61rcWkgMzE".into(),
+        };
+        let mut cmd = CommandRequest::new_publish("traced", vec!["hello".into()]);
+        cmd.trace = Some(trace.clone());
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_res_ok(&res, &[], &[]);
+
+        let msg = sub.next().await.unwrap();
+        assert_eq!(msg.values, vec!["hello".into()]);
+        assert_eq!(msg.trace, Some(trace));
+    }
+
     #[tokio::test]
     async fn event_registration_should_work() {
         fn b(cmd: &CommandRequest) {
@@ -240,36 +1515,8 @@ mod tests {
 
         let mut res = service.execute(CommandRequest::new_hset("t1", "k1", "v1".into()));
         let data = res.next().await.unwrap();
-        assert_eq!(data.status, StatusCode::CREATED.as_u16() as _);
+        assert_eq!(data.status, StatusCode::CREATED.as_u16() as u32);
         assert_eq!(data.message, "");
         assert_eq!(data.values, vec![Value::default()]);
     }
 }
-
-#[cfg(test)]
-use crate::{Kvpair, Value};
-
-use self::top::Broadcaster;
-use self::top::Topic;
-use self::topic_service::StreamingResponse;
-use self::topic_service::TopicService;
-
-// 测试成功返回的结果
-#[cfg(test)]
-pub async fn assert_res_ok(res: &CommandResponse, values: &[Value], pairs: &[Kvpair]) {
-    let mut sorted_pairs = res.pairs.clone();
-    sorted_pairs.sort_by(|a, b| a.partial_cmp(b).unwrap());
-    assert_eq!(res.status, 200);
-    assert_eq!(res.message, "");
-    assert_eq!(res.values, values);
-    assert_eq!(res.pairs, pairs);
-}
-
-// 测试失败返回的结果
-#[cfg(test)]
-pub async fn assert_res_error(res: &CommandResponse, code: u32, msg: &str) {
-    assert_eq!(res.status, code);
-    assert!(res.message.contains(msg));
-    assert_eq!(res.values, &[]);
-    assert_eq!(res.pairs, &[]);
-}
@@ -0,0 +1,862 @@
+//! 主从复制：leader 按顺序记录每次写入修改的 key，follower 先同步一份快照，再按顺序应用之后的写入
+//!
+//! follower 通过 Replicate 命令连接 leader，断线后重新连接并全量同步。复制是异步的，
+//! leader 不等 follower 应用完就返回，follower 上可能读到稍旧的数据。
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{stream, StreamExt};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task;
+use tokio::time::{self, MissedTickBehavior};
+use tracing::{info, warn};
+
+use super::command_service::is_success;
+use super::{snapshot_chunks, Service, StreamingResponse};
+use crate::command_request::RequestData;
+use crate::{
+    value, ClientConfig, ClientConnector, CommandRequest, CommandResponse, KvError, Kvpair,
+    ReplicatedWrite, ReplicationConfig, ReplicationEntry, Shutdown, Snapshot, SnapshotReader,
+    Storage, StreamResult, Value, WriteOp,
+};
+
+// 接收快照时最多预先分配的空间
+const MAX_SNAPSHOT_PREALLOC: usize = 16 * 1024 * 1024;
+
+/// leader 上的写操作记录，每次写入分配一个递增的序号，同时发给所有正在同步的 follower
+pub struct ReplicationLog {
+    // 有 follower 或者正在读取快照时，写入存储和分配序号期间独占，保证序号的顺序就是写入的顺序。
+    // 否则写入只共享持有，互相之间不用等待
+    state: RwLock<LogState>,
+    // 最新的序号
+    seq: AtomicU64,
+    // 每个 follower 是一个接收端，channel 的容量就是 follower 最多能落后的写入次数
+    sender: broadcast::Sender<Arc<CommandResponse>>,
+    heartbeat: Duration,
+    next_stream: AtomicU64,
+}
+
+impl ReplicationLog {
+    pub fn new(config: &ReplicationConfig) -> Self {
+        let (sender, _) = broadcast::channel(config.backlog.max(1));
+        Self {
+            state: RwLock::new(LogState::default()),
+            seq: AtomicU64::new(0),
+            sender,
+            heartbeat: Duration::from_millis(config.heartbeat_ms.max(1)),
+            next_stream: AtomicU64::new(1),
+        }
+    }
+
+    /// 开始一次写入。有 follower 或者正在读取快照时，返回的 Sequencer 释放之前其它写入需要等待
+    pub fn begin(&self) -> Sequencer<'_> {
+        let state = self.state.read().unwrap_or_else(|e| e.into_inner());
+        // follower 和快照都只在独占时加入，共享持有期间不会出现
+        if state.changes.is_empty() && self.sender.receiver_count() == 0 {
+            return Sequencer {
+                state: Guard::Shared(state),
+                log: self,
+            };
+        }
+        drop(state);
+        self.exclusive()
+    }
+
+    // 独占写入，等待正在进行的写入完成
+    fn exclusive(&self) -> Sequencer<'_> {
+        Sequencer {
+            state: Guard::Exclusive(self.state.write().unwrap_or_else(|e| e.into_inner())),
+            log: self,
+        }
+    }
+
+    /// 最新的写操作序号
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Acquire)
+    }
+
+    /// 开始收集之后的写入修改了哪些 key，读取快照期间不用暂停写入
+    pub fn track_changes(&self) -> ChangeSet<'_> {
+        let mut sequencer = self.exclusive();
+        let state = sequencer.state_mut().expect("exclusive sequencer");
+        let id = state.next_changes;
+        state.next_changes += 1;
+        state.changes.insert(id, HashSet::new());
+        ChangeSet {
+            log: self,
+            id,
+            finished: false,
+        }
+    }
+
+    /// 正在同步的 follower 个数
+    pub fn followers(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+impl Default for ReplicationLog {
+    fn default() -> Self {
+        Self::new(&ReplicationConfig::default())
+    }
+}
+
+#[derive(Default)]
+struct LogState {
+    // 正在读取的快照各自收集到的被修改过的 key
+    changes: HashMap<u64, HashSet<(String, String)>>,
+    next_changes: u64,
+}
+
+enum Guard<'a> {
+    // 共享持有只是为了让 track_changes 和新的 follower 等待这次写入完成
+    Shared(#[allow(dead_code)] RwLockReadGuard<'a, LogState>),
+    Exclusive(RwLockWriteGuard<'a, LogState>),
+}
+
+/// 正在进行的一次写入，写完存储之后用 record 记录修改了哪些 key
+pub struct Sequencer<'a> {
+    state: Guard<'a>,
+    log: &'a ReplicationLog,
+}
+
+impl Sequencer<'_> {
+    /// 最新的写操作序号，独占期间不会变化
+    pub fn seq(&self) -> u64 {
+        self.log.seq()
+    }
+
+    /// 记录这次写入，没有修改任何 key 时不分配序号
+    pub fn record(mut self, ops: Vec<WriteOp>) {
+        if ops.is_empty() {
+            return;
+        }
+        self.touch(&ops);
+        let seq = self.log.seq.fetch_add(1, Ordering::AcqRel) + 1;
+        // 没有 follower 时不用生成记录
+        if self.log.sender.receiver_count() == 0 {
+            return;
+        }
+        let entry = ReplicationEntry {
+            seq,
+            timestamp_ms: now_ms(),
+            writes: ops.into_iter().map(ReplicatedWrite::from).collect(),
+        };
+        // 发送失败说明 follower 刚好都断开了
+        let _ = self.log.sender.send(Arc::new(entry.into()));
+    }
+
+    // 告诉正在读取的快照这些 key 被修改了，不分配序号。共享持有时没有正在读取的快照
+    fn touch(&mut self, ops: &[WriteOp]) {
+        let state = match self.state_mut() {
+            Some(state) => state,
+            None => return,
+        };
+        for changes in state.changes.values_mut() {
+            for op in ops {
+                let (table, key) = op.target();
+                changes.insert((table.into(), key.into()));
+            }
+        }
+    }
+
+    fn state_mut(&mut self) -> Option<&mut LogState> {
+        match &mut self.state {
+            Guard::Exclusive(state) => Some(state),
+            Guard::Shared(_) => None,
+        }
+    }
+}
+
+/// 收集 track_changes 之后被修改过的 key，释放时停止收集
+pub struct ChangeSet<'a> {
+    log: &'a ReplicationLog,
+    id: u64,
+    finished: bool,
+}
+
+impl ChangeSet<'_> {
+    /// 停止收集并返回被修改过的 key。收集期间 begin 总是独占，持有 sequencer 时没有其它写入，
+    /// 这些 key 的当前值就是最新的
+    pub fn finish(mut self, sequencer: &mut Sequencer) -> HashSet<(String, String)> {
+        self.finished = true;
+        sequencer
+            .state_mut()
+            .and_then(|state| state.changes.remove(&self.id))
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for ChangeSet<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            if let Some(state) = self.log.exclusive().state_mut() {
+                state.changes.remove(&self.id);
+            }
+        }
+    }
+}
+
+/// follower 的同步状态
+pub struct ReplicaState {
+    // leader 的地址，拒绝写命令时告诉客户端
+    leader: String,
+    connected: AtomicBool,
+    // 已经应用的 leader 写操作序号
+    seq: AtomicU64,
+    // 收到的最后一条记录在 leader 上生成的时间，0 表示还没有收到过
+    last_timestamp_ms: AtomicU64,
+    full_syncs: AtomicU64,
+}
+
+impl ReplicaState {
+    pub fn new(leader: impl Into<String>) -> Self {
+        Self {
+            leader: leader.into(),
+            connected: AtomicBool::new(false),
+            seq: AtomicU64::new(0),
+            last_timestamp_ms: AtomicU64::new(0),
+            full_syncs: AtomicU64::new(0),
+        }
+    }
+
+    pub fn leader(&self) -> &str {
+        &self.leader
+    }
+
+    /// 是否正在从 leader 接收写操作
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    /// 已经应用的 leader 写操作序号
+    pub fn seq(&self) -> u64 {
+        self.seq.load(Ordering::Relaxed)
+    }
+
+    /// 复制的延迟：现在距离收到的最后一条记录在 leader 上生成的时间。leader 没有写入时
+    /// 靠心跳更新，所以最多比实际多一个心跳间隔，断线后会一直增长。依赖两边的时钟同步
+    pub fn lag(&self) -> Option<Duration> {
+        match self.last_timestamp_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ts => Some(Duration::from_millis(now_ms().saturating_sub(ts))),
+        }
+    }
+
+    fn synced(&self, seq: u64) {
+        self.seq.store(seq, Ordering::Relaxed);
+        self.full_syncs.fetch_add(1, Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+    }
+}
+
+impl From<WriteOp> for ReplicatedWrite {
+    fn from(op: WriteOp) -> Self {
+        match op {
+            WriteOp::Set { table, key, value } => Self {
+                table,
+                key,
+                value: Some(value),
+                deleted: false,
+            },
+            WriteOp::Del { table, key } => Self {
+                table,
+                key,
+                value: None,
+                deleted: true,
+            },
+        }
+    }
+}
+
+impl From<ReplicatedWrite> for WriteOp {
+    fn from(write: ReplicatedWrite) -> Self {
+        let (table, key) = (write.table, write.key);
+        match write.deleted {
+            true => WriteOp::Del { table, key },
+            false => WriteOp::Set {
+                table,
+                key,
+                value: write.value.unwrap_or_default(),
+            },
+        }
+    }
+}
+
+impl From<ReplicationEntry> for CommandResponse {
+    fn from(entry: ReplicationEntry) -> Self {
+        Self {
+            replication: Some(entry),
+            ..CommandResponse::ok()
+        }
+    }
+}
+
+/// 命令执行后实际修改的 key，batch 中只包括执行成功的写命令
+pub fn replicated_writes(cmd: &CommandRequest, res: &CommandResponse) -> Vec<WriteOp> {
+    let mut ops = Vec::new();
+    collect_writes(cmd, res, &mut ops);
+    ops
+}
+
+fn collect_writes(cmd: &CommandRequest, res: &CommandResponse, ops: &mut Vec<WriteOp>) {
+    if !is_success(res) {
+        return;
+    }
+    let set = |table: &str, pair: &Kvpair| WriteOp::Set {
+        table: table.into(),
+        key: pair.key.clone(),
+        value: pair.value.clone().unwrap_or_default(),
+    };
+    let del = |table: &str, key: &str| WriteOp::Del {
+        table: table.into(),
+        key: key.into(),
+    };
+    match &cmd.request_data {
+        Some(RequestData::Hset(p)) => ops.extend(p.pair.iter().map(|pair| set(&p.table, pair))),
+        Some(RequestData::Hmset(p)) => ops.extend(p.pairs.iter().map(|pair| set(&p.table, pair))),
+        Some(RequestData::Hdel(p)) => ops.push(del(&p.table, &p.key)),
+        Some(RequestData::Hmdel(p)) => ops.extend(p.keys.iter().map(|key| del(&p.table, key))),
+        // stop_on_error 时没有执行的命令没有响应
+        Some(RequestData::Batch(p)) => {
+            for (cmd, res) in p.requests.iter().zip(&res.responses) {
+                collect_writes(cmd, res, ops);
+            }
+        }
+        _ => {}
+    }
+}
+
+impl<Store: Storage> Service<Store> {
+    /// 作为 follower 从 leader 同步数据，直到收到关闭信号。断线后按 config.reconnect 退避重连，
+    /// 每次连上都重新全量同步
+    pub async fn follow(&self, config: ClientConfig, mut shutdown: Shutdown) {
+        let replica = match &self.inner.replica {
+            Some(replica) => replica,
+            None => return,
+        };
+        let connector = match ClientConnector::new(&config) {
+            Ok(connector) => connector,
+            Err(e) => {
+                warn!("Cannot replicate from {}: {:?}", replica.leader, e);
+                return;
+            }
+        };
+
+        let mut attempt = 0;
+        loop {
+            let res = tokio::select! {
+                res = self.sync_from(&connector, &mut attempt) => res,
+                _ = shutdown.wait() => return,
+            };
+            replica.connected.store(false, Ordering::Relaxed);
+            if let Err(e) = res {
+                warn!("Replication from {} stopped: {}", replica.leader, e);
+            }
+            let delay = config.reconnect.delay(attempt);
+            attempt = attempt.saturating_add(1);
+            tokio::select! {
+                _ = time::sleep(delay) => {}
+                _ = shutdown.wait() => return,
+            }
+        }
+    }
+
+    // 连接 leader，加载快照后一直应用收到的写操作，连上并加载完快照后把 attempt 清零
+    async fn sync_from(
+        &self,
+        connector: &ClientConnector,
+        attempt: &mut u32,
+    ) -> Result<(), KvError> {
+        let replica = self.inner.replica.as_ref().expect("not a follower");
+        // 复制流结束之前连接要一直保持
+        let mut conn = connector.open().await?;
+        let mut stream = conn
+            .open_stream()
+            .await?
+            .execute_streaming(&CommandRequest::new_replicate())
+            .await?;
+
+        let (seq, size) = match next_response(&mut stream).await?.values.as_slice() {
+            [seq, size] => {
+                let seq: i64 = seq.try_into()?;
+                let size: i64 = size.try_into()?;
+                match (u64::try_from(seq), usize::try_from(size)) {
+                    (Ok(seq), Ok(size)) => (seq, size),
+                    _ => return Err(KvError::Internal("Invalid replication header".into())),
+                }
+            }
+            _ => return Err(KvError::Internal("Invalid replication header".into())),
+        };
+        // 大小是 leader 说的，不能据此一次分配，随着收到的数据增长
+        let mut data = Vec::with_capacity(size.min(MAX_SNAPSHOT_PREALLOC));
+        while data.len() < size {
+            for v in next_response(&mut stream).await?.values {
+                match v.value {
+                    Some(value::Value::Binary(chunk)) => data.extend_from_slice(&chunk),
+                    _ => return Err(KvError::Internal("Unexpected data in snapshot".into())),
+                }
+            }
+            if data.len() > size {
+                return Err(KvError::Internal(format!(
+                    "Snapshot is larger than the {} bytes announced by leader",
+                    size
+                )));
+            }
+        }
+        // 加载快照要写入所有数据，放到阻塞线程上
+        let service = self.clone();
+        let keys = task::spawn_blocking(move || service.load_snapshot(&data))
+            .await
+            .map_err(|e| KvError::Internal(format!("Failed to load snapshot: {}", e)))??;
+        replica.synced(seq);
+        *attempt = 0;
+        info!(
+            "Synced {} keys from leader {} at seq {}",
+            keys, replica.leader, seq
+        );
+
+        loop {
+            let entry = next_response(&mut stream)
+                .await?
+                .replication
+                .ok_or_else(|| KvError::Internal("Missing replication entry".into()))?;
+            self.apply_entry(entry)?;
+        }
+    }
+
+    // 用 leader 的快照替换本地的所有数据，返回快照中的 key 个数。
+    // 先写入快照中的 key，再删除快照中没有的 key，同步期间读到的 key 不会凭空消失
+    fn load_snapshot(&self, data: &[u8]) -> Result<u64, KvError> {
+        let mut reader = SnapshotReader::new(data)?;
+        while reader.next_chunk()?.is_some() {}
+
+        let store = &self.inner.store;
+        // follower 不分配序号，只是让同时进行的备份知道哪些 key 被修改了
+        let mut sequencer = self.inner.replication.begin();
+        let mut stale: HashMap<String, HashSet<String>> = HashMap::new();
+        for table in store.stats()?.tables.into_keys() {
+            let keys = store.get_all(&table)?.into_iter().map(|p| p.key).collect();
+            stale.insert(table, keys);
+        }
+
+        let mut reader = SnapshotReader::new(data)?;
+        let mut loaded = 0;
+        while let Some(chunk) = reader.next_chunk()? {
+            let table = chunk.table;
+            let mut keys = stale.get_mut(&table);
+            let mut ops = Vec::with_capacity(chunk.pairs.len());
+            for pair in chunk.pairs {
+                if let Some(keys) = keys.as_mut() {
+                    keys.remove(&pair.key);
+                }
+                self.tracker.invalidate(&table, &pair.key);
+                ops.push(WriteOp::Set {
+                    table: table.clone(),
+                    key: pair.key,
+                    value: pair.value.unwrap_or_default(),
+                });
+            }
+            loaded += ops.len() as u64;
+            sequencer.touch(&ops);
+            store.apply_batch(ops)?;
+        }
+        for (table, keys) in stale {
+            for key in &keys {
+                self.tracker.invalidate(&table, key);
+            }
+            let ops: Vec<WriteOp> = keys
+                .into_iter()
+                .map(|key| WriteOp::Del {
+                    table: table.clone(),
+                    key,
+                })
+                .collect();
+            sequencer.touch(&ops);
+            store.apply_batch(ops)?;
+        }
+        // 配额的使用量下次写入时重新统计
+        self.inner.quotas.reset();
+        Ok(loaded)
+    }
+
+    // 应用 leader 的一次写入，序号不连续时说明漏掉了写入，需要重新同步
+    fn apply_entry(&self, entry: ReplicationEntry) -> Result<(), KvError> {
+        let replica = self.inner.replica.as_ref().expect("not a follower");
+        if !entry.writes.is_empty() {
+            let expected = replica.seq() + 1;
+            if entry.seq != expected {
+                return Err(KvError::Internal(format!(
+                    "Expected seq {} from leader, got {}",
+                    expected, entry.seq
+                )));
+            }
+            let keys: Vec<(String, String)> = entry
+                .writes
+                .iter()
+                .map(|w| (w.table.clone(), w.key.clone()))
+                .collect();
+            let ops: Vec<WriteOp> = entry.writes.into_iter().map(WriteOp::from).collect();
+            {
+                let mut sequencer = self.inner.replication.begin();
+                sequencer.touch(&ops);
+                self.inner.store.apply_batch(ops)?;
+            }
+            for (table, key) in &keys {
+                self.tracker.invalidate(table, key);
+            }
+            replica.seq.store(entry.seq, Ordering::Relaxed);
+        }
+        replica
+            .last_timestamp_ms
+            .store(entry.timestamp_ms, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // 不暂停写入读出快照，读完时开始接收之后的写操作，然后依次返回快照和写操作
+    pub(super) fn replicate(&self) -> StreamingResponse {
+        let service = self.clone();
+        let capture = task::spawn_blocking(move || {
+            let log = &service.inner.replication;
+            // 持有序号时没有正在进行的写入，快照正好是这个序号之后的状态
+            service.capture_snapshot(|sequencer| (sequencer.seq(), log.sender.subscribe()))
+        });
+        let service = self.clone();
+        let res = stream::once(capture).flat_map(move |res| {
+            let res =
+                res.unwrap_or_else(|e| Err(KvError::Internal(format!("Snapshot failed: {}", e))));
+            match res {
+                Ok((snapshot, (seq, rx))) => service.replication_stream(snapshot, seq, rx),
+                Err(e) => service.respond(e.into()),
+            }
+        });
+        Box::pin(res)
+    }
+
+    // 先返回快照，再返回 seq 之后的写操作
+    fn replication_stream(
+        &self,
+        snapshot: Snapshot,
+        seq: u64,
+        rx: broadcast::Receiver<Arc<CommandResponse>>,
+    ) -> StreamingResponse {
+        let log = &self.inner.replication;
+        let id = log.next_stream.fetch_add(1, Ordering::Relaxed);
+        let size = snapshot.size();
+        info!(
+            "Replication stream {} starts at seq {} with a {} byte snapshot",
+            id, seq, size
+        );
+        let header: CommandResponse = Value::from(id as i64).into();
+        let position: CommandResponse =
+            vec![Value::from(seq as i64), Value::from(size as i64)].into();
+        let head = stream::iter([header, position].map(Arc::new)).chain(snapshot_chunks(snapshot));
+
+        let mut ticker = time::interval(log.heartbeat);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let writes = stream::unfold(Some((rx, ticker)), move |state| async move {
+            let (mut rx, mut ticker) = state?;
+            let res = tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(res) => res,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Replication stream {} fell {} writes behind", id, n);
+                        let e = KvError::Unavailable(format!("Follower fell {} writes behind", n));
+                        return Some((Arc::new(e.into()), None));
+                    }
+                    Err(RecvError::Closed) => return None,
+                },
+                _ = ticker.tick() => {
+                    let heartbeat = ReplicationEntry {
+                        timestamp_ms: now_ms(),
+                        ..Default::default()
+                    };
+                    Arc::new(heartbeat.into())
+                }
+            };
+            Some((res, Some((rx, ticker))))
+        });
+        Box::pin(head.chain(writes))
+    }
+
+    // INFO 中复制相关的项
+    pub(super) fn replication_info(&self) -> Vec<Kvpair> {
+        let replica = match &self.inner.replica {
+            Some(replica) => replica,
+            None => {
+                let log = &self.inner.replication;
+                return vec![
+                    Kvpair::new("role", "leader".into()),
+                    Kvpair::new("replication_seq", (log.seq() as i64).into()),
+                    Kvpair::new("connected_followers", (log.followers() as i64).into()),
+                ];
+            }
+        };
+        let link = match replica.is_connected() {
+            true => "up",
+            false => "down",
+        };
+        let full_syncs = replica.full_syncs.load(Ordering::Relaxed);
+        let mut pairs = vec![
+            Kvpair::new("role", "follower".into()),
+            Kvpair::new("leader", replica.leader.as_str().into()),
+            Kvpair::new("link_status", link.into()),
+            Kvpair::new("replication_seq", (replica.seq() as i64).into()),
+            Kvpair::new("replication_full_syncs", (full_syncs as i64).into()),
+        ];
+        if let Some(lag) = replica.lag() {
+            let lag = lag.as_millis() as i64;
+            pairs.push(Kvpair::new("replication_lag_ms", lag.into()));
+        }
+        pairs
+    }
+}
+
+// 复制流中的下一个响应，出错的响应转换成 KvError
+async fn next_response(stream: &mut StreamResult) -> Result<CommandResponse, KvError> {
+    let res = stream
+        .next()
+        .await
+        .ok_or_else(|| KvError::Unavailable("Leader closed the replication stream".into()))??;
+    match is_success(&res) {
+        true => Ok(res),
+        false => Err(res.into()),
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::{
+        KvClient, MemTable, ServerBuilder, ServerConfig, ServerHandle, ServiceInner,
+        TlsServerAcceptor,
+    };
+
+    #[test]
+    fn writes_should_only_be_serialized_when_needed() {
+        let log = ReplicationLog::default();
+        let set = |key: &str| WriteOp::Set {
+            table: "t1".into(),
+            key: key.into(),
+            value: "v".into(),
+        };
+        // 没有 follower 也没有在读取快照时，写入之间不用等待
+        let first = log.begin();
+        let second = log.begin();
+        first.record(vec![set("k1")]);
+        second.record(vec![set("k2")]);
+        assert_eq!(log.seq(), 2);
+
+        // 读取快照期间写入独占，修改过的 key 都被收集到
+        let changes = log.track_changes();
+        let mut sequencer = log.begin();
+        assert!(sequencer.state_mut().is_some());
+        sequencer.record(vec![set("k3")]);
+        let mut sequencer = log.begin();
+        let changed = changes.finish(&mut sequencer);
+        assert_eq!(changed, HashSet::from([("t1".into(), "k3".into())]));
+        assert_eq!(sequencer.seq(), 3);
+    }
+
+    #[tokio::test]
+    async fn replicated_writes_should_skip_failed_commands() {
+        let service: Service = ServiceInner::new(MemTable::new()).into();
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hset("t1", "k1", "v1".into()),
+                CommandRequest::new_hget("t1", "missing"),
+                CommandRequest::new_hdel("t1", "k1"),
+            ],
+            true,
+        );
+        let res = service.execute(cmd.clone()).next().await.unwrap();
+        // 出错的 hget 之后的命令没有执行
+        assert_eq!(
+            replicated_writes(&cmd, &res),
+            vec![WriteOp::Set {
+                table: "t1".into(),
+                key: "k1".into(),
+                value: "v1".into(),
+            }]
+        );
+        assert_eq!(service.inner.replication.seq(), 1);
+
+        // 没有修改任何 key 的命令不占用序号
+        let cmd = CommandRequest::new_hmset("t1", vec![]);
+        service.execute(cmd).next().await.unwrap();
+        assert_eq!(service.inner.replication.seq(), 1);
+    }
+
+    #[tokio::test]
+    async fn followers_should_replicate_from_leader() {
+        let leader = start(MemTable::new(), None).await;
+        let client = connect(leader.local_addr()).await;
+        client.set("t1", "k1", "v1").await.unwrap();
+        client.set("t1", "k2", "v2").await.unwrap();
+
+        // 快照中没有的旧数据会被删除
+        let stale = MemTable::new();
+        stale.set("t1", "old".into(), "x".into()).unwrap();
+        stale.set("t9", "k1".into(), "x".into()).unwrap();
+        let followers = [
+            start(stale, Some(leader.local_addr())).await,
+            start(MemTable::new(), Some(leader.local_addr())).await,
+        ];
+
+        // 同步快照之后的写入按顺序应用
+        client.set("t1", "k3", "v3").await.unwrap();
+        client.del("t1", "k2").await.unwrap();
+        let batch = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hmset("t2", vec![Kvpair::new("k1", 1.into())]),
+                CommandRequest::new_hdel("t1", "k3"),
+            ],
+            false,
+        );
+        client.execute(&batch).await.unwrap();
+        client.set("t1", "k4", "v4").await.unwrap();
+
+        for follower in &followers {
+            let replica = connect(follower.local_addr()).await;
+            wait_for(&replica, "t1", "k4").await;
+            let mut pairs = replica.get_all("t1").await.unwrap();
+            pairs.sort_by(|a, b| a.key.cmp(&b.key));
+            assert_eq!(
+                pairs,
+                vec![
+                    Kvpair::new("k1", "v1".into()),
+                    Kvpair::new("k4", "v4".into())
+                ]
+            );
+            assert_eq!(replica.get("t2", "k1").await.unwrap(), Some(1.into()));
+            assert_eq!(replica.get_all("t9").await.unwrap(), vec![]);
+
+            // 写命令被拒绝，错误中带着 leader 的地址
+            match replica.set("t1", "k5", "v5").await {
+                Err(KvError::ReadOnly(addr)) => assert_eq!(addr, leader.local_addr().to_string()),
+                res => panic!("expect ReadOnly, got {:?}", res),
+            }
+            let res = replica.execute(&CommandRequest::new_flush_all("")).await;
+            assert!(matches!(res, Err(KvError::ReadOnly(_))));
+
+            let info = info(&replica).await;
+            assert_eq!(info["role"], Value::from("follower"));
+            assert_eq!(info["link_status"], Value::from("up"));
+            assert_eq!(info["replication_seq"], Value::from(6));
+            assert_eq!(info["replication_full_syncs"], Value::from(1));
+            let lag: i64 = (&info["replication_lag_ms"]).try_into().unwrap();
+            assert!((0..5000).contains(&lag), "lag is {}", lag);
+        }
+
+        let info = info(&client).await;
+        assert_eq!(info["role"], Value::from("leader"));
+        assert_eq!(info["replication_seq"], Value::from(6));
+        assert_eq!(info["connected_followers"], Value::from(2));
+
+        for follower in followers {
+            follower.shutdown().await.unwrap();
+        }
+        leader.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn follower_should_resync_after_leader_restart() {
+        let leader = start(MemTable::new(), None).await;
+        let addr = leader.local_addr();
+        let client = connect(addr).await;
+        client.set("t1", "k1", "v1").await.unwrap();
+
+        let follower = start(MemTable::new(), Some(addr)).await;
+        let replica = connect(follower.local_addr()).await;
+        wait_for(&replica, "t1", "k1").await;
+
+        // leader 在同一个端口上重启后数据都没有了，follower 重新全量同步
+        leader.shutdown().await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(info(&replica).await["link_status"], Value::from("down"));
+        let leader = start_at(MemTable::new(), None, addr).await;
+        let client = connect(addr).await;
+        client.set("t1", "k2", "v2").await.unwrap();
+
+        wait_for(&replica, "t1", "k2").await;
+        assert_eq!(replica.get("t1", "k1").await.unwrap(), None);
+        let info = info(&replica).await;
+        assert_eq!(info["replication_full_syncs"], Value::from(2));
+        assert_eq!(info["replication_seq"], Value::from(1));
+
+        follower.shutdown().await.unwrap();
+        leader.shutdown().await.unwrap();
+    }
+
+    async fn start(store: MemTable, leader: Option<SocketAddr>) -> ServerHandle {
+        start_at(store, leader, "127.0.0.1:0".parse().unwrap()).await
+    }
+
+    // leader 为 None 时启动 leader，否则启动连接这个 leader 的 follower
+    async fn start_at(
+        store: MemTable,
+        leader: Option<SocketAddr>,
+        addr: SocketAddr,
+    ) -> ServerHandle {
+        let config: ServerConfig =
+            toml::from_str(include_str!("../../fixtures/server.conf")).unwrap();
+        let tls = TlsServerAcceptor::new(&config.tls.cert, &config.tls.key, None).unwrap();
+        let replication = ReplicationConfig {
+            heartbeat_ms: 50,
+            leader: leader.map(client_config),
+            ..Default::default()
+        };
+        ServerBuilder::new(store)
+            .tls(tls)
+            .listen(addr.to_string())
+            .replication(replication)
+            .start()
+            .await
+            .unwrap()
+    }
+
+    fn client_config(addr: SocketAddr) -> ClientConfig {
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.to_string();
+        config.reconnect.initial_delay_ms = 20;
+        config.reconnect.max_delay_ms = 100;
+        config
+    }
+
+    async fn connect(addr: SocketAddr) -> KvClient {
+        KvClient::connect(&client_config(addr)).await.unwrap()
+    }
+
+    // 复制是异步的，等 follower 上出现这个 key
+    async fn wait_for(client: &KvClient, table: &str, key: &str) {
+        for _ in 0..100 {
+            if client.get(table, key).await.unwrap().is_some() {
+                return;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("{}:{} is not replicated", table, key);
+    }
+
+    async fn info(client: &KvClient) -> HashMap<String, Value> {
+        let res = client.execute(&CommandRequest::new_info()).await.unwrap();
+        res.pairs
+            .into_iter()
+            .map(|p| (p.key, p.value.unwrap_or_default()))
+            .collect()
+    }
+}
@@ -0,0 +1,172 @@
+//! 慢命令日志：处理时间超过阈值的命令保存在内存里，用 SlowlogGet 查看
+//!
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::command_request::RequestData;
+use crate::{CommandRequest, Session};
+
+// 命令摘要中最多列出的 key 个数
+const MAX_ARGS: usize = 8;
+
+/// 一条慢命令
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowlogEntry {
+    pub id: u64,
+    // 命令完成时的 Unix 时间，单位秒
+    pub time: u64,
+    pub duration: Duration,
+    pub identity: String,
+    pub peer: String,
+    // 命令的名字和 table、key 等参数，不包括值
+    pub command: String,
+}
+
+// id=1 time=1700000000 duration_us=12000 identity=alice peer=127.0.0.1:5000 cmd=hget t1 k1
+impl fmt::Display for SlowlogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "id={} time={} duration_us={} identity={} peer={} cmd={}",
+            self.id,
+            self.time,
+            self.duration.as_micros(),
+            self.identity,
+            self.peer,
+            self.command
+        )
+    }
+}
+
+/// 最近的慢命令，超过上限时丢弃最旧的
+#[derive(Debug, Default)]
+pub struct SlowLog {
+    entries: Mutex<VecDeque<SlowlogEntry>>,
+    next_id: AtomicU64,
+}
+
+impl SlowLog {
+    /// 记录一条慢命令，最多保留 max_len 条
+    pub fn record(&self, session: &Session, command: String, duration: Duration, max_len: usize) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let entry = SlowlogEntry {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            time,
+            duration,
+            identity: session.identity().label(),
+            peer: session.peer().to_string(),
+            command,
+        };
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.push_front(entry);
+        entries.truncate(max_len);
+    }
+
+    /// 从新到旧最多 count 条，count 为 0 时返回全部
+    pub fn get(&self, count: usize) -> Vec<SlowlogEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let count = match count {
+            0 => entries.len(),
+            n => n,
+        };
+        entries.iter().take(count).cloned().collect()
+    }
+
+    /// 当前保留的条数
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 命令的摘要：名字加上 table、topic 和 key，比如 `hmget t1 k1 k2`
+pub fn summarize(cmd: &CommandRequest) -> String {
+    let args: Vec<&str> = match &cmd.request_data {
+        Some(RequestData::Hget(v)) => vec![&v.table, &v.key],
+        Some(RequestData::Hgetall(v)) => vec![&v.table],
+        Some(RequestData::Hmget(v)) => table_and_keys(&v.table, &v.keys),
+        Some(RequestData::Hset(v)) => {
+            let mut args = vec![v.table.as_str()];
+            args.extend(v.pair.iter().map(|p| p.key.as_str()));
+            args
+        }
+        Some(RequestData::Hmset(v)) => {
+            let mut args = vec![v.table.as_str()];
+            args.extend(v.pairs.iter().map(|p| p.key.as_str()));
+            args
+        }
+        Some(RequestData::Hdel(v)) => vec![&v.table, &v.key],
+        Some(RequestData::Hmdel(v)) => table_and_keys(&v.table, &v.keys),
+        Some(RequestData::Hexist(v)) => vec![&v.table, &v.key],
+        Some(RequestData::Hmexist(v)) => table_and_keys(&v.table, &v.keys),
+        Some(RequestData::Hscan(v)) => vec![&v.table, &v.cursor],
+        Some(RequestData::ListTables(v)) => vec![&v.pattern],
+        Some(RequestData::Subscribe(v)) => vec![&v.topic],
+        Some(RequestData::Unsubscribe(v)) => vec![&v.topic],
+        Some(RequestData::Publish(v)) => vec![&v.topic],
+        Some(RequestData::FlushAll(v)) => vec![&v.table],
+        Some(RequestData::Batch(v)) => {
+            return format!("{} {}", cmd.name(), v.requests.len());
+        }
+        _ => vec![],
+    };
+
+    let mut summary = cmd.name().to_string();
+    for arg in args.iter().take(MAX_ARGS) {
+        summary.push(' ');
+        summary.push_str(arg);
+    }
+    if args.len() > MAX_ARGS {
+        summary.push_str(" ...");
+    }
+    summary
+}
+
+fn table_and_keys<'a>(table: &'a str, keys: &'a [String]) -> Vec<&'a str> {
+    let mut args = vec![table];
+    args.extend(keys.iter().map(|k| k.as_str()));
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slowlog_should_keep_latest_entries() {
+        let slowlog = SlowLog::default();
+        let session = Session::new().with_peer("127.0.0.1:5000");
+        for i in 0..5 {
+            let command = summarize(&CommandRequest::new_hget("t1", format!("k{}", i)));
+            slowlog.record(&session, command, Duration::from_millis(20), 3);
+        }
+
+        assert_eq!(slowlog.len(), 3);
+        let entries = slowlog.get(2);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].id, 4);
+        assert_eq!(entries[0].command, "hget t1 k4");
+        let line = entries[0].to_string();
+        assert!(line.contains("duration_us=20000 identity=anonymous peer=127.0.0.1:5000"));
+        assert!(line.ends_with("cmd=hget t1 k4"));
+    }
+
+    #[test]
+    fn summary_should_not_include_values() {
+        let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+        let cmd = CommandRequest::new_hmget("t1", keys);
+        assert_eq!(summarize(&cmd), "hmget t1 k0 k1 k2 k3 k4 k5 k6 ...");
+        let cmd = CommandRequest::new_hset("t1", "k1", "secret".into());
+        assert_eq!(summarize(&cmd), "hset t1 k1");
+    }
+}
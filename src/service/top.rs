@@ -6,7 +6,7 @@ use std::sync::{
 use tokio::sync::mpsc;
 
 use crate::error::KvError;
-use crate::{CommandResponse, Value};
+use crate::{metrics, CommandResponse, Value};
 use tracing::{debug, info, warn};
 
 //topic里最大存放的数据
@@ -40,7 +40,10 @@ pub struct Broadcaster {
 impl Topic for Arc<Broadcaster> {
     fn subscribe(self, name: String) -> mpsc::Receiver<Arc<CommandResponse>> {
         let id = {
-            let entry = self.topics.entry(name).or_default();
+            let entry = self.topics.entry(name).or_insert_with(|| {
+                metrics().topics.inc();
+                DashSet::new()
+            });
 
             let id = get_next_subscription_id();
             entry.value().insert(id);
@@ -60,6 +63,7 @@ impl Topic for Arc<Broadcaster> {
         });
 
         self.subscriptions.insert(id, tx);
+        metrics().subscribers.inc();
         debug!("Subscription {} is added", id);
 
         rx
@@ -72,31 +76,40 @@ impl Topic for Arc<Broadcaster> {
             if v.is_empty() {
                 info!("Topic: {:?} is deleted", &name);
                 drop(v);
-                self.topics.remove(&name);
+                if self.topics.remove(&name).is_some() {
+                    metrics().topics.dec();
+                }
             }
         }
 
         debug!("Subscription {} is removed!", id);
-        self.subscriptions.remove(&id);
+        if self.subscriptions.remove(&id).is_some() {
+            metrics().subscribers.dec();
+        }
 
         Ok(id)
     }
 
     fn publish(self, name: String, value: Arc<CommandResponse>) {
+        metrics().published_messages.inc();
         tokio::spawn(async move {
-            match self.topics.get(&name) {
-                Some(chan) => {
-                    let chan = chan.value().clone();
-
-                    for id in chan.into_iter() {
-                        if let Some(tx) = self.subscriptions.get(&id) {
-                            if let Err(e) = tx.send(value.clone()).await {
-                                warn!("Publish to {} failed! error: {:?}", id, e);
-                            }
-                        }
-                    }
+            let chan = match self.topics.get(&name) {
+                Some(chan) => chan.value().clone(),
+                None => return,
+            };
+
+            for id in chan.into_iter() {
+                // 不要在 await 的时候持有 DashMap 的锁
+                let tx = match self.subscriptions.get(&id) {
+                    Some(tx) => tx.value().clone(),
+                    None => continue,
+                };
+                if let Err(e) = tx.send(value.clone()).await {
+                    // 订阅者已经断开，清理掉这个订阅
+                    warn!("Publish to {} failed! error: {:?}", id, e);
+                    metrics().dropped_messages.inc();
+                    let _ = self.clone().unsubscribe(name.clone(), id);
                 }
-                None => {}
             }
         });
     }
@@ -137,11 +150,11 @@ mod tests {
         let res2 = stream2.recv().await.unwrap();
 
         assert_eq!(res1, res2);
-        assert_res_ok(&res1, &[v.clone()], &[]);
+        assert_res_ok(&res1, std::slice::from_ref(&v), &[]);
 
         // 如果 subscriber 取消订阅，则收不到新数据
         let result = b.clone().unsubscribe(lobby.clone(), id1 as _).unwrap();
-        assert_eq!(result, id1 as _);
+        assert_eq!(result, id1 as u32);
 
         // publish
         let v: Value = "world".into();
@@ -149,7 +162,7 @@ mod tests {
 
         assert!(stream1.recv().await.is_none());
         let res2 = stream2.recv().await.unwrap();
-        assert_res_ok(&res2, &[v.clone()], &[]);
+        assert_res_ok(&res2, std::slice::from_ref(&v), &[]);
     }
 
     pub async fn get_id(res: &mut Receiver<Arc<CommandResponse>>) -> u32 {
@@ -1,10 +1,9 @@
 use std::pin::Pin;
 use std::sync::Arc;
 
-use crate::{CommandResponse, Publish, Subscribe, Unsubscribe};
+use crate::{CommandResponse, Publish, Subscribe, TraceContext, Unsubscribe};
 use futures::stream;
 use futures::Stream;
-use sled::Subscriber;
 use tokio_stream::wrappers::ReceiverStream;
 
 use super::top::Topic;
@@ -23,14 +22,30 @@ impl TopicService for Subscribe {
 
 impl TopicService for Unsubscribe {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        topic.unsubscribe(self.topic, self.id);
-        Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
+        let res = match topic.unsubscribe(self.topic, self.id) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        };
+        Box::pin(stream::once(async { Arc::new(res) }))
     }
 }
 
 impl TopicService for Publish {
     fn execute(self, topic: impl Topic) -> StreamingResponse {
-        topic.publish(self.topic, Arc::new(self.data.into()));
+        self.execute_traced(topic, None)
+    }
+}
+
+impl Publish {
+    /// 发布消息，订阅者收到的消息带上 trace context
+    pub fn execute_traced(
+        self,
+        topic: impl Topic,
+        trace: Option<TraceContext>,
+    ) -> StreamingResponse {
+        let mut msg: CommandResponse = self.data.into();
+        msg.trace = trace;
+        topic.publish(self.topic, Arc::new(msg));
         Box::pin(stream::once(async { Arc::new(CommandResponse::ok()) }))
     }
 }
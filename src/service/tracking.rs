@@ -0,0 +1,448 @@
+//! 客户端缓存的 key 跟踪：记录每个连接读过的 key，这些 key 被修改时通知对应的连接
+//!
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use dashmap::{DashMap, DashSet};
+use futures::Stream;
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, warn};
+
+use crate::pb::command_request::RequestData;
+use crate::{CommandRequest, CommandResponse, Identity, Value};
+
+// 每个连接积压的失效通知个数，满了之后关闭这个连接的跟踪，客户端会清空缓存
+const INVALIDATION_CAPACITY: usize = 1024;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// 一个客户端连接，同一个连接上的所有 yamux stream 共享
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    // 连接的身份，执行 Auth 命令后会改变
+    identity: RwLock<Identity>,
+    // 客户端的地址，用于日志
+    peer: Option<String>,
+    // 最近一次收到请求的时间，相对于 created 的毫秒数
+    created: Instant,
+    last_active_ms: AtomicU64,
+    // 正在处理的请求个数，有请求（比如订阅）没结束时连接不算空闲
+    in_flight: AtomicUsize,
+    // 打开的 stream 个数和最近一个命令的名字，用于 CLIENT LIST
+    streams: AtomicUsize,
+    last_command: Mutex<&'static str>,
+    // CLIENT KILL 之后连接上所有的 stream 都会结束
+    killed: AtomicBool,
+    kill: Notify,
+}
+
+impl Session {
+    pub fn new() -> Self {
+        Self::with_identity(Identity::Anonymous)
+    }
+
+    /// 使用 TLS 握手得到的身份创建 session
+    pub fn with_identity(identity: Identity) -> Self {
+        Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            identity: RwLock::new(identity),
+            peer: None,
+            created: Instant::now(),
+            last_active_ms: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+            streams: AtomicUsize::new(0),
+            last_command: Mutex::new(""),
+            killed: AtomicBool::new(false),
+            kill: Notify::new(),
+        }
+    }
+
+    /// 记录客户端的地址
+    pub fn with_peer(mut self, peer: impl Into<String>) -> Self {
+        self.peer = Some(peer.into());
+        self
+    }
+
+    /// 客户端的地址，不是来自网络连接时为 "local"
+    pub fn peer(&self) -> &str {
+        self.peer.as_deref().unwrap_or("local")
+    }
+
+    /// 记录连接上有新的请求，所有 stream 共用一个活动时间
+    pub fn touch(&self) {
+        let elapsed = self.created.elapsed().as_millis() as u64;
+        self.last_active_ms.fetch_max(elapsed, Ordering::Relaxed);
+    }
+
+    /// 开始处理一个请求，返回的 guard 释放时请求结束
+    pub fn begin_request(&self, command: &'static str) -> InFlight<'_> {
+        self.touch();
+        *self.last_command.lock().unwrap_or_else(|e| e.into_inner()) = command;
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    /// 连接多久没有收到请求了，有请求正在处理时总是 0
+    pub fn idle_for(&self) -> Duration {
+        if self.in_flight.load(Ordering::Relaxed) > 0 {
+            return Duration::ZERO;
+        }
+        let last = Duration::from_millis(self.last_active_ms.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last)
+    }
+
+    /// 最近一个命令的名字，还没有收到命令时为空
+    pub fn last_command(&self) -> &'static str {
+        *self.last_command.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// 连接建立了多久
+    pub fn age(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// 开始在这个连接上处理一个 stream，返回的 guard 释放时 stream 结束
+    pub fn enter_stream(&self) -> OpenStream<'_> {
+        self.streams.fetch_add(1, Ordering::Relaxed);
+        OpenStream(self)
+    }
+
+    /// 打开的 stream 个数
+    pub fn streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+
+    /// 关闭这个连接
+    pub fn kill(&self) {
+        self.killed.store(true, Ordering::Release);
+        self.kill.notify_waiters();
+    }
+
+    /// 等待连接被关闭，已经关闭时立即返回
+    pub async fn killed(&self) {
+        let notified = self.kill.notified();
+        if self.killed.load(Ordering::Acquire) {
+            return;
+        }
+        notified.await;
+    }
+
+    pub fn identity(&self) -> Identity {
+        self.identity
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    pub fn set_identity(&self, identity: Identity) {
+        *self.identity.write().unwrap_or_else(|e| e.into_inner()) = identity;
+    }
+}
+
+/// 正在处理的请求，释放时更新连接的活动时间
+pub struct InFlight<'a>(&'a Session);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// 正在处理的 stream，释放时减少连接的 stream 个数
+pub struct OpenStream<'a>(&'a Session);
+
+impl Drop for OpenStream<'_> {
+    fn drop(&mut self) {
+        self.0.streams.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// key 跟踪表
+#[derive(Default)]
+pub struct Tracker {
+    // (table, key) -> 读过这个 key 的连接
+    keys: DashMap<(String, String), DashSet<u64>>,
+    // 开启了跟踪的连接
+    sessions: DashMap<u64, Tracking>,
+    next_token: AtomicU64,
+}
+
+// 一个连接的跟踪状态
+struct Tracking {
+    // 用来区分同一个连接的多次开启
+    token: u64,
+    // 发送失效通知的 channel
+    tx: mpsc::Sender<Arc<CommandResponse>>,
+    // 这个连接跟踪的 key，关闭跟踪时从 keys 中清理掉
+    keys: HashSet<(String, String)>,
+}
+
+impl Tracker {
+    /// 为连接开启跟踪，返回的 stream 第一条是连接 id，之后是失效通知，stream 被释放时关闭跟踪
+    pub fn enable(self: Arc<Self>, session: u64) -> TrackingStream {
+        let (tx, rx) = mpsc::channel(INVALIDATION_CAPACITY);
+        let v: Value = (session as i64).into();
+        // channel 刚创建，一定有空间
+        tx.try_send(Arc::new(v.into())).unwrap();
+
+        // 同一个连接重复开启时，旧的 stream 会结束
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let tracking = Tracking {
+            token,
+            tx,
+            keys: HashSet::new(),
+        };
+        if let Some(old) = self.sessions.insert(session, tracking) {
+            self.purge(session, old.keys);
+        }
+        debug!("Tracking is enabled for session {}", session);
+
+        TrackingStream {
+            rx,
+            tracker: self,
+            session,
+            token,
+        }
+    }
+
+    pub fn is_tracking(&self, session: u64) -> bool {
+        self.sessions.contains_key(&session)
+    }
+
+    /// 记录连接读过的 key，连接没有开启跟踪时什么都不做。要在读取之前调用，否则读取期间的修改不会通知
+    pub fn track(&self, session: u64, table: &str, key: &str) {
+        // 持有连接的状态，保证关闭跟踪时能清理掉这里加入的 key
+        let mut tracking = match self.sessions.get_mut(&session) {
+            Some(tracking) => tracking,
+            None => return,
+        };
+        let k = (table.to_string(), key.to_string());
+        self.keys.entry(k.clone()).or_default().insert(session);
+        tracking.keys.insert(k);
+    }
+
+    /// key 被修改，通知所有读过它的连接。通知只发一次，客户端再次读取后才会重新跟踪
+    /// 通知积压满了的连接会被关闭跟踪，客户端发现跟踪 stream 结束后会清空整个缓存
+    pub fn invalidate(&self, table: &str, key: &str) {
+        let k = (table.to_string(), key.to_string());
+        let sessions = match self.keys.remove(&k) {
+            Some((_, sessions)) => sessions,
+            None => return,
+        };
+
+        let res: CommandResponse = vec![Value::from(table), Value::from(key)].into();
+        let res = Arc::new(res);
+        let mut overflowed = Vec::new();
+        for session in sessions.into_iter() {
+            if let Some(mut tracking) = self.sessions.get_mut(&session) {
+                // 期间又读过这个 key 的话需要继续跟踪
+                if !self.keys.get(&k).is_some_and(|s| s.contains(&session)) {
+                    tracking.keys.remove(&k);
+                }
+                if let Err(e) = tracking.tx.try_send(res.clone()) {
+                    warn!(
+                        "Failed to invalidate {}:{} for {}, resetting tracking: {:?}",
+                        table, key, session, e
+                    );
+                    overflowed.push((session, tracking.token));
+                }
+            }
+        }
+        for (session, token) in overflowed {
+            self.disable(session, token);
+        }
+    }
+
+    // 只有当前的 stream 才能关闭跟踪，被替换掉的旧 stream 释放时什么都不做
+    // 关闭后发送端被释放，stream 发完积压的通知后结束
+    fn disable(&self, session: u64, token: u64) {
+        if let Some((_, tracking)) = self.sessions.remove_if(&session, |_, t| t.token == token) {
+            self.purge(session, tracking.keys);
+            debug!("Tracking is disabled for session {}", session);
+        }
+    }
+
+    // 把连接从它跟踪的 key 中去掉
+    fn purge(&self, session: u64, keys: HashSet<(String, String)>) {
+        for k in keys {
+            self.keys.remove_if(&k, |_, sessions| {
+                sessions.remove(&session);
+                sessions.is_empty()
+            });
+        }
+    }
+}
+
+/// Track 命令返回的 stream
+pub struct TrackingStream {
+    rx: mpsc::Receiver<Arc<CommandResponse>>,
+    tracker: Arc<Tracker>,
+    session: u64,
+    token: u64,
+}
+
+impl Stream for TrackingStream {
+    type Item = Arc<CommandResponse>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for TrackingStream {
+    fn drop(&mut self) {
+        self.tracker.disable(self.session, self.token);
+    }
+}
+
+/// 命令读取的 key
+pub fn read_keys(cmd: &CommandRequest) -> Vec<(&str, &str)> {
+    match &cmd.request_data {
+        Some(RequestData::Hget(v)) => vec![(v.table.as_str(), v.key.as_str())],
+        Some(RequestData::Hmget(v)) => v
+            .keys
+            .iter()
+            .map(|k| (v.table.as_str(), k.as_str()))
+            .collect(),
+        Some(RequestData::Batch(v)) => v.requests.iter().flat_map(read_keys).collect(),
+        _ => vec![],
+    }
+}
+
+/// 命令修改的 key
+pub fn written_keys(cmd: &CommandRequest) -> Vec<(&str, &str)> {
+    match &cmd.request_data {
+        Some(RequestData::Hset(v)) => v
+            .pair
+            .iter()
+            .map(|p| (v.table.as_str(), p.key.as_str()))
+            .collect(),
+        Some(RequestData::Hmset(v)) => v
+            .pairs
+            .iter()
+            .map(|p| (v.table.as_str(), p.key.as_str()))
+            .collect(),
+        Some(RequestData::Hdel(v)) => vec![(v.table.as_str(), v.key.as_str())],
+        Some(RequestData::Hmdel(v)) => v
+            .keys
+            .iter()
+            .map(|k| (v.table.as_str(), k.as_str()))
+            .collect(),
+        Some(RequestData::Batch(v)) => v.requests.iter().flat_map(written_keys).collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tracker_should_notify_once() {
+        let tracker = Arc::new(Tracker::default());
+        let mut stream = tracker.clone().enable(1);
+        let id: i64 = stream.next().await.unwrap().as_ref().try_into().unwrap();
+        assert_eq!(id, 1);
+
+        tracker.track(1, "t1", "k1");
+        // 没有读过的 key 不会通知
+        tracker.invalidate("t1", "k2");
+        tracker.invalidate("t1", "k1");
+        tracker.invalidate("t1", "k1");
+
+        let res = stream.next().await.unwrap();
+        assert_eq!(res.values, vec!["t1".into(), "k1".into()]);
+        assert!(stream.rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn dropping_stream_should_disable_tracking() {
+        let tracker = Arc::new(Tracker::default());
+        let old = tracker.clone().enable(1);
+        let new = tracker.clone().enable(1);
+
+        // 被替换的旧 stream 不影响新的
+        drop(old);
+        assert!(tracker.is_tracking(1));
+        drop(new);
+        assert!(!tracker.is_tracking(1));
+    }
+
+    #[tokio::test]
+    async fn disabling_should_purge_tracked_keys() {
+        let tracker = Arc::new(Tracker::default());
+        // 没有开启跟踪的连接不记录
+        tracker.track(1, "t1", "k1");
+        assert!(tracker.keys.is_empty());
+
+        let stream = tracker.clone().enable(1);
+        tracker.track(1, "t1", "k1");
+        tracker.track(1, "t1", "k2");
+        tracker.invalidate("t1", "k1");
+        assert_eq!(tracker.keys.len(), 1);
+
+        drop(stream);
+        assert!(tracker.keys.is_empty());
+    }
+
+    #[tokio::test]
+    async fn overflow_should_reset_tracking() {
+        let tracker = Arc::new(Tracker::default());
+        let mut stream = tracker.clone().enable(1);
+        // 第一条是连接 id，之后的通知填满 channel
+        for i in 1..INVALIDATION_CAPACITY {
+            let key = format!("k{}", i);
+            tracker.track(1, "t1", &key);
+            tracker.invalidate("t1", &key);
+        }
+        tracker.track(1, "t1", "k");
+        assert!(tracker.is_tracking(1));
+
+        // 发不出去的通知会关闭跟踪
+        tracker.track(1, "t1", "k0");
+        tracker.invalidate("t1", "k0");
+
+        // 关闭跟踪后积压的通知发完，stream 结束
+        let mut received = 0;
+        while stream.next().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, INVALIDATION_CAPACITY);
+        assert!(!tracker.is_tracking(1));
+        assert!(tracker.keys.is_empty());
+    }
+
+    #[test]
+    fn keys_in_batch_should_be_collected() {
+        let cmd = CommandRequest::new_batch(
+            vec![
+                CommandRequest::new_hget("t1", "k1"),
+                CommandRequest::new_hset("t1", "k2", "v2".into()),
+                CommandRequest::new_hmdel("t2", vec!["k3".into(), "k4".into()]),
+            ],
+            false,
+        );
+        assert_eq!(read_keys(&cmd), vec![("t1", "k1")]);
+        assert_eq!(
+            written_keys(&cmd),
+            vec![("t1", "k2"), ("t2", "k3"), ("t2", "k4")]
+        );
+    }
+}
@@ -0,0 +1,159 @@
+//! 运行时配置：可以通过 CONFIG GET/SET 读取和修改，服务器和所有连接共享
+//!
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::{glob_match, AdmissionConfig, KvError, SlowlogConfig, TimeoutConfig, Value};
+
+/// 运行时配置，名字和配置文件中的一致，0 表示不限制
+///
+/// 连接数和请求数的上限立即生效，超时和每个连接的 stream 上限对之后建立的连接生效
+#[derive(Debug)]
+pub struct RuntimeSettings {
+    pub(crate) idle_timeout_ms: Arc<AtomicU64>,
+    pub(crate) handshake_timeout_ms: Arc<AtomicU64>,
+    pub(crate) max_connections: Arc<AtomicU64>,
+    pub(crate) max_streams_per_connection: Arc<AtomicU64>,
+    pub(crate) max_in_flight: Arc<AtomicU64>,
+    pub(crate) slowlog_threshold_us: Arc<AtomicU64>,
+    pub(crate) slowlog_max_len: Arc<AtomicU64>,
+}
+
+impl RuntimeSettings {
+    pub fn new(
+        timeouts: &TimeoutConfig,
+        admission: &AdmissionConfig,
+        slowlog: &SlowlogConfig,
+    ) -> Self {
+        let value = |v: u64| Arc::new(AtomicU64::new(v));
+        Self {
+            idle_timeout_ms: value(timeouts.idle_timeout_ms),
+            handshake_timeout_ms: value(timeouts.handshake_timeout_ms),
+            max_connections: value(admission.max_connections as u64),
+            max_streams_per_connection: value(admission.max_streams_per_connection as u64),
+            max_in_flight: value(admission.max_in_flight as u64),
+            slowlog_threshold_us: value(slowlog.threshold_us),
+            slowlog_max_len: value(slowlog.max_len as u64),
+        }
+    }
+
+    /// 连接上没有请求超过这个时间就关闭
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        millis(&self.idle_timeout_ms)
+    }
+
+    /// TLS 握手的最长时间
+    pub fn handshake_timeout(&self) -> Option<Duration> {
+        millis(&self.handshake_timeout_ms)
+    }
+
+    /// 慢命令的阈值，慢命令日志关闭时返回 None
+    pub fn slowlog_threshold(&self) -> Option<Duration> {
+        match self.slowlog_max_len() {
+            0 => None,
+            _ => Some(Duration::from_micros(
+                self.slowlog_threshold_us.load(Ordering::Relaxed),
+            )),
+        }
+    }
+
+    /// 慢命令日志最多保留的条数
+    pub fn slowlog_max_len(&self) -> usize {
+        self.slowlog_max_len.load(Ordering::Relaxed) as usize
+    }
+
+    /// 名字匹配 pattern 的配置和它们的值，pattern 为空时返回全部
+    pub fn get(&self, pattern: &str) -> Vec<(&'static str, u64)> {
+        self.entries()
+            .into_iter()
+            .filter(|(name, _)| pattern.is_empty() || glob_match(pattern, name))
+            .map(|(name, value)| (name, value.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// 修改一个配置，值必须是非负整数
+    pub fn set(&self, name: &str, value: &Value) -> Result<(), KvError> {
+        let (name, setting) = self
+            .entries()
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .ok_or_else(|| KvError::InvalidCommand(format!("Unknown config: {}", name)))?;
+        let value = i64::try_from(value)
+            .ok()
+            .and_then(|v| u64::try_from(v).ok())
+            .ok_or_else(|| {
+                KvError::InvalidCommand(format!("{} must be a non-negative integer", name))
+            })?;
+        setting.store(value, Ordering::Relaxed);
+        info!("Config {} changed to {}", name, value);
+        Ok(())
+    }
+
+    fn entries(&self) -> [(&'static str, &AtomicU64); 7] {
+        [
+            ("timeouts.idle_timeout_ms", &self.idle_timeout_ms),
+            ("timeouts.handshake_timeout_ms", &self.handshake_timeout_ms),
+            ("admission.max_connections", &self.max_connections),
+            (
+                "admission.max_streams_per_connection",
+                &self.max_streams_per_connection,
+            ),
+            ("admission.max_in_flight", &self.max_in_flight),
+            ("slowlog.threshold_us", &self.slowlog_threshold_us),
+            ("slowlog.max_len", &self.slowlog_max_len),
+        ]
+    }
+}
+
+impl Default for RuntimeSettings {
+    fn default() -> Self {
+        Self::new(
+            &TimeoutConfig::default(),
+            &AdmissionConfig::default(),
+            &SlowlogConfig::default(),
+        )
+    }
+}
+
+fn millis(value: &AtomicU64) -> Option<Duration> {
+    match value.load(Ordering::Relaxed) {
+        0 => None,
+        ms => Some(Duration::from_millis(ms)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_should_be_readable_and_writable() {
+        let settings = RuntimeSettings::default();
+        assert_eq!(settings.get("").len(), 7);
+        assert_eq!(
+            settings.get("timeouts.*"),
+            vec![
+                ("timeouts.idle_timeout_ms", 300_000),
+                ("timeouts.handshake_timeout_ms", 10_000)
+            ]
+        );
+
+        settings
+            .set("timeouts.idle_timeout_ms", &Value::from(0))
+            .unwrap();
+        assert_eq!(settings.idle_timeout(), None);
+        settings.set("slowlog.max_len", &Value::from(0)).unwrap();
+        assert_eq!(settings.slowlog_threshold(), None);
+        assert!(settings
+            .set("admission.max_in_flight", &"10".into())
+            .is_err());
+        assert!(settings
+            .set("admission.max_in_flight", &(-1).into())
+            .is_err());
+        assert!(settings.set("unknown", &Value::from(1)).is_err());
+    }
+}
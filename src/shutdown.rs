@@ -0,0 +1,191 @@
+//! 优雅关闭：停止接受新连接，通知订阅者，等正在处理的请求完成后把数据刷到磁盘
+//!
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+
+use crate::metrics::{metrics, Metrics};
+
+/// 运行中的服务器，调用 shutdown 关闭。直接 drop 时服务器会一直运行
+pub struct ServerHandle {
+    addrs: Vec<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    shutdown: watch::Sender<bool>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        addrs: Vec<SocketAddr>,
+        metrics_addr: Option<SocketAddr>,
+        shutdown: watch::Sender<bool>,
+        task: JoinHandle<Result<()>>,
+    ) -> Self {
+        Self {
+            addrs,
+            metrics_addr,
+            shutdown,
+            task,
+        }
+    }
+
+    /// 第一个 TCP 监听的地址，配置的端口是 0 时可以用它拿到分配的端口
+    ///
+    /// 只监听 Unix domain socket 时没有这个地址，调用会 panic
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addrs[0]
+    }
+
+    /// 所有 TCP 监听的地址，顺序和添加的顺序一致，不包括 Unix domain socket
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addrs
+    }
+
+    /// Prometheus 抓取的地址，没有开启时返回 None
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// 进程内的监控指标
+    pub fn metrics(&self) -> &'static Metrics {
+        metrics()
+    }
+
+    /// 关闭服务器，在排空请求并且刷盘之后返回
+    pub async fn shutdown(self) -> Result<()> {
+        // 服务器已经因为错误退出时接收端不存在，直接等它的结果
+        let _ = self.shutdown.send(true);
+        self.task.await?
+    }
+}
+
+/// 关闭信号，默认的 Shutdown 永远不会触发
+#[derive(Clone, Default)]
+pub struct Shutdown(Option<watch::Receiver<bool>>);
+
+impl Shutdown {
+    pub(crate) fn new(rx: watch::Receiver<bool>) -> Self {
+        Self(Some(rx))
+    }
+
+    /// 等待关闭信号，已经关闭时立即返回
+    pub async fn wait(&mut self) {
+        match &mut self.0 {
+            Some(rx) => {
+                while !*rx.borrow() {
+                    if rx.changed().await.is_err() {
+                        // ServerHandle 被 drop 了，不会再有关闭信号
+                        future::pending::<()>().await;
+                    }
+                }
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+/// 统计正在处理的 stream，关闭时等它们都结束
+#[derive(Clone, Default)]
+pub(crate) struct Drain(Arc<DrainInner>);
+
+#[derive(Default)]
+struct DrainInner {
+    active: AtomicUsize,
+    notify: Notify,
+}
+
+pub(crate) struct DrainGuard(Arc<DrainInner>);
+
+impl Drain {
+    pub fn guard(&self) -> DrainGuard {
+        self.0.active.fetch_add(1, Ordering::SeqCst);
+        DrainGuard(self.0.clone())
+    }
+
+    pub async fn wait(&self) {
+        loop {
+            // 先注册再检查，避免错过最后一个 guard 释放时的通知
+            let notified = self.0.notify.notified();
+            if self.0.active.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+impl Drop for DrainGuard {
+    fn drop(&mut self) {
+        if self.0.active.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        start_server_with_config, ClientConfig, CommandRequest, KvClient, KvError, ServerConfig,
+        StorageConfig,
+    };
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[tokio::test]
+    async fn drain_should_wait_for_guards() {
+        let drain = Drain::default();
+        let guard = drain.guard();
+        assert!(time::timeout(Duration::from_millis(10), drain.wait())
+            .await
+            .is_err());
+
+        tokio::spawn(async move {
+            time::sleep(Duration::from_millis(10)).await;
+            drop(guard);
+        });
+        time::timeout(Duration::from_secs(1), drain.wait())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shutdown_should_notify_subscribers_and_stop_accepting() {
+        let mut config: ServerConfig =
+            toml::from_str(include_str!("../fixtures/server.conf")).unwrap();
+        config.general.addr = "127.0.0.1:0".into();
+        config.storage = StorageConfig::MemTable;
+        let server = start_server_with_config(&config).await.unwrap();
+        let addr = server.local_addr().to_string();
+
+        let mut config: ClientConfig =
+            toml::from_str(include_str!("../fixtures/client.conf")).unwrap();
+        config.general.addr = addr.clone();
+        config.reconnect.max_attempts = Some(0);
+        let client = KvClient::connect(&config).await.unwrap();
+        let mut sub = client.subscribe("lobby").await.unwrap();
+        client
+            .execute(&CommandRequest::new_hset("t1", "k1", "v1".into()))
+            .await
+            .unwrap();
+
+        time::timeout(Duration::from_secs(5), server.shutdown())
+            .await
+            .unwrap()
+            .unwrap();
+
+        // 订阅者收到 going away 之后订阅结束
+        match sub.next().await {
+            Some(Err(KvError::Unavailable(_))) => {}
+            other => panic!("expect going away, got {:?}", other),
+        }
+        assert!(tokio::net::TcpStream::connect(&addr).await.is_err());
+    }
+}
@@ -0,0 +1,579 @@
+//! 备份文件：和存储后端无关的数据快照，SledDb 的备份可以恢复到 MemTable，反过来也一样
+//!
+//! 文件格式见 abi.proto 中的 SnapshotManifest。每个 table 都记录了 key 个数和 SHA-256，
+//! 恢复之前会先完整地校验一遍，损坏的备份不会写入任何数据。
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use prost::Message;
+use ring::digest::{Context, SHA256};
+
+use crate::{
+    KvError, Kvpair, SnapshotChunk, SnapshotManifest, SnapshotTable, Storage, Value, WriteOp,
+};
+
+const MAGIC: &[u8; 8] = b"KVSNAP01";
+const SNAPSHOT_VERSION: u32 = 1;
+// 每个 chunk 最多的 key 个数，恢复时每个 chunk 是一次批量写入
+const CHUNK_KEYS: usize = 1024;
+// manifest 或者 chunk 编码后的上限，超过说明文件已经损坏
+const MAX_MESSAGE_LEN: usize = 64 << 20;
+
+/// 读取快照期间被修改过的 key 在读完时的值，None 表示已经删除
+pub type SnapshotPatch = BTreeMap<String, BTreeMap<String, Option<Value>>>;
+
+/// 从存储中读出的快照，已经编码成备份文件的格式，数据放在临时文件中
+pub struct Snapshot {
+    manifest: SnapshotManifest,
+    // 所有 table 编码后的 chunk，顺序和 manifest 中的一致
+    data: File,
+    data_len: u64,
+}
+
+impl Snapshot {
+    /// 读出存储中所有的 table，调用者需要保证读取期间没有写入
+    pub fn capture<S: Storage + ?Sized>(store: &S) -> Result<Self, KvError> {
+        Self::capture_with(store, |_| Ok((SnapshotPatch::new(), ()))).map(|(s, _)| s)
+    }
+
+    /// 读取期间允许写入：逐个 table 读出后先写到临时文件，读完后调用 cut 拿到期间被修改过的
+    /// key 的最新值，合并之后就是调用 cut 那一刻的数据。内存中每次只有一个 table
+    pub fn capture_with<S, F, T>(store: &S, cut: F) -> Result<(Self, T), KvError>
+    where
+        S: Storage + ?Sized,
+        F: FnOnce(&S) -> Result<(SnapshotPatch, T), KvError>,
+    {
+        let created_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let mut manifest = SnapshotManifest {
+            version: SNAPSHOT_VERSION,
+            created_ms,
+            storage: store.name().into(),
+            tables: Vec::new(),
+        };
+
+        // 第一遍：每个 table 排好序写到 spool，记下每个 table 有多少个 chunk
+        let mut spool = BufWriter::new(tempfile::tempfile()?);
+        let mut scanned = Vec::new();
+        for name in store.stats()?.tables.into_keys() {
+            let mut pairs = store.get_all(&name)?;
+            // 同样的数据总是得到同样的文件
+            pairs.sort_by(|a, b| a.key.cmp(&b.key));
+            let chunks = pairs.chunks(CHUNK_KEYS).len();
+            for chunk in pairs.chunks(CHUNK_KEYS) {
+                let chunk = SnapshotChunk {
+                    table: name.clone(),
+                    pairs: chunk.to_vec(),
+                };
+                spool.write_all(&chunk.encode_length_delimited_to_vec())?;
+            }
+            scanned.push((name, chunks));
+        }
+        let (mut patch, extra) = cut(store)?;
+
+        // 第二遍：用 patch 中的值替换读取期间被修改过的 key，重新分 chunk 并计算校验和
+        let mut spool = spool.into_inner().map_err(|e| e.into_error())?;
+        spool.seek(SeekFrom::Start(0))?;
+        let mut spool = BufReader::new(spool);
+        let mut chunks: BTreeMap<String, usize> = scanned.into_iter().collect();
+        let names: BTreeSet<String> = chunks.keys().chain(patch.keys()).cloned().collect();
+        let mut data = BufWriter::new(tempfile::tempfile()?);
+        let mut data_len = 0;
+        for name in names {
+            let scanned = ScannedPairs {
+                reader: &mut spool,
+                chunks: chunks.remove(&name).unwrap_or_default(),
+                pairs: Vec::new().into_iter(),
+            };
+            let changed = patch.remove(&name).unwrap_or_default().into_iter();
+            let mut writer = TableWriter::new(name, &mut data);
+            for pair in merge(scanned, changed.peekable()) {
+                writer.push(pair?)?;
+            }
+            let (table, len) = writer.finish()?;
+            data_len += len;
+            if table.keys > 0 {
+                manifest.tables.push(table);
+            }
+        }
+        let mut data = data.into_inner().map_err(|e| e.into_error())?;
+        data.seek(SeekFrom::Start(0))?;
+        Ok((
+            Self {
+                manifest,
+                data,
+                data_len,
+            },
+            extra,
+        ))
+    }
+
+    /// 备份了哪些 table，以及每个 table 的 key 个数和校验和
+    pub fn manifest(&self) -> &SnapshotManifest {
+        &self.manifest
+    }
+
+    /// 备份文件的字节数
+    pub fn size(&self) -> u64 {
+        self.header().len() as u64 + self.data_len
+    }
+
+    /// 按备份文件的格式读出全部内容
+    pub fn into_reader(self) -> impl Read + Send + 'static {
+        Cursor::new(self.header()).chain(BufReader::new(self.data))
+    }
+
+    /// 按备份文件的格式写出
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<(), KvError> {
+        let mut data = self.data.try_clone()?;
+        data.seek(SeekFrom::Start(0))?;
+        writer.write_all(&self.header())?;
+        io::copy(&mut data, writer)?;
+        Ok(())
+    }
+
+    /// 备份文件的全部内容
+    pub fn to_bytes(&self) -> Result<Vec<u8>, KvError> {
+        let mut buf = Vec::with_capacity(self.size() as usize);
+        self.write_to(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// 写到 path，先写临时文件再改名，不会留下写了一半的备份
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), KvError> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        self.write_to(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // 文件头和 manifest
+    fn header(&self) -> Vec<u8> {
+        let mut buf = MAGIC.to_vec();
+        buf.extend(self.manifest.encode_length_delimited_to_vec());
+        buf
+    }
+}
+
+// 按顺序读出 spool 中一个 table 的所有 key
+struct ScannedPairs<'a, R> {
+    reader: &'a mut R,
+    chunks: usize,
+    pairs: std::vec::IntoIter<Kvpair>,
+}
+
+impl<R: Read> Iterator for ScannedPairs<'_, R> {
+    type Item = Result<Kvpair, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(pair) = self.pairs.next() {
+                return Some(Ok(pair));
+            }
+            if self.chunks == 0 {
+                return None;
+            }
+            self.chunks -= 1;
+            let chunk = read_message(self.reader).and_then(|buf| {
+                let buf =
+                    buf.ok_or_else(|| KvError::Internal("Snapshot spool is truncated".into()))?;
+                Ok(SnapshotChunk::decode(&buf[prefix_len(&buf)..])?)
+            });
+            match chunk {
+                Ok(chunk) => self.pairs = chunk.pairs.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+// 合并两个按 key 排好序的序列，changed 中有的 key 以 changed 为准，值为 None 的 key 去掉
+fn merge<I, C>(
+    mut scanned: I,
+    mut changed: Peekable<C>,
+) -> impl Iterator<Item = Result<Kvpair, KvError>>
+where
+    I: Iterator<Item = Result<Kvpair, KvError>>,
+    C: Iterator<Item = (String, Option<Value>)>,
+{
+    let mut next = scanned.next();
+    std::iter::from_fn(move || loop {
+        let pair = match next.take() {
+            Some(Ok(pair)) => pair,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                let (key, value) = changed.next()?;
+                match value {
+                    Some(value) => return Some(Ok(Kvpair::new(key, value))),
+                    None => continue,
+                }
+            }
+        };
+        match changed.peek() {
+            Some((key, _)) if *key <= pair.key => {
+                let (key, value) = changed.next().expect("peeked");
+                if key == pair.key {
+                    next = scanned.next();
+                } else {
+                    next = Some(Ok(pair));
+                }
+                if let Some(value) = value {
+                    return Some(Ok(Kvpair::new(key, value)));
+                }
+            }
+            _ => {
+                next = scanned.next();
+                return Some(Ok(pair));
+            }
+        }
+    })
+}
+
+// 把一个 table 的 key 分 chunk 写出，同时统计 key 个数和校验和
+struct TableWriter<'a, W> {
+    name: String,
+    writer: &'a mut W,
+    pending: Vec<Kvpair>,
+    keys: u64,
+    len: u64,
+    digest: Context,
+}
+
+impl<'a, W: Write> TableWriter<'a, W> {
+    fn new(name: String, writer: &'a mut W) -> Self {
+        Self {
+            name,
+            writer,
+            pending: Vec::with_capacity(CHUNK_KEYS),
+            keys: 0,
+            len: 0,
+            digest: Context::new(&SHA256),
+        }
+    }
+
+    fn push(&mut self, pair: Kvpair) -> Result<(), KvError> {
+        self.pending.push(pair);
+        if self.pending.len() == CHUNK_KEYS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), KvError> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let chunk = SnapshotChunk {
+            table: self.name.clone(),
+            pairs: std::mem::take(&mut self.pending),
+        };
+        let buf = chunk.encode_length_delimited_to_vec();
+        self.writer.write_all(&buf)?;
+        self.digest.update(&buf);
+        self.keys += chunk.pairs.len() as u64;
+        self.len += buf.len() as u64;
+        Ok(())
+    }
+
+    // 返回 table 在 manifest 中的记录和写出的字节数
+    fn finish(mut self) -> Result<(SnapshotTable, u64), KvError> {
+        self.flush()?;
+        let table = SnapshotTable {
+            name: self.name,
+            keys: self.keys,
+            sha256: Bytes::copy_from_slice(self.digest.finish().as_ref()),
+        };
+        Ok((table, self.len))
+    }
+}
+
+/// 按顺序读出备份文件中的 chunk，读完每个 table 时校验 key 个数和 SHA-256
+pub struct SnapshotReader<R> {
+    reader: R,
+    manifest: SnapshotManifest,
+    // 正在读的 table 在 manifest 中的位置，以及已经读到的 key 个数和校验和
+    table: usize,
+    keys: u64,
+    digest: Context,
+}
+
+impl<R: Read> SnapshotReader<R> {
+    /// 读取并检查文件头和 manifest
+    pub fn new(mut reader: R) -> Result<Self, KvError> {
+        let mut magic = [0u8; 8];
+        reader
+            .read_exact(&mut magic)
+            .map_err(|_| corrupt("missing header"))?;
+        if &magic != MAGIC {
+            return Err(corrupt("not a snapshot file"));
+        }
+        let buf = read_message(&mut reader)?.ok_or_else(|| corrupt("missing manifest"))?;
+        let manifest = SnapshotManifest::decode(&buf[prefix_len(&buf)..])?;
+        if manifest.version != SNAPSHOT_VERSION {
+            return Err(corrupt(format!("unsupported version {}", manifest.version)));
+        }
+        Ok(Self {
+            reader,
+            manifest,
+            table: 0,
+            keys: 0,
+            digest: Context::new(&SHA256),
+        })
+    }
+
+    pub fn manifest(&self) -> &SnapshotManifest {
+        &self.manifest
+    }
+
+    /// 下一个 chunk，所有 table 都读完并且校验通过时返回 None
+    pub fn next_chunk(&mut self) -> Result<Option<SnapshotChunk>, KvError> {
+        loop {
+            let table = match self.manifest.tables.get(self.table) {
+                Some(table) => table,
+                None => {
+                    return match self.reader.read(&mut [0u8; 1])? {
+                        0 => Ok(None),
+                        _ => Err(corrupt("unexpected data after the last table")),
+                    }
+                }
+            };
+            // 当前 table 读完了，校验后换下一个
+            if self.keys == table.keys {
+                let digest = std::mem::replace(&mut self.digest, Context::new(&SHA256)).finish();
+                if digest.as_ref() != &table.sha256[..] {
+                    return Err(corrupt(format!(
+                        "checksum mismatch in table {}",
+                        table.name
+                    )));
+                }
+                self.table += 1;
+                self.keys = 0;
+                continue;
+            }
+
+            let buf = read_message(&mut self.reader)?
+                .ok_or_else(|| corrupt(format!("table {} is truncated", table.name)))?;
+            self.digest.update(&buf);
+            let chunk = SnapshotChunk::decode(&buf[prefix_len(&buf)..])?;
+            self.keys += chunk.pairs.len() as u64;
+            if chunk.table != table.name || chunk.pairs.is_empty() || self.keys > table.keys {
+                return Err(corrupt(format!("unexpected data in table {}", table.name)));
+            }
+            return Ok(Some(chunk));
+        }
+    }
+}
+
+/// 完整地校验备份文件，返回其中的 manifest
+pub fn verify_snapshot(path: impl AsRef<Path>) -> Result<SnapshotManifest, KvError> {
+    let mut reader = SnapshotReader::new(BufReader::new(File::open(path)?))?;
+    while reader.next_chunk()?.is_some() {}
+    Ok(reader.manifest)
+}
+
+/// 校验备份文件后把数据写入 store。store 中已有的同名 key 会被覆盖，其它 key 保持不变
+pub fn restore_snapshot<S: Storage + ?Sized>(
+    path: impl AsRef<Path>,
+    store: &S,
+) -> Result<SnapshotManifest, KvError> {
+    let path = path.as_ref();
+    verify_snapshot(path)?;
+
+    let mut reader = SnapshotReader::new(BufReader::new(File::open(path)?))?;
+    while let Some(chunk) = reader.next_chunk()? {
+        let ops = chunk
+            .pairs
+            .into_iter()
+            .map(|pair| WriteOp::Set {
+                table: chunk.table.clone(),
+                key: pair.key,
+                value: pair.value.unwrap_or_default(),
+            })
+            .collect();
+        store.apply_batch(ops)?;
+    }
+    store.flush()?;
+    Ok(reader.manifest)
+}
+
+// 读一个长度前缀编码的消息，返回包括前缀在内的原始字节。文件在消息开始之前结束时返回 None
+fn read_message(reader: &mut impl Read) -> Result<Option<Vec<u8>>, KvError> {
+    let mut buf = Vec::new();
+    let mut len = 0usize;
+    loop {
+        let mut byte = [0u8; 1];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof && buf.is_empty() => {
+                return Ok(None)
+            }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(corrupt("truncated length"))
+            }
+            Err(e) => return Err(e.into()),
+        }
+        len |= ((byte[0] & 0x7f) as usize) << (7 * buf.len());
+        buf.push(byte[0]);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if buf.len() >= 4 {
+            return Err(corrupt("message is too large"));
+        }
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(corrupt("message is too large"));
+    }
+
+    let start = buf.len();
+    buf.resize(start + len, 0);
+    reader
+        .read_exact(&mut buf[start..])
+        .map_err(|_| corrupt("truncated message"))?;
+    Ok(Some(buf))
+}
+
+// 长度前缀的字节数
+fn prefix_len(buf: &[u8]) -> usize {
+    buf.iter().position(|b| b & 0x80 == 0).map_or(0, |i| i + 1)
+}
+
+fn corrupt(reason: impl Into<String>) -> KvError {
+    KvError::CorruptSnapshot(reason.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MemTable, SledDb, Value};
+    use tempfile::tempdir;
+
+    fn fill(store: &impl Storage) {
+        for i in 0..3000 {
+            store
+                .set("t1", format!("k{}", i), Value::from(i as i64))
+                .unwrap();
+        }
+        store
+            .set("t2", "bin".into(), Value::from(b"\x00\xff"))
+            .unwrap();
+        store.set("t2", "s".into(), "hello".into()).unwrap();
+    }
+
+    #[test]
+    fn sled_snapshot_should_restore_into_memtable() {
+        let dir = tempdir().unwrap();
+        let sled = SledDb::new(dir.path().join("db"));
+        fill(&sled);
+        let snapshot = Snapshot::capture(&sled).unwrap();
+        let tables: Vec<_> = snapshot
+            .manifest()
+            .tables
+            .iter()
+            .map(|t| (t.name.as_str(), t.keys))
+            .collect();
+        assert_eq!(tables, [("t1", 3000), ("t2", 2)]);
+        let path = dir.path().join("backup.kvsnap");
+        snapshot.save(&path).unwrap();
+
+        let memtable = MemTable::new();
+        let manifest = restore_snapshot(&path, &memtable).unwrap();
+        assert_eq!(manifest.storage, "sled");
+        assert_eq!(
+            memtable.stats().unwrap().tables,
+            sled.stats().unwrap().tables
+        );
+        assert_eq!(
+            memtable.get("t2", "bin").unwrap(),
+            Some(Value::from(b"\x00\xff"))
+        );
+
+        // 反过来也一样，而且同样的数据得到同样的内容
+        let sled = SledDb::new(dir.path().join("db2"));
+        restore_snapshot(&path, &sled).unwrap();
+        let a = Snapshot::capture(&memtable).unwrap();
+        let b = Snapshot::capture(&sled).unwrap();
+        assert_eq!(a.manifest().tables, b.manifest().tables);
+        assert_eq!(a.size(), a.to_bytes().unwrap().len() as u64);
+    }
+
+    #[test]
+    fn capture_should_apply_changes_made_while_reading() {
+        let store = MemTable::new();
+        fill(&store);
+        let (snapshot, seen) = Snapshot::capture_with(&store, |store| {
+            // 读取期间修改了 k1，删除了 k10 和整个 t2，新建了 t0 和 t3
+            store.set("t1", "k1".into(), "changed".into())?;
+            store.del("t1", "k10")?;
+            store.del("t2", "bin")?;
+            store.del("t2", "s")?;
+            store.set("t0", "new".into(), "v".into())?;
+            let mut patch = SnapshotPatch::new();
+            let t1 = patch.entry("t1".into()).or_default();
+            t1.insert("k1".into(), Some("changed".into()));
+            t1.insert("k10".into(), None);
+            t1.insert("k99999".into(), Some("late".into()));
+            let t2 = patch.entry("t2".into()).or_default();
+            t2.insert("bin".into(), None);
+            t2.insert("s".into(), None);
+            patch
+                .entry("t0".into())
+                .or_default()
+                .insert("new".into(), Some("v".into()));
+            patch
+                .entry("t3".into())
+                .or_default()
+                .insert("gone".into(), None);
+            Ok((patch, 42))
+        })
+        .unwrap();
+        assert_eq!(seen, 42);
+        store.set("t1", "k99999".into(), "late".into()).unwrap();
+
+        // 和修改之后直接读出的快照完全一样
+        let expected = Snapshot::capture(&store).unwrap();
+        assert_eq!(snapshot.manifest().tables, expected.manifest().tables);
+        let tables: Vec<_> = snapshot
+            .manifest()
+            .tables
+            .iter()
+            .map(|t| (t.name.as_str(), t.keys))
+            .collect();
+        assert_eq!(tables, [("t0", 1), ("t1", 3000)]);
+    }
+
+    #[test]
+    fn corrupt_snapshot_should_not_be_restored() {
+        let dir = tempdir().unwrap();
+        let store = MemTable::new();
+        fill(&store);
+        let mut data = Snapshot::capture(&store).unwrap().to_bytes().unwrap();
+        let path = dir.path().join("backup.kvsnap");
+
+        // 改掉最后一个 chunk 中的一个字节
+        let last = data.len() - 2;
+        data[last] ^= 0xff;
+        fs::write(&path, &data).unwrap();
+        let target = MemTable::new();
+        assert!(restore_snapshot(&path, &target).is_err());
+        assert!(target.stats().unwrap().tables.is_empty());
+
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+        assert!(verify_snapshot(&path).is_err());
+        fs::write(&path, b"not a snapshot").unwrap();
+        let err = verify_snapshot(&path).unwrap_err();
+        assert!(err.to_string().contains("Corrupt snapshot"));
+    }
+}
@@ -4,7 +4,8 @@ use dashmap::{mapref::one::Ref, DashMap};
 use super::StorageIter;
 use crate::error::KvError;
 use crate::pb::Kvpair;
-use crate::storage::Storage;
+use crate::storage::{Storage, StorageStats};
+use prost::Message;
 #[derive(Debug, Clone, Default)]
 pub struct MemTable {
     tables: DashMap<String, DashMap<String, Value>>,
@@ -16,7 +17,7 @@ impl MemTable {
         Self::default()
     }
 
-    fn get_or_create_table(&self, name: &str) -> Ref<String, DashMap<String, Value>> {
+    fn get_or_create_table(&self, name: &str) -> Ref<'_, String, DashMap<String, Value>> {
         // 使用get拿到表
         match self.tables.get(name) {
             Some(table) => table,
@@ -29,6 +30,9 @@ impl MemTable {
 }
 
 impl Storage for MemTable {
+    fn name(&self) -> &'static str {
+        "memtable"
+    }
     // 从表里取数据
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
         let table = self.get_or_create_table(table);
@@ -64,6 +68,21 @@ impl Storage for MemTable {
         let iter = StorageIter::new(table.into_iter());
         Ok(Box::new(iter))
     }
+
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        let mut stats = StorageStats::default();
+        for table in self.tables.iter() {
+            // get 不存在的 table 时也会创建，空的 table 不统计
+            if table.is_empty() {
+                continue;
+            }
+            stats.tables.insert(table.key().clone(), table.len() as u64);
+            for entry in table.iter() {
+                stats.bytes += (entry.key().len() + entry.value().encoded_len()) as u64;
+            }
+        }
+        Ok(stats)
+    }
 }
 
 // 对应的错误：the trait `From<(String, abi::Value)>` is not implemented for `abi::Kvpair`
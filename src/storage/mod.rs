@@ -3,6 +3,7 @@
 
 mod memory;
 mod sleddb;
+#[allow(clippy::module_inception)]
 mod storage;
 use crate::pb::Kvpair;
 pub use memory::*;
@@ -49,6 +50,24 @@ mod tests {
         test_get_all(store);
     }
     #[test]
+    fn sleddb_apply_batch_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_apply_batch(store);
+    }
+    #[test]
+    fn sleddb_scan_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_scan(store);
+    }
+    #[test]
+    fn sleddb_stats_should_work() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        test_stats(store);
+    }
+    #[test]
     fn sleddb_iter_should_work() {
         let dir = tempdir().unwrap();
         let store = SledDb::new(dir);
@@ -70,9 +89,9 @@ mod tests {
         assert_eq!(store.get("t1", "k2").unwrap(), None); // 有表无键
         assert!(store.get("t2", "k1").unwrap().is_none()); // 无表
                                                            // contains 纯在的 key 返回 true，否则 false
-        assert_eq!(store.contains("t1", "k1").unwrap(), true); // contains 用在map上
-        assert_eq!(store.contains("t1", "k2").unwrap(), false);
-        assert_eq!(store.contains("t2", "k1").unwrap(), false);
+        assert!(store.contains("t1", "k1").unwrap()); // contains 用在map上
+        assert!(!store.contains("t1", "k2").unwrap());
+        assert!(!store.contains("t2", "k1").unwrap());
         // del 存在的 key 返回删掉的值
         let v = store.del("t1", "k1");
         assert_eq!(v.unwrap(), Some("v1".into()));
@@ -114,4 +133,56 @@ mod tests {
             ]
         );
     }
+
+    fn test_scan(store: impl Storage) {
+        for key in ["k3", "k1", "k:2", "k2"] {
+            store.set("t4", key.into(), key.into()).unwrap();
+        }
+        store.set("t5", "k0".into(), "v0".into()).unwrap();
+
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+        assert_eq!(keys(store.scan("t4", "", 2).unwrap()), ["k1", "k2"]);
+        assert_eq!(keys(store.scan("t4", "k2", 2).unwrap()), ["k3", "k:2"]);
+        assert!(store.scan("t4", "k:2", 2).unwrap().is_empty());
+        assert_eq!(
+            store.scan("t4", "k2", 1).unwrap(),
+            vec![Kvpair::new("k3", "k3".into())]
+        );
+    }
+
+    fn test_stats(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t2", "k1".into(), "v3".into()).unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.tables.get("t1"), Some(&2));
+        assert_eq!(stats.tables.get("t2"), Some(&1));
+        assert!(stats.bytes > 0);
+    }
+
+    fn test_apply_batch(store: impl Storage) {
+        store.set("t3", "k1".into(), "v0".into()).unwrap();
+        let ops = vec![
+            WriteOp::Set {
+                table: "t3".into(),
+                key: "k1".into(),
+                value: "v1".into(),
+            },
+            WriteOp::Set {
+                table: "t3".into(),
+                key: "k2".into(),
+                value: "v2".into(),
+            },
+            WriteOp::Del {
+                table: "t3".into(),
+                key: "k1".into(),
+            },
+        ];
+        let result = store.apply_batch(ops).unwrap();
+        // 同一批中后面的操作能看到前面写入的值
+        assert_eq!(result, vec![Some("v0".into()), None, Some("v1".into())]);
+        assert!(!store.contains("t3", "k1").unwrap());
+        assert_eq!(store.get("t3", "k2").unwrap(), Some("v2".into()));
+    }
 }
@@ -1,12 +1,14 @@
-use super::Storage;
+use super::{Storage, StorageStats, WriteOp};
 use crate::error::KvError;
 use crate::pb::Kvpair;
 use crate::pb::Value;
 use crate::StorageIter;
+use std::collections::HashMap;
+use std::ops::Bound;
 use std::path::Path;
-use std::result;
 use std::str;
 
+use prost::Message;
 use sled::Db;
 use sled::IVec;
 #[derive(Debug)]
@@ -30,6 +32,24 @@ impl SledDb {
     }
 }
 
+// 新格式的 value 以 0xff 开头，后面是 Value 的 protobuf 编码，整数、二进制等类型都能原样保存。
+// UTF-8 中不会出现 0xff，没有这个前缀的是旧版本按字符串保存的 value
+const ENCODED_VALUE: u8 = 0xff;
+
+fn encode_value(value: &Value) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + value.encoded_len());
+    buf.push(ENCODED_VALUE);
+    buf.extend_from_slice(&value.encode_to_vec());
+    buf
+}
+
+fn decode_value(data: &[u8]) -> Result<Value, KvError> {
+    match data.split_first() {
+        Some((&ENCODED_VALUE, encoded)) => Ok(Value::decode(encoded)?),
+        _ => Ok(String::from_utf8_lossy(data).as_ref().into()),
+    }
+}
+
 // 辅助函数， 反转类型
 fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
     x.map_or(Ok(None), |v| v.map(Some)) // Option 也有一系列map方法和unwrap方法
@@ -38,37 +58,42 @@ fn flip<T, E>(x: Option<Result<T, E>>) -> Result<Option<T>, E> {
 // 更换底层的数据结构只需要实现trait定义好的接口即可
 
 impl Storage for SledDb {
+    fn name(&self) -> &'static str {
+        "sled"
+    }
     // 从表里取数据
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        let result = self.0.get(name.as_bytes())?.map(|v| v.as_ref().try_into());
+        let name = SledDb::get_full_key(table, key);
+        let result = self.0.get(name.as_bytes())?.map(|v| decode_value(&v));
         flip(result)
     }
     // 向表里存数据
     fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
         let name = SledDb::get_full_key(table, &key);
 
-        let data: Vec<u8> = value.try_into()?;
-        let result = self.0.insert(name, data)?.map(|v| v.as_ref().try_into());
+        let result = self
+            .0
+            .insert(name, encode_value(&value))?
+            .map(|v| decode_value(&v));
 
         flip(result)
     } // 返回前值
       // 判断存在性
     fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
-        let name = SledDb::get_full_key(table, &key);
+        let name = SledDb::get_full_key(table, key);
         Ok(self.0.contains_key(name)?)
     }
     // 删除数据
     fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
-        let name = SledDb::get_full_key(table, &key);
-        let result = self.0.remove(name)?.map(|v| v.as_ref().try_into());
+        let name = SledDb::get_full_key(table, key);
+        let result = self.0.remove(name)?.map(|v| decode_value(&v));
         flip(result)
     }
     // 删除表
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
         let prefix = SledDb::get_table_prefix(table);
 
-        let result = self.0.scan_prefix(table).map(|v| v.into()).collect();
+        let result = self.0.scan_prefix(prefix).map(|v| v.into()).collect();
         Ok(result)
     }
     // 把数据转为迭代器，方便遍历，值有多种类型，但是都会实现迭代器trait,并且类型是Kvpair
@@ -79,12 +104,81 @@ impl Storage for SledDb {
 
         Ok(Box::new(iter))
     }
+    // sled 中的 key 是有序的，直接从 after 之后开始读
+    fn scan(&self, table: &str, after: &str, count: usize) -> Result<Vec<Kvpair>, KvError> {
+        let prefix = SledDb::get_table_prefix(table);
+        let start = match after {
+            "" => Bound::Included(prefix.clone()),
+            after => Bound::Excluded(SledDb::get_full_key(table, after)),
+        };
+        self.0
+            .range::<String, _>((start, Bound::Unbounded))
+            .take_while(|item| match item {
+                Ok((key, _)) => key.starts_with(prefix.as_bytes()),
+                Err(_) => true,
+            })
+            .take(count)
+            .map(|item| {
+                let (key, value) = item?;
+                Ok(Kvpair::new(ivec_to_key(&key), decode_value(&value)?))
+            })
+            .collect()
+    }
+    fn flush(&self) -> Result<(), KvError> {
+        self.0.flush()?;
+        Ok(())
+    }
+    // 所有 table 共用一个 tree，按 key 的前缀统计
+    fn stats(&self) -> Result<StorageStats, KvError> {
+        let mut stats = StorageStats::default();
+        for item in self.0.iter() {
+            let (key, value) = item?;
+            stats.bytes += (key.len() + value.len()) as u64;
+            let table = match key.iter().position(|b| *b == b':') {
+                Some(i) => String::from_utf8_lossy(&key[..i]).into_owned(),
+                None => continue,
+            };
+            *stats.tables.entry(table).or_default() += 1;
+        }
+        Ok(stats)
+    }
+
+    // 用 sled::Batch 一次性写入，之前的值先读出来，同一批里后面的操作能看到前面的修改
+    fn apply_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Option<Value>>, KvError> {
+        let mut batch = sled::Batch::default();
+        let mut pending: HashMap<String, Option<Value>> = HashMap::new();
+        let mut result = Vec::with_capacity(ops.len());
+
+        for op in ops {
+            let (name, value) = match op {
+                WriteOp::Set { table, key, value } => {
+                    (SledDb::get_full_key(&table, &key), Some(value))
+                }
+                WriteOp::Del { table, key } => (SledDb::get_full_key(&table, &key), None),
+            };
+
+            let old = match pending.get(&name) {
+                Some(v) => v.clone(),
+                None => flip(self.0.get(name.as_bytes())?.map(|v| decode_value(&v)))?,
+            };
+            result.push(old);
+
+            match &value {
+                Some(v) => batch.insert(name.as_bytes(), encode_value(v)),
+                None => batch.remove(name.as_bytes()),
+            }
+            pending.insert(name, value);
+        }
+
+        self.0.apply_batch(batch)?;
+        Ok(result)
+    }
 }
 
 impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     fn from(value: Result<(IVec, IVec), sled::Error>) -> Self {
         match value {
-            Ok((k, v)) => match v.as_ref().try_into() {
+            Ok((k, v)) => match decode_value(&v) {
                 Ok(v) => Kvpair::new(ivec_to_key(k.as_ref()), v),
                 Err(_) => Kvpair::default(),
             },
@@ -93,9 +187,29 @@ impl From<Result<(IVec, IVec), sled::Error>> for Kvpair {
     }
 }
 
+// 去掉 table 前缀，key 中可以有 :
 fn ivec_to_key(ivec: &[u8]) -> &str {
     let s = str::from_utf8(ivec).unwrap();
-    let mut iter = s.split(":");
-    iter.next();
-    iter.next().unwrap()
+    s.split_once(':').map_or(s, |(_, key)| key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn sleddb_should_keep_value_types() {
+        let dir = tempdir().unwrap();
+        let store = SledDb::new(dir);
+        store.set("t1", "int".into(), 42.into()).unwrap();
+        store.set("t1", "bin".into(), b"\x00\xff".into()).unwrap();
+        assert_eq!(store.get("t1", "int").unwrap(), Some(42.into()));
+        assert_eq!(store.get("t1", "bin").unwrap(), Some(b"\x00\xff".into()));
+
+        // 旧版本按字符串保存的 value 仍然能读出来
+        store.0.insert("t1:old", "hello").unwrap();
+        assert_eq!(store.get("t1", "old").unwrap(), Some("hello".into()));
+        assert_eq!(store.get_all("t1").unwrap().len(), 3);
+    }
 }
@@ -1,8 +1,9 @@
 //! 定义数据库与指令接口
 //!
+use std::collections::BTreeMap;
+
 use crate::error::*;
 use crate::pb::*;
-use crate::storage::MemTable;
 pub trait Storage: Send + Sync + 'static {
     // 从表里取数据
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError>;
@@ -16,36 +17,121 @@ pub trait Storage: Send + Sync + 'static {
     fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError>;
     // 把数据转为迭代器，方便遍历，值有多种类型，但是都会实现迭代器trait,并且类型是Kvpair
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError>;
+    // 按 key 的顺序返回 table 中大于 after 的最多 count 个 kvpair，用于分页读取
+    // 默认遍历整个 table，只保留最小的 count 个 key，有序的存储可以覆盖它
+    fn scan(&self, table: &str, after: &str, count: usize) -> Result<Vec<Kvpair>, KvError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut selected = BTreeMap::new();
+        for pair in self.get_iter(table)? {
+            if pair.key.as_str() <= after {
+                continue;
+            }
+            // 已经选满时，比选中的最大 key 还大的直接跳过
+            if selected.len() == count {
+                match selected.keys().next_back() {
+                    Some(last) if pair.key >= *last => continue,
+                    _ => {
+                        selected.pop_last();
+                    }
+                }
+            }
+            selected.insert(pair.key, pair.value);
+        }
+        Ok(selected
+            .into_iter()
+            .map(|(key, value)| Kvpair { key, value })
+            .collect())
+    }
+    // 统计每个 table 的 key 个数和占用的空间，需要遍历所有数据
+    fn stats(&self) -> Result<StorageStats, KvError>;
+    // 存储后端的名字，用于 INFO 等运维命令
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+    // 把缓存的写入刷到磁盘，内存存储什么都不用做
+    fn flush(&self) -> Result<(), KvError> {
+        Ok(())
+    }
+    // 按顺序执行一组写操作，返回每个操作之前的值，出错时一个都不生效
+    // 默认逐条执行，出错时倒序恢复已经执行的操作之前的值，恢复也失败时返回 Internal 错误
+    // 支持批量写入的存储可以覆盖它
+    fn apply_batch(&self, ops: Vec<WriteOp>) -> Result<Vec<Option<Value>>, KvError> {
+        let mut olds = Vec::with_capacity(ops.len());
+        for op in &ops {
+            let (table, key) = op.target();
+            let old = match op {
+                WriteOp::Set { value, .. } => self.set(table, key.into(), value.clone()),
+                WriteOp::Del { .. } => self.del(table, key),
+            };
+            match old {
+                Ok(old) => olds.push(old),
+                Err(e) => {
+                    let mut undo_errors = Vec::new();
+                    for (op, old) in ops.iter().zip(olds).rev() {
+                        let (table, key) = op.target();
+                        let undone = match old {
+                            Some(old) => self.set(table, key.into(), old),
+                            None => self.del(table, key),
+                        };
+                        // 恢复失败时继续恢复其它操作，最后一起报告
+                        if let Err(undo) = undone {
+                            undo_errors.push(format!("{}/{}: {}", table, key, undo));
+                        }
+                    }
+                    if !undo_errors.is_empty() {
+                        return Err(KvError::Internal(format!(
+                            "{}, and rollback failed, the batch is partially applied: {}",
+                            e,
+                            undo_errors.join("; ")
+                        )));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(olds)
+    }
 }
 
-// 使用泛型结构体包裹类型
-pub struct StorageIter<T> {
-    data: T,
+/// 存储的统计信息
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StorageStats {
+    // table -> key 个数
+    pub tables: BTreeMap<String, u64>,
+    // 所有 key 和编码后的 value 的总字节数
+    pub bytes: u64,
 }
 
-impl<T> StorageIter<T> {
-    pub fn new(data: T) -> Self {
-        Self { data }
-    }
+/// 批量写入中的单个写操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOp {
+    Set {
+        table: String,
+        key: String,
+        value: Value,
+    },
+    Del {
+        table: String,
+        key: String,
+    },
 }
 
-impl<T> Iterator for StorageIter<T>
-where
-    T: Iterator,
-    T::Item: Into<Kvpair>,
-{
-    type Item = Kvpair;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.data.next().map(|v| v.into())
+impl WriteOp {
+    /// 写操作修改的 table 和 key
+    pub fn target(&self) -> (&str, &str) {
+        match self {
+            WriteOp::Set { table, key, .. } | WriteOp::Del { table, key } => (table, key),
+        }
     }
 }
 
 // 单元测试
 #[cfg(test)]
-
 mod tests {
     use super::*;
+    use crate::storage::MemTable;
 
     #[test]
     fn memtable_get_all_should_work() {
@@ -59,6 +145,24 @@ mod tests {
         test_get_iter(store);
     }
 
+    #[test]
+    fn memtable_apply_batch_should_work() {
+        let store = MemTable::new();
+        test_apply_batch(store);
+    }
+
+    #[test]
+    fn memtable_scan_should_work() {
+        let store = MemTable::new();
+        test_scan(store);
+    }
+
+    #[test]
+    fn memtable_stats_should_work() {
+        let store = MemTable::new();
+        test_stats(store);
+    }
+
     #[test]
     fn memtable_basic_interface_should_work() {
         let store = MemTable::new();
@@ -80,9 +184,9 @@ mod tests {
         assert_eq!(store.get("t1", "k2").unwrap(), None); // 有表无键
         assert!(store.get("t2", "k1").unwrap().is_none()); // 无表
                                                            // contains 纯在的 key 返回 true，否则 false
-        assert_eq!(store.contains("t1", "k1").unwrap(), true); // contains 用在map上
-        assert_eq!(store.contains("t1", "k2").unwrap(), false);
-        assert_eq!(store.contains("t2", "k1").unwrap(), false);
+        assert!(store.contains("t1", "k1").unwrap()); // contains 用在map上
+        assert!(!store.contains("t1", "k2").unwrap());
+        assert!(!store.contains("t2", "k1").unwrap());
         // del 存在的 key 返回删掉的值
         let v = store.del("t1", "k1");
         assert_eq!(v.unwrap(), Some("v1".into()));
@@ -124,4 +228,161 @@ mod tests {
             ]
         );
     }
+
+    fn test_scan(store: impl Storage) {
+        for key in ["k3", "k1", "k:2", "k2"] {
+            store.set("t4", key.into(), key.into()).unwrap();
+        }
+        store.set("t5", "k0".into(), "v0".into()).unwrap();
+
+        let keys = |pairs: Vec<Kvpair>| pairs.into_iter().map(|p| p.key).collect::<Vec<_>>();
+        assert_eq!(keys(store.scan("t4", "", 2).unwrap()), ["k1", "k2"]);
+        assert_eq!(keys(store.scan("t4", "k2", 2).unwrap()), ["k3", "k:2"]);
+        assert!(store.scan("t4", "k:2", 2).unwrap().is_empty());
+        assert_eq!(
+            store.scan("t4", "k2", 1).unwrap(),
+            vec![Kvpair::new("k3", "k3".into())]
+        );
+    }
+
+    #[test]
+    fn default_scan_should_page_through_unordered_table() {
+        let store = MemTable::new();
+        // 以打乱的顺序写入 100 个 key
+        for i in 0..100 {
+            let key = format!("k{:03}", i * 37 % 100);
+            store.set("t1", key, i.into()).unwrap();
+        }
+        assert!(store.scan("t1", "", 0).unwrap().is_empty());
+
+        let mut keys = Vec::new();
+        let mut after = String::new();
+        loop {
+            let page = store.scan("t1", &after, 7).unwrap();
+            if page.is_empty() {
+                break;
+            }
+            after = page.last().unwrap().key.clone();
+            keys.extend(page.into_iter().map(|p| p.key));
+        }
+        let expected: Vec<_> = (0..100).map(|i| format!("k{:03}", i)).collect();
+        assert_eq!(keys, expected);
+    }
+
+    fn test_stats(store: impl Storage) {
+        store.set("t1", "k1".into(), "v1".into()).unwrap();
+        store.set("t1", "k2".into(), "v2".into()).unwrap();
+        store.set("t2", "k1".into(), 1.into()).unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.tables.get("t1"), Some(&2));
+        assert_eq!(stats.tables.get("t2"), Some(&1));
+        assert!(stats.bytes > 0);
+    }
+
+    // 写入 key 为 bad 时出错
+    struct FailingStore(MemTable);
+
+    impl Storage for FailingStore {
+        fn get(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.0.get(table, key)
+        }
+        fn set(&self, table: &str, key: String, value: Value) -> Result<Option<Value>, KvError> {
+            if key == "bad" {
+                return Err(KvError::Internal("cannot write bad".into()));
+            }
+            self.0.set(table, key, value)
+        }
+        fn contains(&self, table: &str, key: &str) -> Result<bool, KvError> {
+            self.0.contains(table, key)
+        }
+        fn del(&self, table: &str, key: &str) -> Result<Option<Value>, KvError> {
+            self.0.del(table, key)
+        }
+        fn get_all(&self, table: &str) -> Result<Vec<Kvpair>, KvError> {
+            self.0.get_all(table)
+        }
+        fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = Kvpair>>, KvError> {
+            self.0.get_iter(table)
+        }
+        fn stats(&self) -> Result<StorageStats, KvError> {
+            self.0.stats()
+        }
+    }
+
+    #[test]
+    fn failed_batch_should_be_rolled_back() {
+        // 没有出错时和逐条执行一样
+        test_apply_batch(FailingStore(MemTable::new()));
+
+        let store = FailingStore(MemTable::new());
+        store.set("t1", "k1".into(), "v0".into()).unwrap();
+        store.set("t1", "k2".into(), "v0".into()).unwrap();
+        let set = |key: &str, value: &str| WriteOp::Set {
+            table: "t1".into(),
+            key: key.into(),
+            value: value.into(),
+        };
+        let ops = vec![
+            set("k1", "v1"),
+            WriteOp::Del {
+                table: "t1".into(),
+                key: "k2".into(),
+            },
+            set("k3", "v3"),
+            set("k1", "v2"),
+            set("bad", "v"),
+        ];
+        assert!(store.apply_batch(ops).is_err());
+        // 出错之前执行的写入都撤销了
+        assert_eq!(store.get("t1", "k1").unwrap(), Some("v0".into()));
+        assert_eq!(store.get("t1", "k2").unwrap(), Some("v0".into()));
+        assert_eq!(store.get("t1", "k3").unwrap(), None);
+    }
+
+    #[test]
+    fn failed_rollback_should_be_reported() {
+        let store = FailingStore(MemTable::new());
+        store.0.set("t1", "bad".into(), "v0".into()).unwrap();
+        let ops = vec![
+            WriteOp::Del {
+                table: "t1".into(),
+                key: "bad".into(),
+            },
+            WriteOp::Set {
+                table: "t1".into(),
+                key: "bad".into(),
+                value: "v1".into(),
+            },
+        ];
+        // 删除之后写入失败，恢复 bad 时也失败了
+        let err = store.apply_batch(ops).unwrap_err().to_string();
+        assert!(err.contains("rollback failed"), "{}", err);
+        assert!(err.contains("t1/bad"), "{}", err);
+    }
+
+    fn test_apply_batch(store: impl Storage) {
+        store.set("t3", "k1".into(), "v0".into()).unwrap();
+        let ops = vec![
+            WriteOp::Set {
+                table: "t3".into(),
+                key: "k1".into(),
+                value: "v1".into(),
+            },
+            WriteOp::Set {
+                table: "t3".into(),
+                key: "k2".into(),
+                value: "v2".into(),
+            },
+            WriteOp::Del {
+                table: "t3".into(),
+                key: "k1".into(),
+            },
+        ];
+        let result = store.apply_batch(ops).unwrap();
+        // 同一批中后面的操作能看到前面写入的值
+        assert_eq!(result, vec![Some("v0".into()), None, Some("v1".into())]);
+        assert!(!store.contains("t3", "k1").unwrap());
+        assert_eq!(store.get("t3", "k2").unwrap(), Some("v2".into()));
+    }
 }
@@ -0,0 +1,213 @@
+//! 初始化日志和链路追踪：日志写入滚动文件，span 按配置导出到 stdout、Jaeger 或 OTLP collector。
+//! 命令和订阅消息中带有 W3C trace context，客户端和服务器的 span 可以连成一条链路
+//!
+
+use anyhow::Result;
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector, TextMapPropagator},
+    sdk::{
+        propagation::TraceContextPropagator,
+        trace::{self, Sampler},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Span;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::RollingFileAppender;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{
+    fmt::{self, format},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    EnvFilter,
+};
+
+use crate::{
+    LogConfig, LogFormat, RotationConfig, ServerConfig, TraceContext, TraceExporter, TracingConfig,
+};
+
+const SERVICE_NAME: &str = "kv-server";
+const TRACEPARENT: &str = "traceparent";
+const TRACESTATE: &str = "tracestate";
+
+/// 持有日志的后台写线程，drop 时把缓存的日志和 span 都输出完
+pub struct TracingGuard {
+    _file: WorkerGuard,
+    exporter: TraceExporter,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        if self.exporter != TraceExporter::None {
+            global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// 按配置安装全局的 tracing subscriber，需要在 tokio runtime 中调用
+pub fn init_tracing(config: &ServerConfig) -> Result<TracingGuard> {
+    let file_appender = file_appender(&config.log, "server.log");
+    let (non_blocking, guard) = tracing_appender::non_blocking(file_appender);
+
+    // 两种格式的 layer 类型不同，用 Option 只启用其中一个
+    let tracing = &config.tracing;
+    let (compact, json) = match tracing.format {
+        LogFormat::Compact => (
+            Some(
+                fmt::layer()
+                    .event_format(format().compact())
+                    .with_writer(non_blocking),
+            ),
+            None,
+        ),
+        LogFormat::Json => (None, Some(fmt::layer().json().with_writer(non_blocking))),
+    };
+
+    let filter =
+        EnvFilter::try_from_default_env().or_else(|_| EnvFilter::try_new(&tracing.level))?;
+
+    let opentelemetry =
+        install_tracer(tracing)?.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer));
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(compact)
+        .with(json)
+        .with(opentelemetry)
+        .try_init()?;
+
+    Ok(TracingGuard {
+        _file: guard,
+        exporter: tracing.exporter,
+    })
+}
+
+/// log.path 目录下按 log.rotation 滚动的文件，文件名以 file_name 开头
+pub fn file_appender(log: &LogConfig, file_name: &str) -> RollingFileAppender {
+    match log.rotation {
+        RotationConfig::Hourly => tracing_appender::rolling::hourly(&log.path, file_name),
+        RotationConfig::Daily => tracing_appender::rolling::daily(&log.path, file_name),
+        RotationConfig::Never => tracing_appender::rolling::never(&log.path, file_name),
+    }
+}
+
+// 创建 exporter 并设置全局的 tracer provider，不导出 span 时返回 None。Jaeger 和 OTLP 在后台批量发送，不会阻塞请求
+fn install_tracer(config: &TracingConfig) -> Result<Option<trace::Tracer>> {
+    let trace_config = trace::config()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            SERVICE_NAME,
+        )]));
+
+    let tracer = match config.exporter {
+        TraceExporter::Stdout => opentelemetry::sdk::export::trace::stdout::new_pipeline()
+            .with_trace_config(trace_config)
+            .install_simple(),
+        TraceExporter::Jaeger => {
+            let mut pipeline = opentelemetry_jaeger::new_pipeline()
+                .with_service_name(SERVICE_NAME)
+                .with_trace_config(trace_config);
+            if let Some(endpoint) = &config.endpoint {
+                pipeline = pipeline.with_agent_endpoint(endpoint.as_str());
+            }
+            pipeline.install_batch(opentelemetry::runtime::Tokio)?
+        }
+        TraceExporter::Otlp => {
+            let mut exporter = opentelemetry_otlp::new_exporter().tonic();
+            if let Some(endpoint) = &config.endpoint {
+                exporter = exporter.with_endpoint(endpoint.as_str());
+            }
+            opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(exporter)
+                .with_trace_config(trace_config)
+                .install_batch(opentelemetry::runtime::Tokio)?
+        }
+        TraceExporter::None => return Ok(None),
+    };
+
+    Ok(Some(tracer))
+}
+
+/// 当前 span 的 trace context，没有安装 OpenTelemetry layer 或者 span 没有被采样时返回 None
+pub fn current_trace_context() -> Option<TraceContext> {
+    let mut trace = TraceContext::default();
+    TraceContextPropagator::new().inject_context(&Span::current().context(), &mut trace);
+    if trace.traceparent.is_empty() {
+        None
+    } else {
+        Some(trace)
+    }
+}
+
+/// 把对端传来的 trace context 设为 span 的父节点
+pub fn set_trace_parent(span: &Span, trace: &TraceContext) {
+    span.set_parent(TraceContextPropagator::new().extract(trace));
+}
+
+impl Injector for TraceContext {
+    fn set(&mut self, key: &str, value: String) {
+        match key {
+            TRACEPARENT => self.traceparent = value,
+            TRACESTATE => self.tracestate = value,
+            _ => {}
+        }
+    }
+}
+
+impl Extractor for TraceContext {
+    fn get(&self, key: &str) -> Option<&str> {
+        let value = match key {
+            TRACEPARENT => &self.traceparent,
+            TRACESTATE => &self.tracestate,
+            _ => return None,
+        };
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        vec![TRACEPARENT, TRACESTATE]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider};
+    use tracing::info_span;
+    use tracing_subscriber::Registry;
+
+    #[test]
+    fn trace_context_should_propagate() {
+        // tracer 只持有 provider 的弱引用，provider 要一直存在
+        let provider = opentelemetry::sdk::trace::TracerProvider::builder().build();
+        let tracer = provider.tracer("test", None);
+        let subscriber =
+            Registry::default().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+        tracing::subscriber::with_default(subscriber, || {
+            // 没有 span 时不传递
+            assert_eq!(current_trace_context(), None);
+
+            let client = info_span!("client");
+            let trace = client.in_scope(current_trace_context).unwrap();
+            assert!(trace.traceparent.starts_with("00-"));
+
+            let server = info_span!("server");
+            set_trace_parent(&server, &trace);
+            let client_id = client.context().span().span_context().trace_id();
+            let server_id = server.context().span().span_context().trace_id();
+            assert_eq!(client_id, server_id);
+        });
+    }
+}
@@ -0,0 +1,561 @@
+//! 导入导出：kv-export 和 kv-import 使用的 JSON Lines、CSV 格式，以及断点续传和进度
+//!
+//! 每条记录是 table、key 和 value，value 的类型原样保留：
+//! - JSON Lines：`{"table":"t1","key":"k1","value":42}`，value 的写法见 [`value_to_json`]
+//! - CSV：表头是 `table,key,type,value`，type 是 string、binary、integer、float、bool 或 null，
+//!   binary 的 value 是 base64
+
+use std::fs;
+use std::future::Future;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use tokio::time;
+use tracing::warn;
+
+use crate::{value, value_to_json, KvError, Value};
+
+const CSV_HEADER: [&str; 4] = ["table", "key", "type", "value"];
+// 重试的初始间隔和最大间隔
+const RETRY_INITIAL_DELAY: Duration = Duration::from_millis(200);
+const RETRY_MAX_DELAY: Duration = Duration::from_secs(5);
+// 输出进度的间隔
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 导入导出的文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DataFormat {
+    /// 每行一个 JSON 对象
+    #[default]
+    JsonLines,
+    /// 带表头的 CSV
+    Csv,
+}
+
+impl FromStr for DataFormat {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Self::JsonLines),
+            "csv" => Ok(Self::Csv),
+            _ => Err(KvError::InvalidCommand(format!(
+                "Unknown format: {} (expected jsonl or csv)",
+                s
+            ))),
+        }
+    }
+}
+
+impl DataFormat {
+    /// 根据扩展名判断文件格式，.csv 之外都当作 JSON Lines
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("csv") => Self::Csv,
+            _ => Self::JsonLines,
+        }
+    }
+}
+
+/// 导入导出的一条记录
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Record {
+    pub table: String,
+    pub key: String,
+    pub value: Value,
+}
+
+#[derive(Serialize)]
+struct JsonRecordRef<'a> {
+    table: &'a str,
+    key: &'a str,
+    value: Json,
+}
+
+#[derive(Deserialize)]
+struct JsonRecord {
+    table: String,
+    key: String,
+    // 没有 value 时是 null
+    #[serde(default)]
+    value: Json,
+}
+
+/// 按格式写出记录
+pub struct RecordWriter<W: Write> {
+    inner: WriterInner<W>,
+    written: Arc<AtomicU64>,
+}
+
+enum WriterInner<W: Write> {
+    JsonLines(BufWriter<Counter<W>>),
+    Csv(Box<csv::Writer<Counter<W>>>),
+}
+
+// 统计写入的字节数，用来记录断点续传的位置
+struct Counter<W> {
+    inner: W,
+    written: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for Counter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// offset 是 writer 中已经有的字节数，续传时接着写，为 0 时 CSV 先写表头
+    pub fn new(writer: W, format: DataFormat, offset: u64) -> Result<Self, KvError> {
+        let written = Arc::new(AtomicU64::new(offset));
+        let counter = Counter {
+            inner: writer,
+            written: written.clone(),
+        };
+        let inner = match format {
+            DataFormat::JsonLines => WriterInner::JsonLines(BufWriter::new(counter)),
+            DataFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(counter);
+                if offset == 0 {
+                    writer.write_record(CSV_HEADER).map_err(csv_error)?;
+                }
+                WriterInner::Csv(Box::new(writer))
+            }
+        };
+        Ok(Self { inner, written })
+    }
+
+    pub fn write(&mut self, record: &Record) -> Result<(), KvError> {
+        match &mut self.inner {
+            WriterInner::JsonLines(writer) => {
+                let record = JsonRecordRef {
+                    table: &record.table,
+                    key: &record.key,
+                    value: value_to_json(&record.value),
+                };
+                serde_json::to_writer(&mut *writer, &record)
+                    .map_err(|e| KvError::InvalidRecord(e.to_string()))?;
+                writer.write_all(b"\n")?;
+            }
+            WriterInner::Csv(writer) => {
+                let (kind, text) = value_to_csv(&record.value);
+                writer
+                    .write_record([&record.table, &record.key, kind, &text])
+                    .map_err(csv_error)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 把缓存的记录写入 writer，返回 writer 中的总字节数
+    pub fn flush(&mut self) -> Result<u64, KvError> {
+        match &mut self.inner {
+            WriterInner::JsonLines(writer) => writer.flush()?,
+            WriterInner::Csv(writer) => writer.flush()?,
+        }
+        Ok(self.written.load(Ordering::Relaxed))
+    }
+}
+
+/// 按格式读出记录
+pub struct RecordReader<R: Read> {
+    inner: ReaderInner<R>,
+}
+
+enum ReaderInner<R: Read> {
+    // 同时记录行号，用于错误信息
+    JsonLines(io::Lines<BufReader<R>>, u64),
+    Csv(csv::StringRecordsIntoIter<R>),
+}
+
+impl<R: Read> RecordReader<R> {
+    /// CSV 需要先读出并检查表头
+    pub fn new(reader: R, format: DataFormat) -> Result<Self, KvError> {
+        let inner = match format {
+            DataFormat::JsonLines => ReaderInner::JsonLines(BufReader::new(reader).lines(), 0),
+            DataFormat::Csv => {
+                let mut reader = csv::Reader::from_reader(reader);
+                let headers = reader.headers().map_err(csv_error)?;
+                if headers.iter().ne(CSV_HEADER) {
+                    return Err(KvError::InvalidRecord(format!(
+                        "CSV header should be {}",
+                        CSV_HEADER.join(",")
+                    )));
+                }
+                ReaderInner::Csv(reader.into_records())
+            }
+        };
+        Ok(Self { inner })
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Record, KvError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.inner {
+            ReaderInner::JsonLines(lines, line_no) => loop {
+                *line_no += 1;
+                let line = match lines.next()? {
+                    Ok(line) => line,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                // 跳过空行
+                if line.trim().is_empty() {
+                    continue;
+                }
+                return Some(
+                    parse_json_record(&line)
+                        .map_err(|e| KvError::InvalidRecord(format!("line {}: {}", line_no, e))),
+                );
+            },
+            ReaderInner::Csv(records) => {
+                let record = match records.next()? {
+                    Ok(record) => record,
+                    Err(e) => return Some(Err(csv_error(e))),
+                };
+                let line = record.position().map_or(0, |p| p.line());
+                Some(match record.iter().collect::<Vec<_>>().as_slice() {
+                    [table, key, kind, text] => csv_to_value(kind, text)
+                        .map(|value| Record {
+                            table: table.to_string(),
+                            key: key.to_string(),
+                            value,
+                        })
+                        .map_err(|e| KvError::InvalidRecord(format!("line {}: {}", line, e))),
+                    _ => Err(KvError::InvalidRecord(format!(
+                        "line {}: expected 4 fields",
+                        line
+                    ))),
+                })
+            }
+        }
+    }
+}
+
+fn parse_json_record(line: &str) -> Result<Record, String> {
+    let record: JsonRecord = serde_json::from_str(line).map_err(|e| e.to_string())?;
+    Ok(Record {
+        table: record.table,
+        key: record.key,
+        value: json_to_value(&record.value)?,
+    })
+}
+
+/// [`value_to_json`] 的逆过程，整数和带小数点或指数的浮点数是不同的类型
+pub fn json_to_value(json: &Json) -> Result<Value, String> {
+    let value = match json {
+        Json::Null => Value::default(),
+        Json::Bool(b) => (*b).into(),
+        Json::String(s) => s.as_str().into(),
+        Json::Number(n) if n.is_f64() => n.as_f64().unwrap_or_default().into(),
+        Json::Number(n) => n
+            .as_i64()
+            .ok_or_else(|| format!("integer {} is out of range", n))?
+            .into(),
+        Json::Object(obj) if obj.len() == 1 => match obj.iter().next() {
+            Some((name, Json::String(data))) if name == "base64" => STANDARD
+                .decode(data)
+                .map(|data| Bytes::from(data).into())
+                .map_err(|e| format!("invalid base64: {}", e))?,
+            Some((name, Json::String(f))) if name == "float" => f
+                .parse::<f64>()
+                .map_err(|_| format!("invalid float: {}", f))?
+                .into(),
+            _ => return Err(format!("unsupported value: {}", json)),
+        },
+        _ => return Err(format!("unsupported value: {}", json)),
+    };
+    Ok(value)
+}
+
+// CSV 中的类型和文本，浮点数用 {:?} 保证能原样解析回来
+fn value_to_csv(v: &Value) -> (&'static str, String) {
+    match &v.value {
+        None => ("null", String::new()),
+        Some(value::Value::String(s)) => ("string", s.clone()),
+        Some(value::Value::Binary(b)) => ("binary", STANDARD.encode(b)),
+        Some(value::Value::Integer(i)) => ("integer", i.to_string()),
+        Some(value::Value::Float(f)) => ("float", format!("{:?}", f)),
+        Some(value::Value::Bool(b)) => ("bool", b.to_string()),
+    }
+}
+
+fn csv_to_value(kind: &str, text: &str) -> Result<Value, String> {
+    let invalid = || format!("invalid {}: {}", kind, text);
+    let value = match kind {
+        "null" => Value::default(),
+        "string" => text.into(),
+        "binary" => Bytes::from(STANDARD.decode(text).map_err(|_| invalid())?).into(),
+        "integer" => text.parse::<i64>().map_err(|_| invalid())?.into(),
+        "float" => text.parse::<f64>().map_err(|_| invalid())?.into(),
+        "bool" => text.parse::<bool>().map_err(|_| invalid())?.into(),
+        _ => return Err(format!("unknown type: {}", kind)),
+    };
+    Ok(value)
+}
+
+fn csv_error(e: csv::Error) -> KvError {
+    if !e.is_io_error() {
+        return KvError::InvalidRecord(e.to_string());
+    }
+    match e.into_kind() {
+        csv::ErrorKind::Io(e) => e.into(),
+        kind => KvError::InvalidRecord(format!("{:?}", kind)),
+    }
+}
+
+/// 导出的断点：table 按名字的顺序导出，之前的 table 都已经导出完
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ExportCheckpoint {
+    pub table: String,
+    // 下一页的 cursor，为 None 时这个 table 也已经导出完
+    pub cursor: Option<String>,
+    pub records: u64,
+    // 输出文件中已经写完的字节数，续传时截掉之后的内容
+    pub offset: u64,
+}
+
+/// 导入的断点：文件中前 records 条记录已经写入
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ImportCheckpoint {
+    pub records: u64,
+}
+
+/// 数据文件对应的断点文件，比如 data.jsonl.checkpoint
+pub fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".checkpoint");
+    PathBuf::from(name)
+}
+
+/// 读取断点，文件不存在时返回 None
+pub fn load_checkpoint<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, KvError> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| KvError::InvalidRecord(format!("checkpoint {}: {}", path.display(), e)))
+}
+
+/// 保存断点，先写临时文件再改名，中途退出不会留下损坏的断点
+pub fn save_checkpoint<T: Serialize>(path: &Path, checkpoint: &T) -> Result<(), KvError> {
+    let data = serde_json::to_vec(checkpoint).map_err(|e| KvError::InvalidRecord(e.to_string()))?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// 执行 f，遇到可以重试的错误时按指数退避最多重试 retries 次
+pub async fn with_retry<T, F, Fut>(retries: u32, mut f: F) -> Result<T, KvError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, KvError>>,
+{
+    let mut delay = RETRY_INITIAL_DELAY;
+    let mut attempt = 0;
+    loop {
+        match f().await {
+            Err(e) if e.is_retryable() && attempt < retries => {
+                attempt += 1;
+                warn!("{}, retrying ({}/{}) in {:?}", e, attempt, retries, delay);
+                time::sleep(delay).await;
+                delay = (delay * 2).min(RETRY_MAX_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// 在 stderr 上输出处理的记录数和速度，最多每秒一次
+pub struct Progress {
+    action: &'static str,
+    total: Option<u64>,
+    done: u64,
+    // 续传时之前已经处理的记录数，不计入速度
+    resumed: u64,
+    started: Instant,
+    reported: Instant,
+    quiet: bool,
+}
+
+impl Progress {
+    pub fn new(action: &'static str, total: Option<u64>, resumed: u64, quiet: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            action,
+            total,
+            done: resumed,
+            resumed,
+            started: now,
+            reported: now,
+            quiet,
+        }
+    }
+
+    /// 已经处理的记录数，包括续传之前的
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    pub fn add(&mut self, records: u64) {
+        self.done += records;
+        if self.reported.elapsed() >= PROGRESS_INTERVAL {
+            self.report();
+        }
+    }
+
+    /// 输出最终的结果
+    pub fn finish(&mut self) {
+        self.report();
+    }
+
+    fn report(&mut self) {
+        self.reported = Instant::now();
+        if self.quiet {
+            return;
+        }
+        let secs = self.started.elapsed().as_secs_f64().max(0.001);
+        let rate = (self.done - self.resumed) as f64 / secs;
+        match self.total {
+            Some(total) if total > 0 => eprintln!(
+                "{} {}/{} records ({:.1}%), {:.0} records/s",
+                self.action,
+                self.done,
+                total,
+                self.done as f64 * 100.0 / total as f64,
+                rate
+            ),
+            _ => eprintln!(
+                "{} {} records, {:.0} records/s",
+                self.action, self.done, rate
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn records() -> Vec<Record> {
+        let values: Vec<Value> = vec![
+            "hello, \"world\"\n".into(),
+            Bytes::from_static(b"\x00\xff").into(),
+            42.into(),
+            (-1.5).into(),
+            3.0.into(),
+            f64::NAN.into(),
+            f64::NEG_INFINITY.into(),
+            true.into(),
+            Value::default(),
+        ];
+        values
+            .into_iter()
+            .enumerate()
+            .map(|(i, value)| Record {
+                table: "t1".into(),
+                key: format!("k:{}", i),
+                value,
+            })
+            .collect()
+    }
+
+    fn round_trip(format: DataFormat) -> Vec<Record> {
+        let mut writer = RecordWriter::new(Vec::new(), format, 0).unwrap();
+        for record in records() {
+            writer.write(&record).unwrap();
+        }
+        writer.flush().unwrap();
+        let data = match writer.inner {
+            WriterInner::JsonLines(w) => w.into_inner().ok().unwrap().inner,
+            WriterInner::Csv(w) => w.into_inner().ok().unwrap().inner,
+        };
+        RecordReader::new(&data[..], format)
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    fn assert_same(actual: Vec<Record>) {
+        let expected = records();
+        assert_eq!(actual.len(), expected.len());
+        for (a, b) in actual.iter().zip(&expected) {
+            // NaN 和自己不相等，比较编码后的形式
+            assert_eq!(value_to_csv(&a.value), value_to_csv(&b.value));
+            assert_eq!(a.value.value.is_some(), b.value.value.is_some());
+            assert_eq!((&a.table, &a.key), (&b.table, &b.key));
+        }
+        assert_eq!(actual[4].value, 3.0.into());
+        assert_eq!(actual[2].value, 42.into());
+    }
+
+    #[test]
+    fn records_should_round_trip_through_json_lines() {
+        assert_same(round_trip(DataFormat::JsonLines));
+    }
+
+    #[test]
+    fn records_should_round_trip_through_csv() {
+        assert_same(round_trip(DataFormat::Csv));
+    }
+
+    #[test]
+    fn invalid_records_should_be_rejected() {
+        let data = "{\"table\":\"t1\",\"key\":\"k1\",\"value\":1}\n\n{\"table\":\"t1\"}\n";
+        let mut reader = RecordReader::new(data.as_bytes(), DataFormat::JsonLines).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("line 3"));
+
+        let data = "table,key,type,value\nt1,k1,integer,abc\n";
+        let mut reader = RecordReader::new(data.as_bytes(), DataFormat::Csv).unwrap();
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(err.to_string().contains("line 2: invalid integer"));
+        assert!(RecordReader::new("a,b\n".as_bytes(), DataFormat::Csv).is_err());
+    }
+
+    #[test]
+    fn checkpoint_should_be_saved_and_loaded() {
+        let dir = tempdir().unwrap();
+        let path = checkpoint_path(&dir.path().join("data.csv"));
+        assert!(path.ends_with("data.csv.checkpoint"));
+        assert_eq!(load_checkpoint::<ExportCheckpoint>(&path).unwrap(), None);
+
+        let checkpoint = ExportCheckpoint {
+            table: "t1".into(),
+            cursor: Some("k9".into()),
+            records: 10,
+            offset: 120,
+        };
+        save_checkpoint(&path, &checkpoint).unwrap();
+        assert_eq!(load_checkpoint(&path).unwrap(), Some(checkpoint));
+        assert_eq!(DataFormat::from_path(Path::new("a.CSV")), DataFormat::Csv);
+        assert_eq!(
+            DataFormat::from_path(Path::new("a.jsonl")),
+            DataFormat::JsonLines
+        );
+    }
+}